@@ -0,0 +1,95 @@
+//! Dispatch latency and allocation counts.
+//!
+//! Run with `cargo bench --bench dispatch`. Each case dispatches the same
+//! event loop many times and reports the mean time and heap allocations per
+//! dispatch, counted by a wrapping global allocator.
+
+use fsmportal::generic::{Response, StateMachine};
+use fsmportal::{CallEvent, CallState};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const DISPATCHES: usize = 200_000;
+
+/// Dispatches `events` round-robin and prints per-dispatch figures.
+fn bench(name: &str, mut sm: StateMachine<CallState, CallEvent, ()>, events: &[CallEvent]) {
+    for event in events.iter().cycle().take(1_000) {
+        black_box(sm.dispatch(event).ok());
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for event in events.iter().cycle().take(DISPATCHES) {
+        black_box(sm.dispatch(event).ok());
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{:<22} {:>8.1} ns/dispatch {:>6.2} allocs/dispatch",
+        name,
+        elapsed.as_nanos() as f64 / DISPATCHES as f64,
+        allocations as f64 / DISPATCHES as f64
+    );
+}
+
+fn flat() -> StateMachine<CallState, CallEvent, ()> {
+    let mut sm = StateMachine::new(CallState::Idle, ());
+    sm.add_transition_to(CallState::Idle, CallEvent::Dial, CallState::Dialing);
+    sm.add_transition_to(CallState::Dialing, CallEvent::HangUp, CallState::Idle);
+    sm
+}
+
+fn guarded() -> StateMachine<CallState, CallEvent, ()> {
+    let mut sm = flat();
+    sm.add_guarded_transition(
+        CallState::Idle,
+        CallEvent::Incoming,
+        |_| true,
+        |_, _| Ok(Response::Transition(CallState::Ringing)),
+    );
+    sm.add_transition_to(CallState::Ringing, CallEvent::HangUp, CallState::Idle);
+    sm
+}
+
+fn hierarchical() -> StateMachine<CallState, CallEvent, ()> {
+    let mut sm = StateMachine::new(CallState::Ringing, ());
+    sm.add_substate(CallState::Connected, CallState::Ringing);
+    sm.add_substate(CallState::Connected, CallState::Dialing);
+    sm.add_transition_to(CallState::Ringing, CallEvent::Answer, CallState::Dialing);
+    sm.add_transition_to(CallState::Connected, CallEvent::Reset, CallState::Ringing);
+    sm
+}
+
+fn main() {
+    bench("flat", flat(), &[CallEvent::Dial, CallEvent::HangUp]);
+    bench(
+        "guarded",
+        guarded(),
+        &[CallEvent::Incoming, CallEvent::HangUp],
+    );
+    bench(
+        "hierarchical",
+        hierarchical(),
+        &[CallEvent::Answer, CallEvent::Reset],
+    );
+    bench("unhandled", flat(), &[CallEvent::Answer]);
+}
@@ -0,0 +1,45 @@
+/* C interface to fsmportal machines, built with the `ffi` feature. */
+
+#ifndef FSMPORTAL_H
+#define FSMPORTAL_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define FSM_OK 0
+/* The current state has no transition for the event. */
+#define FSM_NO_TRANSITION 1
+/* The event is not named in the spec, or its id is out of range. */
+#define FSM_UNKNOWN_EVENT 2
+/* Dispatch failed for another reason, such as a rejected event or a panic. */
+#define FSM_ERROR 3
+#define FSM_INVALID_ARGUMENT -1
+
+/* An opaque machine handle. */
+typedef struct FsmMachine FsmMachine;
+
+/* Builds a machine from spec text, or returns NULL if it does not parse. */
+FsmMachine *fsm_machine_from_spec(const char *spec);
+
+void fsm_machine_free(FsmMachine *machine);
+
+/* Dispatches an event by name. */
+int fsm_dispatch(FsmMachine *machine, const char *event);
+
+/* Dispatches an event by id: its index among the spec's sorted event names. */
+int fsm_dispatch_id(FsmMachine *machine, uint32_t event);
+
+/* The current state's name, valid until the next dispatch or free. */
+const char *fsm_current_state(const FsmMachine *machine);
+
+/* The current state's index among the spec's sorted state names, or -1. */
+int32_t fsm_current_state_id(const FsmMachine *machine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* FSMPORTAL_H */
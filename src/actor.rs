@@ -0,0 +1,1079 @@
+//! Running a machine on its own thread.
+//!
+//! [`ActorHandle::spawn`] moves the machine onto a dedicated thread that
+//! processes commands one at a time, in the order they were sent. Handles are
+//! cheap to clone; the thread stops once every handle has been dropped, or
+//! when [`ActorHandle::abort`] is called.
+//!
+//! A [`Throttle`] installed with [`ActorHandle::throttled`] is shared by all
+//! clones of the handle and checked before events are queued.
+//!
+//! Events are dispatched with [`StateMachine::dispatch_async`], so async
+//! handlers run on the actor thread with a fresh [`CancellationToken`] per
+//! command. [`ActorHandle::cancel_current`] and [`ActorHandle::abort`] cancel
+//! the command in progress.
+//!
+//! Events marked with [`ActorHandle::preemptive`] skip ahead of everything
+//! else waiting in the mailbox and cancel an in-flight async handler for a
+//! non-preemptive event. A synchronous handler in progress still finishes first.
+//!
+//! [`ActorHandle::spawn_bounded`] caps the mailbox at a number of queued
+//! commands; a sender finding it full blocks, is refused or evicts the oldest
+//! command, per its [`Overflow`] policy. Preemptive events are never held back.
+//! [`ActorHandle::mailbox_metrics`] reports the queue depth and what the
+//! policy has done.
+//!
+//! [`ActorHandle::shutdown`] stops an actor cleanly: new commands are refused
+//! at once, queued ones are processed or dropped per its [`Drain`] mode, and an
+//! optional deadline aborts whatever is still running when it passes. The
+//! actor reports [`ActorStatus::Stopped`] once its thread has exited.
+//!
+//! Events scheduled through a [`DispatchCtx`](crate::dispatch_ctx::DispatchCtx)
+//! are dispatched on the actor thread as they fall due, ahead of the next
+//! command; their results wait in
+//! [`take_follow_ups`](StateMachine::take_follow_ups).
+//!
+//! A [`WeakHandle`], from [`ActorHandle::downgrade`], refers to an actor
+//! without keeping it running, for monitors that should not outlive the
+//! sessions they watch.
+
+use crate::audit::AuditContext;
+use crate::generic::{Event, HandlerResult, Response, State, StateMachine, StateMachineError};
+use crate::request::{oneshot, ReplyReceiver, ReplySender, RequestError};
+use crate::task::{block_on, timeout, CancellationToken};
+use crate::throttle::{Admission, OnExceeded, Throttle};
+use crate::view::{MachineView, Projection};
+use crate::watch;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+type Command<S, E, C, O> =
+    Box<dyn FnOnce(&mut StateMachine<S, E, C, O>, &CancellationToken) + Send>;
+
+/// What a bounded mailbox does with a command that finds it full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Block the sender until there is room. A handler sending to its own
+    /// full actor would wait forever.
+    Block,
+    /// Refuse the command: `dispatch` fails with
+    /// `StateMachineError::MailboxFull`, `send` drops the event and a
+    /// refused [`with`](ActorHandle::with) reports `Disconnected`.
+    Reject,
+    /// Evict the oldest queued command, whose reply reports `Disconnected`.
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxMetrics {
+    /// Commands waiting, preemptive ones included.
+    pub depth: usize,
+    /// The deepest the mailbox has been.
+    pub high_water: usize,
+    /// Commands evicted under [`Overflow::DropOldest`].
+    pub dropped: u64,
+    /// Commands refused under [`Overflow::Reject`].
+    pub rejected: u64,
+}
+
+struct Queue<T> {
+    urgent: VecDeque<T>,
+    normal: VecDeque<T>,
+    /// The most commands `normal` may hold, if bounded.
+    capacity: Option<usize>,
+    overflow: Overflow,
+    metrics: MailboxMetrics,
+    handles: usize,
+    stopped: bool,
+    /// Set by `shutdown`: no new commands, stop once the lanes are empty.
+    draining: bool,
+    /// Set once the actor thread has exited.
+    finished: bool,
+    /// Told when the actor thread exits.
+    on_finished: Vec<ReplySender<()>>,
+    /// The token of the command in progress, and whether it came from the urgent lane.
+    current: Option<(CancellationToken, bool)>,
+}
+
+impl<T> Queue<T> {
+    /// Whether the actor still has handles and takes new commands.
+    fn accepting(&self) -> bool {
+        self.handles > 0 && !self.stopped && !self.draining && !self.finished
+    }
+}
+
+/// The actor's two-lane queue; the thread drains `urgent` before `normal`.
+struct Mailbox<T> {
+    queue: Mutex<Queue<T>>,
+    ready: Condvar,
+    /// Signalled when a bounded `normal` lane has room again.
+    space: Condvar,
+    /// Signalled when the actor thread exits.
+    done: Condvar,
+}
+
+/// What the actor thread does next.
+enum Next<T> {
+    Run(T, CancellationToken),
+    /// A scheduled event fell due with no command waiting.
+    Wake,
+    Stop,
+}
+
+impl<T> Mailbox<T> {
+    /// Queues `command`, handing it back if the overflow policy refuses it.
+    fn push(&self, command: T, urgent: bool) -> Result<(), T> {
+        let mut queue = self.queue.lock().unwrap();
+        // Dropping the command of a stopped actor drops its reply sender.
+        if queue.stopped || queue.draining {
+            return Ok(());
+        }
+        if urgent {
+            if let Some((token, false)) = &queue.current {
+                token.cancel();
+            }
+            queue.urgent.push_back(command);
+        } else {
+            while queue
+                .capacity
+                .is_some_and(|capacity| queue.normal.len() >= capacity)
+            {
+                match queue.overflow {
+                    Overflow::Block => {
+                        queue = self.space.wait(queue).unwrap();
+                        if queue.stopped || queue.draining {
+                            return Ok(());
+                        }
+                    }
+                    Overflow::Reject => {
+                        queue.metrics.rejected += 1;
+                        return Err(command);
+                    }
+                    Overflow::DropOldest => {
+                        queue.normal.pop_front();
+                        queue.metrics.dropped += 1;
+                    }
+                }
+            }
+            queue.normal.push_back(command);
+        }
+        let depth = queue.urgent.len() + queue.normal.len();
+        queue.metrics.high_water = queue.metrics.high_water.max(depth);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    fn metrics(&self) -> MailboxMetrics {
+        let queue = self.queue.lock().unwrap();
+        MailboxMetrics {
+            depth: queue.urgent.len() + queue.normal.len(),
+            ..queue.metrics
+        }
+    }
+
+    /// Waits for the next command and makes it current, or for `due` to
+    /// pass if given.
+    fn pop(&self, due: Option<Duration>) -> Next<T> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.current = None;
+        loop {
+            if queue.stopped {
+                return Next::Stop;
+            }
+            let next = match queue.urgent.pop_front() {
+                Some(command) => Some((command, true)),
+                None => queue.normal.pop_front().map(|command| {
+                    self.space.notify_one();
+                    (command, false)
+                }),
+            };
+            if let Some((command, urgent)) = next {
+                let token = CancellationToken::new();
+                queue.current = Some((token.clone(), urgent));
+                return Next::Run(command, token);
+            }
+            if queue.handles == 0 || queue.draining {
+                return Next::Stop;
+            }
+            match due {
+                Some(due) => {
+                    let (guard, waited) = self.ready.wait_timeout(queue, due).unwrap();
+                    if waited.timed_out() {
+                        return Next::Wake;
+                    }
+                    queue = guard;
+                }
+                None => queue = self.ready.wait(queue).unwrap(),
+            }
+        }
+    }
+
+    fn stop(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.stopped = true;
+        if let Some((token, _)) = &queue.current {
+            token.cancel();
+        }
+        queue.urgent.clear();
+        queue.normal.clear();
+        self.ready.notify_all();
+        self.space.notify_all();
+    }
+
+    /// Refuses new commands and stops once the queued ones, if kept, are done.
+    fn drain(&self, drain: Drain) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.draining = true;
+        let discarded = match drain {
+            Drain::Queued => Default::default(),
+            Drain::Discard => (
+                std::mem::take(&mut queue.urgent),
+                std::mem::take(&mut queue.normal),
+            ),
+        };
+        self.ready.notify_all();
+        self.space.notify_all();
+        // Dropped unlocked, in case a command holds a handle.
+        drop(queue);
+        drop(discarded);
+    }
+
+    /// Stops the actor unless its thread exits within `deadline`.
+    fn stop_after(&self, deadline: Duration) {
+        let queue = self.queue.lock().unwrap();
+        let (queue, _) = self
+            .done
+            .wait_timeout_while(queue, deadline, |queue| !queue.finished)
+            .unwrap();
+        if !queue.finished {
+            drop(queue);
+            self.stop();
+        }
+    }
+
+    /// Called by the actor thread as it exits.
+    fn finish(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.stopped = true;
+        queue.finished = true;
+        for waiter in queue.on_finished.drain(..) {
+            let _ = waiter.send(());
+        }
+        self.done.notify_all();
+    }
+}
+
+/// What [`ActorHandle::shutdown`] does with commands already queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drain {
+    /// Process them before stopping.
+    Queued,
+    /// Drop them, so their replies report `Disconnected`; the command in
+    /// progress still finishes.
+    Discard,
+}
+
+/// Where an actor is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActorStatus {
+    Running,
+    /// Shut down or aborted, with the actor thread still finishing its work.
+    Stopping,
+    /// The actor thread has exited; no command will run again.
+    Stopped,
+}
+
+/// Why [`ActorHandle::wait_for_state`] resolved without reaching the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    TimedOut,
+    /// The actor stopped first.
+    Stopped,
+}
+
+pub struct ActorHandle<S, E, C = std::collections::HashMap<String, usize>, O = ()>
+where
+    S: State,
+    E: Event,
+{
+    mailbox: Arc<Mailbox<Command<S, E, C, O>>>,
+    throttle: Option<Arc<Mutex<Throttle<E>>>>,
+    preemptive: HashSet<E>,
+    state: watch::Receiver<Option<S>>,
+}
+
+impl<S, E, C, O> Clone for ActorHandle<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    fn clone(&self) -> Self {
+        self.mailbox.queue.lock().unwrap().handles += 1;
+        ActorHandle {
+            mailbox: self.mailbox.clone(),
+            throttle: self.throttle.clone(),
+            preemptive: self.preemptive.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S, E, C, O> Drop for ActorHandle<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    fn drop(&mut self) {
+        let mut queue = self.mailbox.queue.lock().unwrap();
+        queue.handles -= 1;
+        if queue.handles == 0 {
+            self.mailbox.ready.notify_all();
+        }
+    }
+}
+
+/// A handle that does not keep the actor running; see [`ActorHandle::downgrade`].
+pub struct WeakHandle<S, E, C = std::collections::HashMap<String, usize>, O = ()>
+where
+    S: State,
+    E: Event,
+{
+    mailbox: Weak<Mailbox<Command<S, E, C, O>>>,
+    throttle: Option<Arc<Mutex<Throttle<E>>>>,
+    preemptive: HashSet<E>,
+    state: watch::Receiver<Option<S>>,
+}
+
+impl<S, E, C, O> Clone for WeakHandle<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    fn clone(&self) -> Self {
+        WeakHandle {
+            mailbox: self.mailbox.clone(),
+            throttle: self.throttle.clone(),
+            preemptive: self.preemptive.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S, E, C, O> WeakHandle<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// A handle to the actor, unless every handle has been dropped or it is
+    /// stopping or stopped.
+    pub fn upgrade(&self) -> Option<ActorHandle<S, E, C, O>> {
+        let mailbox = self.mailbox.upgrade()?;
+        {
+            let mut queue = mailbox.queue.lock().unwrap();
+            if !queue.accepting() {
+                return None;
+            }
+            queue.handles += 1;
+        }
+        Some(ActorHandle {
+            mailbox,
+            throttle: self.throttle.clone(),
+            preemptive: self.preemptive.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    /// Whether [`upgrade`](Self::upgrade) would succeed now.
+    pub fn is_alive(&self) -> bool {
+        self.mailbox
+            .upgrade()
+            .is_some_and(|mailbox| mailbox.queue.lock().unwrap().accepting())
+    }
+}
+
+impl<S, E, C, O> ActorHandle<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// A handle to this actor that does not keep it running.
+    pub fn downgrade(&self) -> WeakHandle<S, E, C, O> {
+        WeakHandle {
+            mailbox: Arc::downgrade(&self.mailbox),
+            throttle: self.throttle.clone(),
+            preemptive: self.preemptive.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S, E, C, O> ActorHandle<S, E, C, O>
+where
+    S: State + Send + 'static,
+    E: Event + Send + 'static,
+    C: Send + 'static,
+    O: Default + Send + 'static,
+{
+    pub fn spawn(machine: StateMachine<S, E, C, O>) -> Self {
+        Self::spawn_with(machine, None, Overflow::Block)
+    }
+
+    /// Like [`spawn`](Self::spawn), with at most `capacity` non-preemptive
+    /// commands waiting; further ones are handled per `overflow`.
+    pub fn spawn_bounded(
+        machine: StateMachine<S, E, C, O>,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Self {
+        Self::spawn_with(machine, Some(capacity.max(1)), overflow)
+    }
+
+    fn spawn_with(
+        mut machine: StateMachine<S, E, C, O>,
+        capacity: Option<usize>,
+        overflow: Overflow,
+    ) -> Self {
+        let mailbox: Arc<Mailbox<Command<S, E, C, O>>> = Arc::new(Mailbox {
+            queue: Mutex::new(Queue {
+                urgent: VecDeque::new(),
+                normal: VecDeque::new(),
+                capacity,
+                overflow,
+                metrics: MailboxMetrics::default(),
+                handles: 1,
+                stopped: false,
+                draining: false,
+                finished: false,
+                on_finished: Vec::new(),
+                current: None,
+            }),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            done: Condvar::new(),
+        });
+        let publish = machine.state_sender();
+        let state = publish.subscribe();
+
+        let queue = mailbox.clone();
+        thread::spawn(move || {
+            loop {
+                if machine.time_to_scheduled() == Some(Duration::ZERO) {
+                    machine.run_scheduled();
+                }
+                match queue.pop(machine.time_to_scheduled()) {
+                    Next::Run(command, token) => command(&mut machine, &token),
+                    Next::Wake => {}
+                    Next::Stop => break,
+                }
+                // Catches changes observers never see, such as direct edits in `with`.
+                publish.send(machine.current_state.clone());
+            }
+            queue.finish();
+        });
+        ActorHandle {
+            mailbox,
+            throttle: None,
+            preemptive: HashSet::new(),
+            state,
+        }
+    }
+
+    /// Stops the actor, cancelling the command in progress. Queued and later
+    /// commands are dropped, so their replies report `Disconnected`.
+    pub fn abort(&self) {
+        self.mailbox.stop();
+    }
+
+    /// Cancels the token of the command in progress, if any.
+    pub fn cancel_current(&self) {
+        if let Some((token, _)) = &self.mailbox.queue.lock().unwrap().current {
+            token.cancel();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.mailbox.queue.lock().unwrap().stopped
+    }
+
+    /// Stops the actor once the commands kept by `drain` have run, refusing
+    /// new ones meanwhile: their replies report `Disconnected`. If the actor
+    /// is still running after `deadline`, it is aborted. The returned
+    /// receiver is told when the actor thread has exited.
+    pub fn shutdown(&self, drain: Drain, deadline: Option<Duration>) -> ReplyReceiver<()> {
+        let (finished, receiver) = oneshot();
+        {
+            let mut queue = self.mailbox.queue.lock().unwrap();
+            if queue.finished {
+                return ReplyReceiver::ready(());
+            }
+            queue.on_finished.push(finished);
+        }
+        self.mailbox.drain(drain);
+        if let Some(deadline) = deadline {
+            let mailbox = self.mailbox.clone();
+            thread::spawn(move || mailbox.stop_after(deadline));
+        }
+        receiver
+    }
+
+    pub fn status(&self) -> ActorStatus {
+        let queue = self.mailbox.queue.lock().unwrap();
+        if queue.finished {
+            ActorStatus::Stopped
+        } else if queue.stopped || queue.draining {
+            ActorStatus::Stopping
+        } else {
+            ActorStatus::Running
+        }
+    }
+
+    pub fn mailbox_metrics(&self) -> MailboxMetrics {
+        self.mailbox.metrics()
+    }
+
+    /// Marks `event` as preemptive for this handle and clones made from it.
+    pub fn preemptive(mut self, event: E) -> Self {
+        self.preemptive.insert(event);
+        self
+    }
+
+    /// Applies `throttle` to every event sent through this handle and its clones.
+    pub fn throttled(mut self, throttle: Throttle<E>) -> Self {
+        self.throttle = Some(Arc::new(Mutex::new(throttle)));
+        self
+    }
+
+    fn admit(&self, event: &E) -> Admission {
+        match &self.throttle {
+            Some(throttle) => throttle.lock().unwrap().admit_now(event),
+            None => Admission::Accept,
+        }
+    }
+
+    /// Runs `f` on the actor thread with exclusive access to the machine.
+    pub fn with<R, F>(&self, f: F) -> ReplyReceiver<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut StateMachine<S, E, C, O>) -> R + Send + 'static,
+    {
+        self.enqueue(move |machine, _| f(machine), false).0
+    }
+
+    /// Like [`with`](Self::with), ahead of queued commands and cancelling the
+    /// token of a non-preemptive command in progress.
+    pub(crate) fn with_urgent<R, F>(&self, f: F) -> ReplyReceiver<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut StateMachine<S, E, C, O>) -> R + Send + 'static,
+    {
+        self.enqueue(move |machine, _| f(machine), true).0
+    }
+
+    /// Runs `f` as [`with_urgent`](Self::with_urgent) does, then stops the
+    /// actor as [`abort`](Self::abort) does.
+    pub(crate) fn finish_urgently<F>(&self, f: F)
+    where
+        F: FnOnce(&mut StateMachine<S, E, C, O>) + Send + 'static,
+    {
+        // The mailbox, not a handle: dropping a handle locks the queue, which
+        // `stop` holds while it drops unrun commands.
+        let mailbox = self.mailbox.clone();
+        let _ = self.mailbox.push(
+            Box::new(move |machine, _| {
+                f(machine);
+                mailbox.stop();
+            }),
+            true,
+        );
+    }
+
+    /// Queues `f`, also reporting whether the overflow policy refused it; a
+    /// refused command's receiver reports `Disconnected`.
+    fn enqueue<R, F>(&self, f: F, urgent: bool) -> (ReplyReceiver<R>, bool)
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut StateMachine<S, E, C, O>, &CancellationToken) -> R + Send + 'static,
+    {
+        let (reply, receiver) = oneshot();
+        let refused = self
+            .mailbox
+            .push(
+                Box::new(move |machine, token| {
+                    let _ = reply.send(f(machine, token));
+                }),
+                urgent,
+            )
+            .is_err();
+        (receiver, refused)
+    }
+
+    /// Queues `event` without waiting for its result. Throttled events are dropped.
+    pub fn send(&self, event: E) {
+        if self.admit(&event) != Admission::Accept {
+            return;
+        }
+        let urgent = self.preemptive.contains(&event);
+        let _ = self.mailbox.push(
+            Box::new(move |machine, token| {
+                let _ = block_on(machine.dispatch_async(&event, token));
+            }),
+            urgent,
+        );
+    }
+
+    /// Queues `event`. A coalesced event reports `Response::Handled` with the
+    /// default output without reaching the machine.
+    pub fn dispatch(&self, event: E) -> ReplyReceiver<HandlerResult<S, E, O>> {
+        self.dispatch_as(event, AuditContext::default())
+    }
+
+    /// Like [`dispatch`](Self::dispatch), recording `context` in the audit log.
+    pub fn dispatch_as(
+        &self,
+        event: E,
+        context: AuditContext,
+    ) -> ReplyReceiver<HandlerResult<S, E, O>> {
+        self.dispatch_checked(event, context, None)
+    }
+
+    /// Like [`dispatch`](Self::dispatch), failing with
+    /// `StateMachineError::StaleVersion` if the machine is no longer at
+    /// version `expected` when the event's turn comes.
+    pub fn dispatch_if_version(
+        &self,
+        expected: u64,
+        event: E,
+    ) -> ReplyReceiver<HandlerResult<S, E, O>> {
+        self.dispatch_checked(event, AuditContext::default(), Some(expected))
+    }
+
+    fn dispatch_checked(
+        &self,
+        event: E,
+        context: AuditContext,
+        expected: Option<u64>,
+    ) -> ReplyReceiver<HandlerResult<S, E, O>> {
+        match self.admit(&event) {
+            Admission::Accept => {
+                let urgent = self.preemptive.contains(&event);
+                let queued = event.clone();
+                match self.enqueue(
+                    move |machine, token| {
+                        if let Some(expected) = expected {
+                            machine.expect_version(expected)?;
+                        }
+                        block_on(machine.dispatch_async_as(&queued, token, &context))
+                    },
+                    urgent,
+                ) {
+                    (_, true) => {
+                        ReplyReceiver::ready(Err(StateMachineError::MailboxFull { event }))
+                    }
+                    (receiver, false) => receiver,
+                }
+            }
+            Admission::Exceeded(OnExceeded::Coalesce) => {
+                ReplyReceiver::ready(Ok((Response::Handled, O::default())))
+            }
+            Admission::Exceeded(OnExceeded::Reject) => {
+                ReplyReceiver::ready(Err(StateMachineError::Throttled { event }))
+            }
+        }
+    }
+
+    /// Queues `request`; its reply arrives on the returned channel. A coalesced
+    /// request reports `NoReply`.
+    pub fn dispatch_request<Req, Resp>(
+        &self,
+        request: Req,
+    ) -> ReplyReceiver<Result<Resp, RequestError<S, E>>>
+    where
+        Req: Into<E> + Send + 'static,
+        Resp: Send + 'static,
+        O: Into<Option<Resp>>,
+    {
+        let event = request.into();
+        match self.admit(&event) {
+            Admission::Accept => {
+                let urgent = self.preemptive.contains(&event);
+                let queued = event.clone();
+                match self.enqueue(
+                    move |machine, token| {
+                        let (_, output) = block_on(machine.dispatch_async(&queued, token))?;
+                        output.into().ok_or(RequestError::NoReply { event: queued })
+                    },
+                    urgent,
+                ) {
+                    (_, true) => ReplyReceiver::ready(Err(RequestError::Machine(
+                        StateMachineError::MailboxFull { event },
+                    ))),
+                    (receiver, false) => receiver,
+                }
+            }
+            Admission::Exceeded(OnExceeded::Coalesce) => {
+                ReplyReceiver::ready(Err(RequestError::NoReply { event }))
+            }
+            Admission::Exceeded(OnExceeded::Reject) => {
+                ReplyReceiver::ready(Err(RequestError::Machine(StateMachineError::Throttled {
+                    event,
+                })))
+            }
+        }
+    }
+
+    /// Resolves once the machine is in `target`, immediately if it already is.
+    /// States a single dispatch passes through, such as eventless hops, count.
+    pub fn wait_for_state(
+        &self,
+        target: S,
+        limit: Option<Duration>,
+    ) -> impl Future<Output = Result<(), WaitError>> + Send {
+        let reached = self
+            .state
+            .wait_for(move |state| state.as_ref() == Some(&target));
+        async move {
+            let reached = match limit {
+                Some(limit) => timeout(limit, reached)
+                    .await
+                    .map_err(|_| WaitError::TimedOut)?,
+                None => reached.await,
+            };
+            reached.map(|_| ()).map_err(|_| WaitError::Stopped)
+        }
+    }
+
+    /// A receiver of the machine's state, updated after every transition;
+    /// `None` until a machine spawned unstarted is started.
+    pub fn subscribe(&self) -> watch::Receiver<Option<S>> {
+        let mut receiver = self.state.clone();
+        receiver.borrow_and_update();
+        receiver
+    }
+
+    pub fn state(&self) -> Result<S, RequestError<S, E>> {
+        self.with(|machine| machine.get_current_state().cloned().map_err(Into::into))
+            .wait()
+    }
+
+    /// The machine's [`version`](StateMachine::version), once queued commands have run.
+    pub fn version(&self) -> Result<u64, RequestError<S, E>> {
+        self.with(|machine| machine.version())
+            .recv()
+            .map_err(|_| RequestError::Disconnected)
+    }
+
+    /// A [view](crate::view) of the machine through `projection`, taken on
+    /// the actor thread between commands.
+    pub fn view(&self, projection: &Projection<C>) -> Result<MachineView<S>, RequestError<S, E>> {
+        let projection = projection.clone();
+        self.with(move |machine| machine.view(&projection))
+            .recv()
+            .map_err(|_| RequestError::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Response;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_actor_processes_events_in_order() {
+        let handle = ActorHandle::spawn(init_state_machine());
+        handle.send(CallEvent::Incoming);
+        handle.send(CallEvent::Answer);
+        assert_eq!(handle.state().unwrap(), CallState::Connected);
+        assert!(handle.dispatch(CallEvent::Dial).recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_actor_request_reply() {
+        let mut sm: StateMachine<CallState, CallEvent, HashMap<String, usize>, Option<u32>> =
+            StateMachine::new(CallState::Ringing, HashMap::new());
+        sm.add_transition_with_output(CallState::Ringing, CallEvent::Answer, |_sm, _event| {
+            Ok((Response::Transition(CallState::Connected), Some(42)))
+        });
+
+        let handle = ActorHandle::spawn(sm);
+        let reply = handle.dispatch_request::<_, u32>(CallEvent::Answer);
+        assert_eq!(reply.wait().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_throttled_events_never_reach_the_machine() {
+        let throttle = Throttle::new().rate_limit(
+            CallEvent::Incoming,
+            1,
+            std::time::Duration::from_secs(60),
+            OnExceeded::Reject,
+        );
+        let handle = ActorHandle::spawn(init_state_machine()).throttled(throttle);
+        handle
+            .dispatch(CallEvent::Incoming)
+            .recv()
+            .unwrap()
+            .unwrap();
+        handle.dispatch(CallEvent::HangUp).recv().unwrap().unwrap();
+        handle.dispatch(CallEvent::Reset).recv().unwrap().unwrap();
+
+        assert!(matches!(
+            handle.clone().dispatch(CallEvent::Incoming).recv().unwrap(),
+            Err(StateMachineError::Throttled {
+                event: CallEvent::Incoming
+            })
+        ));
+        assert_eq!(handle.state().unwrap(), CallState::Idle);
+    }
+
+    #[test]
+    fn test_preemptive_events_skip_the_queue() {
+        let handle = ActorHandle::spawn(init_state_machine()).preemptive(CallEvent::HangUp);
+        handle
+            .dispatch(CallEvent::Incoming)
+            .recv()
+            .unwrap()
+            .unwrap();
+
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        handle.with(move |_| blocked.recv().unwrap());
+        let answer = handle.dispatch(CallEvent::Answer);
+        wait_until_busy(&handle);
+        let hang_up = handle.dispatch(CallEvent::HangUp);
+        release.send(()).unwrap();
+
+        assert!(matches!(
+            hang_up.recv().unwrap(),
+            Ok((Response::Transition(CallState::Disconnected), ()))
+        ));
+        assert!(answer.recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_bounded_mailbox_overflow() {
+        for overflow in [Overflow::Reject, Overflow::DropOldest] {
+            let handle = ActorHandle::spawn_bounded(init_state_machine(), 1, overflow);
+            let (release, blocked) = std::sync::mpsc::channel::<()>();
+            handle.with(move |_| blocked.recv().unwrap());
+            wait_until_busy(&handle);
+            let incoming = handle.dispatch(CallEvent::Incoming);
+            let dial = handle.dispatch(CallEvent::Dial);
+            release.send(()).unwrap();
+
+            if overflow == Overflow::Reject {
+                assert!(incoming.recv().unwrap().is_ok());
+                assert!(matches!(
+                    dial.recv().unwrap(),
+                    Err(StateMachineError::MailboxFull {
+                        event: CallEvent::Dial
+                    })
+                ));
+                assert_eq!(handle.state().unwrap(), CallState::Ringing);
+            } else {
+                assert!(incoming.recv().is_err());
+                assert!(dial.recv().unwrap().is_ok());
+                assert_eq!(handle.state().unwrap(), CallState::Dialing);
+            }
+            let metrics = handle.mailbox_metrics();
+            assert_eq!(metrics.high_water, 1);
+            assert_eq!(metrics.depth, 0);
+            assert_eq!(
+                (metrics.rejected, metrics.dropped),
+                if overflow == Overflow::Reject {
+                    (1, 0)
+                } else {
+                    (0, 1)
+                }
+            );
+        }
+    }
+
+    /// Waits for the last command sent to be taken up; until then `current`
+    /// may still be the one before it, which has already replied.
+    fn wait_until_busy(handle: &ActorHandle<CallState, CallEvent>) {
+        loop {
+            let queue = handle.mailbox.queue.lock().unwrap();
+            if queue.current.is_some() && queue.urgent.is_empty() && queue.normal.is_empty() {
+                return;
+            }
+            drop(queue);
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_preemption_cancels_async_handler() {
+        let mut sm = init_state_machine();
+        sm.add_async_transition(
+            CallState::Ringing,
+            CallEvent::Answer,
+            |_sm, _event, _token| {
+                // Media negotiation that never completes on its own.
+                Box::pin(std::future::pending())
+            },
+        );
+        let handle = ActorHandle::spawn(sm).preemptive(CallEvent::HangUp);
+        handle
+            .dispatch(CallEvent::Incoming)
+            .recv()
+            .unwrap()
+            .unwrap();
+
+        let answer = handle.dispatch(CallEvent::Answer);
+        wait_until_busy(&handle);
+        let hang_up = handle.dispatch(CallEvent::HangUp);
+        assert!(matches!(
+            answer.recv().unwrap(),
+            Err(StateMachineError::Cancelled {
+                state: CallState::Ringing,
+                event: CallEvent::Answer
+            })
+        ));
+        hang_up.recv().unwrap().unwrap();
+        assert_eq!(handle.state().unwrap(), CallState::Disconnected);
+    }
+
+    #[test]
+    fn test_cancel_current() {
+        let mut sm = init_state_machine();
+        sm.add_async_transition(CallState::Idle, CallEvent::Dial, |_sm, _event, token| {
+            Box::pin(async move {
+                token.cancelled().await;
+                Ok((Response::Transition(CallState::Disconnected), ()))
+            })
+        });
+        let handle = ActorHandle::spawn(sm);
+        let dial = handle.dispatch(CallEvent::Dial);
+        wait_until_busy(&handle);
+        handle.cancel_current();
+        assert!(dial.recv().unwrap().is_err());
+        assert_eq!(handle.state().unwrap(), CallState::Idle);
+    }
+
+    #[test]
+    fn test_wait_for_state() {
+        let handle = ActorHandle::spawn(init_state_machine());
+        let connected = handle.wait_for_state(CallState::Connected, None);
+        let caller = handle.clone();
+        thread::spawn(move || {
+            caller.send(CallEvent::Incoming);
+            caller.send(CallEvent::Answer);
+        });
+        assert_eq!(block_on(connected), Ok(()));
+
+        let idle = handle.wait_for_state(CallState::Idle, Some(Duration::from_millis(10)));
+        assert_eq!(block_on(idle), Err(WaitError::TimedOut));
+
+        let idle = handle.wait_for_state(CallState::Idle, None);
+        handle.abort();
+        assert_eq!(block_on(idle), Err(WaitError::Stopped));
+    }
+
+    #[test]
+    fn test_subscribers_follow_the_actor() {
+        let handle = ActorHandle::spawn(init_state_machine());
+        let mut states = handle.subscribe();
+        assert_eq!(states.borrow(), Some(CallState::Idle));
+
+        handle.dispatch(CallEvent::Dial).recv().unwrap().unwrap();
+        block_on(states.changed()).unwrap();
+        assert_eq!(states.borrow(), Some(CallState::Dialing));
+
+        // A machine spawned unstarted publishes nothing until it starts.
+        let mut sm: StateMachine<CallState, CallEvent> = StateMachine::unstarted(HashMap::new());
+        sm.set_initial_state(CallState::Idle);
+        let handle = ActorHandle::spawn(sm);
+        let mut states = handle.subscribe();
+        assert_eq!(states.borrow(), None);
+        handle
+            .with(|machine| machine.start().cloned())
+            .recv()
+            .unwrap()
+            .unwrap();
+        block_on(states.changed()).unwrap();
+        assert_eq!(states.borrow(), Some(CallState::Idle));
+    }
+
+    #[test]
+    fn test_shutdown_drains_the_queue() {
+        let handle = ActorHandle::spawn(init_state_machine());
+        let states = handle.subscribe();
+        handle.send(CallEvent::Incoming);
+        let answer = handle.dispatch(CallEvent::Answer);
+        let stopped = handle.shutdown(Drain::Queued, None);
+        assert!(handle.dispatch(CallEvent::HangUp).recv().is_err());
+        assert_ne!(handle.status(), ActorStatus::Running);
+
+        stopped.recv().unwrap();
+        answer.recv().unwrap().unwrap();
+        assert_eq!(handle.status(), ActorStatus::Stopped);
+        assert_eq!(states.borrow(), Some(CallState::Connected));
+        handle.shutdown(Drain::Queued, None).recv().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_deadline_aborts_stuck_work() {
+        let mut sm = init_state_machine();
+        sm.add_async_transition(CallState::Idle, CallEvent::Dial, |_sm, _event, _token| {
+            Box::pin(std::future::pending())
+        });
+        let handle = ActorHandle::spawn(sm);
+        let dial = handle.dispatch(CallEvent::Dial);
+        wait_until_busy(&handle);
+        let queued = handle.dispatch(CallEvent::HangUp);
+
+        handle
+            .shutdown(Drain::Discard, Some(Duration::from_millis(10)))
+            .recv()
+            .unwrap();
+        assert!(queued.recv().is_err());
+        assert!(matches!(
+            dial.recv().unwrap(),
+            Err(StateMachineError::Cancelled { .. })
+        ));
+        assert_eq!(handle.status(), ActorStatus::Stopped);
+    }
+
+    #[test]
+    fn test_weak_handle_does_not_keep_actor_alive() {
+        let handle = ActorHandle::spawn(init_state_machine());
+        let weak = handle.downgrade();
+        assert!(weak.is_alive());
+        let upgraded = weak.upgrade().unwrap();
+        upgraded.send(CallEvent::Incoming);
+        assert_eq!(upgraded.state().unwrap(), CallState::Ringing);
+
+        drop(handle);
+        assert!(weak.is_alive());
+        drop(upgraded);
+        assert!(!weak.is_alive());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_dispatch_if_version_refuses_stale_callers() {
+        let handle = ActorHandle::spawn(init_state_machine());
+        let seen = handle.version().unwrap();
+        handle.send(CallEvent::Incoming);
+        assert!(matches!(
+            handle
+                .dispatch_if_version(seen, CallEvent::Dial)
+                .recv()
+                .unwrap(),
+            Err(StateMachineError::StaleVersion {
+                expected: 0,
+                actual: 1
+            })
+        ));
+        handle
+            .dispatch_if_version(seen + 1, CallEvent::Answer)
+            .recv()
+            .unwrap()
+            .unwrap();
+        assert_eq!(handle.state().unwrap(), CallState::Connected);
+    }
+}
@@ -0,0 +1,386 @@
+//! Static analysis of transition tables.
+//!
+//! The analyses only see transitions whose target is declared up front (see
+//! [`add_transition_to`](StateMachine::add_transition_to)); a handler that
+//! picks its target at runtime, or a guard, makes the answer unknowable and is
+//! reported as [`AnalysisError::DynamicTransition`]. Events bubble to parent
+//! states as they do when dispatching; eventless transitions are not followed.
+
+use crate::generic::{Event, State, StateMachine};
+use crate::spec::{MachineSpec, TransitionSpec};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnalysisError<S, E> {
+    NoCurrentState,
+    /// The transition on `event` in `from` is guarded or has no static target.
+    DynamicTransition {
+        from: S,
+        event: E,
+    },
+}
+
+/// The outcome of [`equivalent_to`](StateMachine::equivalent_to).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Equivalence<E> {
+    Equivalent,
+    /// One machine accepts `sequence` and the other does not; it is a
+    /// shortest such sequence.
+    Differ {
+        sequence: Vec<E>,
+    },
+}
+
+/// The result of [`minimize`](StateMachine::minimize): the reachable states
+/// grouped into classes of equivalent states.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Minimization<S, E> {
+    /// Every class, each ordered by Debug representation; the first state of
+    /// a class is its representative.
+    pub classes: Vec<Vec<S>>,
+    initial: S,
+    /// `(from, event, to)` between representatives.
+    transitions: Vec<(S, E, S)>,
+}
+
+impl<S: State, E: Event> Minimization<S, E> {
+    /// Classes of more than one state, whose members can be merged.
+    pub fn mergeable(&self) -> impl Iterator<Item = &[S]> {
+        self.classes
+            .iter()
+            .filter(|class| class.len() > 1)
+            .map(Vec::as_slice)
+    }
+
+    /// Whether no two reachable states are equivalent.
+    pub fn is_minimal(&self) -> bool {
+        self.mergeable().next().is_none()
+    }
+
+    /// The representative of `state`'s class, or `None` if it is unreachable.
+    pub fn representative(&self, state: &S) -> Option<&S> {
+        self.classes
+            .iter()
+            .find(|class| class.contains(state))
+            .map(|class| &class[0])
+    }
+
+    /// The minimized machine as a spec over the representatives' Debug names.
+    /// Hierarchy is not expressible in specs: each state's bubbled events
+    /// become transitions of its own.
+    pub fn to_spec(&self) -> MachineSpec {
+        MachineSpec {
+            initial: format!("{:?}", self.initial),
+            initial_line: 1,
+            transitions: self
+                .transitions
+                .iter()
+                .enumerate()
+                .map(|(index, (from, event, to))| TransitionSpec {
+                    from: format!("{:?}", from),
+                    event: format!("{:?}", event),
+                    to: format!("{:?}", to),
+                    line: index + 2,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// The state `event` leads to from `state`, or `None` if no state in its
+    /// dispatch chain handles it.
+    pub(crate) fn static_next(
+        &self,
+        state: &S,
+        event: &E,
+    ) -> Result<Option<S>, AnalysisError<S, E>> {
+        for handler in self.dispatch_chain(state) {
+            let key = (handler, event.clone());
+            if !self.transitions.contains_key(&key) {
+                continue;
+            }
+            return match self.targets.get(&key) {
+                Some(to) if !self.guards.contains_key(&key) => Ok(Some(to.clone())),
+                _ => Err(AnalysisError::DynamicTransition {
+                    from: key.0,
+                    event: key.1,
+                }),
+            };
+        }
+        Ok(None)
+    }
+
+    /// Every event with a registered transition, ordered by Debug representation.
+    pub(crate) fn static_events(&self) -> Vec<E> {
+        let mut events: Vec<E> = Vec::new();
+        for (_, event) in self.transitions.keys() {
+            if !events.contains(event) {
+                events.push(event.clone());
+            }
+        }
+        events.sort_by_cached_key(|event| format!("{:?}", event));
+        events
+    }
+
+    pub(crate) fn initial_state(&self) -> Result<S, AnalysisError<S, E>> {
+        self.current_state
+            .clone()
+            .or_else(|| self.initial().cloned())
+            .ok_or(AnalysisError::NoCurrentState)
+    }
+
+    /// Groups the states reachable from the current state into classes that
+    /// accept the same event sequences and could be merged, by partition
+    /// refinement. Final substates and states with different parents are
+    /// never merged, since completions depend on them.
+    pub fn minimize(&self) -> Result<Minimization<S, E>, AnalysisError<S, E>> {
+        let initial = self.initial_state()?;
+        let events = self.static_events();
+
+        let mut states = vec![initial.clone()];
+        let mut next: HashMap<S, Vec<Option<S>>> = HashMap::new();
+        let mut index = 0;
+        while let Some(state) = states.get(index).cloned() {
+            let targets = events
+                .iter()
+                .map(|event| self.static_next(&state, event))
+                .collect::<Result<Vec<_>, _>>()?;
+            for to in targets.iter().flatten() {
+                if !states.contains(to) {
+                    states.push(to.clone());
+                }
+            }
+            next.insert(state, targets);
+            index += 1;
+        }
+        states.sort_by_cached_key(|state| format!("{:?}", state));
+
+        let mut block: HashMap<S, usize> = HashMap::new();
+        let mut count = 0;
+        loop {
+            let mut signatures: Vec<(usize, String, Vec<Option<usize>>)> = Vec::new();
+            let mut refined = HashMap::new();
+            for state in &states {
+                let signature = (
+                    block.get(state).copied().unwrap_or(0),
+                    format!(
+                        "{}{:?}",
+                        self.finals.contains(state),
+                        self.parents.get(state)
+                    ),
+                    next[state]
+                        .iter()
+                        .map(|to| to.as_ref().map(|to| block.get(to).copied().unwrap_or(0)))
+                        .collect(),
+                );
+                let id = match signatures.iter().position(|s| *s == signature) {
+                    Some(id) => id,
+                    None => {
+                        signatures.push(signature);
+                        signatures.len() - 1
+                    }
+                };
+                refined.insert(state.clone(), id);
+            }
+            block = refined;
+            if signatures.len() == count {
+                break;
+            }
+            count = signatures.len();
+        }
+
+        let mut classes: Vec<Vec<S>> = vec![Vec::new(); count];
+        for state in &states {
+            classes[block[state]].push(state.clone());
+        }
+        let representative = |state: &S| classes[block[state]][0].clone();
+        let mut transitions = Vec::new();
+        for class in &classes {
+            for (event, to) in events.iter().zip(&next[&class[0]]) {
+                if let Some(to) = to {
+                    transitions.push((class[0].clone(), event.clone(), representative(to)));
+                }
+            }
+        }
+        Ok(Minimization {
+            initial: representative(&initial),
+            classes,
+            transitions,
+        })
+    }
+
+    /// Whether this machine and `other`, each from its current state, accept
+    /// exactly the same event sequences. Since both are deterministic this is
+    /// also bisimilarity; state names do not need to match.
+    ///
+    /// Errors on a dynamic transition reachable in either machine, tagged
+    /// with the side it was found on.
+    pub fn equivalent_to<S2, C2, O2>(
+        &self,
+        other: &StateMachine<S2, E, C2, O2>,
+    ) -> Result<Equivalence<E>, PairError<S, S2, E>>
+    where
+        S2: State,
+    {
+        let start = (
+            self.initial_state().map_err(PairError::Left)?,
+            other.initial_state().map_err(PairError::Right)?,
+        );
+        let mut events = self.static_events();
+        for event in other.static_events() {
+            if !events.contains(&event) {
+                events.push(event);
+            }
+        }
+
+        let mut seen = HashSet::from([start.clone()]);
+        let mut queue = VecDeque::from([(start, Vec::new())]);
+        while let Some(((left, right), path)) = queue.pop_front() {
+            for event in &events {
+                let next_left = self.static_next(&left, event).map_err(PairError::Left)?;
+                let next_right = other.static_next(&right, event).map_err(PairError::Right)?;
+                let mut sequence = path.clone();
+                sequence.push(event.clone());
+                match (next_left, next_right) {
+                    (Some(l), Some(r)) => {
+                        if seen.insert((l.clone(), r.clone())) {
+                            queue.push_back(((l, r), sequence));
+                        }
+                    }
+                    (None, None) => {}
+                    _ => return Ok(Equivalence::Differ { sequence }),
+                }
+            }
+        }
+        Ok(Equivalence::Equivalent)
+    }
+
+    /// A shortest event sequence leading from the current state to `goal`,
+    /// found by breadth-first search; empty if the machine is already there.
+    /// Transitions without a static target are not taken, so `None` means no
+    /// path is known, not that none exists. Ties go to events earlier in
+    /// Debug order.
+    pub fn path_to(&self, goal: &S) -> Option<Vec<E>> {
+        let start = self.current_state.clone()?;
+        let events = self.static_events();
+        let mut seen = HashSet::from([start.clone()]);
+        let mut queue = VecDeque::from([(start, Vec::new())]);
+        while let Some((state, path)) = queue.pop_front() {
+            if state == *goal {
+                return Some(path);
+            }
+            for event in &events {
+                let Ok(Some(to)) = self.static_next(&state, event) else {
+                    continue;
+                };
+                if seen.insert(to.clone()) {
+                    let mut path = path.clone();
+                    path.push(event.clone());
+                    queue.push_back((to, path));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// An [`AnalysisError`] from one side of an analysis of two machines, such
+/// as [`equivalent_to`](StateMachine::equivalent_to) or the combinators in
+/// [`compose`](crate::compose).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PairError<S, S2, E> {
+    Left(AnalysisError<S, E>),
+    Right(AnalysisError<S2, E>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Phase {
+        Quiet,
+        Busy,
+    }
+
+    fn call_table() -> StateMachine<CallState, CallEvent, ()> {
+        let mut sm = StateMachine::new(CallState::Idle, ());
+        sm.add_transition_to(CallState::Idle, CallEvent::Dial, CallState::Dialing);
+        sm.add_transition_to(CallState::Dialing, CallEvent::HangUp, CallState::Idle);
+        sm.add_transition_to(CallState::Idle, CallEvent::Incoming, CallState::Ringing);
+        sm.add_transition_to(CallState::Ringing, CallEvent::HangUp, CallState::Idle);
+        sm
+    }
+
+    #[test]
+    fn test_minimize_merges_equivalent_states() {
+        let minimization = call_table().minimize().unwrap();
+        assert_eq!(
+            minimization.mergeable().collect::<Vec<_>>(),
+            vec![&[CallState::Dialing, CallState::Ringing][..]]
+        );
+        assert_eq!(
+            minimization.representative(&CallState::Ringing),
+            Some(&CallState::Dialing)
+        );
+        assert_eq!(minimization.representative(&CallState::Connected), None);
+        assert_eq!(
+            minimization.to_spec().to_string(),
+            "initial Idle\nDialing --HangUp--> Idle\nIdle --Dial--> Dialing\nIdle --Incoming--> Dialing\n"
+        );
+
+        let mut sm = call_table();
+        sm.add_transition_to(CallState::Ringing, CallEvent::Answer, CallState::Connected);
+        assert!(sm.minimize().unwrap().is_minimal());
+    }
+
+    #[test]
+    fn test_equivalence_ignores_state_names() {
+        let mut phases: StateMachine<Phase, CallEvent, ()> = StateMachine::new(Phase::Quiet, ());
+        phases.add_transition_to(Phase::Quiet, CallEvent::Dial, Phase::Busy);
+        phases.add_transition_to(Phase::Quiet, CallEvent::Incoming, Phase::Busy);
+        phases.add_transition_to(Phase::Busy, CallEvent::HangUp, Phase::Quiet);
+        assert_eq!(
+            call_table().equivalent_to(&phases),
+            Ok(Equivalence::Equivalent)
+        );
+
+        phases.add_transition_to(Phase::Busy, CallEvent::Dial, Phase::Busy);
+        assert_eq!(
+            call_table().equivalent_to(&phases),
+            Ok(Equivalence::Differ {
+                sequence: vec![CallEvent::Dial, CallEvent::Dial]
+            })
+        );
+
+        assert!(matches!(
+            call_table().equivalent_to(&init_state_machine()),
+            Err(PairError::Right(AnalysisError::DynamicTransition { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_path_to_takes_static_transitions() {
+        let mut sm = call_table();
+        sm.add_transition_to(CallState::Ringing, CallEvent::Answer, CallState::Connected);
+        assert_eq!(
+            sm.path_to(&CallState::Connected),
+            Some(vec![CallEvent::Incoming, CallEvent::Answer])
+        );
+        assert_eq!(sm.path_to(&CallState::Idle), Some(vec![]));
+        assert_eq!(sm.path_to(&CallState::Disconnected), None);
+
+        sm.add_transition(CallState::Dialing, CallEvent::Answer, |_, _| {
+            Ok(crate::generic::Response::Transition(
+                CallState::Disconnected,
+            ))
+        });
+        assert_eq!(sm.path_to(&CallState::Disconnected), None);
+    }
+}
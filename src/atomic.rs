@@ -0,0 +1,72 @@
+//! All-or-nothing event sequences.
+//!
+//! [`StateMachine::handle_events_atomic`] applies a multi-step command, such
+//! as "answer and immediately hold", as one unit: if any event fails, the
+//! machine's state and context are put back as they were before the first.
+//!
+//! The rollback is a [`restore`](StateMachine::restore) of a snapshot taken
+//! up front, so it only covers what a snapshot holds. Observers, journals and
+//! audit sinks have already seen the steps that succeeded, and subscribers
+//! see the machine return to its earlier state.
+
+use crate::generic::{Event, Response, State, StateMachine, StateMachineError};
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+    C: Clone,
+    O: Default,
+{
+    /// Dispatches each of `events` in turn, returning their responses, or
+    /// the first error after rolling the state and context back.
+    pub fn handle_events_atomic(
+        &mut self,
+        events: &[E],
+    ) -> Result<Vec<Response<S>>, StateMachineError<S, E>> {
+        let before = self.snapshot()?;
+        let mut responses = Vec::with_capacity(events.len());
+        for event in events {
+            match self.dispatch(event) {
+                Ok((response, _)) => responses.push(response),
+                Err(e) => {
+                    self.restore(before);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generic::{Response, StateMachineError};
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_failed_batch_rolls_back() {
+        let mut sm = init_state_machine();
+        sm.add_transition(CallState::Idle, CallEvent::Incoming, |sm, _| {
+            *sm.get_context_mut().entry("rings".into()).or_default() += 1;
+            Ok(Response::Transition(CallState::Ringing))
+        });
+
+        let error = sm
+            .handle_events_atomic(&[CallEvent::Incoming, CallEvent::Answer, CallEvent::Dial])
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            StateMachineError::TransitionNotFound { .. }
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+        assert!(sm.get_context().is_empty());
+
+        let responses = sm
+            .handle_events_atomic(&[CallEvent::Incoming, CallEvent::Answer])
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+        assert_eq!(sm.get_context()["rings"], 1);
+    }
+}
@@ -0,0 +1,382 @@
+//! Structured audit records of every dispatch.
+//!
+//! Once a sink is added with [`StateMachine::add_audit_sink`], each dispatch,
+//! successful or not, produces one [`AuditRecord`]. Dispatching with
+//! [`StateMachine::dispatch_as`] attaches who or what injected the event.
+
+use crate::clock::Clock;
+use crate::correlation;
+use crate::diff::ContextDiff;
+use crate::generic::{Event, HandlerResult, Response, State, StateMachine};
+use crate::json;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Who injected an event, plus free-form attributes such as a request ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditContext {
+    pub actor: Option<String>,
+    pub attributes: BTreeMap<String, String>,
+    /// Links the dispatch to the rest of its journey; see [`crate::correlation`].
+    pub correlation_id: Option<String>,
+    /// Identifies the delivery for duplicate suppression; see [`crate::dedup`].
+    pub idempotency_key: Option<String>,
+}
+
+impl AuditContext {
+    pub fn new(actor: impl Into<String>) -> Self {
+        AuditContext {
+            actor: Some(actor.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn correlation_id(mut self, id: impl Into<String>) -> Self {
+        self.correlation_id = Some(id.into());
+        self
+    }
+
+    pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    pub fn attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditOutcome<S> {
+    Transitioned {
+        to: S,
+    },
+    Handled,
+    /// The dispatch failed; `error` is the error's Debug representation.
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord<S, E> {
+    pub at: SystemTime,
+    pub context: AuditContext,
+    pub from: S,
+    pub event: E,
+    pub outcome: AuditOutcome<S>,
+    /// The target the handler chose, when a redirect replaced it; see
+    /// [`add_redirect`](StateMachine::add_redirect).
+    pub redirected_from: Option<S>,
+    /// Context fields the dispatch changed; empty unless the machine was
+    /// built [`with_context_diffs`](StateMachine::with_context_diffs).
+    pub context_diff: ContextDiff,
+}
+
+impl<S: Debug, E: Debug> AuditRecord<S, E> {
+    /// Encodes the record as a JSON object; states and events use their Debug
+    /// names and `at` is in milliseconds since the Unix epoch. A redirect is
+    /// added as `redirected_from` and a non-empty context diff as `context_diff`.
+    pub fn to_json(&self) -> String {
+        let name = |value: &dyn Debug| json::escape(&format!("{:?}", value));
+        let millis = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let actor = match &self.context.actor {
+            Some(actor) => format!("\"{}\"", json::escape(actor)),
+            None => "null".to_string(),
+        };
+        let attributes: Vec<String> = self
+            .context
+            .attributes
+            .iter()
+            .map(|(k, v)| format!("\"{}\":\"{}\"", json::escape(k), json::escape(v)))
+            .collect();
+        let correlation_id = match &self.context.correlation_id {
+            Some(id) => format!(",\"correlation_id\":\"{}\"", json::escape(id)),
+            None => String::new(),
+        };
+        let idempotency_key = match &self.context.idempotency_key {
+            Some(key) => format!(",\"idempotency_key\":\"{}\"", json::escape(key)),
+            None => String::new(),
+        };
+        let outcome = match &self.outcome {
+            AuditOutcome::Transitioned { to } => {
+                format!("\"outcome\":\"transitioned\",\"to\":\"{}\"", name(to))
+            }
+            AuditOutcome::Handled => "\"outcome\":\"handled\"".to_string(),
+            AuditOutcome::Failed { error } => {
+                format!(
+                    "\"outcome\":\"failed\",\"error\":\"{}\"",
+                    json::escape(error)
+                )
+            }
+        };
+        let redirected_from = match &self.redirected_from {
+            Some(state) => format!(",\"redirected_from\":\"{}\"", name(state)),
+            None => String::new(),
+        };
+        let context_diff = if self.context_diff.is_empty() {
+            String::new()
+        } else {
+            format!(",\"context_diff\":{}", self.context_diff.to_json())
+        };
+        format!(
+            "{{\"at\":{},\"actor\":{},\"attributes\":{{{}}}{}{},\"from\":\"{}\",\"event\":\"{}\",{}{}{}}}",
+            millis,
+            actor,
+            attributes.join(","),
+            correlation_id,
+            idempotency_key,
+            name(&self.from),
+            name(&self.event),
+            outcome,
+            redirected_from,
+            context_diff
+        )
+    }
+}
+
+pub trait AuditSink<S, E>: Send + Sync {
+    fn record(&self, record: &AuditRecord<S, E>);
+}
+
+/// Lets the caller keep a handle on a sink it has added, e.g. to collect errors.
+impl<S, E, K> AuditSink<S, E> for Arc<K>
+where
+    K: AuditSink<S, E> + ?Sized,
+{
+    fn record(&self, record: &AuditRecord<S, E>) {
+        (**self).record(record)
+    }
+}
+
+/// Records collected by a [`MemorySink`].
+pub type AuditLog<S, E> = Arc<Mutex<Vec<AuditRecord<S, E>>>>;
+
+/// Keeps records in memory, mostly for tests.
+pub struct MemorySink<S, E> {
+    records: AuditLog<S, E>,
+}
+
+impl<S, E> MemorySink<S, E> {
+    /// The sink and a shared view of the records it collects.
+    pub fn new() -> (Self, AuditLog<S, E>) {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        (
+            MemorySink {
+                records: records.clone(),
+            },
+            records,
+        )
+    }
+}
+
+impl<S, E> AuditSink<S, E> for MemorySink<S, E>
+where
+    S: Clone + Send,
+    E: Clone + Send,
+{
+    fn record(&self, record: &AuditRecord<S, E>) {
+        self.records.lock().unwrap().push(record.clone());
+    }
+}
+
+/// Writes each record as one line of JSON.
+///
+/// Recording never fails the dispatch; the first failed write is kept until
+/// [`take_error`](Self::take_error) collects it.
+pub struct JsonLinesSink<W> {
+    writer: Mutex<W>,
+    failed: Mutex<Option<std::io::Error>>,
+}
+
+impl<W> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink {
+            writer: Mutex::new(writer),
+            failed: Mutex::new(None),
+        }
+    }
+
+    /// The first write that failed since the last call, if any.
+    pub fn take_error(&self) -> Option<std::io::Error> {
+        self.failed.lock().unwrap().take()
+    }
+}
+
+impl<S, E, W> AuditSink<S, E> for JsonLinesSink<W>
+where
+    S: Debug,
+    E: Debug,
+    W: Write + Send,
+{
+    fn record(&self, record: &AuditRecord<S, E>) {
+        if let Err(e) = writeln!(self.writer.lock().unwrap(), "{}", record.to_json()) {
+            self.failed.lock().unwrap().get_or_insert(e);
+        }
+    }
+}
+
+/// The closure returned by a [`ContextDiffer`](crate::diff::ContextDiffer).
+pub(crate) type PendingDiff<C> = Box<dyn FnOnce(&C) -> ContextDiff>;
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    pub fn add_audit_sink<K>(&mut self, sink: K)
+    where
+        K: AuditSink<S, E> + 'static,
+    {
+        self.audit_sinks.push(Arc::new(sink));
+    }
+
+    /// What [`audit`](Self::audit) needs to diff the context, if anything will be recorded.
+    pub(crate) fn capture_context(&self) -> Option<PendingDiff<C>> {
+        if self.audit_sinks.is_empty() {
+            return None;
+        }
+        self.context_differ.map(|capture| capture(&self.context))
+    }
+
+    /// Hands a record of the dispatch of `event` from `from` to every sink.
+    pub(crate) fn audit(
+        &self,
+        from: S,
+        before: Option<PendingDiff<C>>,
+        event: &E,
+        context: &AuditContext,
+        result: &HandlerResult<S, E, O>,
+    ) {
+        if self.audit_sinks.is_empty() {
+            return;
+        }
+        let outcome = match result {
+            Ok((Response::Transition(to), _)) => AuditOutcome::Transitioned { to: to.clone() },
+            Ok(_) => AuditOutcome::Handled,
+            Err(e) => AuditOutcome::Failed {
+                error: format!("{:?}", e),
+            },
+        };
+        let mut context = context.clone();
+        if context.correlation_id.is_none() {
+            context.correlation_id = correlation::current();
+        }
+        let record = AuditRecord {
+            at: self.clock.wall_time(),
+            context,
+            from,
+            event: event.clone(),
+            outcome,
+            redirected_from: self.redirected_from.clone(),
+            context_diff: before.map(|diff| diff(&self.context)).unwrap_or_default(),
+        };
+        for sink in &self.audit_sinks {
+            sink.record(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_dispatches_are_audited() {
+        let (sink, records) = MemorySink::new();
+        let mut sm = init_state_machine();
+        sm.add_audit_sink(sink);
+
+        let operator = AuditContext::new("operator:42").attribute("ticket", "T-7");
+        sm.dispatch_as(&CallEvent::Incoming, &operator).unwrap();
+        assert!(sm.dispatch(&CallEvent::Dial).is_err());
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].context.actor.as_deref(), Some("operator:42"));
+        assert_eq!(
+            records[0].outcome,
+            AuditOutcome::Transitioned {
+                to: CallState::Ringing
+            }
+        );
+        assert_eq!(records[1].context, AuditContext::default());
+        assert!(matches!(records[1].outcome, AuditOutcome::Failed { .. }));
+    }
+
+    #[test]
+    fn test_redirects_are_audited() {
+        let (sink, records) = MemorySink::new();
+        let mut sm = init_state_machine();
+        sm.add_audit_sink(sink);
+        sm.add_redirect(|_, _, to, context| {
+            (*to == CallState::Connected && context.contains_key("outage"))
+                .then_some(CallState::Disconnected)
+        });
+        sm.get_context_mut().insert("outage".to_string(), 1);
+
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        sm.dispatch(&CallEvent::Answer).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Disconnected);
+
+        let records = records.lock().unwrap();
+        assert_eq!(records[0].redirected_from, None);
+        assert_eq!(records[1].redirected_from, Some(CallState::Connected));
+        assert!(records[1]
+            .to_json()
+            .ends_with("\"to\":\"Disconnected\",\"redirected_from\":\"Connected\"}"));
+    }
+
+    #[test]
+    fn test_record_json() {
+        let record = AuditRecord {
+            at: UNIX_EPOCH + std::time::Duration::from_millis(1500),
+            context: AuditContext::new("svc").attribute("id", "a\"b"),
+            from: CallState::Idle,
+            event: CallEvent::Dial,
+            outcome: AuditOutcome::Transitioned {
+                to: CallState::Dialing,
+            },
+            redirected_from: None,
+            context_diff: ContextDiff::default(),
+        };
+        assert_eq!(
+            record.to_json(),
+            "{\"at\":1500,\"actor\":\"svc\",\"attributes\":{\"id\":\"a\\\"b\"},\
+             \"from\":\"Idle\",\"event\":\"Dial\",\"outcome\":\"transitioned\",\"to\":\"Dialing\"}"
+        );
+    }
+
+    #[test]
+    fn test_json_lines_sink_keeps_write_errors() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::StorageFull.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let sink = Arc::new(JsonLinesSink::new(Full));
+        let mut sm = init_state_machine();
+        sm.add_audit_sink(sink.clone());
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+
+        let error = sink.take_error().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+        assert!(sink.take_error().is_none());
+    }
+}
@@ -0,0 +1,185 @@
+//! Validates, renders and simulates machine spec files.
+//!
+//! ```text
+//! fsmportal validate call.fsm
+//! fsmportal render call.fsm --format dot|mermaid|plantuml|svg
+//! fsmportal simulate call.fsm --events events.txt
+//! fsmportal repl call.fsm
+//! ```
+//!
+//! SVG output is laid out in-process by `to_svg`; no Graphviz install is needed.
+
+use fsmportal::generic::{Response, StateMachine};
+use fsmportal::spec::{MachineSpec, SpecError};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::str::FromStr;
+
+const USAGE: &str = "usage:
+    fsmportal validate <spec>
+    fsmportal render <spec> [--format dot|mermaid|plantuml|svg]
+    fsmportal simulate <spec> --events <file>
+    fsmportal repl <spec>";
+
+/// A state or event name from a spec; its Debug output is the bare name, so
+/// exports and errors read like the spec.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Name(String);
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Name {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(Name(s.to_string()))
+    }
+}
+
+type SpecMachine = StateMachine<Name, Name, ()>;
+
+/// Parses and builds the spec at `path` once, keeping both forms.
+fn load(path: &str) -> Result<(MachineSpec, SpecMachine), String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let describe = |e: SpecError| match e.diagnostic(&source) {
+        Some(diagnostic) => format!("{}:\n{}", path, diagnostic),
+        None => format!("{}: {:?}", path, e),
+    };
+    let spec = MachineSpec::parse(&source).map_err(describe)?;
+    let sm = spec.build(()).map_err(describe)?;
+    Ok((spec, sm))
+}
+
+/// The value following `flag` in `args`, if the flag is present.
+fn option<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>, String> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => args
+            .get(i + 1)
+            .map(|value| Some(value.as_str()))
+            .ok_or_else(|| format!("{} needs a value", flag)),
+        None => Ok(None),
+    }
+}
+
+fn validate(path: &str) -> Result<(), String> {
+    let (spec, _) = load(path)?;
+    println!(
+        "{}: ok, {} states, {} transitions",
+        path,
+        spec.states().len(),
+        spec.transitions.len()
+    );
+    Ok(())
+}
+
+fn render(path: &str, format: &str) -> Result<(), String> {
+    let (_, sm) = load(path)?;
+    let rendered = match format {
+        "dot" => sm.to_dot(),
+        "mermaid" => sm.to_mermaid(),
+        "plantuml" => sm.to_plantuml(),
+        "svg" => sm.to_svg(),
+        other => return Err(format!("unknown format `{}`", other)),
+    };
+    print!("{}", rendered);
+    Ok(())
+}
+
+fn simulate(path: &str, events_path: &str, out: &mut impl Write) -> Result<(), String> {
+    let (_, mut sm) = load(path)?;
+    let events = fs::read_to_string(events_path).map_err(|e| format!("{}: {}", events_path, e))?;
+    for (index, line) in events.lines().enumerate() {
+        let event = line.trim();
+        if event.is_empty() || event.starts_with('#') {
+            continue;
+        }
+        let from = sm
+            .get_current_state()
+            .map_err(|e| format!("{:?}", e))?
+            .clone();
+        let written = match sm.dispatch(&Name(event.to_string())) {
+            Ok((Response::Transition(to), ())) => {
+                writeln!(out, "{:?} --{}--> {:?}", from, event, to)
+            }
+            Ok(_) => writeln!(out, "{:?} --{}--> (handled)", from, event),
+            Err(e) => return Err(format!("{}:{}: {:?}", events_path, index + 1, e)),
+        };
+        written.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn repl(path: &str) -> Result<(), String> {
+    let (_, mut sm) = load(path)?;
+    sm.set_event_parser_from_str();
+    fsmportal::repl::run(&mut sm, io::stdin().lock(), io::stdout().lock())
+        .map_err(|e| e.to_string())
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, path) = match args {
+        [command, path, ..] => (command.as_str(), path.as_str()),
+        _ => return Err(USAGE.to_string()),
+    };
+    let options = &args[2..];
+    match command {
+        "validate" => validate(path),
+        "render" => render(path, option(options, "--format")?.unwrap_or("dot")),
+        "simulate" => match option(options, "--events")? {
+            Some(events) => simulate(path, events, &mut io::stdout().lock()),
+            None => Err(USAGE.to_string()),
+        },
+        "repl" => repl(path),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(message) = run(&args) {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulate_prints_each_step() {
+        let dir = env::temp_dir().join(format!("fsmportal-cli-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let spec = dir.join("call.fsm");
+        let events = dir.join("events.txt");
+        fs::write(
+            &spec,
+            "initial Idle\nIdle --Dial--> Dialing\nDialing --HangUp--> Idle\n",
+        )
+        .unwrap();
+        fs::write(&events, "Dial\n# comment\n\nHangUp\n").unwrap();
+
+        let mut out = Vec::new();
+        simulate(spec.to_str().unwrap(), events.to_str().unwrap(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Idle --Dial--> Dialing\nDialing --HangUp--> Idle\n"
+        );
+
+        fs::write(&events, "Dial\nDial\n").unwrap();
+        let error = simulate(
+            spec.to_str().unwrap(),
+            events.to_str().unwrap(),
+            &mut Vec::new(),
+        );
+        assert!(error.unwrap_err().contains("events.txt:2:"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! A synchronous front-end to the actor.
+//!
+//! [`BlockingHandle`] wraps an [`ActorHandle`] and waits for every reply
+//! itself, driving futures such as [`ActorHandle::wait_for_state`] on the
+//! calling thread with [`block_on`]. Callers that are not async need no
+//! runtime of their own; async code can still reach the wrapped handle
+//! through [`BlockingHandle::handle`].
+//!
+//! Each call blocks the calling thread, so it must not be made from a
+//! handler running on the actor it talks to.
+
+use crate::actor::{ActorHandle, Drain, WaitError};
+use crate::audit::AuditContext;
+use crate::generic::{Event, Response, State, StateMachine};
+use crate::request::RequestError;
+use crate::task::block_on;
+use std::time::Duration;
+
+pub struct BlockingHandle<S, E, C = std::collections::HashMap<String, usize>, O = ()>
+where
+    S: State,
+    E: Event,
+{
+    handle: ActorHandle<S, E, C, O>,
+}
+
+impl<S, E, C, O> Clone for BlockingHandle<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    fn clone(&self) -> Self {
+        BlockingHandle {
+            handle: self.handle.clone(),
+        }
+    }
+}
+
+impl<S, E, C, O> From<ActorHandle<S, E, C, O>> for BlockingHandle<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    fn from(handle: ActorHandle<S, E, C, O>) -> Self {
+        BlockingHandle { handle }
+    }
+}
+
+impl<S, E, C, O> BlockingHandle<S, E, C, O>
+where
+    S: State + Send + 'static,
+    E: Event + Send + 'static,
+    C: Send + 'static,
+    O: Default + Send + 'static,
+{
+    /// Spawns the machine on its own thread, as [`ActorHandle::spawn`] does.
+    pub fn spawn(machine: StateMachine<S, E, C, O>) -> Self {
+        ActorHandle::spawn(machine).into()
+    }
+
+    /// The wrapped handle, for async callers sharing the same actor.
+    pub fn handle(&self) -> &ActorHandle<S, E, C, O> {
+        &self.handle
+    }
+
+    /// Dispatches `event` and waits for the handler's response and output.
+    pub fn dispatch(&self, event: E) -> Result<(Response<S>, O), RequestError<S, E>> {
+        self.dispatch_as(event, AuditContext::default())
+    }
+
+    /// Like [`dispatch`](Self::dispatch), recording `context` in the audit log.
+    pub fn dispatch_as(
+        &self,
+        event: E,
+        context: AuditContext,
+    ) -> Result<(Response<S>, O), RequestError<S, E>> {
+        match self.handle.dispatch_as(event, context).recv() {
+            Ok(result) => result.map_err(RequestError::Machine),
+            Err(_) => Err(RequestError::Disconnected),
+        }
+    }
+
+    pub fn dispatch_request<Req, Resp>(&self, request: Req) -> Result<Resp, RequestError<S, E>>
+    where
+        Req: Into<E> + Send + 'static,
+        Resp: Send + 'static,
+        O: Into<Option<Resp>>,
+    {
+        self.handle.dispatch_request(request).wait()
+    }
+
+    /// Dispatches `event` unless the machine has moved past version `expected`.
+    pub fn dispatch_if_version(
+        &self,
+        expected: u64,
+        event: E,
+    ) -> Result<(Response<S>, O), RequestError<S, E>> {
+        match self.handle.dispatch_if_version(expected, event).recv() {
+            Ok(result) => result.map_err(RequestError::Machine),
+            Err(_) => Err(RequestError::Disconnected),
+        }
+    }
+
+    /// Blocks until the machine is in `target`, or at most `limit`.
+    pub fn wait_for_state(&self, target: S, limit: Option<Duration>) -> Result<(), WaitError> {
+        block_on(self.handle.wait_for_state(target, limit))
+    }
+
+    pub fn state(&self) -> Result<S, RequestError<S, E>> {
+        self.handle.state()
+    }
+
+    /// Shuts the actor down as [`ActorHandle::shutdown`] does, blocking until
+    /// its thread has exited.
+    pub fn shutdown(&self, drain: Drain, deadline: Option<Duration>) {
+        let _ = self.handle.shutdown(drain, deadline).recv();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::thread;
+
+    #[test]
+    fn test_blocking_handle_from_sync_code() {
+        let blocking = BlockingHandle::spawn(init_state_machine());
+        let waiter = {
+            let blocking = blocking.clone();
+            thread::spawn(move || blocking.wait_for_state(CallState::Connected, None))
+        };
+        blocking.dispatch(CallEvent::Incoming).unwrap();
+        assert!(matches!(
+            blocking.dispatch(CallEvent::Dial),
+            Err(RequestError::Machine(_))
+        ));
+        blocking.dispatch(CallEvent::Answer).unwrap();
+        assert!(waiter.join().unwrap().is_ok());
+        assert_eq!(blocking.state().unwrap(), CallState::Connected);
+        assert!(matches!(
+            blocking.wait_for_state(CallState::Idle, Some(Duration::from_millis(20))),
+            Err(WaitError::TimedOut)
+        ));
+    }
+}
@@ -0,0 +1,147 @@
+//! Driving one machine from another's transitions.
+//!
+//! A [`Bridge`] maps each transition committed by a source machine to an
+//! optional event for a target, such as `StartBilling` for a billing machine
+//! whenever a call reaches `Connected`. The target is an actor, which queues
+//! the event without blocking the source, or a machine behind a mutex, which
+//! is dispatched to before the source's dispatch returns.
+//!
+//! Errors from the target's dispatch stay with the target, in its audit log
+//! and recovery policy, as for any other event it receives. Bridging a
+//! mutex-held machine back into its own source deadlocks; cycles need actors.
+
+use crate::actor::ActorHandle;
+use crate::generic::{Event, State, StateMachine};
+use std::sync::{Arc, Mutex};
+
+/// Where a bridge delivers the events it produces.
+pub trait BridgeTarget<E>: Send + Sync {
+    fn deliver(&self, event: E);
+}
+
+impl<S, E, C, O> BridgeTarget<E> for ActorHandle<S, E, C, O>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    C: Send + 'static,
+    O: Default + Send + 'static,
+{
+    fn deliver(&self, event: E) {
+        self.send(event);
+    }
+}
+
+impl<S, E, C, O> BridgeTarget<E> for Arc<Mutex<StateMachine<S, E, C, O>>>
+where
+    S: State,
+    E: Event,
+    O: Default,
+    StateMachine<S, E, C, O>: Send,
+{
+    fn deliver(&self, event: E) {
+        let _ = self.lock().unwrap().dispatch(&event);
+    }
+}
+
+pub type Transform<S, E, T> = Box<dyn Fn(&S, &E, &S) -> Option<T> + Send + Sync>;
+
+/// Maps a source machine's transitions, `(from, event, to)`, to target events.
+pub struct Bridge<S, E, T> {
+    transform: Transform<S, E, T>,
+}
+
+impl<S, E, T> Bridge<S, E, T>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    /// Delivers the event `transform` returns for each transition, if any.
+    pub fn new<F>(transform: F) -> Self
+    where
+        F: Fn(&S, &E, &S) -> Option<T> + 'static + Send + Sync,
+    {
+        Bridge {
+            transform: Box::new(transform),
+        }
+    }
+
+    /// Delivers `event` whenever the source enters `state` from another state.
+    pub fn on_enter(state: S, event: T) -> Self {
+        Self::new(move |from, _, to| (to == &state && from != to).then(|| event.clone()))
+    }
+
+    /// Starts delivering the events produced from `source`'s transitions to
+    /// `target`.
+    pub fn connect<C, O>(
+        self,
+        source: &mut StateMachine<S, E, C, O>,
+        target: impl BridgeTarget<T> + 'static,
+    ) {
+        let transform = self.transform;
+        source.add_observer(move |from, event, to| {
+            if let Some(event) = transform(from, event, to) {
+                target.deliver(event);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Billing {
+        Idle,
+        Running,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum BillingEvent {
+        Start,
+        Stop,
+    }
+
+    fn billing() -> StateMachine<Billing, BillingEvent, ()> {
+        let mut sm = StateMachine::new(Billing::Idle, ());
+        sm.add_transition_to(Billing::Idle, BillingEvent::Start, Billing::Running);
+        sm.add_transition_to(Billing::Running, BillingEvent::Stop, Billing::Idle);
+        sm
+    }
+
+    #[test]
+    fn test_bridge_drives_billing_from_calls() {
+        let mut calls = init_state_machine();
+        let shared = Arc::new(Mutex::new(billing()));
+        Bridge::new(|_, _, to: &CallState| match to {
+            CallState::Connected => Some(BillingEvent::Start),
+            CallState::Disconnected => Some(BillingEvent::Stop),
+            _ => None,
+        })
+        .connect(&mut calls, shared.clone());
+        let actor = ActorHandle::spawn(billing());
+        Bridge::on_enter(CallState::Connected, BillingEvent::Start)
+            .connect(&mut calls, actor.clone());
+
+        calls.dispatch(&CallEvent::Dial).unwrap();
+        assert_eq!(
+            shared.lock().unwrap().get_current_state().unwrap(),
+            &Billing::Idle
+        );
+        calls.dispatch(&CallEvent::Answer).unwrap();
+        assert_eq!(
+            shared.lock().unwrap().get_current_state().unwrap(),
+            &Billing::Running
+        );
+        assert_eq!(actor.state().unwrap(), Billing::Running);
+
+        calls.dispatch(&CallEvent::HangUp).unwrap();
+        assert_eq!(
+            shared.lock().unwrap().get_current_state().unwrap(),
+            &Billing::Idle
+        );
+        assert_eq!(actor.state().unwrap(), Billing::Running);
+    }
+}
@@ -0,0 +1,142 @@
+//! Time sources, so time-dependent behaviour can be tested deterministically.
+//!
+//! A machine reads one clock, set with
+//! [`with_clock`](crate::generic::StateMachine::with_clock), for its timed
+//! clocks, state deadlines, checkpoint intervals and the timestamps of audit
+//! records, history entries and recorded failures. Watchdogs and throttles,
+//! which sit outside any one machine, take their own.
+
+use crate::generic::{Event, State, StateMachine};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, for timestamps; the system's unless overridden.
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The real monotonic clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to; clones share the same time.
+///
+/// Its wall time starts at the system's when created, or at the time given to
+/// [`starting_at`](Self::starting_at), and advances with it.
+#[doc(alias = "MockClock")]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+    origin: (Instant, SystemTime),
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::starting_at(SystemTime::now())
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A clock whose wall time starts at `wall_time`.
+    pub fn starting_at(wall_time: SystemTime) -> Self {
+        let now = Instant::now();
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+            origin: (now, wall_time),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        let (instant, wall_time) = self.origin;
+        wall_time + self.now().saturating_duration_since(instant)
+    }
+}
+
+/// A machine's clock, shared with the hooks and observers that read it, so
+/// `with_clock` also reaches those installed before it.
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<RwLock<Arc<dyn Clock>>>);
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock(Arc::new(RwLock::new(Arc::new(SystemClock))))
+    }
+}
+
+impl Clock for SharedClock {
+    fn now(&self) -> Instant {
+        self.0.read().unwrap().now()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.0.read().unwrap().wall_time()
+    }
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+{
+    /// Reads time from `source` wherever this machine reads the time,
+    /// including hooks and observers installed earlier.
+    pub fn with_clock(self, source: impl Clock + 'static) -> Self {
+        *self.clock.0.write().unwrap() = Arc::new(source);
+        self
+    }
+
+    /// Reads the machine's time source, following later
+    /// [`with_clock`](Self::with_clock) calls.
+    pub(crate) fn time_source(&self) -> impl Fn() -> Instant + Send + Sync + 'static {
+        let clock = self.clock.clone();
+        move || clock.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::MemorySink;
+    use crate::{init_state_machine, CallEvent};
+
+    #[test]
+    fn test_machine_timestamps_follow_its_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = ManualClock::starting_at(start);
+        let (sink, records) = MemorySink::new();
+        let mut sm = init_state_machine().with_history(4);
+        sm.add_audit_sink(sink);
+        let mut sm = sm.with_clock(clock.clone());
+
+        clock.advance(Duration::from_secs(5));
+        sm.dispatch(&CallEvent::Dial).unwrap();
+
+        let at = start + Duration::from_secs(5);
+        assert_eq!(sm.recent_transitions()[0].at, at);
+        assert_eq!(records.lock().unwrap()[0].at, at);
+    }
+}
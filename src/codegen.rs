@@ -0,0 +1,270 @@
+//! Rust source generated from spec files, for build scripts.
+//!
+//! [`Codegen`] turns a [`MachineSpec`] into a module with a state enum, an
+//! event enum and a constructor that registers every transition with its
+//! static target, so large machines can live in `.fsm` files:
+//!
+//! ```no_run
+//! // In build.rs's `main`, with fsmportal as a build-dependency:
+//! let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("call.rs");
+//! fsmportal::codegen::Codegen::new()
+//!     .generate_file("call.fsm", out)
+//!     .unwrap();
+//! println!("cargo:rerun-if-changed=call.fsm");
+//! ```
+//!
+//! The crate then includes it with
+//! `include!(concat!(env!("OUT_DIR"), "/call.rs"));`.
+//!
+//! [`Codegen::with_arbitrary`] also emits `arbitrary::Arbitrary` impls for
+//! both enums, for fuzzing and property tests. They are compiled only when the
+//! including crate enables the named feature, which should pull in its own
+//! `arbitrary` dependency; fsmportal does not need one.
+
+use crate::spec::{MachineSpec, SpecError};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+pub struct Codegen {
+    state_enum: String,
+    event_enum: String,
+    constructor: String,
+    arbitrary_feature: Option<String>,
+}
+
+impl Default for Codegen {
+    fn default() -> Self {
+        Codegen {
+            state_enum: "State".to_string(),
+            event_enum: "Event".to_string(),
+            constructor: "machine".to_string(),
+            arbitrary_feature: None,
+        }
+    }
+}
+
+// Strict and reserved keywords, which cannot name a variant.
+const KEYWORDS: &[&str] = &[
+    "_", "abstract", "as", "async", "await", "become", "box", "break", "const", "continue",
+    "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if",
+    "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+}
+
+fn write_enum(out: &mut String, name: &str, variants: &BTreeSet<&str>) {
+    let _ = writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]");
+    let _ = writeln!(out, "pub enum {} {{", name);
+    for variant in variants {
+        let _ = writeln!(out, "    {},", variant);
+    }
+    let _ = writeln!(out, "}}\n");
+    let _ = writeln!(out, "impl ::std::str::FromStr for {} {{", name);
+    let _ = writeln!(out, "    type Err = ();\n");
+    let _ = writeln!(
+        out,
+        "    fn from_str(s: &str) -> ::std::result::Result<Self, ()> {{"
+    );
+    let _ = writeln!(out, "        match s {{");
+    for variant in variants {
+        let _ = writeln!(out, "            \"{0}\" => Ok({1}::{0}),", variant, name);
+    }
+    let _ = writeln!(out, "            _ => Err(()),");
+    let _ = writeln!(out, "        }}\n    }}\n}}\n");
+}
+
+fn write_arbitrary(out: &mut String, name: &str, variants: &BTreeSet<&str>, feature: &str) {
+    let _ = writeln!(out, "#[cfg(feature = \"{}\")]", feature);
+    let _ = writeln!(out, "impl<'a> ::arbitrary::Arbitrary<'a> for {} {{", name);
+    let _ = writeln!(
+        out,
+        "    fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {{"
+    );
+    let all: Vec<String> = variants
+        .iter()
+        .map(|variant| format!("{}::{}", name, variant))
+        .collect();
+    let _ = writeln!(
+        out,
+        "        const ALL: [{}; {}] = [{}];",
+        name,
+        all.len(),
+        all.join(", ")
+    );
+    let _ = writeln!(out, "        Ok(*u.choose(&ALL)?)");
+    let _ = writeln!(out, "    }}\n}}\n");
+}
+
+impl Codegen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the generated state enum; `State` by default.
+    pub fn with_state_enum(mut self, name: impl Into<String>) -> Self {
+        self.state_enum = name.into();
+        self
+    }
+
+    /// Names the generated event enum; `Event` by default.
+    pub fn with_event_enum(mut self, name: impl Into<String>) -> Self {
+        self.event_enum = name.into();
+        self
+    }
+
+    /// Names the generated constructor; `machine` by default.
+    pub fn with_constructor(mut self, name: impl Into<String>) -> Self {
+        self.constructor = name.into();
+        self
+    }
+
+    /// Also generates `arbitrary::Arbitrary` impls for the state and event
+    /// enums, enabled by the including crate's `feature`.
+    pub fn with_arbitrary(mut self, feature: impl Into<String>) -> Self {
+        self.arbitrary_feature = Some(feature.into());
+        self
+    }
+
+    /// Generates the module source for `spec`. State and event names must be
+    /// Rust identifiers other than keywords, and each `(from, event)` pair may
+    /// appear once.
+    pub fn generate(&self, spec: &MachineSpec) -> Result<String, SpecError> {
+        let not_identifier = |line: usize, name: &str| SpecError::Parse {
+            line,
+            message: format!("`{}` is not a Rust identifier", name),
+        };
+        if !is_identifier(&spec.initial) {
+            return Err(not_identifier(spec.initial_line, &spec.initial));
+        }
+        let mut seen = HashSet::new();
+        for t in &spec.transitions {
+            for name in [&t.from, &t.event, &t.to] {
+                if !is_identifier(name) {
+                    return Err(not_identifier(t.line, name));
+                }
+            }
+            if !seen.insert((&t.from, &t.event)) {
+                return Err(SpecError::DuplicateTransition {
+                    line: t.line,
+                    from: t.from.clone(),
+                    event: t.event.clone(),
+                });
+            }
+        }
+
+        let (state, event) = (&self.state_enum, &self.event_enum);
+        let events: BTreeSet<&str> = spec.transitions.iter().map(|t| t.event.as_str()).collect();
+        let mut out = String::from("// Generated by fsmportal::codegen. Do not edit.\n\n");
+        let states = spec.states();
+        write_enum(&mut out, state, &states);
+        write_enum(&mut out, event, &events);
+        if let Some(feature) = &self.arbitrary_feature {
+            write_arbitrary(&mut out, state, &states, feature);
+            write_arbitrary(&mut out, event, &events, feature);
+        }
+
+        let _ = writeln!(
+            out,
+            "pub fn {}<C>(context: C) -> ::fsmportal::generic::StateMachine<{}, {}, C> {{",
+            self.constructor, state, event
+        );
+        let _ = writeln!(
+            out,
+            "    let mut sm = ::fsmportal::generic::StateMachine::with_capacity({}::{}, context, {});",
+            state,
+            spec.initial,
+            spec.transitions.len()
+        );
+        for t in &spec.transitions {
+            let _ = writeln!(
+                out,
+                "    sm.add_transition_to({0}::{2}, {1}::{3}, {0}::{4});",
+                state, event, t.from, t.event, t.to
+            );
+        }
+        out.push_str("    sm\n}\n");
+        Ok(out)
+    }
+
+    /// Reads the spec at `spec_path` and writes the generated module to `out_path`.
+    pub fn generate_file(
+        &self,
+        spec_path: impl AsRef<Path>,
+        out_path: impl AsRef<Path>,
+    ) -> Result<(), SpecError> {
+        let source = self.generate(&MachineSpec::from_file(spec_path)?)?;
+        fs::write(out_path, source)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_module() {
+        let spec =
+            MachineSpec::parse("initial Off\nOff --Toggle--> On\nOn --Toggle--> Off\n").unwrap();
+        let source = Codegen::new()
+            .with_state_enum("Light")
+            .with_constructor("light")
+            .generate(&spec)
+            .unwrap();
+
+        assert!(source.contains("pub enum Light {\n    Off,\n    On,\n}\n"));
+        assert!(source.contains("            \"Toggle\" => Ok(Event::Toggle),\n"));
+        assert!(source.contains("pub fn light<C>(context: C)"));
+        assert!(
+            source.contains("    sm.add_transition_to(Light::On, Event::Toggle, Light::Off);\n")
+        );
+
+        assert!(!source.contains("arbitrary"));
+        let source = Codegen::new()
+            .with_arbitrary("fuzzing")
+            .generate(&spec)
+            .unwrap();
+        assert!(source.contains(
+            "#[cfg(feature = \"fuzzing\")]\nimpl<'a> ::arbitrary::Arbitrary<'a> for State {\n"
+        ));
+        assert!(source.contains("        const ALL: [State; 2] = [State::Off, State::On];\n"));
+
+        let spec = MachineSpec::parse("initial Off\nOff --Switch on--> On\n").unwrap();
+        assert!(matches!(
+            Codegen::new().generate(&spec),
+            Err(SpecError::Parse { line: 2, .. })
+        ));
+        for keyword in ["type", "Self", "match"] {
+            let spec =
+                MachineSpec::parse(&format!("initial Off\nOff --{}--> On\n", keyword)).unwrap();
+            assert!(matches!(
+                Codegen::new().generate(&spec),
+                Err(SpecError::Parse { line: 2, .. })
+            ));
+        }
+    }
+
+    // tests/codegen.rs compiles the fixture.
+    #[test]
+    fn test_fixture_is_current() {
+        let spec = MachineSpec::parse(
+            "initial Off\nOff --Toggle--> On\nOn --Toggle--> Off\nOn --Dim--> Dimmed\nDimmed --Toggle--> Off\n",
+        )
+        .unwrap();
+        let source = Codegen::new()
+            .with_state_enum("Light")
+            .with_constructor("light")
+            .generate(&spec)
+            .unwrap();
+        assert_eq!(source, include_str!("../tests/fixtures/light.rs"));
+    }
+}
@@ -0,0 +1,208 @@
+//! Combining two machines over the same events into one with tuple states.
+//!
+//! Like the [`analysis`](crate::analysis) module, the combinators read the
+//! static transition tables and report handlers without a declared target
+//! as [`DynamicTransition`](crate::analysis::AnalysisError::DynamicTransition).
+//! The results are context-free machines with declared targets, so they can
+//! be analysed, exported and composed further.
+
+use crate::analysis::PairError;
+use crate::generic::{Event, State, StateMachine};
+use std::collections::{HashSet, VecDeque};
+
+pub type Product<S1, S2, E> = StateMachine<(S1, S2), E, ()>;
+pub type Union<S1, S2, E> = StateMachine<(Option<S1>, Option<S2>), E, ()>;
+
+/// Builds the reachable part of a machine over `P` from `start`, where
+/// `step` gives a state's successor on an event.
+fn explore<P, E, R, F>(start: P, events: &[E], mut step: F) -> Result<StateMachine<P, E, ()>, R>
+where
+    P: State + Send + Sync + 'static,
+    E: Event,
+    F: FnMut(&P, &E) -> Result<Option<P>, R>,
+{
+    let mut sm = StateMachine::new(start.clone(), ());
+    let mut seen = HashSet::from([start.clone()]);
+    let mut queue = VecDeque::from([start]);
+    while let Some(state) = queue.pop_front() {
+        for event in events {
+            let Some(next) = step(&state, event)? else {
+                continue;
+            };
+            if seen.insert(next.clone()) {
+                queue.push_back(next.clone());
+            }
+            sm.add_transition_to(state.clone(), event.clone(), next);
+        }
+    }
+    Ok(sm)
+}
+
+/// The events of both machines, each once, ordered by Debug representation.
+fn alphabet<S1, S2, E, C1, O1, C2, O2>(
+    left: &StateMachine<S1, E, C1, O1>,
+    right: &StateMachine<S2, E, C2, O2>,
+) -> (Vec<E>, Vec<E>, Vec<E>)
+where
+    S1: State,
+    S2: State,
+    E: Event,
+{
+    let (left_events, right_events) = (left.static_events(), right.static_events());
+    let mut events = left_events.clone();
+    for event in &right_events {
+        if !events.contains(event) {
+            events.push(event.clone());
+        }
+    }
+    events.sort_by_cached_key(|event| format!("{:?}", event));
+    (events, left_events, right_events)
+}
+
+fn next_left<S1: State, S2, E: Event, C, O>(
+    machine: &StateMachine<S1, E, C, O>,
+    state: &S1,
+    event: &E,
+) -> Result<Option<S1>, PairError<S1, S2, E>> {
+    machine.static_next(state, event).map_err(PairError::Left)
+}
+
+fn next_right<S1, S2: State, E: Event, C, O>(
+    machine: &StateMachine<S2, E, C, O>,
+    state: &S2,
+    event: &E,
+) -> Result<Option<S2>, PairError<S1, S2, E>> {
+    machine.static_next(state, event).map_err(PairError::Right)
+}
+
+/// The synchronous product, starting from both machines' current states.
+/// An event both machines know must be accepted by both and moves both; an
+/// event only one knows moves that one alone. The product therefore accepts
+/// the interleavings that respect both machines, such as a call that may
+/// only connect once billing has authorised it.
+pub fn product<S1, S2, E, C1, O1, C2, O2>(
+    left: &StateMachine<S1, E, C1, O1>,
+    right: &StateMachine<S2, E, C2, O2>,
+) -> Result<Product<S1, S2, E>, PairError<S1, S2, E>>
+where
+    S1: State + Send + Sync + 'static,
+    S2: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+{
+    let start = (
+        left.initial_state().map_err(PairError::Left)?,
+        right.initial_state().map_err(PairError::Right)?,
+    );
+    let (events, left_events, right_events) = alphabet(left, right);
+    explore(start, &events, |(l, r): &(S1, S2), event: &E| {
+        let l = match left_events.contains(event) {
+            true => next_left(left, l, event)?,
+            false => Some(l.clone()),
+        };
+        let r = match right_events.contains(event) {
+            true => next_right(right, r, event)?,
+            false => Some(r.clone()),
+        };
+        Ok(l.zip(r))
+    })
+}
+
+/// The union, starting from both machines' current states: an event is
+/// accepted while either machine accepts it. A side becomes `None` once it
+/// has rejected an event and takes no further part.
+pub fn union<S1, S2, E, C1, O1, C2, O2>(
+    left: &StateMachine<S1, E, C1, O1>,
+    right: &StateMachine<S2, E, C2, O2>,
+) -> Result<Union<S1, S2, E>, PairError<S1, S2, E>>
+where
+    S1: State + Send + Sync + 'static,
+    S2: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+{
+    let start = (
+        Some(left.initial_state().map_err(PairError::Left)?),
+        Some(right.initial_state().map_err(PairError::Right)?),
+    );
+    let (events, _, _) = alphabet(left, right);
+    explore(
+        start,
+        &events,
+        |(l, r): &(Option<S1>, Option<S2>), event: &E| {
+            let l = match l {
+                Some(l) => next_left(left, l, event)?,
+                None => None,
+            };
+            let r = match r {
+                Some(r) => next_right(right, r, event)?,
+                None => None,
+            };
+            Ok((l.is_some() || r.is_some()).then_some((l, r)))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CallEvent, CallState};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Billing {
+        Unauthorised,
+        Authorised,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Event {
+        Call(CallEvent),
+        Authorise,
+    }
+
+    fn calls() -> StateMachine<CallState, Event, ()> {
+        let mut sm = StateMachine::new(CallState::Idle, ());
+        for (from, event, to) in [
+            (CallState::Idle, CallEvent::Dial, CallState::Dialing),
+            (CallState::Dialing, CallEvent::Answer, CallState::Connected),
+            (CallState::Connected, CallEvent::HangUp, CallState::Idle),
+        ] {
+            sm.add_transition_to(from, Event::Call(event), to);
+        }
+        sm
+    }
+
+    fn billing() -> StateMachine<Billing, Event, ()> {
+        let mut sm = StateMachine::new(Billing::Unauthorised, ());
+        sm.add_transition_to(Billing::Unauthorised, Event::Authorise, Billing::Authorised);
+        sm.add_transition_to(
+            Billing::Authorised,
+            Event::Call(CallEvent::Answer),
+            Billing::Authorised,
+        );
+        sm
+    }
+
+    #[test]
+    fn test_product_enforces_both() {
+        let mut sm = product(&calls(), &billing()).unwrap();
+        sm.dispatch(&Event::Call(CallEvent::Dial)).unwrap();
+        assert!(sm.dispatch(&Event::Call(CallEvent::Answer)).is_err());
+        sm.dispatch(&Event::Authorise).unwrap();
+        sm.dispatch(&Event::Call(CallEvent::Answer)).unwrap();
+        assert_eq!(
+            sm.get_current_state().ok(),
+            Some(&(CallState::Connected, Billing::Authorised))
+        );
+    }
+
+    #[test]
+    fn test_union_accepts_either() {
+        let mut sm = union(&calls(), &billing()).unwrap();
+        sm.dispatch(&Event::Authorise).unwrap();
+        assert_eq!(
+            sm.get_current_state().ok(),
+            Some(&(None, Some(Billing::Authorised)))
+        );
+        sm.dispatch(&Event::Call(CallEvent::Answer)).unwrap();
+        assert!(sm.dispatch(&Event::Call(CallEvent::Dial)).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Containing panics in handlers.
+//!
+//! A machine built [`with_panic_containment`](StateMachine::with_panic_containment)
+//! catches a panic in one of its handlers, sync or async, and fails the
+//! dispatch with `StateMachineError::HandlerPanicked` carrying the panic
+//! message. The machine stays in the state it was in, so a panicking handler
+//! neither kills an actor thread nor poisons the lock around a shared
+//! machine. The recovery policy applies as to any other handler error; the
+//! handler's own changes to the context before it panicked remain.
+//!
+//! Hooks, observers and guards are not contained.
+
+use crate::generic::{Event, State, StateMachine, StateMachineError};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+{
+    /// Turns panics in handlers into `StateMachineError::HandlerPanicked`.
+    pub fn with_panic_containment(mut self) -> Self {
+        self.contain_panics = true;
+        self
+    }
+}
+
+/// Runs `f`, catching a panic as its message if `enabled`.
+pub(crate) fn contain<T>(enabled: bool, f: impl FnOnce() -> T) -> Result<T, String> {
+    if !enabled {
+        return Ok(f());
+    }
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| message(&*payload))
+}
+
+/// The error for a handler registered in `state` that panicked with `message`.
+pub(crate) fn panicked<S: Clone, E: Clone>(
+    state: &S,
+    event: &E,
+    message: String,
+) -> StateMachineError<S, E> {
+    StateMachineError::HandlerPanicked {
+        state: state.clone(),
+        event: event.clone(),
+        message,
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| String::from("panic with a non-string payload")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::actor::ActorHandle;
+    use crate::generic::StateMachineError;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_panicking_handlers_fail_the_dispatch() {
+        let mut sm = init_state_machine().with_panic_containment();
+        sm.add_transition(CallState::Idle, CallEvent::Dial, |_, _| {
+            panic!("no route to {}", "+15550100")
+        });
+        sm.add_async_transition(CallState::Idle, CallEvent::Incoming, |_, _, _| {
+            Box::pin(async { panic!("codec missing") })
+        });
+        let handle = ActorHandle::spawn(sm);
+
+        match handle.dispatch(CallEvent::Dial).recv().unwrap() {
+            Err(StateMachineError::HandlerPanicked { state, message, .. }) => {
+                assert_eq!(state, CallState::Idle);
+                assert_eq!(message, "no route to +15550100");
+            }
+            other => panic!("expected a contained panic, got {:?}", other),
+        }
+        assert!(matches!(
+            handle.dispatch(CallEvent::Incoming).recv().unwrap(),
+            Err(StateMachineError::HandlerPanicked { .. })
+        ));
+        assert_eq!(handle.state().unwrap(), CallState::Idle);
+        assert!(!handle.is_stopped());
+    }
+}
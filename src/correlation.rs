@@ -0,0 +1,150 @@
+//! Correlation IDs linking one call's journey across logs and services.
+//!
+//! An ID attached with [`AuditContext::correlation_id`] is current on the
+//! dispatching thread for the whole of [`dispatch_as`](crate::generic::StateMachine::dispatch_as)
+//! (and its async and actor counterparts), so handlers, hooks and observers
+//! can read it with [`current`] and stamp their own logs or outgoing
+//! requests. Audit records carry it too. Dispatches without an ID of their
+//! own inherit the current one, so a handler that dispatches into another
+//! machine propagates it. Async dispatches make the ID current only while
+//! they are being polled, so it never leaks to other work on the thread.
+//!
+//! [`AuditContext::correlation_id`]: crate::audit::AuditContext::correlation_id
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::pin;
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The correlation ID of the dispatch running on this thread, if any.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Restores the previous ID when dropped.
+pub(crate) struct Scope(Option<Option<String>>);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Makes `id` current until the returned scope is dropped; `None` keeps the
+/// current ID.
+pub(crate) fn enter(id: Option<&str>) -> Scope {
+    Scope(id.map(|id| CURRENT.with(|current| current.replace(Some(id.to_string())))))
+}
+
+/// Makes `id` current around each poll of `future`; `None` keeps the current ID.
+pub(crate) async fn within<F: Future>(id: Option<String>, future: F) -> F::Output {
+    let mut future = pin!(future);
+    std::future::poll_fn(|cx| {
+        let _scope = enter(id.as_deref());
+        future.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// Runs `f` with `id` as the current correlation ID, for work started outside
+/// a dispatch, such as handling an incoming request.
+pub fn scope<R>(id: &str, f: impl FnOnce() -> R) -> R {
+    let _scope = enter(Some(id));
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{AuditContext, MemorySink};
+    use crate::generic::Response;
+    use crate::task::CancellationToken;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+
+    #[test]
+    fn test_dispatch_propagates_correlation_id() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (sink, log) = MemorySink::new();
+        let mut sm = init_state_machine();
+        sm.add_audit_sink(sink);
+        sm.add_observer({
+            let seen = seen.clone();
+            move |_, _, _| seen.lock().unwrap().push(current())
+        });
+
+        let context = AuditContext::new("switch").correlation_id("call-42");
+        sm.dispatch_as(&CallEvent::Incoming, &context).unwrap();
+        scope("call-43", || sm.dispatch(&CallEvent::Answer).unwrap());
+        sm.dispatch(&CallEvent::HangUp).unwrap();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                Some("call-42".to_string()),
+                Some("call-43".to_string()),
+                None
+            ]
+        );
+        let log = log.lock().unwrap();
+        assert_eq!(log[1].context.correlation_id.as_deref(), Some("call-43"));
+        assert!(log[0].to_json().contains("\"correlation_id\":\"call-42\""));
+        assert!(!log[2].to_json().contains("correlation_id"));
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn test_scopes_nest_and_restore() {
+        assert_eq!(current(), None);
+        scope("outer", || {
+            assert_eq!(current().as_deref(), Some("outer"));
+            {
+                let _inner = enter(Some("inner"));
+                assert_eq!(current().as_deref(), Some("inner"));
+                let _keep = enter(None);
+                assert_eq!(current().as_deref(), Some("inner"));
+            }
+            assert_eq!(current().as_deref(), Some("outer"));
+        });
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn test_async_dispatch_scopes_each_poll() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut sm = init_state_machine();
+        let log = seen.clone();
+        sm.add_async_transition(CallState::Idle, CallEvent::Dial, move |_, _, _| {
+            let log = log.clone();
+            Box::pin(async move {
+                let mut yielded = false;
+                std::future::poll_fn(|cx| {
+                    log.lock().unwrap().push(current());
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+                Ok((Response::Transition(CallState::Dialing), ()))
+            })
+        });
+
+        let token = CancellationToken::new();
+        let context = AuditContext::default().correlation_id("call-7");
+        let mut dispatch = pin!(sm.dispatch_async_as(&CallEvent::Dial, &token, &context));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(dispatch.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(current(), None);
+        assert!(dispatch.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(*seen.lock().unwrap(), vec![Some("call-7".to_string()); 2]);
+    }
+}
@@ -0,0 +1,246 @@
+//! State residency deadlines: "`Ringing` must be left within 30s of entering it".
+//!
+//! A deadline set with [`set_state_deadline`](StateMachine::set_state_deadline)
+//! is armed each time the machine enters its state and disarmed when it
+//! leaves; a self-transition does not restart it. Unlike a
+//! [timed guard](crate::timed), the deadline belongs to the visit, so a
+//! handler that is still making progress can push it back with
+//! [`extend_deadline`](StateMachine::extend_deadline).
+//!
+//! Deadlines are checked by [`check_deadlines`](StateMachine::check_deadlines),
+//! which whatever drives the machine calls periodically, for example a timer
+//! thread going through [`ActorHandle::with`](crate::actor::ActorHandle::with).
+//! An overdue deadline escalates once per visit. Time is read from the
+//! machine's [`Clock`](crate::clock::Clock).
+
+use crate::dispatch_ctx::DispatchCtx;
+use crate::generic::{Event, HandlerResult, Response, State, StateMachine};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub type DeadlineCallback<S, C> = Arc<dyn Fn(&mut C, &S) + Send + Sync>;
+
+/// What happens when a state's deadline passes.
+pub enum OnDeadline<S, E, C> {
+    /// Dispatches the event, typically one leading to an error or cleanup state.
+    Dispatch(E),
+    /// Calls back with the context and the overdue state.
+    Callback(DeadlineCallback<S, C>),
+}
+
+impl<S, E: Clone, C> Clone for OnDeadline<S, E, C> {
+    fn clone(&self) -> Self {
+        match self {
+            OnDeadline::Dispatch(event) => OnDeadline::Dispatch(event.clone()),
+            OnDeadline::Callback(callback) => OnDeadline::Callback(callback.clone()),
+        }
+    }
+}
+
+struct Deadlines<S, E, C> {
+    limits: HashMap<S, (Duration, OnDeadline<S, E, C>)>,
+    /// The state being visited and when its deadline is due.
+    armed: Option<(S, Instant)>,
+    now: Box<dyn Fn() -> Instant + Send + Sync>,
+}
+
+impl<S: State, E, C> Deadlines<S, E, C> {
+    fn enter(&mut self, state: &S) {
+        if self.armed.as_ref().is_some_and(|(armed, _)| armed == state) {
+            return;
+        }
+        self.armed = self
+            .limits
+            .get(state)
+            .map(|(limit, _)| (state.clone(), (self.now)() + *limit));
+    }
+
+    fn extend(&mut self, by: Duration) {
+        if let Some((_, due)) = &mut self.armed {
+            *due += by;
+        }
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        let (_, due) = self.armed.as_ref()?;
+        Some(due.saturating_duration_since((self.now)()))
+    }
+}
+
+/// Shared with the observer and state listener that arm deadlines.
+struct Shared<S, E, C>(Arc<Mutex<Deadlines<S, E, C>>>);
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    C: 'static,
+    O: Default,
+{
+    fn deadlines(&mut self) -> Arc<Mutex<Deadlines<S, E, C>>> {
+        if let Some(Shared(deadlines)) = self.ext::<Shared<S, E, C>>() {
+            return deadlines.clone();
+        }
+        let deadlines = Arc::new(Mutex::new(Deadlines {
+            limits: HashMap::new(),
+            armed: None,
+            now: Box::new(self.time_source()),
+        }));
+        self.insert_ext(Shared(deadlines.clone()));
+        let entered = deadlines.clone();
+        self.add_observer(move |_, _, to| entered.lock().unwrap().enter(to));
+        let announced = deadlines.clone();
+        self.state_listeners.push(Arc::new(move |state| {
+            announced.lock().unwrap().enter(state)
+        }));
+        deadlines
+    }
+
+    /// Requires `state` to be left within `limit` of entering it, escalating
+    /// per `on_deadline` otherwise. Replaces any earlier deadline for `state`.
+    pub fn set_state_deadline(
+        &mut self,
+        state: S,
+        limit: Duration,
+        on_deadline: OnDeadline<S, E, C>,
+    ) {
+        let deadlines = self.deadlines();
+        let mut deadlines = deadlines.lock().unwrap();
+        deadlines.limits.insert(state, (limit, on_deadline));
+        if let Some(current) = &self.current_state {
+            deadlines.enter(current);
+        }
+    }
+
+    /// Pushes back the deadline of the current visit by `by`; does nothing if
+    /// the current state has no deadline armed.
+    pub fn extend_deadline(&mut self, by: Duration) {
+        if let Some(Shared(deadlines)) = self.ext::<Shared<S, E, C>>() {
+            deadlines.lock().unwrap().extend(by);
+        }
+    }
+
+    /// Time left before the current visit's deadline, zero once it has passed.
+    pub fn time_to_deadline(&self) -> Option<Duration> {
+        let Shared(deadlines) = self.ext::<Shared<S, E, C>>()?;
+        let remaining = deadlines.lock().unwrap().remaining();
+        remaining
+    }
+
+    /// Escalates the current visit if its deadline has passed, returning the
+    /// escalation event's dispatch result, or `Response::Handled` for a
+    /// callback. `None` if nothing was overdue.
+    pub fn check_deadlines(&mut self) -> Option<HandlerResult<S, E, O>> {
+        let Shared(deadlines) = self.ext::<Shared<S, E, C>>()?;
+        let (state, on_deadline) = {
+            let mut deadlines = deadlines.lock().unwrap();
+            let (_, due) = deadlines.armed.as_ref()?;
+            if (deadlines.now)() < *due {
+                return None;
+            }
+            let (state, _) = deadlines.armed.take()?;
+            let (_, on_deadline) = deadlines.limits.get(&state)?;
+            (state, on_deadline.clone())
+        };
+        match on_deadline {
+            OnDeadline::Dispatch(event) => Some(self.dispatch(&event)),
+            OnDeadline::Callback(callback) => {
+                callback(&mut self.context, &state);
+                Some(Ok((Response::Handled, O::default())))
+            }
+        }
+    }
+}
+
+impl<S, E, C, O, H> DispatchCtx<'_, S, E, C, O, H>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    C: 'static,
+    H: BuildHasher,
+{
+    /// Like [`StateMachine::extend_deadline`], from inside a handler.
+    pub fn extend_deadline(&mut self, by: Duration) {
+        if let Some(Shared(deadlines)) = self.ext::<Shared<S, E, C>>() {
+            deadlines.lock().unwrap().extend(by);
+        }
+    }
+
+    /// Like [`StateMachine::time_to_deadline`].
+    pub fn time_to_deadline(&self) -> Option<Duration> {
+        let Shared(deadlines) = self.ext::<Shared<S, E, C>>()?;
+        let remaining = deadlines.lock().unwrap().remaining();
+        remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_deadline_escalates_unless_extended() {
+        let clock = ManualClock::new();
+        let mut sm = init_state_machine().with_clock(clock.clone());
+        sm.set_state_deadline(
+            CallState::Ringing,
+            Duration::from_secs(30),
+            OnDeadline::Dispatch(CallEvent::HangUp),
+        );
+        sm.set_state_deadline(
+            CallState::Idle,
+            Duration::from_secs(60),
+            OnDeadline::Callback(Arc::new(|context: &mut HashMap<String, usize>, _| {
+                *context.entry("idle_escalations".to_string()).or_default() += 1;
+            })),
+        );
+        assert_eq!(sm.time_to_deadline(), Some(Duration::from_secs(60)));
+
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        clock.advance(Duration::from_secs(20));
+        assert!(sm.check_deadlines().is_none());
+        sm.extend_deadline(Duration::from_secs(10));
+        clock.advance(Duration::from_secs(15));
+        assert!(sm.check_deadlines().is_none());
+        assert_eq!(sm.time_to_deadline(), Some(Duration::from_secs(5)));
+
+        clock.advance(Duration::from_secs(5));
+        sm.check_deadlines().unwrap().unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Disconnected);
+        assert_eq!(sm.time_to_deadline(), None);
+
+        sm.dispatch(&CallEvent::Reset).unwrap();
+        clock.advance(Duration::from_secs(60));
+        assert!(matches!(
+            sm.check_deadlines(),
+            Some(Ok((Response::Handled, ())))
+        ));
+        assert!(sm.check_deadlines().is_none());
+        assert_eq!(sm.get_context()["idle_escalations"], 1);
+    }
+
+    #[test]
+    fn test_handler_extends_deadline() {
+        let clock = ManualClock::new();
+        let mut sm = init_state_machine().with_clock(clock.clone());
+        sm.set_state_deadline(
+            CallState::Ringing,
+            Duration::from_secs(30),
+            OnDeadline::Dispatch(CallEvent::HangUp),
+        );
+        sm.add_transition_with_ctx(CallState::Ringing, CallEvent::Incoming, |ctx, _| {
+            assert_eq!(ctx.time_to_deadline(), Some(Duration::from_secs(10)));
+            ctx.extend_deadline(Duration::from_secs(20));
+            Ok(Response::Handled)
+        });
+
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        clock.advance(Duration::from_secs(20));
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        assert_eq!(sm.time_to_deadline(), Some(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,361 @@
+//! Live visualization server, enabled with the `debug-server` feature.
+//!
+//! Serves a page showing the machine's SVG diagram with the current state
+//! highlighted, and pushes every transition to connected browsers over a
+//! WebSocket at `/ws`. The page needs no network access beyond the server.
+//!
+//! Each browser gets its own writer thread fed through a bounded queue, so a
+//! stalled tab is dropped instead of blocking the machine's dispatch.
+
+use crate::export::mermaid_id;
+use crate::generic::{Event, State, StateMachine};
+use crate::json;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Frames queued for one client before it is considered stalled.
+const CLIENT_QUEUE: usize = 64;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PAGE: &str = r##"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>fsmportal</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+#log { font-family: monospace; }
+</style>
+</head>
+<body>
+<h1 id="state"></h1>
+<div id="diagram"></div>
+<ol id="log" reversed></ol>
+<script>
+function render(current) {
+  document.getElementById("state").textContent = current.name;
+  for (const node of document.querySelectorAll("#diagram g[data-state]")) {
+    const on = node.dataset.state === current.name;
+    node.classList.toggle("current", on);
+    const rect = node.querySelector("rect");
+    rect.setAttribute("fill", on ? "#fde68a" : "#f8fafc");
+    rect.setAttribute("stroke-width", on ? "2.5" : "1.2");
+  }
+}
+(async () => {
+  document.getElementById("diagram").innerHTML = await (await fetch("/diagram")).text();
+  const current = await (await fetch("/state")).json();
+  if (current) render(current);
+  const ws = new WebSocket("ws://" + location.host + "/ws");
+  ws.onmessage = (message) => {
+    const t = JSON.parse(message.data);
+    const item = document.createElement("li");
+    item.textContent = t.from.name + " --" + t.event + "--> " + t.to.name;
+    document.getElementById("log").prepend(item);
+    render(t.to);
+  };
+})();
+</script>
+</body>
+</html>
+"##;
+
+struct Shared {
+    diagram: String,
+    current: Mutex<String>,
+    clients: Mutex<Vec<SyncSender<Arc<[u8]>>>>,
+}
+
+/// Handle to a running debug server; the listener thread lives as long as the process.
+pub struct DebugServer {
+    addr: SocketAddr,
+}
+
+impl DebugServer {
+    /// Binds `addr` and starts serving the diagram of `sm`, registering an
+    /// observer on the machine that pushes each transition to the browser.
+    ///
+    /// The diagram is captured once, so transitions added afterwards are not drawn.
+    pub fn attach<S, E, C, O>(
+        sm: &mut StateMachine<S, E, C, O>,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<DebugServer>
+    where
+        S: State,
+        E: Event,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let current = match &sm.current_state {
+            Some(state) => state_json(state),
+            None => String::from("null"),
+        };
+        let shared = Arc::new(Shared {
+            diagram: sm.to_svg(),
+            current: Mutex::new(current),
+            clients: Mutex::new(Vec::new()),
+        });
+
+        let observed = shared.clone();
+        sm.add_observer(move |from, event, to| {
+            let to = state_json(to);
+            let message = format!(
+                "{{\"from\":{},\"event\":\"{}\",\"to\":{}}}",
+                state_json(from),
+                json::escape(&format!("{:?}", event)),
+                to
+            );
+            *observed.current.lock().unwrap() = to;
+            let frame: Arc<[u8]> = text_frame(&message).into();
+            // A full queue means the client stopped reading; drop it.
+            observed
+                .clients
+                .lock()
+                .unwrap()
+                .retain(|client| client.try_send(frame.clone()).is_ok());
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let _ = serve(stream, &shared);
+                });
+            }
+        });
+
+        Ok(DebugServer { addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn serve(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let mut websocket_key = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                websocket_key = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    match (path, websocket_key) {
+        ("/ws", Some(key)) => {
+            let accept = base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+            // Hold the client list across the handshake so no frame can precede it.
+            let mut clients = shared.clients.lock().unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            )?;
+            let (frames, queued) = mpsc::sync_channel::<Arc<[u8]>>(CLIENT_QUEUE);
+            clients.push(frames);
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            thread::spawn(move || {
+                // Returning drops the receiver, and the observer then drops the client.
+                for frame in queued {
+                    if stream.write_all(&frame).is_err() {
+                        return;
+                    }
+                }
+            });
+            Ok(())
+        }
+        ("/", _) => respond(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE),
+        ("/diagram", _) => respond(&mut stream, "200 OK", "image/svg+xml", &shared.diagram),
+        ("/state", _) => {
+            let current = shared.current.lock().unwrap().clone();
+            respond(&mut stream, "200 OK", "application/json", &current)
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "not found"),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+fn state_json<S: State>(state: &S) -> String {
+    let name = format!("{:?}", state);
+    format!(
+        "{{\"name\":\"{}\",\"id\":\"{}\"}}",
+        json::escape(&name),
+        json::escape(&mermaid_id(&name))
+    )
+}
+
+fn text_frame(message: &str) -> Vec<u8> {
+    let payload = message.as_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = (u32::from(chunk[0]) << 16)
+            | (u32::from(*chunk.get(1).unwrap_or(&0)) << 8)
+            | u32::from(*chunk.get(2).unwrap_or(&0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Stateful;
+    use crate::{init_state_machine, CallEvent};
+    use std::io::Read;
+
+    #[test]
+    fn test_websocket_accept_key() {
+        // Example handshake from RFC 6455, section 1.3.
+        let key = format!("{}{}", "dGhlIHNhbXBsZSBub25jZQ==", WEBSOCKET_GUID);
+        assert_eq!(
+            base64(&sha1(key.as_bytes())),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_page_works_offline() {
+        assert!(!PAGE.contains("https://"));
+        let mut sm = init_state_machine();
+        let server = DebugServer::attach(&mut sm, "127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        write!(stream, "GET /diagram HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.contains("Content-Type: image/svg+xml"));
+        assert!(response.contains("data-state=\"Idle\""));
+    }
+
+    #[test]
+    fn test_pushes_transitions_to_websocket_clients() {
+        let mut sm = init_state_machine();
+        let server = DebugServer::attach(&mut sm, "127.0.0.1:0").unwrap();
+
+        let mut ws = TcpStream::connect(server.local_addr()).unwrap();
+        write!(
+            ws,
+            "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        )
+        .unwrap();
+        let mut reader = BufReader::new(ws);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 101"));
+        while line.trim() != "" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+
+        sm.handle_event(&CallEvent::Dial).unwrap();
+
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x81);
+        let mut payload = vec![0u8; header[1] as usize];
+        reader.read_exact(&mut payload).unwrap();
+        let message = String::from_utf8(payload).unwrap();
+        assert!(message.contains("\"event\":\"Dial\""));
+        assert!(message.contains("\"to\":{\"name\":\"Dialing\",\"id\":\"Dialing\"}"));
+    }
+}
@@ -0,0 +1,149 @@
+//! Idempotency keys and duplicate suppression.
+//!
+//! Events delivered by at-least-once message buses can arrive more than once.
+//! A dispatch given a key with [`AuditContext::idempotency_key`] is processed
+//! at most once per key while the key stays in the machine's window of
+//! recently processed keys, set up with
+//! [`with_deduplication`](StateMachine::with_deduplication). A key enters the
+//! window only once its dispatch succeeds, so a redelivery after a failure is
+//! processed again. Dispatches without a key are never deduplicated.
+
+use crate::audit::AuditContext;
+use crate::generic::{Event, HandlerResult, Response, State, StateMachine, StateMachineError};
+use std::collections::{HashSet, VecDeque};
+use std::hash::BuildHasher;
+
+/// What a dispatch with an already processed key does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Succeed with `Response::Handled` and the default output, without
+    /// reaching any handler.
+    Ignore,
+    /// Fail with `StateMachineError::Duplicate`.
+    Report,
+}
+
+struct Window {
+    order: VecDeque<String>,
+    keys: HashSet<String>,
+    capacity: usize,
+    duplicates: Duplicates,
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    /// Remembers the last `capacity` processed idempotency keys. Calling it
+    /// again changes the policy and resizes the window, forgetting the oldest
+    /// keys if it shrinks.
+    pub fn with_deduplication(mut self, capacity: usize, duplicates: Duplicates) -> Self {
+        let window = match self.ext_mut::<Window>() {
+            Some(window) => window,
+            None => {
+                self.insert_ext(Window {
+                    order: VecDeque::new(),
+                    keys: HashSet::new(),
+                    capacity,
+                    duplicates,
+                });
+                return self;
+            }
+        };
+        window.capacity = capacity;
+        window.duplicates = duplicates;
+        while window.order.len() > capacity {
+            if let Some(key) = window.order.pop_front() {
+                window.keys.remove(&key);
+            }
+        }
+        self
+    }
+
+    /// Whether a dispatch with `key` succeeded recently enough to be remembered.
+    pub fn is_processed(&self, key: &str) -> bool {
+        self.ext::<Window>()
+            .is_some_and(|window| window.keys.contains(key))
+    }
+
+    /// The result for a dispatch whose key was already processed, or `None`
+    /// if it should go ahead.
+    pub(crate) fn deduplicate(
+        &self,
+        event: &E,
+        context: &AuditContext,
+    ) -> Option<HandlerResult<S, E, O>>
+    where
+        O: Default,
+    {
+        let key = context.idempotency_key.as_ref()?;
+        let window = self.ext::<Window>()?;
+        if !window.keys.contains(key) {
+            return None;
+        }
+        Some(match window.duplicates {
+            Duplicates::Ignore => Ok((Response::Handled, O::default())),
+            Duplicates::Report => Err(StateMachineError::Duplicate {
+                event: event.clone(),
+                key: key.clone(),
+            }),
+        })
+    }
+
+    /// Adds the dispatch's key to the window if it succeeded.
+    pub(crate) fn remember_key(&mut self, context: &AuditContext, result: &HandlerResult<S, E, O>) {
+        let (Some(key), Ok(_)) = (&context.idempotency_key, result) else {
+            return;
+        };
+        let Some(window) = self.ext_mut::<Window>() else {
+            return;
+        };
+        if window.capacity == 0 || !window.keys.insert(key.clone()) {
+            return;
+        }
+        if window.order.len() == window.capacity {
+            if let Some(oldest) = window.order.pop_front() {
+                window.keys.remove(&oldest);
+            }
+        }
+        window.order.push_back(key.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_redelivered_events_are_ignored() {
+        let mut sm = init_state_machine().with_deduplication(2, Duplicates::Ignore);
+        let delivery = |key: &str| AuditContext::default().idempotency_key(key);
+
+        sm.dispatch_as(&CallEvent::Incoming, &delivery("m1"))
+            .unwrap();
+        // The first Answer fails, so its redelivery is processed.
+        sm.dispatch_as(&CallEvent::HangUp, &delivery("m2")).unwrap();
+        assert!(sm.dispatch_as(&CallEvent::Answer, &delivery("m3")).is_err());
+        assert!(!sm.is_processed("m3"));
+        sm.dispatch_as(&CallEvent::Reset, &delivery("m4")).unwrap();
+        sm.dispatch_as(&CallEvent::Incoming, &delivery("m5"))
+            .unwrap();
+
+        assert!(matches!(
+            sm.dispatch_as(&CallEvent::Incoming, &delivery("m5")),
+            Ok((Response::Handled, ()))
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Ringing);
+        assert!(!sm.is_processed("m1"));
+
+        let mut sm = sm.with_deduplication(2, Duplicates::Report);
+        assert!(matches!(
+            sm.dispatch_as(&CallEvent::Answer, &delivery("m4")),
+            Err(StateMachineError::Duplicate { key, .. }) if key == "m4"
+        ));
+        sm.dispatch(&CallEvent::Answer).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Field-level differences between two values of a context.
+//!
+//! Implement [`Diffable`] for a context struct with the [`diffable!`](crate::diffable)
+//! macro, then call [`StateMachine::with_context_diffs`] to have audit records
+//! carry exactly which fields a dispatch changed.
+
+use crate::audit::PendingDiff;
+use crate::generic::{Event, State, StateMachine};
+use crate::json;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Debug, Display};
+use std::hash::{BuildHasher, Hash};
+
+/// One changed field; values are their Debug representations, and `None`
+/// means the field was absent on that side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContextDiff {
+    pub changes: Vec<FieldChange>,
+}
+
+impl ContextDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Records `field` if `before` and `after` differ.
+    pub fn compare<T: PartialEq + Debug>(&mut self, field: &str, before: &T, after: &T) {
+        if before != after {
+            self.changes.push(FieldChange {
+                field: field.to_string(),
+                before: Some(format!("{:?}", before)),
+                after: Some(format!("{:?}", after)),
+            });
+        }
+    }
+
+    pub fn get(&self, field: &str) -> Option<&FieldChange> {
+        self.changes.iter().find(|change| change.field == field)
+    }
+
+    /// Encodes the changes as a JSON array of `{"field","before","after"}` objects.
+    pub fn to_json(&self) -> String {
+        let side = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", json::escape(value)),
+            None => "null".to_string(),
+        };
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|change| {
+                format!(
+                    "{{\"field\":\"{}\",\"before\":{},\"after\":{}}}",
+                    json::escape(&change.field),
+                    side(&change.before),
+                    side(&change.after)
+                )
+            })
+            .collect();
+        format!("[{}]", changes.join(","))
+    }
+}
+
+/// A context that can report which of its fields differ from another value.
+pub trait Diffable {
+    fn diff(&self, after: &Self) -> ContextDiff;
+}
+
+/// Map contexts, including the default `HashMap<String, usize>`, diff by key,
+/// in key order.
+impl<K, V> Diffable for HashMap<K, V>
+where
+    K: Display + Eq + Hash + Ord,
+    V: Debug + PartialEq,
+{
+    fn diff(&self, after: &Self) -> ContextDiff {
+        let keys: BTreeSet<&K> = self.keys().chain(after.keys()).collect();
+        let changes = keys
+            .into_iter()
+            .filter_map(|key| {
+                let (before, after) = (self.get(key), after.get(key));
+                (before != after).then(|| FieldChange {
+                    field: key.to_string(),
+                    before: before.map(|v| format!("{:?}", v)),
+                    after: after.map(|v| format!("{:?}", v)),
+                })
+            })
+            .collect();
+        ContextDiff { changes }
+    }
+}
+
+/// Implements [`Diffable`] for a struct by comparing the listed fields.
+///
+/// ```
+/// # use fsmportal::diffable;
+/// #[derive(Debug, Clone, PartialEq)]
+/// struct Call {
+///     attempts: u32,
+///     number: String,
+/// }
+/// diffable!(Call { attempts, number });
+/// ```
+#[macro_export]
+macro_rules! diffable {
+    ($type:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::diff::Diffable for $type {
+            fn diff(&self, after: &Self) -> $crate::diff::ContextDiff {
+                let mut diff = $crate::diff::ContextDiff::default();
+                $(diff.compare(stringify!($field), &self.$field, &after.$field);)*
+                diff
+            }
+        }
+    };
+}
+
+/// Captures the context before a dispatch, yielding its diff against the context after.
+pub type ContextDiffer<C> = fn(&C) -> PendingDiff<C>;
+
+fn capture<C: Diffable + Clone + 'static>(before: &C) -> PendingDiff<C> {
+    let before = before.clone();
+    Box::new(move |after| before.diff(after))
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    C: Diffable + Clone + 'static,
+    H: BuildHasher,
+{
+    /// Makes audit records carry the context fields each dispatch changed.
+    /// The context is cloned before every dispatch while an audit sink is set.
+    pub fn with_context_diffs(mut self) -> Self {
+        self.context_differ = Some(capture::<C>);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::MemorySink;
+    use crate::generic::Response;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Call {
+        attempts: u32,
+        number: String,
+    }
+    diffable!(Call { attempts, number });
+
+    #[test]
+    fn test_struct_diff() {
+        let before = Call {
+            attempts: 1,
+            number: "555".to_string(),
+        };
+        let mut after = before.clone();
+        after.attempts = 2;
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.changes.len(), 1);
+        assert_eq!(
+            diff.get("attempts"),
+            Some(&FieldChange {
+                field: "attempts".to_string(),
+                before: Some("1".to_string()),
+                after: Some("2".to_string()),
+            })
+        );
+        assert!(before.diff(&before).is_empty());
+    }
+
+    #[test]
+    fn test_map_diff() {
+        let before = HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]);
+        let after = HashMap::from([("b".to_string(), 3), ("c".to_string(), 4)]);
+        assert_eq!(
+            before.diff(&after).to_json(),
+            "[{\"field\":\"a\",\"before\":\"1\",\"after\":null},\
+             {\"field\":\"b\",\"before\":\"2\",\"after\":\"3\"},\
+             {\"field\":\"c\",\"before\":null,\"after\":\"4\"}]"
+        );
+    }
+
+    #[test]
+    fn test_audit_records_context_diff() {
+        let (sink, records) = MemorySink::new();
+        let mut sm = init_state_machine().with_context_diffs();
+        sm.add_audit_sink(sink);
+        sm.add_transition(CallState::Idle, CallEvent::Dial, |sm, _| {
+            sm.get_context_mut().insert("dials".to_string(), 1);
+            Ok(Response::Transition(CallState::Dialing))
+        });
+        sm.dispatch(&CallEvent::Dial).unwrap();
+
+        let records = records.lock().unwrap();
+        let change = records[0].context_diff.get("dials").unwrap();
+        assert_eq!(
+            (change.before.as_deref(), change.after.as_deref()),
+            (None, Some("1"))
+        );
+    }
+}
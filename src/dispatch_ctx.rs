@@ -0,0 +1,425 @@
+//! Handlers that reach the machine's services through disjoint borrows.
+//!
+//! A handler given the whole machine can read the state and write the context
+//! only one at a time, and cannot dispatch from inside a dispatch. A
+//! [`DispatchCtx`] borrows the current state, the context, the extensions,
+//! the state locals, the deadlines and the transition table separately, so a
+//! handler registered with
+//! [`add_transition_with_ctx`](StateMachine::add_transition_with_ctx) can hold
+//! all of them at once.
+//!
+//! Events a handler [posts](DispatchCtx::post) are dispatched once the
+//! outermost dispatch returns successfully, in the order posted; a failed
+//! dispatch drops them. Those it [schedules](DispatchCtx::schedule) are due
+//! after their delay by the machine's clock and are dispatched by
+//! [`dispatch_scheduled`](StateMachine::dispatch_scheduled), which an
+//! [actor](crate::actor) calls itself as they fall due.
+//!
+//! Follow-up dispatches are audited like any other, without the idempotency
+//! key of the dispatch that queued them. Their results, and those of the
+//! scheduled events an actor dispatched, are kept for
+//! [`take_follow_ups`](StateMachine::take_follow_ups).
+
+use crate::audit::AuditContext;
+use crate::clock::{Clock, SharedClock};
+use crate::extensions::Extensions;
+use crate::generic::{
+    Event, HandlerResult, Response, State, StateMachine, StateMachineError, Transitions,
+};
+use crate::state_local::StateLocals;
+use std::any::Any;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// An event dispatched by the machine on its own, with its result.
+pub type FollowUp<S, E, O = ()> = (E, HandlerResult<S, E, O>);
+
+/// Events queued by handlers, and how deep the dispatch queuing them is.
+pub(crate) struct Agenda<S, E, O> {
+    posted: VecDeque<E>,
+    scheduled: Vec<(Instant, E)>,
+    depth: usize,
+    /// Set while posted events are dispatched, which leave the rest to the
+    /// loop dispatching them.
+    flushing: bool,
+    follow_ups: Vec<FollowUp<S, E, O>>,
+}
+
+impl<S, E, O> Default for Agenda<S, E, O> {
+    fn default() -> Self {
+        Agenda {
+            posted: VecDeque::new(),
+            scheduled: Vec::new(),
+            depth: 0,
+            flushing: false,
+            follow_ups: Vec::new(),
+        }
+    }
+}
+
+/// A handler's view of the machine dispatching to it.
+pub struct DispatchCtx<'a, S, E, C, O = (), H = RandomState>
+where
+    S: State,
+    E: Event,
+{
+    state: Option<&'a S>,
+    chain: Vec<S>,
+    context: &'a mut C,
+    extensions: &'a mut Extensions,
+    state_locals: &'a mut StateLocals<S, E, C>,
+    transitions: &'a Transitions<S, E, C, O, H>,
+    targets: &'a HashMap<(S, E), S>,
+    clock: &'a SharedClock,
+    agenda: &'a mut Agenda<S, E, O>,
+    version: u64,
+}
+
+impl<S, E, C, O, H> DispatchCtx<'_, S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    /// `None` for a machine not yet started.
+    pub fn state(&self) -> Option<&S> {
+        self.state
+    }
+
+    pub fn context(&self) -> &C {
+        self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut C {
+        self.context
+    }
+
+    pub fn ext<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.extensions.get::<T>()
+    }
+
+    pub fn ext_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut::<T>()
+    }
+
+    /// The current state's value of type `T`; see [`crate::state_local`].
+    pub fn state_local<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.state_locals.values.get()
+    }
+
+    pub fn state_local_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.state_locals.values.get_mut()
+    }
+
+    /// Like [`StateMachine::set_state_local`].
+    pub fn set_state_local<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.state_locals.values.insert(value)
+    }
+
+    /// The state along the current state's dispatch chain whose handler for
+    /// `event` is offered it first.
+    fn handler_state(&self, event: &E) -> Option<&S> {
+        self.chain.iter().find(|state| {
+            self.transitions
+                .contains_key(&((*state).clone(), event.clone()))
+        })
+    }
+
+    /// Whether a handler for `event` is registered along the current state's
+    /// dispatch chain; guards are not evaluated.
+    pub fn handles(&self, event: &E) -> bool {
+        self.handler_state(event).is_some()
+    }
+
+    /// The declared target of the first transition for `event` along the
+    /// current state's dispatch chain, if it has one.
+    pub fn static_target(&self, event: &E) -> Option<&S> {
+        let state = self.handler_state(event)?;
+        self.targets.get(&(state.clone(), event.clone()))
+    }
+
+    /// The number of changes of state so far; see
+    /// [`StateMachine::version`].
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The machine's time.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Dispatches `event` once the outermost dispatch returns.
+    pub fn post(&mut self, event: E) {
+        self.agenda.posted.push_back(event);
+    }
+
+    /// Dispatches `event` from the first
+    /// [`dispatch_scheduled`](StateMachine::dispatch_scheduled) at least
+    /// `delay` from now.
+    pub fn schedule(&mut self, delay: Duration, event: E) {
+        let due = self.clock.now() + delay;
+        self.agenda.scheduled.push((due, event));
+    }
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    /// Borrows the machine as a [`DispatchCtx`].
+    pub fn dispatch_ctx(&mut self) -> DispatchCtx<'_, S, E, C, O, H> {
+        let chain = match &self.current_state {
+            Some(state) => self.dispatch_chain(state),
+            None => Vec::new(),
+        };
+        DispatchCtx {
+            state: self.current_state.as_ref(),
+            chain,
+            context: &mut self.context,
+            extensions: &mut self.extensions,
+            state_locals: &mut self.state_locals,
+            transitions: &self.transitions,
+            targets: &self.targets,
+            clock: &self.clock,
+            agenda: &mut self.agenda,
+            version: self.version,
+        }
+    }
+
+    /// Registers a handler given a [`DispatchCtx`] rather than the machine.
+    pub fn add_transition_with_ctx<F>(&mut self, from: S, event: E, transition: F)
+    where
+        F: Fn(
+                &mut DispatchCtx<'_, S, E, C, O, H>,
+                &E,
+            ) -> Result<Response<S>, StateMachineError<S, E>>
+            + 'static
+            + Send
+            + Sync,
+        O: Default,
+    {
+        self.add_transition(from, event, move |sm, event| {
+            transition(&mut sm.dispatch_ctx(), event)
+        });
+    }
+
+    /// Time left before the earliest scheduled event is due, zero once it is.
+    pub fn time_to_scheduled(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.agenda
+            .scheduled
+            .iter()
+            .map(|(due, _)| due.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Events posted and not yet dispatched, in order.
+    pub fn posted_events(&self) -> impl Iterator<Item = &E> {
+        self.agenda.posted.iter()
+    }
+
+    /// Scheduled events with the time left before each is due, earliest first.
+    pub fn scheduled_events(&self) -> Vec<(Duration, &E)> {
+        let now = self.clock.now();
+        let mut scheduled: Vec<_> = self
+            .agenda
+            .scheduled
+            .iter()
+            .map(|(due, event)| (due.saturating_duration_since(now), event))
+            .collect();
+        scheduled.sort_by_key(|(due, _)| *due);
+        scheduled
+    }
+
+    /// Takes the results of the events the machine dispatched on its own
+    /// since the last call, oldest first: those handlers posted, and the
+    /// scheduled ones an actor dispatched.
+    pub fn take_follow_ups(&mut self) -> Vec<FollowUp<S, E, O>> {
+        std::mem::take(&mut self.agenda.follow_ups)
+    }
+
+    /// Marks a dispatch as started, so events posted during it wait for the
+    /// outermost one to return.
+    pub(crate) fn enter_dispatch(&mut self) {
+        self.agenda.depth += 1;
+    }
+
+    /// The earliest scheduled event that is due, taken off the agenda.
+    fn take_due(&mut self) -> Option<E> {
+        let now = self.clock.now();
+        let (index, _) = self
+            .agenda
+            .scheduled
+            .iter()
+            .enumerate()
+            .filter(|(_, (due, _))| *due <= now)
+            .min_by_key(|(_, (due, _))| *due)?;
+        Some(self.agenda.scheduled.remove(index).1)
+    }
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    O: Default,
+    H: BuildHasher,
+{
+    /// Marks a dispatch as finished. If it was the outermost, dispatches the
+    /// events posted during it when it `succeeded` and drops them otherwise.
+    pub(crate) fn leave_dispatch(&mut self, context: &AuditContext, succeeded: bool) {
+        self.agenda.depth -= 1;
+        if self.agenda.depth > 0 || self.agenda.flushing {
+            return;
+        }
+        if !succeeded {
+            self.agenda.posted.clear();
+            return;
+        }
+        let context = AuditContext {
+            idempotency_key: None,
+            ..context.clone()
+        };
+        self.agenda.flushing = true;
+        while let Some(event) = self.agenda.posted.pop_front() {
+            let queued = self.agenda.posted.len();
+            let result = self.dispatch_as(&event, &context);
+            if result.is_err() {
+                self.agenda.posted.truncate(queued);
+            }
+            self.agenda.follow_ups.push((event, result));
+        }
+        self.agenda.flushing = false;
+    }
+
+    /// Dispatches the scheduled events that are due, earliest first,
+    /// returning their results.
+    pub fn dispatch_scheduled(&mut self) -> Vec<HandlerResult<S, E, O>> {
+        let mut results = Vec::new();
+        while let Some(event) = self.take_due() {
+            results.push(self.dispatch(&event));
+        }
+        results
+    }
+
+    /// Like [`dispatch_scheduled`](Self::dispatch_scheduled), keeping the
+    /// results as follow-ups.
+    pub(crate) fn run_scheduled(&mut self) {
+        while let Some(event) = self.take_due() {
+            let result = self.dispatch(&event);
+            self.agenda.follow_ups.push((event, result));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_ctx_handler_posts_and_schedules() {
+        let clock = ManualClock::new();
+        let mut sm: StateMachine<CallState, CallEvent, Vec<CallState>> =
+            StateMachine::new(CallState::Idle, Vec::new()).with_clock(clock.clone());
+        sm.add_transition_with_ctx(CallState::Idle, CallEvent::Incoming, |ctx, _| {
+            let state = ctx.state().unwrap().clone();
+            ctx.context_mut().push(state);
+            assert!(ctx.handles(&CallEvent::Incoming));
+            ctx.post(CallEvent::Answer);
+            ctx.schedule(Duration::from_secs(30), CallEvent::HangUp);
+            Ok(Response::Transition(CallState::Ringing))
+        });
+        sm.add_transition_to(CallState::Ringing, CallEvent::Answer, CallState::Connected);
+        sm.add_transition_to(CallState::Connected, CallEvent::HangUp, CallState::Idle);
+
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+        assert_eq!(sm.get_context(), &vec![CallState::Idle]);
+
+        assert!(matches!(
+            sm.take_follow_ups()[..],
+            [(CallEvent::Answer, Ok(_))]
+        ));
+
+        assert!(sm.dispatch_scheduled().is_empty());
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(sm.time_to_scheduled(), Some(Duration::ZERO));
+        assert_eq!(sm.dispatch_scheduled().len(), 1);
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+        assert_eq!(sm.time_to_scheduled(), None);
+    }
+
+    #[test]
+    fn test_ctx_reaches_state_locals() {
+        let mut sm = init_state_machine();
+        sm.add_transition_with_ctx(CallState::Idle, CallEvent::Incoming, |ctx, _| {
+            *ctx.state_local_mut::<u32>().unwrap() += 1;
+            Ok(Response::Handled)
+        });
+        sm.set_state_local(1u32);
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        assert_eq!(sm.state_local::<u32>(), Some(&3));
+    }
+
+    #[test]
+    fn test_failed_dispatch_drops_posted_events() {
+        let mut sm = init_state_machine();
+        sm.add_transition_with_ctx(CallState::Idle, CallEvent::Dial, |ctx, event| {
+            ctx.post(CallEvent::Answer);
+            Err(StateMachineError::UnexpectedEvent {
+                state: ctx.state().unwrap().clone(),
+                event: event.clone(),
+            })
+        });
+        sm.add_transition_with_ctx(CallState::Idle, CallEvent::Incoming, |ctx, _| {
+            ctx.post(CallEvent::Dial);
+            ctx.post(CallEvent::HangUp);
+            Ok(Response::Transition(CallState::Ringing))
+        });
+
+        assert!(sm.dispatch(&CallEvent::Dial).is_err());
+        assert!(sm.take_follow_ups().is_empty());
+
+        // Dial finds no transition from Ringing; HangUp still runs.
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        let follow_ups = sm.take_follow_ups();
+        assert!(matches!(
+            follow_ups[..],
+            [(CallEvent::Dial, Err(_)), (CallEvent::HangUp, Ok(_))]
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Disconnected);
+    }
+
+    #[test]
+    fn test_actor_dispatches_scheduled_events() {
+        use crate::actor::ActorHandle;
+
+        let clock = ManualClock::new();
+        let mut sm = init_state_machine().with_clock(clock.clone());
+        sm.add_transition_with_ctx(CallState::Idle, CallEvent::Incoming, |ctx, _| {
+            ctx.schedule(Duration::from_secs(30), CallEvent::HangUp);
+            Ok(Response::Transition(CallState::Ringing))
+        });
+        let handle = ActorHandle::spawn(sm);
+        handle
+            .dispatch(CallEvent::Incoming)
+            .recv()
+            .unwrap()
+            .unwrap();
+
+        clock.advance(Duration::from_secs(30));
+        // The actor runs due events before its next command.
+        handle.with(|_| ()).recv().unwrap();
+        let follow_ups = handle.with(|sm| sm.take_follow_ups()).recv().unwrap();
+        assert!(matches!(follow_ups[..], [(CallEvent::HangUp, Ok(_))]));
+        assert_eq!(handle.state().unwrap(), CallState::Disconnected);
+    }
+}
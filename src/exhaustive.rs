@@ -0,0 +1,157 @@
+//! Checks that every `(state, event)` pair has been thought about.
+//!
+//! A forgotten transition, such as an `Incoming` call while `Connected`,
+//! otherwise only shows up as `TransitionNotFound` at runtime.
+//! [`assert_fsm_exhaustive!`](crate::assert_fsm_exhaustive) catches it at
+//! compile time for a table written out in the macro, and
+//! [`check_exhaustive`](StateMachine::check_exhaustive) at construction
+//! time for a machine built in code. Pairs that should do nothing are marked
+//! with [`ignore`](StateMachine::ignore) rather than left out.
+
+use crate::generic::{Event, Response, State, StateMachine};
+use std::hash::BuildHasher;
+
+/// Fails to compile unless the table maps or ignores every pair of the two
+/// enums; the compiler's error names the pairs not covered.
+///
+/// Each line names a state and an event, then either the target state or
+/// `_` for a pair that is deliberately ignored:
+///
+/// ```
+/// use fsmportal::assert_fsm_exhaustive;
+///
+/// #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// enum Line { Idle, Busy }
+/// #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// enum Signal { Call, Hang }
+///
+/// assert_fsm_exhaustive! {
+///     Line, Signal;
+///     Idle, Call => Busy;
+///     Idle, Hang => _;
+///     Busy, Call => _;
+///     Busy, Hang => Idle;
+/// }
+/// ```
+///
+/// Leaving out `Busy, Call => _;` fails with "non-exhaustive patterns:
+/// `(&Line::Busy, &Signal::Call)` not covered":
+///
+/// ```compile_fail
+/// # use fsmportal::assert_fsm_exhaustive;
+/// # #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// # enum Line { Idle, Busy }
+/// # #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// # enum Signal { Call, Hang }
+/// assert_fsm_exhaustive! {
+///     Line, Signal;
+///     Idle, Call => Busy;
+///     Idle, Hang => _;
+///     Busy, Hang => Idle;
+/// }
+/// ```
+#[macro_export]
+macro_rules! assert_fsm_exhaustive {
+    (@target $state:ident, _) => {};
+    (@target $state:ident, $to:ident) => {
+        let _ = $state::$to;
+    };
+    (
+        $state:ident, $event:ident;
+        $($from:ident, $on:ident => $to:tt;)*
+    ) => {
+        const _: () = {
+            #[allow(dead_code)]
+            fn exhaustive(state: &$state, event: &$event) {
+                match (state, event) {
+                    $(($state::$from, $event::$on) => {
+                        $crate::assert_fsm_exhaustive!(@target $state, $to);
+                    })*
+                }
+            }
+        };
+    };
+}
+
+/// The pairs [`check_exhaustive`](StateMachine::check_exhaustive) found
+/// neither mapped nor ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unmapped<S, E> {
+    pub pairs: Vec<(S, E)>,
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    O: Default,
+    H: BuildHasher,
+{
+    /// Marks `event` in `state` as deliberately doing nothing: it is handled
+    /// without a transition instead of failing with `TransitionNotFound`.
+    pub fn ignore(&mut self, state: S, event: E) {
+        self.add_transition(state, event, |_, _| Ok(Response::Handled));
+    }
+
+    /// Checks every pair of `states` and `events` has a handler in the state
+    /// or one of its ancestors, guarded or not, or is ignored.
+    pub fn check_exhaustive(&self, states: &[S], events: &[E]) -> Result<(), Unmapped<S, E>> {
+        let pairs: Vec<(S, E)> = states
+            .iter()
+            .flat_map(|state| events.iter().map(move |event| (state, event)))
+            .filter(|(state, event)| {
+                !self
+                    .dispatch_chain(state)
+                    .into_iter()
+                    .any(|handler| self.transitions.contains_key(&(handler, (*event).clone())))
+            })
+            .map(|(state, event)| (state.clone(), event.clone()))
+            .collect();
+        if pairs.is_empty() {
+            Ok(())
+        } else {
+            Err(Unmapped { pairs })
+        }
+    }
+
+    /// Like [`check_exhaustive`](Self::check_exhaustive), for use at the end
+    /// of a builder chain: panics listing the unmapped pairs.
+    pub fn build_exhaustive(self, states: &[S], events: &[E]) -> Self {
+        if let Err(Unmapped { pairs }) = self.check_exhaustive(states, events) {
+            panic!("transitions not mapped or ignored: {:?}", pairs);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_check_exhaustive_lists_forgotten_pairs() {
+        let states = [CallState::Ringing, CallState::Connected];
+        let events = [CallEvent::Incoming, CallEvent::HangUp];
+        let mut sm = init_state_machine();
+        assert_eq!(
+            sm.check_exhaustive(&states, &events),
+            Err(Unmapped {
+                pairs: vec![
+                    (CallState::Ringing, CallEvent::Incoming),
+                    (CallState::Connected, CallEvent::Incoming)
+                ]
+            })
+        );
+
+        sm.ignore(CallState::Ringing, CallEvent::Incoming);
+        sm.ignore(CallState::Connected, CallEvent::Incoming);
+        let mut sm = sm.build_exhaustive(&states, &events);
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        assert!(matches!(
+            sm.dispatch(&CallEvent::Incoming),
+            Ok((Response::Handled, ()))
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Ringing);
+    }
+}
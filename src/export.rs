@@ -0,0 +1,374 @@
+use crate::generic::{Event, State, StateMachine};
+use crate::metadata::{StateMetadata, TransitionMetadata};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+// Handlers pick their target at runtime, so a transition without a static
+// target is drawn as an edge into a choice pseudo-state owned by its
+// (state, event) pair.
+pub(crate) fn choice_id(from: &str, event: &str) -> String {
+    format!("{}_{}", from, event)
+}
+
+// Mermaid state ids must be plain identifiers, Debug output may not be.
+pub(crate) fn mermaid_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Mermaid reads these characters as syntax; it accepts entity codes instead.
+fn mermaid_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("#quot;"),
+            ';' => out.push_str("#59;"),
+            '|' => out.push_str("#124;"),
+            '\n' => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// PlantUML reads `\` sequences and quotes as syntax; `<U+...>` stands for
+// the character itself.
+fn plantuml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("<U+0022>"),
+            '\\' => out.push_str("<U+005C>"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Display name followed by `#tag` markers, joined with `separator`.
+pub(crate) fn node_label(name: &str, metadata: &StateMetadata, separator: &str) -> String {
+    let mut label = metadata
+        .display_name
+        .clone()
+        .unwrap_or_else(|| name.to_string());
+    if !metadata.tags.is_empty() {
+        let tags: Vec<String> = metadata.tags.iter().map(|t| format!("#{}", t)).collect();
+        label.push_str(separator);
+        label.push_str(&tags.join(" "));
+    }
+    label
+}
+
+// The metadata label, or the event's Debug representation.
+pub(crate) fn edge_label(event: &str, metadata: Option<&TransitionMetadata>) -> String {
+    metadata
+        .and_then(|m| m.label.clone())
+        .unwrap_or_else(|| event.to_string())
+}
+
+// The edge label, then the transition's description if it has one.
+fn described_label(
+    event: &str,
+    metadata: Option<&TransitionMetadata>,
+    separator: &str,
+    escape: fn(&str) -> String,
+) -> String {
+    let mut label = escape(&edge_label(event, metadata));
+    if let Some(description) = metadata.and_then(|m| m.description.as_ref()) {
+        label.push_str(separator);
+        label.push_str(&escape(description));
+    }
+    label
+}
+
+// DOT attributes for a transition edge.
+fn dot_edge_attrs(event: &str, metadata: Option<&TransitionMetadata>) -> String {
+    let mut attrs = format!("label=\"{}\"", escape(&edge_label(event, metadata)));
+    if let Some(description) = metadata.and_then(|m| m.description.as_ref()) {
+        let _ = write!(attrs, ", tooltip=\"{}\"", escape(description));
+    }
+    attrs
+}
+
+pub(crate) type EdgeNames<'a, K> = BTreeMap<K, Option<&'a TransitionMetadata>>;
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// Every known state and its metadata, ordered by Debug representation so exports are stable.
+    pub(crate) fn state_names(&self) -> BTreeMap<String, Option<&StateMetadata>> {
+        self.current_state
+            .iter()
+            .chain(self.initial())
+            .chain(self.transitions.keys().map(|(from, _)| from))
+            .chain(self.eventless.iter().flat_map(|(from, targets)| {
+                std::iter::once(from).chain(targets.iter().map(|(to, _)| to))
+            }))
+            .chain(
+                self.parents
+                    .iter()
+                    .flat_map(|(child, parent)| [child, parent]),
+            )
+            .chain(
+                self.completions
+                    .iter()
+                    .flat_map(|(parent, to)| [parent, to]),
+            )
+            .chain(self.metadata.keys())
+            .map(|s| (format!("{:?}", s), self.metadata.get(s)))
+            .collect()
+    }
+
+    /// Every eventless, completion and initial-substate `(from, to)` edge,
+    /// ordered by Debug representation.
+    pub(crate) fn eventless_names(&self) -> BTreeSet<(String, String)> {
+        self.eventless
+            .iter()
+            .flat_map(|(from, targets)| targets.iter().map(move |(to, _)| (from, to)))
+            .chain(self.completions.iter())
+            .chain(self.initial.substates.iter())
+            .map(|(from, to)| (format!("{:?}", from), format!("{:?}", to)))
+            .collect()
+    }
+
+    /// Every registered `(from, event)` pair without a static target and its
+    /// metadata, ordered by Debug representation.
+    pub(crate) fn transition_names(&self) -> EdgeNames<'_, (String, String)> {
+        self.transitions
+            .keys()
+            .filter(|key| !self.targets.contains_key(key))
+            .map(|key| {
+                (
+                    (format!("{:?}", key.0), format!("{:?}", key.1)),
+                    self.transition_metadata.get(key),
+                )
+            })
+            .collect()
+    }
+
+    /// Every `(from, event, to)` of a transition with a static target and its
+    /// metadata, ordered by Debug representation.
+    pub(crate) fn target_names(&self) -> EdgeNames<'_, (String, String, String)> {
+        self.targets
+            .iter()
+            .map(|(key, to)| {
+                (
+                    (
+                        format!("{:?}", key.0),
+                        format!("{:?}", key.1),
+                        format!("{:?}", to),
+                    ),
+                    self.transition_metadata.get(key),
+                )
+            })
+            .collect()
+    }
+
+    /// Renders the transition table as a Graphviz DOT digraph.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph StateMachine {\n    rankdir=LR;\n");
+
+        for (state, metadata) in self.state_names() {
+            match metadata {
+                Some(metadata) => {
+                    let mut attrs = vec![format!(
+                        "label=\"{}\"",
+                        escape(&node_label(&state, metadata, "\n")).replace('\n', "\\n")
+                    )];
+                    if let Some(description) = &metadata.description {
+                        attrs.push(format!("tooltip=\"{}\"", escape(description)));
+                    }
+                    let _ = writeln!(out, "    \"{}\" [{}];", escape(&state), attrs.join(", "));
+                }
+                None => {
+                    let _ = writeln!(out, "    \"{}\";", escape(&state));
+                }
+            }
+        }
+        for ((from, event), metadata) in self.transition_names() {
+            let choice = escape(&choice_id(&from, &event));
+            let _ = writeln!(out, "    \"{}\" [shape=diamond, label=\"\"];", choice);
+            let _ = writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [{}];",
+                escape(&from),
+                choice,
+                dot_edge_attrs(&event, metadata)
+            );
+        }
+        for ((from, event, to), metadata) in self.target_names() {
+            let _ = writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [{}];",
+                escape(&from),
+                escape(&to),
+                dot_edge_attrs(&event, metadata)
+            );
+        }
+        for (from, to) in self.eventless_names() {
+            let _ = writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [style=dashed];",
+                escape(&from),
+                escape(&to)
+            );
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// Renders the transition table as a Mermaid `stateDiagram-v2`.
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("stateDiagram-v2\n");
+
+        for (state, metadata) in self.state_names() {
+            let id = mermaid_id(&state);
+            let label = match metadata {
+                Some(metadata) => node_label(&state, metadata, " "),
+                None => state.clone(),
+            };
+            if id == label {
+                let _ = writeln!(out, "    state {}", id);
+            } else {
+                let _ = writeln!(out, "    state \"{}\" as {}", label, id);
+            }
+            if let Some(description) = metadata.and_then(|m| m.description.as_ref()) {
+                let _ = writeln!(out, "    note right of {} : {}", id, description);
+            }
+        }
+        for ((from, event), metadata) in self.transition_names() {
+            let choice = mermaid_id(&choice_id(&from, &event));
+            let _ = writeln!(out, "    state {} <<choice>>", choice);
+            let _ = writeln!(
+                out,
+                "    {} --> {}: {}",
+                mermaid_id(&from),
+                choice,
+                described_label(&event, metadata, ": ", mermaid_escape)
+            );
+        }
+        for ((from, event, to), metadata) in self.target_names() {
+            let _ = writeln!(
+                out,
+                "    {} --> {}: {}",
+                mermaid_id(&from),
+                mermaid_id(&to),
+                described_label(&event, metadata, ": ", mermaid_escape)
+            );
+        }
+        for (from, to) in self.eventless_names() {
+            let _ = writeln!(out, "    {} --> {}", mermaid_id(&from), mermaid_id(&to));
+        }
+
+        out
+    }
+
+    /// Renders the transition table as a PlantUML state diagram. Eventless
+    /// transitions are dashed and descriptions follow the labels.
+    pub fn to_plantuml(&self) -> String {
+        let mut out = String::from("@startuml\nhide empty description\n");
+
+        for (state, metadata) in self.state_names() {
+            let id = mermaid_id(&state);
+            let label = match metadata {
+                Some(metadata) => node_label(&state, metadata, " "),
+                None => state.clone(),
+            };
+            let _ = if id == label {
+                write!(out, "state {}", id)
+            } else {
+                write!(out, "state \"{}\" as {}", plantuml_escape(&label), id)
+            };
+            if let Some(description) = metadata.and_then(|m| m.description.as_ref()) {
+                let _ = write!(out, " : {}", plantuml_escape(description));
+            }
+            out.push('\n');
+        }
+        for ((from, event), metadata) in self.transition_names() {
+            let choice = mermaid_id(&choice_id(&from, &event));
+            let _ = writeln!(out, "state {} <<choice>>", choice);
+            let _ = writeln!(
+                out,
+                "{} --> {} : {}",
+                mermaid_id(&from),
+                choice,
+                described_label(&event, metadata, "\\n", plantuml_escape)
+            );
+        }
+        for ((from, event, to), metadata) in self.target_names() {
+            let _ = writeln!(
+                out,
+                "{} --> {} : {}",
+                mermaid_id(&from),
+                mermaid_id(&to),
+                described_label(&event, metadata, "\\n", plantuml_escape)
+            );
+        }
+        for (from, to) in self.eventless_names() {
+            let _ = writeln!(out, "{} -[dashed]-> {}", mermaid_id(&from), mermaid_id(&to));
+        }
+
+        out.push_str("@enduml\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generic::{Response, StateMachine};
+    use crate::{CallEvent, CallState};
+
+    #[test]
+    fn test_exports_are_sorted() {
+        let mut sm: StateMachine<CallState, CallEvent> =
+            StateMachine::new(CallState::Idle, Default::default());
+        sm.add_transition(CallState::Ringing, CallEvent::Answer, |_, _| {
+            Ok(Response::Transition(CallState::Connected))
+        });
+        sm.add_transition(CallState::Idle, CallEvent::Incoming, |_, _| {
+            Ok(Response::Transition(CallState::Ringing))
+        });
+
+        let dot = sm.to_dot();
+        let idle = dot.find("\"Idle\";").unwrap();
+        let ringing = dot.find("\"Ringing\";").unwrap();
+        assert!(idle < ringing);
+        assert!(dot.contains("\"Idle\" -> \"Idle_Incoming\" [label=\"Incoming\"];"));
+
+        sm.add_eventless_transition(CallState::Disconnected, CallState::Idle, |_| true);
+        assert!(sm
+            .to_dot()
+            .contains("\"Disconnected\" -> \"Idle\" [style=dashed];"));
+
+        sm.add_transition_to(
+            CallState::Connected,
+            CallEvent::HangUp,
+            CallState::Disconnected,
+        );
+        assert!(sm
+            .to_dot()
+            .contains("\"Connected\" -> \"Disconnected\" [label=\"HangUp\"];"));
+
+        let mermaid = sm.to_mermaid();
+        assert!(mermaid.starts_with("stateDiagram-v2\n"));
+        assert!(mermaid.contains("    Connected --> Disconnected: HangUp\n"));
+        assert!(mermaid.contains("    Ringing --> Ringing_Answer: Answer\n"));
+        assert!(mermaid.contains("    Disconnected --> Idle\n"));
+
+        let plantuml = sm.to_plantuml();
+        assert!(plantuml.starts_with("@startuml\n"));
+        assert!(plantuml.ends_with("@enduml\n"));
+        assert!(plantuml.contains("\nstate Ringing_Answer <<choice>>\n"));
+        assert!(plantuml.contains("\nConnected --> Disconnected : HangUp\n"));
+        assert!(plantuml.contains("\nDisconnected -[dashed]-> Idle\n"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/// Type-keyed storage letting optional subsystems attach their own state to a machine.
+///
+/// Holds at most one value per type; wrap values in a newtype to store several of the same type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `value`, returning the previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    /// The values stored under each of `ids`, or `None` if one is missing or
+    /// the same id appears twice.
+    pub(crate) fn entries_mut<const N: usize>(
+        &mut self,
+        ids: [&TypeId; N],
+    ) -> Option<[&mut Box<dyn Any + Send + Sync>; N]> {
+        if (1..N).any(|i| ids[..i].contains(&ids[i])) {
+            return None;
+        }
+        let entries = self.map.get_disjoint_mut(ids);
+        if entries.iter().any(Option::is_none) {
+            return None;
+        }
+        Some(entries.map(Option::unwrap))
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generic::{Response, Stateful};
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[derive(Debug, PartialEq)]
+    struct DialCount(usize);
+
+    #[test]
+    fn test_handlers_reach_extensions() {
+        let mut sm = init_state_machine();
+        assert!(sm.ext::<DialCount>().is_none());
+        assert_eq!(sm.insert_ext(DialCount(0)), None);
+
+        sm.add_transition(CallState::Idle, CallEvent::Dial, |sm, _| {
+            if let Some(count) = sm.ext_mut::<DialCount>() {
+                count.0 += 1;
+            }
+            Ok(Response::Transition(CallState::Dialing))
+        });
+        sm.handle_event(&CallEvent::Dial).unwrap();
+
+        assert_eq!(sm.ext::<DialCount>(), Some(&DialCount(1)));
+        assert_eq!(sm.insert_ext(DialCount(5)), Some(DialCount(1)));
+        assert_eq!(sm.remove_ext::<DialCount>(), Some(DialCount(5)));
+        assert!(sm.extensions().is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+//! C interface to machines built from specs.
+//!
+//! Machines are created from spec text with [`fsm_machine_from_spec`] and
+//! driven by event name or by id. Ids index the spec's state and event names
+//! in sorted order, so they are stable for a given spec. The declarations are
+//! in `include/fsmportal.h`; build a C library with
+//! `cargo rustc --release --features ffi --crate-type cdylib` (or `staticlib`).
+//!
+//! No panic crosses into C: an entry point that panics returns `FSM_ERROR`,
+//! or null or -1 where it returns a pointer or an id.
+
+use crate::generic::StateMachine;
+use crate::spec::MachineSpec;
+use std::collections::BTreeSet;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::str::FromStr;
+
+pub const FSM_OK: c_int = 0;
+/// The current state has no transition for the event.
+pub const FSM_NO_TRANSITION: c_int = 1;
+/// The event is not named in the spec, or its id is out of range.
+pub const FSM_UNKNOWN_EVENT: c_int = 2;
+/// Dispatch failed for another reason, such as a rejected event or a panic.
+pub const FSM_ERROR: c_int = 3;
+pub const FSM_INVALID_ARGUMENT: c_int = -1;
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Name(String);
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Name {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(Name(s.to_string()))
+    }
+}
+
+/// An opaque machine handle.
+pub struct FsmMachine {
+    machine: StateMachine<Name, Name, ()>,
+    states: Vec<Name>,
+    events: Vec<Name>,
+    /// The current state's name, handed out by [`fsm_current_state`].
+    state_name: CString,
+}
+
+/// Runs `f`, returning `on_panic` if it panics.
+fn contain<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+impl FsmMachine {
+    fn current(&self) -> Option<&Name> {
+        self.machine.get_current_state().ok()
+    }
+
+    fn dispatch(&mut self, event: Name) -> c_int {
+        if !self.events.contains(&event) {
+            return FSM_UNKNOWN_EVENT;
+        }
+        let code = match self.machine.dispatch(&event) {
+            Ok(_) => FSM_OK,
+            Err(crate::generic::StateMachineError::TransitionNotFound { .. }) => FSM_NO_TRANSITION,
+            Err(_) => FSM_ERROR,
+        };
+        if let Some(current) = self.current() {
+            self.state_name = CString::new(current.0.clone()).unwrap_or_default();
+        }
+        code
+    }
+}
+
+/// Builds a machine from NUL-terminated spec text, or returns null if the
+/// spec does not parse. Free it with [`fsm_machine_free`].
+///
+/// # Safety
+///
+/// `spec` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn fsm_machine_from_spec(spec: *const c_char) -> *mut FsmMachine {
+    contain(ptr::null_mut(), || machine_from_spec(spec))
+}
+
+unsafe fn machine_from_spec(spec: *const c_char) -> *mut FsmMachine {
+    if spec.is_null() {
+        return ptr::null_mut();
+    }
+    let Ok(source) = CStr::from_ptr(spec).to_str() else {
+        return ptr::null_mut();
+    };
+    let Ok(spec) = MachineSpec::parse(source) else {
+        return ptr::null_mut();
+    };
+    let Ok(machine) = spec.build::<Name, Name, (), ()>(()) else {
+        return ptr::null_mut();
+    };
+    let states = spec
+        .states()
+        .into_iter()
+        .map(|s| Name(s.to_string()))
+        .collect();
+    let events: BTreeSet<&str> = spec.transitions.iter().map(|t| t.event.as_str()).collect();
+    Box::into_raw(Box::new(FsmMachine {
+        machine,
+        states,
+        events: events.into_iter().map(|e| Name(e.to_string())).collect(),
+        state_name: CString::new(spec.initial.clone()).unwrap_or_default(),
+    }))
+}
+
+/// # Safety
+///
+/// `machine` must be null or a pointer from [`fsm_machine_from_spec`] not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn fsm_machine_free(machine: *mut FsmMachine) {
+    if !machine.is_null() {
+        contain((), || drop(Box::from_raw(machine)));
+    }
+}
+
+/// Dispatches the event named by the NUL-terminated `event`.
+///
+/// # Safety
+///
+/// `machine` must be a live pointer from [`fsm_machine_from_spec`] and `event`
+/// null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn fsm_dispatch(machine: *mut FsmMachine, event: *const c_char) -> c_int {
+    let (Some(machine), false) = (machine.as_mut(), event.is_null()) else {
+        return FSM_INVALID_ARGUMENT;
+    };
+    contain(FSM_ERROR, || match CStr::from_ptr(event).to_str() {
+        Ok(event) => machine.dispatch(Name(event.to_string())),
+        Err(_) => FSM_UNKNOWN_EVENT,
+    })
+}
+
+/// Dispatches the event with id `event`.
+///
+/// # Safety
+///
+/// `machine` must be a live pointer from [`fsm_machine_from_spec`].
+#[no_mangle]
+pub unsafe extern "C" fn fsm_dispatch_id(machine: *mut FsmMachine, event: u32) -> c_int {
+    let Some(machine) = machine.as_mut() else {
+        return FSM_INVALID_ARGUMENT;
+    };
+    contain(FSM_ERROR, || match machine.events.get(event as usize) {
+        Some(name) => machine.dispatch(name.clone()),
+        None => FSM_UNKNOWN_EVENT,
+    })
+}
+
+/// The current state's name, valid until the next dispatch or until the
+/// machine is freed; null for a null machine.
+///
+/// # Safety
+///
+/// `machine` must be null or a live pointer from [`fsm_machine_from_spec`].
+#[no_mangle]
+pub unsafe extern "C" fn fsm_current_state(machine: *const FsmMachine) -> *const c_char {
+    match machine.as_ref() {
+        Some(machine) => machine.state_name.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// The current state's id, or -1 for a null machine or one without a state.
+///
+/// # Safety
+///
+/// `machine` must be null or a live pointer from [`fsm_machine_from_spec`].
+#[no_mangle]
+pub unsafe extern "C" fn fsm_current_state_id(machine: *const FsmMachine) -> i32 {
+    let Some(machine) = machine.as_ref() else {
+        return -1;
+    };
+    contain(-1, || {
+        let Some(current) = machine.current() else {
+            return -1;
+        };
+        machine
+            .states
+            .iter()
+            .position(|state| state == current)
+            .map_or(-1, |id| id as i32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispatch_by_name_and_id() {
+        let spec = c"initial Idle\nIdle --Dial--> Dialing\nDialing --HangUp--> Idle\n";
+        unsafe {
+            let machine = fsm_machine_from_spec(spec.as_ptr());
+            assert!(!machine.is_null());
+            assert_eq!(fsm_dispatch(machine, c"Dial".as_ptr()), FSM_OK);
+            assert_eq!(
+                CStr::from_ptr(fsm_current_state(machine)).to_str(),
+                Ok("Dialing")
+            );
+            // States sort as Dialing, Idle; events as Dial, HangUp.
+            assert_eq!(fsm_current_state_id(machine), 0);
+            assert_eq!(fsm_dispatch_id(machine, 0), FSM_NO_TRANSITION);
+            assert_eq!(fsm_dispatch_id(machine, 1), FSM_OK);
+            assert_eq!(fsm_current_state_id(machine), 1);
+            assert_eq!(fsm_dispatch(machine, c"Answer".as_ptr()), FSM_UNKNOWN_EVENT);
+            fsm_machine_free(machine);
+
+            assert!(fsm_machine_from_spec(c"Idle --Dial--> Dialing".as_ptr()).is_null());
+        }
+    }
+
+    #[test]
+    fn test_panics_do_not_cross_into_c() {
+        let spec = c"initial Idle\nIdle --Dial--> Dialing\n";
+        unsafe {
+            let machine = fsm_machine_from_spec(spec.as_ptr());
+            (*machine)
+                .machine
+                .add_transition(Name("Idle".into()), Name("Dial".into()), |_, _| {
+                    panic!("handler bug")
+                });
+            assert_eq!(fsm_dispatch(machine, c"Dial".as_ptr()), FSM_ERROR);
+            assert_eq!(fsm_dispatch_id(machine, 0), FSM_ERROR);
+            assert_eq!(
+                CStr::from_ptr(fsm_current_state(machine)).to_str(),
+                Ok("Idle")
+            );
+            (*machine).machine.current_state = None;
+            assert_eq!(fsm_current_state_id(machine), -1);
+            fsm_machine_free(machine);
+        }
+    }
+}
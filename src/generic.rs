@@ -1,18 +1,273 @@
-use std::collections::HashMap;
+use crate::audit::{AuditContext, AuditSink};
+use crate::clock::SharedClock;
+use crate::correlation;
+use crate::diff::ContextDiffer;
+use crate::dispatch_ctx::Agenda;
+use crate::extensions::Extensions;
+use crate::initial::Initial;
+use crate::json;
+use crate::metadata::{StateMetadata, TransitionMetadata};
+use crate::outcome::DispatchTrace;
+use crate::recovery::Recovery;
+use crate::retry::RetryPolicy;
+use crate::semantics::{DispatchOrder, ExitTiming, SelfTransition, Semantics, Unhandled};
+use crate::state_local::StateLocals;
+use crate::task::AsyncTransitionFunction;
+use std::any::Any;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::hash::Hash;
+use std::hash::{BuildHasher, Hash};
 use std::sync::Arc;
+use std::time::Duration;
 
+/// Implemented for every type usable as a state.
 pub trait State: Clone + Debug + Eq + Hash {}
+/// Implemented for every type usable as an event.
 pub trait Event: Clone + Debug + Eq + Hash {}
 
+impl<T: Clone + Debug + Eq + Hash> State for T {}
+impl<T: Clone + Debug + Eq + Hash> Event for T {}
+
 #[derive(Debug)]
 pub enum StateMachineError<S, E> {
-    UnexpectedEvent { state: S, event: E },
-    TransitionNotFound { from: S, event: E },
+    UnexpectedEvent {
+        state: S,
+        event: E,
+    },
+    TransitionNotFound {
+        from: S,
+        event: E,
+    },
+    /// Eventless transitions led back into `state` within a single dispatch.
+    EventlessCycle {
+        state: S,
+    },
+    /// A validator refused `event` before any transition was looked up.
+    Rejected {
+        state: S,
+        event: E,
+        reason: RejectReason,
+    },
+    /// The dispatch was cancelled before its async handler finished.
+    Cancelled {
+        state: S,
+        event: E,
+    },
+    /// The async handler for `event` in `from` ran past its
+    /// [timeout](StateMachine::set_handler_timeout) and was dropped.
+    HandlerTimeout {
+        from: S,
+        event: E,
+    },
+    /// The handler for `event` in `state` panicked with `message`; see
+    /// [`with_panic_containment`](StateMachine::with_panic_containment).
+    HandlerPanicked {
+        state: S,
+        event: E,
+        message: String,
+    },
+    /// The actor front-end refused `event` under its throttle policy.
+    Throttled {
+        event: E,
+    },
+    /// The actor's bounded mailbox was full and its overflow policy is `Reject`.
+    MailboxFull {
+        event: E,
+    },
+    /// The handler for `event` needs a resource of the named type that is not
+    /// in the extension store.
+    MissingResource {
+        state: S,
+        event: E,
+        resource: &'static str,
+    },
+    /// A veto hook refused the transition from `from` to `to`; the handler
+    /// had already run.
+    Vetoed {
+        from: S,
+        event: E,
+        to: S,
+        reason: RejectReason,
+    },
+    /// A dispatch with idempotency key `key` was already processed.
+    Duplicate {
+        event: E,
+        key: String,
+    },
+    /// A [`MachinePool`](crate::pool::MachinePool) already has `limit`
+    /// sessions in `state`. A `deferred` event is dispatched again once one
+    /// of them leaves.
+    StateLimitReached {
+        state: S,
+        event: E,
+        limit: usize,
+        deferred: bool,
+    },
+    /// [`dispatch_if_version`](StateMachine::dispatch_if_version) expected
+    /// the machine at version `expected`; it was at `actual`.
+    StaleVersion {
+        expected: u64,
+        actual: u64,
+    },
+    /// [`dispatch_named`](StateMachine::dispatch_named) found no event called
+    /// `name`, or the machine has no event parser.
+    UnknownEventName {
+        name: String,
+    },
     NotInitialized,
 }
 
+impl<S: Debug, E: Debug> StateMachineError<S, E> {
+    /// The variant's name in snake case, as used by [`to_json`](Self::to_json).
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::UnexpectedEvent { .. } => "unexpected_event",
+            Self::TransitionNotFound { .. } => "transition_not_found",
+            Self::EventlessCycle { .. } => "eventless_cycle",
+            Self::Rejected { .. } => "rejected",
+            Self::Cancelled { .. } => "cancelled",
+            Self::HandlerTimeout { .. } => "handler_timeout",
+            Self::HandlerPanicked { .. } => "handler_panicked",
+            Self::Throttled { .. } => "throttled",
+            Self::MailboxFull { .. } => "mailbox_full",
+            Self::MissingResource { .. } => "missing_resource",
+            Self::Vetoed { .. } => "vetoed",
+            Self::Duplicate { .. } => "duplicate",
+            Self::StateLimitReached { .. } => "state_limit_reached",
+            Self::StaleVersion { .. } => "stale_version",
+            Self::UnknownEventName { .. } => "unknown_event_name",
+            Self::NotInitialized => "not_initialized",
+        }
+    }
+
+    /// Encodes the error as a JSON object with a `kind` and the variant's
+    /// fields; states and events use their Debug names.
+    pub fn to_json(&self) -> String {
+        let name = |value: &dyn Debug| format!("\"{}\"", json::escape(&format!("{:?}", value)));
+        let fields: Vec<(&str, String)> = match self {
+            Self::UnexpectedEvent { state, event } | Self::Cancelled { state, event } => {
+                vec![("state", name(state)), ("event", name(event))]
+            }
+            Self::TransitionNotFound { from, event } | Self::HandlerTimeout { from, event } => {
+                vec![("from", name(from)), ("event", name(event))]
+            }
+            Self::EventlessCycle { state } => vec![("state", name(state))],
+            Self::HandlerPanicked {
+                state,
+                event,
+                message,
+            } => vec![
+                ("state", name(state)),
+                ("event", name(event)),
+                ("message", format!("\"{}\"", json::escape(message))),
+            ],
+            Self::Rejected {
+                state,
+                event,
+                reason,
+            } => vec![
+                ("state", name(state)),
+                ("event", name(event)),
+                ("reason", format!("\"{}\"", json::escape(&reason.message))),
+            ],
+            Self::MissingResource {
+                state,
+                event,
+                resource,
+            } => vec![
+                ("state", name(state)),
+                ("event", name(event)),
+                ("resource", format!("\"{}\"", json::escape(resource))),
+            ],
+            Self::Vetoed {
+                from,
+                event,
+                to,
+                reason,
+            } => vec![
+                ("from", name(from)),
+                ("event", name(event)),
+                ("to", name(to)),
+                ("reason", format!("\"{}\"", json::escape(&reason.message))),
+            ],
+            Self::Duplicate { event, key } => vec![
+                ("event", name(event)),
+                ("key", format!("\"{}\"", json::escape(key))),
+            ],
+            Self::Throttled { event } | Self::MailboxFull { event } => {
+                vec![("event", name(event))]
+            }
+            Self::StateLimitReached {
+                state,
+                event,
+                limit,
+                deferred,
+            } => vec![
+                ("state", name(state)),
+                ("event", name(event)),
+                ("limit", limit.to_string()),
+                ("deferred", deferred.to_string()),
+            ],
+            Self::StaleVersion { expected, actual } => vec![
+                ("expected", expected.to_string()),
+                ("actual", actual.to_string()),
+            ],
+            Self::UnknownEventName { name } => {
+                vec![("name", format!("\"{}\"", json::escape(name)))]
+            }
+            Self::NotInitialized => Vec::new(),
+        };
+        let mut out = format!("{{\"kind\":\"{}\"", self.kind());
+        for (key, value) in fields {
+            out.push_str(&format!(",\"{}\":{}", key, value));
+        }
+        out.push('}');
+        out
+    }
+}
+
+/// Why a validator refused an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectReason {
+    pub message: String,
+}
+
+impl RejectReason {
+    pub fn new(message: impl Into<String>) -> Self {
+        RejectReason {
+            message: message.into(),
+        }
+    }
+}
+
+/// A veto hook's verdict on a transition about to be committed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Veto {
+    Allow,
+    Deny(RejectReason),
+}
+
+/// A committed transition, as handed to journals and publishers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransitionRecord<S, E> {
+    pub from: S,
+    pub event: E,
+    pub to: S,
+}
+
+impl<S: Debug, E: Debug> TransitionRecord<S, E> {
+    /// Encodes the record as a JSON object of Debug names.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"from\":\"{}\",\"event\":\"{}\",\"to\":\"{}\"}}",
+            json::escape(&format!("{:?}", self.from)),
+            json::escape(&format!("{:?}", self.event)),
+            json::escape(&format!("{:?}", self.to))
+        )
+    }
+}
+
 pub enum Response<S> {
     Handled,
     Super,
@@ -35,54 +290,631 @@ where
     }
 }
 
-pub trait Stateful<S, CTX, E>
+pub trait Stateful<S, CTX, E, O = (), H = RandomState>
 where
     S: State,
     E: Debug + Event,
 {
-    fn on_enter(&self, event: &E)
-        -> Result<TransitionFunction<S, E, CTX>, StateMachineError<S, E>>;
+    fn on_enter(&self, event: &E) -> TransitionLookup<S, E, CTX, O, H>;
 
     fn handle_event(&mut self, event: &E) -> Result<Response<S>, StateMachineError<S, E>>;
 
     fn on_exit(&self);
 }
-pub type TransitionFunction<S, E, C> = Arc<
-    dyn Fn(&mut StateMachine<S, E, C>, &E) -> Result<Response<S>, StateMachineError<S, E>>
-        + Send
-        + Sync,
->;
-pub struct StateMachine<S, E, C = HashMap<String, usize>>
+/// A handler's response together with the output value it produced.
+pub type HandlerResult<S, E, O> = Result<(Response<S>, O), StateMachineError<S, E>>;
+pub type TransitionFunction<S, E, C, O = (), H = RandomState> =
+    Arc<dyn Fn(&mut StateMachine<S, E, C, O, H>, &E) -> HandlerResult<S, E, O> + Send + Sync>;
+/// The handler [`Stateful::on_enter`] found for an event.
+pub type TransitionLookup<S, E, C, O = (), H = RandomState> =
+    Result<TransitionFunction<S, E, C, O, H>, StateMachineError<S, E>>;
+pub type TransitionObserver<S, E> = Arc<dyn Fn(&S, &E, &S) + Send + Sync>;
+pub type Guard<S, E, C, O = (), H = RandomState> =
+    Arc<dyn Fn(&StateMachine<S, E, C, O, H>) -> bool + Send + Sync>;
+/// Runs as a transition is committed, with the context to update and the
+/// `(from, to, event)` of the transition.
+pub type LifecycleHook<S, E, C> = Arc<dyn Fn(&mut C, &S, &S, &E) + Send + Sync>;
+/// Checks an event against the current state and context before dispatch.
+pub type Validator<S, E, C> = Arc<dyn Fn(&S, &E, &C) -> Result<(), RejectReason> + Send + Sync>;
+/// Judges a transition `(from, event, to)` once its target is known.
+pub type VetoHook<S, E, C> = Arc<dyn Fn(&S, &E, &S, &C) -> Veto + Send + Sync>;
+/// Given a transition `(from, event, to)`, returns the state to go to instead, if any.
+pub type RedirectHook<S, E, C> = Arc<dyn Fn(&S, &E, &S, &C) -> Option<S> + Send + Sync>;
+/// An eventless transition's target and the guard that must pass to take it.
+pub type EventlessTransition<S, E, C, O = (), H = RandomState> = (S, Guard<S, E, C, O, H>);
+/// A handler along the dispatch chain, with the state it is registered in.
+pub(crate) type ChainHandler<S, E, C, O, H> = (S, TransitionFunction<S, E, C, O, H>);
+/// A dispatch chain's first handler and its `Super` fallbacks.
+pub(crate) type ChainHandlers<S, E, C, O, H> = (
+    ChainHandler<S, E, C, O, H>,
+    Vec<ChainHandler<S, E, C, O, H>>,
+);
+pub(crate) type Transitions<S, E, C, O, H> = HashMap<(S, E), TransitionFunction<S, E, C, O, H>, H>;
+type AsyncTransitions<S, E, C, O, H> = HashMap<(S, E), AsyncTransitionFunction<S, E, C, O, H>>;
+type Guards<S, E, C, O, H> = HashMap<(S, E), Guard<S, E, C, O, H>>;
+type EventlessTransitions<S, E, C, O, H> = HashMap<S, Vec<EventlessTransition<S, E, C, O, H>>>;
+pub(crate) type StateListener<S> = Arc<dyn Fn(&S) + Send + Sync>;
+/// A hierarchical state machine. `H` hashes the transition table; see
+/// [`with_hasher`](Self::with_hasher).
+pub struct StateMachine<S, E, C = HashMap<String, usize>, O = (), H = RandomState>
 where
     S: State,
     E: Event,
 {
-    current_state: Option<S>,
-    context: C,
-    transitions: HashMap<(S, E), TransitionFunction<S, E, C>>,
+    pub(crate) current_state: Option<S>,
+    pub(crate) initial: Initial<S, C>,
+    pub(crate) context: C,
+    pub(crate) transitions: Transitions<S, E, C, O, H>,
+    pub(crate) async_transitions: AsyncTransitions<S, E, C, O, H>,
+    pub(crate) guards: Guards<S, E, C, O, H>,
+    /// Targets of transitions declared with [`add_transition_to`](Self::add_transition_to).
+    pub(crate) targets: HashMap<(S, E), S>,
+    pub(crate) eventless: EventlessTransitions<S, E, C, O, H>,
+    pub(crate) parents: HashMap<S, S>,
+    pub(crate) finals: HashSet<S>,
+    pub(crate) completions: HashMap<S, S>,
+    pub(crate) metadata: HashMap<S, StateMetadata>,
+    pub(crate) transition_metadata: HashMap<(S, E), TransitionMetadata>,
+    pub(crate) entry_hooks: HashMap<S, Vec<LifecycleHook<S, E, C>>>,
+    pub(crate) exit_hooks: HashMap<S, Vec<LifecycleHook<S, E, C>>>,
+    pub(crate) transition_hooks: Vec<LifecycleHook<S, E, C>>,
+    pub(crate) observers: Vec<TransitionObserver<S, E>>,
+    /// Told of states set without a transition, by `start` and `restore`.
+    pub(crate) state_listeners: Vec<StateListener<S>>,
+    pub(crate) validators: Vec<Validator<S, E, C>>,
+    pub(crate) vetoes: Vec<VetoHook<S, E, C>>,
+    pub(crate) redirects: Vec<RedirectHook<S, E, C>>,
+    /// The target a redirect replaced during the dispatch being audited.
+    pub(crate) redirected_from: Option<S>,
+    pub(crate) audit_sinks: Vec<Arc<dyn AuditSink<S, E>>>,
+    pub(crate) context_differ: Option<ContextDiffer<C>>,
+    pub(crate) extensions: Extensions,
+    pub(crate) state_locals: StateLocals<S, E, C>,
+    pub(crate) recovery: Recovery<S, E, C>,
+    pub(crate) retries: HashMap<(S, E), RetryPolicy<S, E>>,
+    pub(crate) handler_timeouts: HashMap<(S, E), Duration>,
+    pub(crate) semantics: Semantics,
+    pub(crate) clock: SharedClock,
+    pub(crate) trace: DispatchTrace,
+    pub(crate) contain_panics: bool,
+    pub(crate) agenda: Agenda<S, E, O>,
+    /// Bumped on every change of state; see [`version`](Self::version).
+    pub(crate) version: u64,
 }
 
-impl<S, E, C> StateMachine<S, E, C>
+impl<S, E, C, O> StateMachine<S, E, C, O>
 where
     S: State,
     E: Event,
 {
     pub fn new(initial_state: S, context: C) -> Self {
+        Self::with_capacity(initial_state, context, 0)
+    }
+
+    /// A machine with room for `capacity` transitions before reallocating.
+    pub fn with_capacity(initial_state: S, context: C, capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(initial_state, context, capacity, RandomState::new())
+    }
+
+    /// A machine with no current state yet, to be given an initial state
+    /// and [started](Self::start); see [`crate::initial`].
+    pub fn unstarted(context: C) -> Self {
+        Self::build(None, context, 0, RandomState::new())
+    }
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    /// A machine whose transition table uses `hasher`, for example a faster
+    /// non-DoS-resistant hasher when states and events come from trusted code.
+    ///
+    /// Extension modules such as the actor, persistence and specs work with
+    /// the default hasher only.
+    pub fn with_hasher(initial_state: S, context: C, hasher: H) -> Self {
+        Self::with_capacity_and_hasher(initial_state, context, 0, hasher)
+    }
+
+    /// Like [`with_hasher`](Self::with_hasher), with room for `capacity` transitions.
+    pub fn with_capacity_and_hasher(
+        initial_state: S,
+        context: C,
+        capacity: usize,
+        hasher: H,
+    ) -> Self {
+        Self::build(Some(initial_state), context, capacity, hasher)
+    }
+
+    fn build(current_state: Option<S>, context: C, capacity: usize, hasher: H) -> Self {
         StateMachine {
-            current_state: Some(initial_state),
+            initial: Initial::new(current_state.clone()),
+            current_state,
             context,
-            transitions: HashMap::new(),
+            transitions: HashMap::with_capacity_and_hasher(capacity, hasher),
+            async_transitions: HashMap::new(),
+            guards: HashMap::new(),
+            targets: HashMap::new(),
+            eventless: HashMap::new(),
+            parents: HashMap::new(),
+            finals: HashSet::new(),
+            completions: HashMap::new(),
+            metadata: HashMap::new(),
+            transition_metadata: HashMap::new(),
+            entry_hooks: HashMap::new(),
+            exit_hooks: HashMap::new(),
+            transition_hooks: Vec::new(),
+            observers: Vec::new(),
+            state_listeners: Vec::new(),
+            validators: Vec::new(),
+            vetoes: Vec::new(),
+            redirects: Vec::new(),
+            redirected_from: None,
+            audit_sinks: Vec::new(),
+            context_differ: None,
+            extensions: Extensions::new(),
+            state_locals: StateLocals::new(),
+            recovery: Recovery::new(),
+            retries: HashMap::new(),
+            handler_timeouts: HashMap::new(),
+            semantics: Semantics::default(),
+            clock: SharedClock::default(),
+            trace: DispatchTrace::default(),
+            contain_panics: false,
+            agenda: Agenda::default(),
+            version: 0,
         }
     }
 
+    /// Releases spare capacity in the handler tables once the machine is built.
+    pub fn shrink_to_fit(&mut self) {
+        self.transitions.shrink_to_fit();
+        self.async_transitions.shrink_to_fit();
+        self.guards.shrink_to_fit();
+        self.eventless.shrink_to_fit();
+        for transitions in self.eventless.values_mut() {
+            transitions.shrink_to_fit();
+        }
+    }
+
+    /// Registers a handler; dispatching through it yields `O::default()` as output.
     pub fn add_transition<F>(&mut self, from: S, event: E, transition: F)
     where
-        F: Fn(&mut StateMachine<S, E, C>, &E) -> Result<Response<S>, StateMachineError<S, E>>
+        F: Fn(&mut StateMachine<S, E, C, O, H>, &E) -> Result<Response<S>, StateMachineError<S, E>>
+            + 'static
+            + Send
+            + Sync,
+        O: Default,
+    {
+        self.add_transition_with_output(from, event, move |sm, event| {
+            transition(sm, event).map(|response| (response, O::default()))
+        });
+    }
+
+    /// Registers a handler that returns an output value alongside its response,
+    /// handed back to the caller by [`dispatch`](Self::dispatch).
+    pub fn add_transition_with_output<F>(&mut self, from: S, event: E, transition: F)
+    where
+        F: Fn(&mut StateMachine<S, E, C, O, H>, &E) -> HandlerResult<S, E, O>
+            + 'static
+            + Send
+            + Sync,
+    {
+        let key = (from, event);
+        self.guards.remove(&key);
+        self.targets.remove(&key);
+        self.async_transitions.remove(&key);
+        self.transitions.insert(key, Arc::new(transition));
+    }
+
+    /// Registers a transition that always goes to `to`. Unlike a handler's
+    /// returned target, `to` is known up front, to exports and
+    /// [`static_target`](Self::static_target).
+    pub fn add_transition_to(&mut self, from: S, event: E, to: S)
+    where
+        S: Send + Sync + 'static,
+        O: Default,
+    {
+        self.add_transition_with_action(from, event, to, |_, _| {});
+    }
+
+    /// Like [`add_transition_to`](Self::add_transition_to), running `action`
+    /// with the context before the transition is committed.
+    pub fn add_transition_with_action<F>(&mut self, from: S, event: E, to: S, action: F)
+    where
+        S: Send + Sync + 'static,
+        F: Fn(&mut C, &E) + 'static + Send + Sync,
+        O: Default,
+    {
+        let target = to.clone();
+        self.add_transition(from.clone(), event.clone(), move |sm, event| {
+            action(sm.get_context_mut(), event);
+            Ok(Response::Transition(target.clone()))
+        });
+        self.targets.insert((from, event), to);
+    }
+
+    /// The declared target of the transition `from` takes on `event`, if it has a static one.
+    pub fn static_target(&self, from: &S, event: &E) -> Option<&S> {
+        self.targets.get(&(from.clone(), event.clone()))
+    }
+
+    /// Registers a transition that is only taken while `guard` passes; otherwise
+    /// the event is treated as if `from` had no transition for it.
+    pub fn add_guarded_transition<G, F>(&mut self, from: S, event: E, guard: G, transition: F)
+    where
+        G: Fn(&StateMachine<S, E, C, O, H>) -> bool + 'static + Send + Sync,
+        F: Fn(&mut StateMachine<S, E, C, O, H>, &E) -> Result<Response<S>, StateMachineError<S, E>>
             + 'static
             + Send
             + Sync,
+        O: Default,
+    {
+        self.add_transition(from.clone(), event.clone(), transition);
+        self.guards.insert((from, event), Arc::new(guard));
+    }
+
+    /// Registers a transition with no triggering event, taken as soon as `from`
+    /// is entered and `guard` passes. Eventless transitions out of one state are
+    /// checked in registration order and the first passing guard wins.
+    pub fn add_eventless_transition<G>(&mut self, from: S, to: S, guard: G)
+    where
+        G: Fn(&StateMachine<S, E, C, O, H>) -> bool + 'static + Send + Sync,
+    {
+        self.eventless
+            .entry(from)
+            .or_default()
+            .push((to, Arc::new(guard)));
+    }
+
+    /// Nests `child` inside the composite state `parent`. Events `child` does not
+    /// handle, or whose handler returns [`Response::Super`], are offered to `parent`.
+    pub fn add_substate(&mut self, parent: S, child: S) {
+        self.parents.insert(child, parent);
+    }
+
+    /// Nests `child` inside `parent` and marks it as one of `parent`'s final substates.
+    pub fn add_final_substate(&mut self, parent: S, child: S) {
+        self.finals.insert(child.clone());
+        self.add_substate(parent, child);
+    }
+
+    /// Registers the transition `parent` takes once one of its final substates is reached.
+    pub fn add_completion_transition(&mut self, parent: S, to: S) {
+        self.completions.insert(parent, to);
+    }
+
+    pub fn parent_of(&self, state: &S) -> Option<&S> {
+        self.parents.get(state)
+    }
+
+    /// Whether the current state is `state` or one of its substates.
+    pub fn is_in(&self, state: &S) -> bool {
+        let mut current = self.current_state.as_ref();
+        while let Some(s) = current {
+            if s == state {
+                return true;
+            }
+            current = self.parents.get(s);
+        }
+        false
+    }
+
+    /// `state` and its ancestors, innermost first.
+    fn lineage(&self, state: &S) -> Vec<S> {
+        let mut lineage = vec![state.clone()];
+        while let Some(parent) = self.parents.get(lineage.last().unwrap()) {
+            if lineage.contains(parent) {
+                break;
+            }
+            lineage.push(parent.clone());
+        }
+        lineage
+    }
+
+    /// `state` and its ancestors, in the order events are offered to them.
+    pub(crate) fn dispatch_chain(&self, state: &S) -> Vec<S> {
+        let mut chain = self.lineage(state);
+        if self.semantics.dispatch == DispatchOrder::ParentFirst {
+            chain.reverse();
+        }
+        chain
+    }
+
+    /// The states a transition from `from` to `to` exits, innermost first,
+    /// and enters, outermost first: those below the innermost state holding
+    /// both. A transition into the state it leaves exits and re-enters it.
+    fn crossed(&self, from: &S, to: &S) -> (Vec<S>, Vec<S>) {
+        if from == to {
+            return (vec![from.clone()], vec![to.clone()]);
+        }
+        let (mut exited, mut entered) = (self.lineage(from), self.lineage(to));
+        if let Some(common) = exited.iter().position(|state| entered.contains(state)) {
+            let shared = entered.iter().position(|state| *state == exited[common]);
+            exited.truncate(common);
+            entered.truncate(shared.unwrap_or(entered.len()));
+        }
+        entered.reverse();
+        (exited, entered)
+    }
+
+    /// The first enabled handler for `event` along `state`'s dispatch chain,
+    /// then those further along that run when it returns `Super`, each with
+    /// the state it is registered in. Without composite states nothing is
+    /// allocated.
+    pub(crate) fn chain_handlers(
+        &self,
+        state: &S,
+        event: &E,
+    ) -> Option<ChainHandlers<S, E, C, O, H>> {
+        if self.parents.is_empty() {
+            let transition = self.enabled_transition(state, event)?.clone();
+            return Some(((state.clone(), transition), Vec::new()));
+        }
+        let mut handlers = self.dispatch_chain(state).into_iter().filter_map(|state| {
+            let transition = self.enabled_transition(&state, event)?.clone();
+            Some((state, transition))
+        });
+        let first = handlers.next()?;
+        Some((first, handlers.collect()))
+    }
+
+    /// The handler `state` has for `event`, if any and its guard passes.
+    pub(crate) fn enabled_transition(
+        &self,
+        state: &S,
+        event: &E,
+    ) -> Option<&TransitionFunction<S, E, C, O, H>> {
+        let key = (state.clone(), event.clone());
+        match self.guards.get(&key) {
+            Some(guard) if !guard(self) => None,
+            _ => self.transitions.get(&key),
+        }
+    }
+
+    /// Events with a transition from the current state or one of its ancestors,
+    /// ignoring guards. Each event is yielded once.
+    pub fn available_events(&self) -> impl Iterator<Item = &E> {
+        self.events_from_current(false).into_iter()
+    }
+
+    /// Like [`available_events`](Self::available_events), but skips transitions whose guard fails.
+    pub fn enabled_events(&self) -> impl Iterator<Item = &E> {
+        self.events_from_current(true).into_iter()
+    }
+
+    fn events_from_current(&self, respect_guards: bool) -> Vec<&E> {
+        let mut events: Vec<&E> = Vec::new();
+        let Some(current) = &self.current_state else {
+            return events;
+        };
+        for state in self.dispatch_chain(current) {
+            for (from, event) in self.transitions.keys() {
+                if *from != state || events.contains(&event) {
+                    continue;
+                }
+                if respect_guards && self.enabled_transition(from, event).is_none() {
+                    continue;
+                }
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Runs `hook` whenever a transition enters `state`, after exit and
+    /// transition hooks and before observers. Entering a substate from
+    /// outside its parent enters the parent first; hooks of the states
+    /// entered run outermost first.
+    pub fn add_entry_hook<F>(&mut self, state: S, hook: F)
+    where
+        F: Fn(&mut C, &S, &S, &E) + 'static + Send + Sync,
+    {
+        self.entry_hooks
+            .entry(state)
+            .or_default()
+            .push(Arc::new(hook));
+    }
+
+    /// Runs `hook` whenever a transition leaves `state`, before any other hook.
+    /// Leaving a parent from one of its substates exits the substate first;
+    /// moving between states inside a parent does not exit it.
+    pub fn add_exit_hook<F>(&mut self, state: S, hook: F)
+    where
+        F: Fn(&mut C, &S, &S, &E) + 'static + Send + Sync,
+    {
+        self.exit_hooks
+            .entry(state)
+            .or_default()
+            .push(Arc::new(hook));
+    }
+
+    /// Runs `hook` on every committed transition, between exit and entry hooks.
+    pub fn add_transition_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&mut C, &S, &S, &E) + 'static + Send + Sync,
+    {
+        self.transition_hooks.push(Arc::new(hook));
+    }
+
+    /// Registers a callback invoked with `(from, event, to)` after every committed transition.
+    pub fn add_observer<F>(&mut self, observer: F)
+    where
+        F: Fn(&S, &E, &S) + 'static + Send + Sync,
+    {
+        self.observers.push(Arc::new(observer));
+    }
+
+    /// Adds a validator run before every dispatch, after those already added.
+    /// The first one to fail rejects the event with `StateMachineError::Rejected`.
+    pub fn add_validator<F>(&mut self, validator: F)
+    where
+        F: Fn(&S, &E, &C) -> Result<(), RejectReason> + 'static + Send + Sync,
+    {
+        self.validators.push(Arc::new(validator));
+    }
+
+    /// Adds a hook run after a handler has chosen a target and before the
+    /// transition is committed, for policies that sit outside handlers such
+    /// as refusing new calls during shutdown. The first one to deny aborts
+    /// the transition with `StateMachineError::Vetoed`; the machine stays in
+    /// its state, though the handler's own effects on the context remain.
+    /// Eventless follow-up steps are not vetoed.
+    pub fn add_veto<F>(&mut self, veto: F)
+    where
+        F: Fn(&S, &E, &S, &C) -> Veto + 'static + Send + Sync,
     {
-        self.transitions.insert((from, event), Arc::new(transition));
+        self.vetoes.push(Arc::new(veto));
+    }
+
+    /// Adds a hook run after a handler has chosen a target, before vetoes,
+    /// that may substitute another target, such as sending calls that would
+    /// become `Connected` to `OnHold` during an outage. Redirects run in the
+    /// order added, each seeing the target as rewritten so far, and a
+    /// substitution is recorded in the dispatch's audit record as
+    /// `redirected_from`. Eventless follow-up steps are not redirected.
+    pub fn add_redirect<F>(&mut self, redirect: F)
+    where
+        F: Fn(&S, &E, &S, &C) -> Option<S> + 'static + Send + Sync,
+    {
+        self.redirects.push(Arc::new(redirect));
+    }
+
+    fn apply_redirects(&mut self, event: &E, to: S) -> Result<S, StateMachineError<S, E>> {
+        let from = self.get_current_state()?;
+        let mut target = to.clone();
+        for redirect in &self.redirects {
+            if let Some(substitute) = redirect(from, event, &target, &self.context) {
+                target = substitute;
+            }
+        }
+        if target != to {
+            self.redirected_from = Some(to);
+        }
+        Ok(target)
+    }
+
+    fn check_vetoes(&self, event: &E, to: &S) -> Result<(), StateMachineError<S, E>> {
+        let from = self.get_current_state()?;
+        for veto in &self.vetoes {
+            if let Veto::Deny(reason) = veto(from, event, to, &self.context) {
+                return Err(StateMachineError::Vetoed {
+                    from: from.clone(),
+                    event: event.clone(),
+                    to: to.clone(),
+                    reason,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate(&self, event: &E) -> Result<(), StateMachineError<S, E>> {
+        let state = self.get_current_state()?;
+        for validator in &self.validators {
+            if let Err(reason) = validator(state, event, &self.context) {
+                return Err(StateMachineError::Rejected {
+                    state: state.clone(),
+                    event: event.clone(),
+                    reason,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells state listeners about a state entered without a transition.
+    pub(crate) fn announce_state(&mut self) {
+        self.version += 1;
+        if let Some(state) = &self.current_state {
+            for listener in &self.state_listeners {
+                listener(state);
+            }
+        }
+    }
+
+    pub(crate) fn commit(&mut self, new_state: S, event: &E) {
+        self.commit_with(new_state, event, true);
+    }
+
+    /// Whether exit hooks are still due when a handler's transition commits.
+    pub(crate) fn exit_hooks_pending(&self) -> bool {
+        self.semantics.exit == ExitTiming::OnTransition
+    }
+
+    /// Like [`commit`](Self::commit), skipping exit hooks that already ran
+    /// ahead of the handler.
+    pub(crate) fn commit_with(&mut self, new_state: S, event: &E, exit_hooks: bool) {
+        let previous = self.current_state.replace(new_state.clone());
+        self.version += 1;
+        if let Some(from) = previous {
+            let (exited, entered) = self.crossed(&from, &new_state);
+            // The current state's own exit hooks may have run ahead of the handler.
+            let exited = exited.iter().filter(|state| exit_hooks || **state != from);
+            for state in exited {
+                for hook in self.exit_hooks.get(state).into_iter().flatten() {
+                    hook(&mut self.context, &from, &new_state, event);
+                    self.trace.actions_run += 1;
+                }
+            }
+            self.state_locals.exit();
+            for hook in &self.transition_hooks {
+                hook(&mut self.context, &from, &new_state, event);
+                self.trace.actions_run += 1;
+            }
+            for state in &entered {
+                for hook in self.entry_hooks.get(state).into_iter().flatten() {
+                    hook(&mut self.context, &from, &new_state, event);
+                    self.trace.actions_run += 1;
+                }
+            }
+            self.state_locals
+                .enter(&new_state, &mut self.context, event);
+            for observer in &self.observers {
+                observer(&from, event, &new_state);
+            }
+        }
+    }
+
+    /// Follows completion and eventless transitions until none applies,
+    /// reporting each one to observers under the `event` that started the dispatch.
+    pub(crate) fn run_to_completion(&mut self, event: &E) -> Result<(), StateMachineError<S, E>> {
+        // Filled on the first step, as most transitions take none.
+        let mut visited: Vec<S> = Vec::new();
+        loop {
+            let current = self.get_current_state()?;
+            let substate = self.initial.substates.get(current).cloned();
+            let completion = if self.finals.contains(current) {
+                self.parents
+                    .get(current)
+                    .and_then(|parent| self.completions.get(parent))
+                    .cloned()
+            } else {
+                None
+            };
+            let next = substate.or(completion).or_else(|| {
+                self.eventless.get(current).and_then(|candidates| {
+                    candidates
+                        .iter()
+                        .find(|(_, guard)| guard(self))
+                        .map(|(to, _)| to.clone())
+                })
+            });
+            let Some(next) = next else {
+                return Ok(());
+            };
+            if visited.is_empty() {
+                visited.push(self.get_current_state()?.clone());
+            }
+            if visited.contains(&next) {
+                self.commit(next.clone(), event);
+                return Err(StateMachineError::EventlessCycle { state: next });
+            }
+            visited.push(next.clone());
+            self.commit(next, event);
+        }
     }
 
     pub fn get_current_state(&self) -> Result<&S, StateMachineError<S, E>> {
@@ -92,6 +924,14 @@ where
         }
     }
 
+    pub fn semantics(&self) -> Semantics {
+        self.semantics
+    }
+
+    pub fn set_semantics(&mut self, semantics: Semantics) {
+        self.semantics = semantics;
+    }
+
     pub fn get_context(&self) -> &C {
         &self.context
     }
@@ -99,50 +939,170 @@ where
     pub fn get_context_mut(&mut self) -> &mut C {
         &mut self.context
     }
+
+    /// Attaches `value` to the machine, returning the previous value of the same type.
+    pub fn insert_ext<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.extensions.insert(value)
+    }
+
+    pub fn ext<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    pub fn ext_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut()
+    }
+
+    pub fn remove_ext<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.extensions.remove()
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
 }
-impl<S, E, C> Stateful<S, C, E> for StateMachine<S, E, C>
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
 where
     S: State,
     E: Event,
+    O: Default,
+    H: BuildHasher,
 {
-    fn on_enter(&self, event: &E) -> Result<TransitionFunction<S, E, C>, StateMachineError<S, E>> {
-        let current_state = self.get_current_state()?.clone();
+    /// Dispatches `event`, returning the handler's response and output value.
+    ///
+    /// Events that are discarded, or handled only by eventless follow-ups,
+    /// yield `O::default()`.
+    pub fn dispatch(&mut self, event: &E) -> HandlerResult<S, E, O> {
+        self.dispatch_as(event, &AuditContext::default())
+    }
 
-        println!("Transition initiated, Call Event: {:?} triggered", event);
+    /// Like [`dispatch`](Self::dispatch), recording `context` in the audit log.
+    pub fn dispatch_as(&mut self, event: &E, context: &AuditContext) -> HandlerResult<S, E, O> {
+        let _scope = correlation::enter(context.correlation_id.as_deref());
+        let from = self.get_current_state()?.clone();
+        let before = self.capture_context();
+        self.redirected_from = None;
+        self.enter_dispatch();
+        let result = match self.deduplicate(event, context) {
+            Some(duplicate) => duplicate,
+            None => self.dispatch_unaudited(event),
+        };
+        self.remember_key(context, &result);
+        self.audit(from, before, event, context, &result);
+        self.leave_dispatch(context, result.is_ok());
+        result
+    }
 
-        match self
-            .transitions
-            .get(&(current_state.clone(), event.clone()))
-        {
-            Some(t) => Ok(t.clone()),
-            None => Err(StateMachineError::TransitionNotFound {
+    fn dispatch_unaudited(&mut self, event: &E) -> HandlerResult<S, E, O> {
+        self.validate(event)?;
+        let current_state = self.get_current_state()?.clone();
+        let Some((first, fallbacks)) = self.chain_handlers(&current_state, event) else {
+            if self.semantics.unhandled == Unhandled::Discard {
+                return Ok((Response::Handled, O::default()));
+            }
+            return Err(StateMachineError::TransitionNotFound {
                 from: current_state,
                 event: event.clone(),
-            }),
+            });
+        };
+        self.exit_before_handler(event);
+
+        for (handler, transition) in std::iter::once(first).chain(fallbacks) {
+            let (response, output) = self
+                .call_with_retries(&handler, event, &transition)
+                .map_err(|e| self.recover(&current_state, event, e))?;
+            if let Some(result) = self.complete(response, output, event) {
+                return result;
+            }
         }
+
+        Err(StateMachineError::UnexpectedEvent {
+            state: self.get_current_state()?.clone(),
+            event: event.clone(),
+        })
     }
 
-    fn handle_event(&mut self, event: &E) -> Result<Response<S>, StateMachineError<S, E>> {
-        let transition = match self.on_enter(event) {
-            Ok(t) => t,
-            Err(e) => return Err(e),
-        };
+    /// Runs the current state's exit work ahead of the handler under
+    /// [`ExitTiming::BeforeHandler`]. The target is not known yet, so exit
+    /// hooks see the state being exited as `to`.
+    pub(crate) fn exit_before_handler(&mut self, event: &E) {
+        if self.semantics.exit != ExitTiming::BeforeHandler {
+            return;
+        }
         self.on_exit();
+        let Some(state) = self.current_state.clone() else {
+            return;
+        };
+        for hook in self.exit_hooks.get(&state).into_iter().flatten() {
+            hook(&mut self.context, &state, &state, event);
+            self.trace.actions_run += 1;
+        }
+    }
 
-        match transition(self, event)? {
-            Response::Handled => Ok(Response::Handled),
-            Response::Transition(new_state) => {
-                self.current_state = Some(new_state.clone());
-                Ok(Response::Transition(new_state))
+    /// Acts on a handler's response; `None` means it deferred with `Super`.
+    pub(crate) fn complete(
+        &mut self,
+        response: Response<S>,
+        output: O,
+        event: &E,
+    ) -> Option<HandlerResult<S, E, O>> {
+        match response {
+            Response::Handled => Some(Ok((Response::Handled, output))),
+            Response::Transition(new_state) => Some(self.transition_to(new_state, output, event)),
+            Response::Super => {
+                self.trace.deferred = true;
+                None
             }
-            Response::Super => Err(StateMachineError::UnexpectedEvent {
-                state: self.get_current_state()?.clone(),
+        }
+    }
+
+    fn transition_to(&mut self, new_state: S, output: O, event: &E) -> HandlerResult<S, E, O> {
+        let new_state = self.apply_redirects(event, new_state)?;
+        self.check_vetoes(event, &new_state)?;
+        if self.semantics.self_transition == SelfTransition::Local
+            && self.current_state.as_ref() == Some(&new_state)
+        {
+            // The state is never left, but the handler may have enabled an
+            // eventless transition out of it.
+            self.run_to_completion(event)?;
+            let state = self.get_current_state()?.clone();
+            return Ok((Response::Transition(state), output));
+        }
+        if self.exit_hooks_pending() {
+            self.on_exit();
+        }
+        self.commit_with(new_state, event, self.exit_hooks_pending());
+        self.run_to_completion(event)?;
+        let state = self.get_current_state()?.clone();
+        Ok((Response::Transition(state), output))
+    }
+}
+impl<S, E, C, O, H> Stateful<S, C, E, O, H> for StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    O: Default,
+    H: BuildHasher,
+{
+    fn on_enter(&self, event: &E) -> TransitionLookup<S, E, C, O, H> {
+        let current_state = self.get_current_state()?.clone();
+        match self.chain_handlers(&current_state, event) {
+            Some(((_, t), _)) => Ok(t),
+            None => Err(StateMachineError::TransitionNotFound {
+                from: current_state,
                 event: event.clone(),
             }),
         }
     }
 
-    fn on_exit(&self) {
-        println!("Exiting state: {:?}", self.current_state);
+    fn handle_event(&mut self, event: &E) -> Result<Response<S>, StateMachineError<S, E>> {
+        self.dispatch(event).map(|(response, _)| response)
     }
+
+    // Exit work belongs in `add_exit_hook`; the library never writes to stdout.
+    fn on_exit(&self) {}
 }
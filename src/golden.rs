@@ -0,0 +1,166 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Set this variable to rewrite golden files from the current output instead of comparing.
+pub const UPDATE_ENV: &str = "FSMPORTAL_UPDATE_GOLDEN";
+
+enum Node {
+    Line(String),
+    Block {
+        open: String,
+        children: Vec<Node>,
+        close: String,
+    },
+}
+
+impl Node {
+    fn key(&self) -> &str {
+        match self {
+            Node::Line(line) => line,
+            Node::Block { open, .. } => open,
+        }
+    }
+
+    fn render(&self, depth: usize, out: &mut String) {
+        let indent = "    ".repeat(depth);
+        match self {
+            Node::Line(line) => {
+                out.push_str(&indent);
+                out.push_str(line);
+                out.push('\n');
+            }
+            Node::Block {
+                open,
+                children,
+                close,
+            } => {
+                out.push_str(&indent);
+                out.push_str(open);
+                out.push('\n');
+                for child in children {
+                    child.render(depth + 1, out);
+                }
+                out.push_str(&indent);
+                out.push_str(close);
+                out.push('\n');
+            }
+        }
+    }
+}
+
+fn parse<'a>(lines: &mut impl Iterator<Item = &'a str>) -> (Vec<Node>, Option<String>) {
+    let mut nodes = Vec::new();
+    while let Some(line) = lines.next() {
+        if line.starts_with('}') {
+            return (nodes, Some(line.to_string()));
+        }
+        if line.ends_with('{') {
+            let (mut children, close) = parse(lines);
+            children.sort_by(|a, b| a.key().cmp(b.key()));
+            nodes.push(Node::Block {
+                open: line.to_string(),
+                children,
+                close: close.unwrap_or_else(|| "}".to_string()),
+            });
+        } else {
+            nodes.push(Node::Line(line.to_string()));
+        }
+    }
+    (nodes, None)
+}
+
+/// Normalizes DOT or Mermaid text so that two diagrams describing the same
+/// graph compare equal: whitespace is trimmed, blank lines and comments are
+/// dropped, and the statements inside every block are sorted.
+///
+/// The first line of a Mermaid diagram (`stateDiagram-v2`) is kept in place.
+pub fn normalize(diagram: &str) -> String {
+    let mut lines = diagram
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with("%%"));
+
+    let mut out = String::new();
+    let mut nodes = Vec::new();
+    if let Some(header) = lines.next() {
+        if header.ends_with('{') {
+            let (mut children, close) = parse(&mut lines);
+            children.sort_by(|a, b| a.key().cmp(b.key()));
+            nodes.push(Node::Block {
+                open: header.to_string(),
+                children,
+                close: close.unwrap_or_else(|| "}".to_string()),
+            });
+        } else {
+            out.push_str(header);
+            out.push('\n');
+            let (mut children, _) = parse(&mut lines);
+            children.sort_by(|a, b| a.key().cmp(b.key()));
+            nodes = children;
+        }
+    }
+
+    let depth = usize::from(!out.is_empty());
+    for node in &nodes {
+        node.render(depth, &mut out);
+    }
+    out
+}
+
+/// Compares `actual` against the golden file at `path` after normalizing both.
+///
+/// When [`UPDATE_ENV`] is set, or the golden file does not exist yet, the
+/// normalized output is written to `path` instead.
+///
+/// # Panics
+///
+/// Panics when the normalized diagrams differ, printing both versions.
+pub fn assert_golden(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    let actual = normalize(actual);
+
+    if env::var_os(UPDATE_ENV).is_some() || !path.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("Failed to create golden directory");
+        }
+        fs::write(path, &actual).expect("Failed to write golden file");
+        return;
+    }
+
+    let expected = fs::read_to_string(path).expect("Failed to read golden file");
+    let expected = normalize(&expected);
+    assert!(
+        expected == actual,
+        "diagram does not match golden file {}\n--- expected\n{}--- actual\n{}\nrerun with {}=1 to accept the new output",
+        path.display(),
+        expected,
+        actual,
+        UPDATE_ENV
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_sorts_statements() {
+        let a = "digraph G {\n    \"B\";\n    \"A\" -> \"B\";\n\n    \"A\";\n}\n";
+        let b = "digraph G {\n\"A\";\n  \"A\" -> \"B\";\n\"B\";\n}";
+        assert_eq!(normalize(a), normalize(b));
+        assert_eq!(
+            normalize(a),
+            "digraph G {\n    \"A\" -> \"B\";\n    \"A\";\n    \"B\";\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_keeps_mermaid_header_and_nested_blocks() {
+        let diagram = "stateDiagram-v2\n    state Call {\n        b\n        a\n    }\n    %% comment\n    Idle --> Call\n";
+        assert_eq!(
+            normalize(diagram),
+            "stateDiagram-v2\n    Idle --> Call\n    state Call {\n        a\n        b\n    }\n"
+        );
+    }
+}
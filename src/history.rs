@@ -0,0 +1,123 @@
+use crate::clock::Clock;
+use crate::generic::{Event, State, StateMachine};
+use crate::json;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A committed transition and when it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry<S, E> {
+    pub from: S,
+    pub event: E,
+    pub to: S,
+    pub at: SystemTime,
+}
+
+impl<S: Debug, E: Debug> HistoryEntry<S, E> {
+    /// Encodes the entry as a JSON object of Debug names, with `at` in
+    /// milliseconds since the Unix epoch.
+    pub fn to_json(&self) -> String {
+        let millis = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        format!(
+            "{{\"from\":\"{}\",\"event\":\"{}\",\"to\":\"{}\",\"at\":{}}}",
+            json::escape(&format!("{:?}", self.from)),
+            json::escape(&format!("{:?}", self.event)),
+            json::escape(&format!("{:?}", self.to)),
+            millis
+        )
+    }
+}
+
+struct Ring<S, E> {
+    entries: VecDeque<HistoryEntry<S, E>>,
+    capacity: usize,
+}
+
+struct History<S, E>(Arc<Mutex<Ring<S, E>>>);
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State + Send + 'static,
+    E: Event + Send + 'static,
+{
+    /// Keeps the last `capacity` transitions for [`recent_transitions`](Self::recent_transitions).
+    /// Calling it again resizes the history, dropping the oldest entries if it shrinks.
+    pub fn with_history(mut self, capacity: usize) -> Self {
+        if let Some(History(ring)) = self.ext::<History<S, E>>() {
+            let mut ring = ring.lock().unwrap();
+            ring.capacity = capacity;
+            let excess = ring.entries.len().saturating_sub(capacity);
+            ring.entries.drain(..excess);
+            drop(ring);
+            return self;
+        }
+
+        let ring = Arc::new(Mutex::new(Ring {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }));
+        let log = ring.clone();
+        let clock = self.clock.clone();
+        self.add_observer(move |from, event, to| {
+            let mut ring = log.lock().unwrap();
+            if ring.capacity == 0 {
+                return;
+            }
+            if ring.entries.len() == ring.capacity {
+                ring.entries.pop_front();
+            }
+            ring.entries.push_back(HistoryEntry {
+                from: from.clone(),
+                event: event.clone(),
+                to: to.clone(),
+                at: clock.wall_time(),
+            });
+        });
+        self.insert_ext(History(ring));
+        self
+    }
+
+    /// The recorded transitions, oldest first; empty without [`with_history`](Self::with_history).
+    pub fn recent_transitions(&self) -> Vec<HistoryEntry<S, E>> {
+        self.ext::<History<S, E>>()
+            .map(|History(ring)| ring.lock().unwrap().entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Stateful;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_history_keeps_most_recent() {
+        let mut sm = init_state_machine().with_history(2);
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+
+        let recent = sm.recent_transitions();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].from, CallState::Ringing);
+        assert_eq!(recent[1].event, CallEvent::HangUp);
+        assert!(recent[0].at <= recent[1].at);
+        assert!(init_state_machine().recent_transitions().is_empty());
+
+        let entry = HistoryEntry {
+            at: UNIX_EPOCH + std::time::Duration::from_millis(7),
+            ..recent[0].clone()
+        };
+        assert_eq!(
+            entry.to_json(),
+            "{\"from\":\"Ringing\",\"event\":\"Answer\",\"to\":\"Connected\",\"at\":7}"
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! A REST/JSON front-end for a [`MachinePool`], enabled with the `http` feature.
+//!
+//! [`HttpFacade::serve`] exposes two routes over plain HTTP/1.1:
+//!
+//! - `POST /machines/{id}/events` with a body such as
+//!   `{"event":"Dial","payload":{"number":"+15550100"}}` dispatches the event
+//!   to machine `id`, created by the pool's factory if it is new, and answers
+//!   with the resulting state. Events are parsed with
+//!   [`StateMachine::parse_named`](crate::generic::StateMachine::parse_named),
+//!   so the factory must give each machine an event parser, and dispatched
+//!   through [`MachinePool::dispatch`], so the pool's state limits apply.
+//! - `GET /machines/{id}/state` answers with the machine's current state, or
+//!   404 if the pool has no machine `id`.
+//!
+//! States are reported as `{"state":"Dialing"}` using their Debug names, and a
+//! failed dispatch answers with the error's [`to_json`] encoding: 400 for an
+//! unknown event name, 202 for an event deferred by a full state, 503 for one
+//! refused by it, and 409 for any other error. The server is std-only, one
+//! thread per connection, with the pool behind a mutex it shares with the
+//! rest of the application.
+//!
+//! [`to_json`]: crate::generic::StateMachineError::to_json
+
+use crate::generic::{Event, State, StateMachineError};
+use crate::json;
+use crate::pool::MachinePool;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Request bodies above this size are refused.
+const MAX_BODY: usize = 64 * 1024;
+
+/// A pool shared between the HTTP server and the rest of the application.
+pub type SharedPool<S, E, C, O> = Arc<Mutex<MachinePool<String, S, E, C, O>>>;
+
+/// Handle to a running server; the listener thread lives as long as the process.
+pub struct HttpFacade {
+    addr: SocketAddr,
+}
+
+impl HttpFacade {
+    /// Binds `addr` and starts serving the machines of `pool`.
+    pub fn serve<S, E, C, O>(
+        pool: SharedPool<S, E, C, O>,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<HttpFacade>
+    where
+        S: State + Send + 'static,
+        E: Event + Send + Sync + 'static,
+        C: Send + 'static,
+        O: Default + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let _ = serve(stream, &pool);
+                });
+            }
+        });
+        Ok(HttpFacade { addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn serve<S, E, C, O>(mut stream: TcpStream, pool: &SharedPool<S, E, C, O>) -> io::Result<()>
+where
+    S: State + Send,
+    E: Event + Send + Sync + 'static,
+    C: Send,
+    O: Default + Send,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return respond(&mut stream, "413 Payload Too Large", &error("too_large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let body = String::from_utf8_lossy(&body);
+
+    let (status, body) = route(pool, &method, &path, &body);
+    respond(&mut stream, status, &body)
+}
+
+fn route<S, E, C, O>(
+    pool: &SharedPool<S, E, C, O>,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (&'static str, String)
+where
+    S: State + Send,
+    E: Event + Send + Sync + 'static,
+    C: Send,
+    O: Default + Send,
+{
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("POST", ["machines", id, "events"]) => {
+            let Some((name, payload)) = parse_event(body) else {
+                return ("400 Bad Request", error("bad_request"));
+            };
+            let id = id.to_string();
+            let mut pool = pool.lock().unwrap();
+            let event = match pool.get_or_create(id.clone()).parse_named(&name, payload) {
+                Ok(event) => event,
+                Err(e) => return ("400 Bad Request", e.to_json()),
+            };
+            match pool.dispatch(id.clone(), &event) {
+                Ok(_) => {
+                    let state = pool
+                        .get(&id)
+                        .and_then(|machine| machine.current_state.as_ref());
+                    ("200 OK", state_json(state))
+                }
+                Err(e @ StateMachineError::StateLimitReached { deferred: true, .. }) => {
+                    ("202 Accepted", e.to_json())
+                }
+                Err(e @ StateMachineError::StateLimitReached { .. }) => {
+                    ("503 Service Unavailable", e.to_json())
+                }
+                Err(e) => ("409 Conflict", e.to_json()),
+            }
+        }
+        ("GET", ["machines", id, "state"]) => match pool.lock().unwrap().get(&id.to_string()) {
+            Some(machine) => ("200 OK", state_json(machine.current_state.as_ref())),
+            None => ("404 Not Found", error("not_found")),
+        },
+        _ => ("404 Not Found", error("not_found")),
+    }
+}
+
+/// The `event` name and raw `payload` of a request body.
+fn parse_event(body: &str) -> Option<(String, Option<&str>)> {
+    let fields = json::fields(body)?;
+    let name = fields
+        .iter()
+        .find(|(key, _)| key == "event")
+        .and_then(|(_, value)| json::string(value))?;
+    let payload = fields
+        .iter()
+        .find(|(key, _)| key == "payload")
+        .map(|(_, value)| *value);
+    Some((name, payload))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn state_json<S: State>(state: Option<&S>) -> String {
+    match state {
+        Some(state) => format!(
+            "{{\"state\":\"{}\"}}",
+            json::escape(&format!("{:?}", state))
+        ),
+        None => String::from("{\"state\":null}"),
+    }
+}
+
+fn error(kind: &str) -> String {
+    format!("{{\"kind\":\"{}\"}}", kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::OnLimit;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_events_and_state_routes() {
+        let pool = MachinePool::new(|_: &String| {
+            let mut sm = init_state_machine();
+            sm.set_event_parser(|name, payload| match (name, payload) {
+                ("Dial", Some(payload)) if payload.contains("\"number\"") => Some(CallEvent::Dial),
+                ("HangUp", _) => Some(CallEvent::HangUp),
+                _ => None,
+            });
+            sm
+        });
+        let server = HttpFacade::serve(Arc::new(Mutex::new(pool)), "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+
+        let response = request(addr, "GET", "/machines/a/state", "");
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        let dial = r#"{"event": "Dial", "payload": {"number": "+1 555, 0100"}}"#;
+        let response = request(addr, "POST", "/machines/a/events", dial);
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("{\"state\":\"Dialing\"}"));
+
+        let response = request(addr, "POST", "/machines/a/events", r#"{"event":"Hold"}"#);
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(response.contains("\"kind\":\"unknown_event_name\""));
+
+        let response = request(addr, "POST", "/machines/a/events", "Dial");
+        assert!(response.starts_with("HTTP/1.1 400"));
+
+        request(addr, "POST", "/machines/a/events", r#"{"event":"HangUp"}"#);
+        let response = request(addr, "POST", "/machines/a/events", r#"{"event":"HangUp"}"#);
+        assert!(response.starts_with("HTTP/1.1 409"));
+
+        let response = request(addr, "GET", "/machines/a/state", "");
+        assert!(response.ends_with("{\"state\":\"Disconnected\"}"));
+    }
+
+    #[test]
+    fn test_events_route_respects_state_limits() {
+        let pool = MachinePool::new(|_: &String| {
+            let mut sm = init_state_machine();
+            sm.set_event_parser(|name, _| match name {
+                "Dial" => Some(CallEvent::Dial),
+                "Incoming" => Some(CallEvent::Incoming),
+                _ => None,
+            });
+            sm
+        })
+        .with_state_limit(CallState::Ringing, 1, OnLimit::Reject)
+        .with_state_limit(CallState::Dialing, 1, OnLimit::Defer);
+        let server = HttpFacade::serve(Arc::new(Mutex::new(pool)), "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        let incoming = r#"{"event":"Incoming"}"#;
+        let dial = r#"{"event":"Dial"}"#;
+
+        let response = request(addr, "POST", "/machines/a/events", incoming);
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let response = request(addr, "POST", "/machines/b/events", incoming);
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.contains("\"deferred\":false"));
+
+        request(addr, "POST", "/machines/c/events", dial);
+        let response = request(addr, "POST", "/machines/d/events", dial);
+        assert!(response.starts_with("HTTP/1.1 202"));
+        let response = request(addr, "GET", "/machines/d/state", "");
+        assert!(response.ends_with("{\"state\":\"Idle\"}"));
+    }
+}
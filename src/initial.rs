@@ -0,0 +1,150 @@
+//! Initial pseudo-states.
+//!
+//! Instead of naming its starting state in [`new`](StateMachine::new), a
+//! machine can be created [`unstarted`](StateMachine::unstarted), declare its
+//! initial target with [`set_initial_state`](StateMachine::set_initial_state)
+//! and an initializer run with the context, and be started with
+//! [`start`](StateMachine::start) once it is built.
+//!
+//! Each composite state can likewise declare a default substate with
+//! [`set_initial_substate`](StateMachine::set_initial_substate): a transition
+//! targeting the composite continues into its initial substate, recursively,
+//! as an eventless step reported under the same event.
+
+use crate::generic::{Event, State, StateMachine, StateMachineError};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+
+/// Runs with the context when the machine starts.
+pub type Initializer<C> = Arc<dyn Fn(&mut C) + Send + Sync>;
+
+pub(crate) struct Initial<S, C> {
+    state: Option<S>,
+    initializer: Option<Initializer<C>>,
+    pub(crate) substates: HashMap<S, S>,
+}
+
+impl<S, C> Initial<S, C> {
+    pub(crate) fn new(state: Option<S>) -> Self {
+        Initial {
+            state,
+            initializer: None,
+            substates: HashMap::new(),
+        }
+    }
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    /// Declares the state [`start`](Self::start) enters. Machines created
+    /// with [`new`](Self::new) start in the state given there.
+    pub fn set_initial_state(&mut self, state: S) {
+        self.initial.state = Some(state);
+    }
+
+    pub fn initial(&self) -> Option<&S> {
+        self.initial.state.as_ref()
+    }
+
+    /// Runs `initializer` with the context each time the machine starts,
+    /// before its initial state is entered.
+    pub fn set_initializer<F>(&mut self, initializer: F)
+    where
+        F: Fn(&mut C) + 'static + Send + Sync,
+    {
+        self.initial.initializer = Some(Arc::new(initializer));
+    }
+
+    /// Makes transitions into `parent` continue into `child`. `child` also
+    /// becomes a substate of `parent` if it is not nested yet.
+    pub fn set_initial_substate(&mut self, parent: S, child: S) {
+        if !self.parents.contains_key(&child) {
+            self.add_substate(parent.clone(), child.clone());
+        }
+        self.initial.substates.insert(parent, child);
+    }
+
+    pub fn initial_substate(&self, parent: &S) -> Option<&S> {
+        self.initial.substates.get(parent)
+    }
+
+    /// Runs the initializer and moves to the initial state, descending into
+    /// initial substates, discarding any current state. Hooks and observers
+    /// do not run, as no event caused the move.
+    pub fn start(&mut self) -> Result<&S, StateMachineError<S, E>> {
+        let mut state = self
+            .initial
+            .state
+            .clone()
+            .ok_or(StateMachineError::NotInitialized)?;
+        if let Some(initializer) = self.initial.initializer.clone() {
+            initializer(&mut self.context);
+        }
+        let mut visited = vec![state.clone()];
+        while let Some(child) = self.initial.substates.get(&state) {
+            if visited.contains(child) {
+                break;
+            }
+            state = child.clone();
+            visited.push(state.clone());
+        }
+        self.state_locals.exit();
+        self.current_state = Some(state);
+        self.announce_state();
+        self.get_current_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Line {
+        Off,
+        Active,
+        Talking,
+        Held,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Press {
+        Dial,
+        Hold,
+        Hook,
+    }
+
+    #[test]
+    fn test_start_and_default_substates() {
+        let mut sm: StateMachine<Line, Press, u32> = StateMachine::unstarted(7);
+        assert!(matches!(
+            sm.dispatch(&Press::Dial),
+            Err(StateMachineError::NotInitialized)
+        ));
+        assert!(sm.start().is_err());
+
+        sm.set_initial_state(Line::Off);
+        sm.set_initializer(|resets| *resets = 0);
+        sm.set_initial_substate(Line::Active, Line::Talking);
+        sm.add_substate(Line::Active, Line::Held);
+        sm.add_transition_to(Line::Off, Press::Dial, Line::Active);
+        sm.add_transition_to(Line::Talking, Press::Hold, Line::Held);
+        sm.add_transition_to(Line::Active, Press::Hook, Line::Off);
+        assert_eq!(sm.start().unwrap(), &Line::Off);
+        assert_eq!(sm.get_context(), &0);
+
+        sm.dispatch(&Press::Dial).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &Line::Talking);
+        sm.dispatch(&Press::Hold).unwrap();
+        sm.dispatch(&Press::Hook).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &Line::Off);
+
+        sm.set_initial_state(Line::Active);
+        assert_eq!(sm.start().unwrap(), &Line::Talking);
+    }
+}
@@ -0,0 +1,355 @@
+//! Terminal inspector, enabled with the `inspector` feature.
+//!
+//! Redraws a screen with the current state, the events accepted from it, the
+//! work waiting on the machine, the most recent transitions and the context,
+//! then reads event names typed at the prompt and dispatches them. Waiting
+//! work is the events handlers [posted](crate::dispatch_ctx::DispatchCtx::post)
+//! or [scheduled](crate::dispatch_ctx::DispatchCtx::schedule) and, for a
+//! machine on an actor, the commands in its mailbox.
+
+use crate::actor::{ActorHandle, MailboxMetrics};
+use crate::generic::{Event, State, StateMachine, Stateful};
+use crate::request::Canceled;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::{Debug, Write as _};
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+const CLEAR: &str = "\x1b[2J\x1b[H";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+pub type EventParser<E> = Box<dyn Fn(&str) -> Option<E>>;
+
+pub struct Inspector<E> {
+    parse: EventParser<E>,
+    recent: RecentLog,
+    capacity: usize,
+}
+
+type RecentLog = Arc<Mutex<VecDeque<String>>>;
+
+// Appends `line` to `log`, keeping the last `capacity` lines.
+fn record(log: &RecentLog, capacity: usize, line: String) {
+    if capacity == 0 {
+        return;
+    }
+    let mut log = log.lock().unwrap();
+    while log.len() >= capacity {
+        log.pop_front();
+    }
+    log.push_back(line);
+}
+
+fn screen<S, E, C, O>(
+    sm: &StateMachine<S, E, C, O>,
+    mailbox: Option<MailboxMetrics>,
+    recent: &RecentLog,
+    capacity: usize,
+) -> String
+where
+    S: State,
+    E: Event,
+    C: Debug,
+{
+    let mut out = String::new();
+    let _ = match &sm.current_state {
+        Some(state) => writeln!(out, "{}State:{} {:?}", BOLD, RESET, state),
+        None => writeln!(out, "{}State:{} <not initialized>", BOLD, RESET),
+    };
+
+    let available: BTreeSet<String> = sm.available_events().map(|e| format!("{:?}", e)).collect();
+    let available: Vec<String> = available.into_iter().collect();
+    let _ = writeln!(out, "{}Events:{} {}", BOLD, RESET, available.join(", "));
+
+    if let Some(mailbox) = mailbox {
+        let _ = writeln!(
+            out,
+            "{}Mailbox:{} {} waiting (high water {})",
+            BOLD, RESET, mailbox.depth, mailbox.high_water
+        );
+    }
+    let posted: Vec<String> = sm.posted_events().map(|e| format!("{:?}", e)).collect();
+    let _ = writeln!(out, "{}Posted:{} {}", BOLD, RESET, posted.join(", "));
+    let scheduled: Vec<String> = sm
+        .scheduled_events()
+        .into_iter()
+        .map(|(due, e)| format!("{:?} in {:?}", e, due))
+        .collect();
+    let _ = writeln!(out, "{}Scheduled:{} {}", BOLD, RESET, scheduled.join(", "));
+
+    let _ = writeln!(
+        out,
+        "{}Recent transitions{} (last {}):",
+        BOLD, RESET, capacity
+    );
+    for line in recent.lock().unwrap().iter().rev() {
+        let _ = writeln!(out, "  {}", line);
+    }
+
+    let _ = writeln!(out, "{}Context:{} {:#?}", BOLD, RESET, sm.context);
+    out
+}
+
+impl<E> Inspector<E>
+where
+    E: Event,
+{
+    /// Attaches to `sm`, keeping the last `capacity` transitions for display.
+    /// `parse` turns a typed line into an event.
+    pub fn attach<S, C, O, F>(sm: &mut StateMachine<S, E, C, O>, capacity: usize, parse: F) -> Self
+    where
+        S: State,
+        F: Fn(&str) -> Option<E> + 'static,
+    {
+        let recent = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let log = recent.clone();
+        sm.add_observer(move |from, event, to| {
+            record(
+                &log,
+                capacity,
+                format!("{:?} --{:?}--> {:?}", from, event, to),
+            )
+        });
+        Inspector {
+            parse: Box::new(parse),
+            recent,
+            capacity,
+        }
+    }
+
+    /// Like [`attach`](Self::attach), for a machine running on an actor.
+    /// Fails if the actor has stopped.
+    pub fn attach_actor<S, C, O, F>(
+        handle: &ActorHandle<S, E, C, O>,
+        capacity: usize,
+        parse: F,
+    ) -> Result<Self, Canceled>
+    where
+        S: State + Send + 'static,
+        E: Send + 'static,
+        C: Send + 'static,
+        O: Default + Send + 'static,
+        F: Fn(&str) -> Option<E> + 'static,
+    {
+        let recent = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let log = recent.clone();
+        handle
+            .with(move |sm| {
+                sm.add_observer(move |from, event, to| {
+                    record(
+                        &log,
+                        capacity,
+                        format!("{:?} --{:?}--> {:?}", from, event, to),
+                    )
+                })
+            })
+            .recv()?;
+        Ok(Inspector {
+            parse: Box::new(parse),
+            recent,
+            capacity,
+        })
+    }
+
+    /// Renders one screen of the inspector, without the clear-screen prefix.
+    pub fn render<S, C, O>(&self, sm: &StateMachine<S, E, C, O>) -> String
+    where
+        S: State,
+        C: Debug,
+    {
+        screen(sm, None, &self.recent, self.capacity)
+    }
+
+    /// Renders one screen from the machine behind `handle`.
+    pub fn render_actor<S, C, O>(
+        &self,
+        handle: &ActorHandle<S, E, C, O>,
+    ) -> Result<String, Canceled>
+    where
+        S: State + Send + 'static,
+        E: Send + 'static,
+        C: Debug + Send + 'static,
+        O: Default + Send + 'static,
+    {
+        let (recent, capacity) = (self.recent.clone(), self.capacity);
+        let mailbox = handle.mailbox_metrics();
+        handle
+            .with(move |sm| screen(sm, Some(mailbox), &recent, capacity))
+            .recv()
+    }
+
+    /// Runs the inspector until `quit` or end of input.
+    pub fn run<S, C, O, R, W>(
+        &self,
+        sm: &mut StateMachine<S, E, C, O>,
+        input: R,
+        output: W,
+    ) -> io::Result<()>
+    where
+        S: State,
+        C: Debug,
+        O: Default,
+        R: BufRead,
+        W: Write,
+    {
+        let sm = RefCell::new(sm);
+        self.drive(
+            || self.render(&sm.borrow()),
+            |event| match sm.borrow_mut().handle_event(event) {
+                Ok(response) => format!("{:?} -> {:?}", event, response),
+                Err(e) => format!("{:?} failed: {:?}", event, e),
+            },
+            input,
+            output,
+        )
+    }
+
+    /// Like [`run`](Self::run), dispatching through `handle`; typed events
+    /// queue behind whatever else the actor is doing.
+    pub fn run_actor<S, C, O, R, W>(
+        &self,
+        handle: &ActorHandle<S, E, C, O>,
+        input: R,
+        output: W,
+    ) -> io::Result<()>
+    where
+        S: State + Send + 'static,
+        E: Send + 'static,
+        C: Debug + Send + 'static,
+        O: Default + Send + 'static,
+        R: BufRead,
+        W: Write,
+    {
+        self.drive(
+            || {
+                self.render_actor(handle)
+                    .unwrap_or_else(|_| String::from("actor stopped\n"))
+            },
+            |event| match handle.dispatch(event.clone()).recv() {
+                Ok(Ok((response, _))) => format!("{:?} -> {:?}", event, response),
+                Ok(Err(e)) => format!("{:?} failed: {:?}", event, e),
+                Err(Canceled) => format!("{:?} failed: actor stopped", event),
+            },
+            input,
+            output,
+        )
+    }
+
+    fn drive<R, W>(
+        &self,
+        render: impl Fn() -> String,
+        dispatch: impl Fn(&E) -> String,
+        input: R,
+        mut output: W,
+    ) -> io::Result<()>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        let mut lines = input.lines();
+        let mut status = String::new();
+        loop {
+            write!(output, "{}{}", CLEAR, render())?;
+            if !status.is_empty() {
+                writeln!(output, "{}", status)?;
+            }
+            write!(output, "event> ")?;
+            output.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            let line = line.trim();
+            if line == "quit" {
+                return Ok(());
+            }
+
+            status = match (self.parse)(line) {
+                Some(event) => dispatch(&event),
+                None => format!("unknown event: {}", line),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    fn parse(name: &str) -> Option<CallEvent> {
+        match name {
+            "dial" => Some(CallEvent::Dial),
+            "answer" => Some(CallEvent::Answer),
+            "hangup" => Some(CallEvent::HangUp),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_run_dispatches_typed_events() {
+        let mut sm = init_state_machine();
+        let inspector = Inspector::attach(&mut sm, 1, parse);
+
+        let mut output = Vec::new();
+        inspector
+            .run(
+                &mut sm,
+                "dial\nbogus\nanswer\nquit\nhangup\n".as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("unknown event: bogus"));
+
+        let screen = inspector.render(&sm);
+        assert!(screen.contains("Dialing --Answer--> Connected"));
+        assert!(!screen.contains("Idle --Dial--> Dialing"));
+        assert!(screen.contains("HangUp"));
+    }
+
+    #[test]
+    fn test_actor_inspector() {
+        let handle = ActorHandle::spawn(init_state_machine());
+        let inspector = Inspector::attach_actor(&handle, 0, parse).unwrap();
+
+        let mut output = Vec::new();
+        inspector
+            .run_actor(&handle, "dial\nanswer\n".as_bytes(), &mut output)
+            .unwrap();
+
+        assert_eq!(handle.state().unwrap(), CallState::Connected);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Answer -> Transition(Connected)"));
+        // A zero capacity keeps no transitions at all.
+        assert!(inspector.recent.lock().unwrap().is_empty());
+        let screen = inspector.render_actor(&handle).unwrap();
+        assert!(screen.contains("State:\x1b[0m Connected"));
+        assert!(screen.contains("Mailbox:\x1b[0m 0 waiting"));
+    }
+
+    #[test]
+    fn test_screen_shows_waiting_events() {
+        use crate::generic::Response;
+        use std::time::Duration;
+
+        let mut sm = init_state_machine();
+        sm.add_transition_with_ctx(CallState::Idle, CallEvent::Dial, |ctx, _| {
+            ctx.schedule(Duration::from_secs(30), CallEvent::HangUp);
+            Ok(Response::Transition(CallState::Dialing))
+        });
+        let inspector = Inspector::attach(&mut sm, 1, parse);
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        sm.dispatch_ctx().post(CallEvent::Answer);
+
+        let screen = inspector.render(&sm);
+        assert!(screen.contains("Posted:\x1b[0m Answer\n"));
+        assert!(screen.contains("Scheduled:\x1b[0m HangUp in "));
+        assert!(!screen.contains("Mailbox:"));
+    }
+}
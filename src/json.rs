@@ -0,0 +1,101 @@
+/// Escapes `value` for use inside a JSON string literal.
+pub(crate) fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(feature = "http")]
+/// Splits a JSON object into its keys and raw value texts, without
+/// interpreting the values. Returns `None` unless `text` is one object.
+pub(crate) fn fields(text: &str) -> Option<Vec<(String, &str)>> {
+    let body = text.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let mut fields = Vec::new();
+    let mut rest = body.trim_start();
+    while !rest.is_empty() {
+        let key_len = value_len(rest)?;
+        let key = string(&rest[..key_len])?;
+        rest = rest[key_len..].trim_start().strip_prefix(':')?.trim_start();
+        let len = value_len(rest)?;
+        fields.push((key, rest[..len].trim_end()));
+        rest = rest[len..].trim_start();
+        if let Some(next) = rest.strip_prefix(',') {
+            rest = next.trim_start();
+        } else if !rest.is_empty() {
+            return None;
+        }
+    }
+    Some(fields)
+}
+
+#[cfg(feature = "http")]
+/// Decodes a JSON string literal.
+pub(crate) fn string(raw: &str) -> Option<String> {
+    let mut chars = raw.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => out.push('\n'),
+            't' => out.push('\t'),
+            'r' => out.push('\r'),
+            'b' => out.push('\u{8}'),
+            'f' => out.push('\u{c}'),
+            'u' => {
+                let code: String = chars.by_ref().take(4).collect();
+                out.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+            }
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+#[cfg(feature = "http")]
+/// The length of the value at the start of `text`: a string, a bracketed
+/// value, or a bare literal running to the next `,` or `}`.
+fn value_len(text: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    in_string = false;
+                    if depth == 0 {
+                        return Some(i + 1);
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            ',' | '}' | ']' if depth == 0 => return (i > 0).then_some(i),
+            _ => {}
+        }
+    }
+    (depth == 0 && !in_string && !text.is_empty()).then_some(text.len())
+}
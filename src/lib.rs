@@ -1,5 +1,68 @@
+pub mod actor;
+pub mod analysis;
+pub mod atomic;
+pub mod audit;
+pub mod blocking;
+pub mod bridge;
+pub mod clock;
+pub mod codegen;
+pub mod compose;
+pub mod containment;
+pub mod correlation;
+pub mod deadline;
+#[cfg(feature = "debug-server")]
+pub mod debug_server;
+pub mod dedup;
+pub mod diff;
+pub mod dispatch_ctx;
+pub mod exhaustive;
+pub mod export;
+pub mod extensions;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod generic;
-use generic::{Event, Response, State, StateMachine};
+pub mod golden;
+pub mod history;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod initial;
+#[cfg(feature = "inspector")]
+pub mod inspector;
+mod json;
+pub mod metadata;
+pub mod named;
+pub mod nfa;
+pub mod outcome;
+pub mod pattern;
+pub mod persistence;
+pub mod pool;
+pub mod publish;
+pub mod pure;
+pub mod recovery;
+#[cfg(feature = "repl")]
+pub mod repl;
+pub mod request;
+pub mod resources;
+pub mod retry;
+pub mod semantics;
+pub mod simulation;
+pub mod snapshot;
+pub mod spec;
+pub mod state_local;
+pub mod static_dispatch;
+#[cfg(feature = "svg")]
+pub mod svg;
+pub mod task;
+#[cfg(feature = "telephony")]
+pub mod telephony;
+pub mod throttle;
+pub mod timed;
+pub mod upgrade;
+pub mod version;
+pub mod view;
+pub mod watch;
+pub mod watchdog;
+use generic::{Response, StateMachine};
 use std::collections::HashMap;
 use std::fmt::Debug;
 
@@ -21,60 +84,39 @@ pub enum CallEvent {
     Reset,
 }
 
-impl Event for CallEvent {}
-impl State for CallState {}
+/// The call lifecycle, `(from, event, to)`; the single table behind both
+/// [`init_state_machine`] and the `telephony` module.
+pub(crate) const CALL_TRANSITIONS: [(CallState, CallEvent, CallState); 8] = [
+    (CallState::Idle, CallEvent::Dial, CallState::Dialing),
+    (CallState::Idle, CallEvent::Incoming, CallState::Ringing),
+    (CallState::Dialing, CallEvent::Answer, CallState::Connected),
+    (
+        CallState::Dialing,
+        CallEvent::HangUp,
+        CallState::Disconnected,
+    ),
+    (CallState::Ringing, CallEvent::Answer, CallState::Connected),
+    (
+        CallState::Ringing,
+        CallEvent::HangUp,
+        CallState::Disconnected,
+    ),
+    (
+        CallState::Connected,
+        CallEvent::HangUp,
+        CallState::Disconnected,
+    ),
+    (CallState::Disconnected, CallEvent::Reset, CallState::Idle),
+];
 
 pub fn init_state_machine() -> StateMachine<CallState, CallEvent> {
-    let mut sm = StateMachine::new(CallState::Idle, HashMap::new());
-
-    // Transition from Idle to Dialing on Dial event
-    sm.add_transition(CallState::Idle, CallEvent::Dial, |_sm, _event| {
-        println!("Transitioning from Idle to Dialing");
-        Ok(Response::Transition(CallState::Dialing))
-    });
-
-    // Transition from Idle to Ringing on Incoming event
-    sm.add_transition(CallState::Idle, CallEvent::Incoming, |_sm, _event| {
-        println!("Transitioning from Idle to Ringing");
-        Ok(Response::Transition(CallState::Ringing))
-    });
-
-    // Transition from Dialing to Connected on Answer event
-    sm.add_transition(CallState::Dialing, CallEvent::Answer, |_sm, _event| {
-        println!("Transitioning from Dialing to Connected");
-        Ok(Response::Transition(CallState::Connected))
-    });
-
-    // Transition from Dialing to Disconnected on HangUp event
-    sm.add_transition(CallState::Dialing, CallEvent::HangUp, |_sm, _event| {
-        println!("Transitioning from Dialing to Disconnected");
-        Ok(Response::Transition(CallState::Disconnected))
-    });
-
-    // Transition from Ringing to Connected on Answer event
-    sm.add_transition(CallState::Ringing, CallEvent::Answer, |_sm, _event| {
-        println!("Transitioning from Ringing to Connected");
-        Ok(Response::Transition(CallState::Connected))
-    });
-
-    // Transition from Ringing to Disconnected on HangUp event
-    sm.add_transition(CallState::Ringing, CallEvent::HangUp, |_sm, _event| {
-        println!("Transitioning from Ringing to Disconnected");
-        Ok(Response::Transition(CallState::Disconnected))
-    });
-
-    // Transition from Connected to Disconnected on HangUp event
-    sm.add_transition(CallState::Connected, CallEvent::HangUp, |_sm, _event| {
-        println!("Transitioning from Connected to Disconnected");
-        Ok(Response::Transition(CallState::Disconnected))
-    });
-
-    // Transition from any state to Idle on Reset event
-    sm.add_transition(CallState::Disconnected, CallEvent::Reset, |_sm, _event| {
-        println!("Resetting to Idle");
-        Ok(Response::Transition(CallState::Idle))
-    });
-
+    let mut sm =
+        StateMachine::with_capacity(CallState::Idle, HashMap::new(), CALL_TRANSITIONS.len());
+    for (from, event, to) in CALL_TRANSITIONS {
+        sm.add_transition(from, event, move |_sm, _event| {
+            Ok(Response::Transition(to.clone()))
+        });
+    }
     sm
 }
 #[cfg(test)]
@@ -112,4 +154,344 @@ mod tests {
         ));
         assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
     }
+
+    #[test]
+    fn test_diagram_golden_files() {
+        let sm = init_state_machine();
+        let golden_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+
+        golden::assert_golden(format!("{}/call_machine.dot", golden_dir), &sm.to_dot());
+        golden::assert_golden(format!("{}/call_machine.mmd", golden_dir), &sm.to_mermaid());
+    }
+
+    #[test]
+    fn test_eventless_transitions_route_onward() {
+        let mut sm = init_state_machine();
+        sm.add_eventless_transition(CallState::Disconnected, CallState::Idle, |sm| {
+            sm.get_context().get("auto_reset") == Some(&1)
+        });
+
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Disconnected);
+
+        sm.get_context_mut().insert("auto_reset".to_string(), 1);
+        sm.handle_event(&CallEvent::Reset).unwrap();
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        let response = sm.handle_event(&CallEvent::HangUp).unwrap();
+        assert!(matches!(response, Response::Transition(CallState::Idle)));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+    }
+
+    #[test]
+    fn test_eventless_cycle_is_reported() {
+        let mut sm = init_state_machine();
+        sm.add_eventless_transition(CallState::Ringing, CallState::Connected, |_| true);
+        sm.add_eventless_transition(CallState::Connected, CallState::Ringing, |_| true);
+
+        let result = sm.handle_event(&CallEvent::Incoming);
+        assert!(matches!(
+            result,
+            Err(StateMachineError::EventlessCycle {
+                state: CallState::Ringing
+            })
+        ));
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Media {
+        Negotiating,
+        Offer,
+        Agreed,
+        Streaming,
+        Failed,
+    }
+
+    fn media_machine() -> StateMachine<Media, CallEvent> {
+        let mut sm = StateMachine::new(Media::Offer, HashMap::new());
+        sm.add_substate(Media::Negotiating, Media::Offer);
+        sm.add_final_substate(Media::Negotiating, Media::Agreed);
+        sm.add_completion_transition(Media::Negotiating, Media::Streaming);
+
+        sm.add_transition(Media::Offer, CallEvent::Answer, |_sm, _event| {
+            Ok(Response::Transition(Media::Agreed))
+        });
+        sm.add_transition(Media::Offer, CallEvent::HangUp, |_sm, _event| {
+            Ok(Response::Super)
+        });
+        sm.add_transition(Media::Negotiating, CallEvent::HangUp, |_sm, _event| {
+            Ok(Response::Transition(Media::Failed))
+        });
+        sm
+    }
+
+    #[test]
+    fn test_completion_transition_from_composite_state() {
+        let mut sm = media_machine();
+        assert!(sm.is_in(&Media::Negotiating));
+
+        let response = sm.handle_event(&CallEvent::Answer).unwrap();
+        assert!(matches!(response, Response::Transition(Media::Streaming)));
+        assert!(!sm.is_in(&Media::Negotiating));
+    }
+
+    #[test]
+    fn test_super_defers_to_parent_state() {
+        let mut sm = media_machine();
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &Media::Failed);
+
+        let result = sm.handle_event(&CallEvent::Dial);
+        assert!(matches!(
+            result,
+            Err(StateMachineError::TransitionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_available_events() {
+        let mut sm = init_state_machine();
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+
+        let mut events: Vec<_> = sm.available_events().cloned().collect();
+        events.sort_by_key(|e| format!("{:?}", e));
+        assert_eq!(events, vec![CallEvent::Answer, CallEvent::HangUp]);
+
+        sm.add_guarded_transition(
+            CallState::Ringing,
+            CallEvent::Answer,
+            |sm| sm.get_context().get("lines_free") > Some(&0),
+            |_sm, _event| Ok(Response::Transition(CallState::Connected)),
+        );
+        assert_eq!(sm.available_events().count(), 2);
+        assert_eq!(
+            sm.enabled_events().collect::<Vec<_>>(),
+            vec![&CallEvent::HangUp]
+        );
+        assert!(matches!(
+            sm.handle_event(&CallEvent::Answer),
+            Err(StateMachineError::TransitionNotFound { .. })
+        ));
+
+        sm.get_context_mut().insert("lines_free".to_string(), 1);
+        assert_eq!(sm.enabled_events().count(), 2);
+        sm.handle_event(&CallEvent::Answer).unwrap();
+    }
+
+    #[test]
+    fn test_handler_outputs_are_returned() {
+        let mut sm: StateMachine<CallState, CallEvent, HashMap<String, usize>, Option<u32>> =
+            StateMachine::new(CallState::Idle, HashMap::new());
+        sm.add_transition(CallState::Idle, CallEvent::Incoming, |_sm, _event| {
+            Ok(Response::Transition(CallState::Ringing))
+        });
+        sm.add_transition_with_output(CallState::Ringing, CallEvent::Answer, |sm, _event| {
+            let calls = sm.get_context_mut().entry("calls".to_string()).or_default();
+            *calls += 1;
+            Ok((
+                Response::Transition(CallState::Connected),
+                Some(*calls as u32),
+            ))
+        });
+
+        assert!(matches!(
+            sm.dispatch(&CallEvent::Incoming),
+            Ok((Response::Transition(CallState::Ringing), None))
+        ));
+        assert!(matches!(
+            sm.dispatch(&CallEvent::Answer),
+            Ok((Response::Transition(CallState::Connected), Some(1)))
+        ));
+    }
+
+    #[test]
+    fn test_validators_reject_before_lookup() {
+        let mut sm = init_state_machine();
+        sm.add_validator(|_state, event, context| {
+            if *event == CallEvent::Dial && context.get("barred").is_some() {
+                return Err(generic::RejectReason::new("outgoing calls barred"));
+            }
+            Ok(())
+        });
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+        sm.handle_event(&CallEvent::Reset).unwrap();
+
+        sm.get_context_mut().insert("barred".to_string(), 1);
+        match sm.handle_event(&CallEvent::Dial) {
+            Err(StateMachineError::Rejected { state, reason, .. }) => {
+                assert_eq!(state, CallState::Idle);
+                assert_eq!(reason.message, "outgoing calls barred");
+            }
+            other => panic!("expected rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_vetoes_abort_transitions() {
+        let mut sm = init_state_machine();
+        sm.add_veto(|_from, _event, to, context| {
+            if *to == CallState::Dialing && context.contains_key("shutting_down") {
+                return generic::Veto::Deny(generic::RejectReason::new("shutting down"));
+            }
+            generic::Veto::Allow
+        });
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+        sm.handle_event(&CallEvent::Reset).unwrap();
+
+        sm.get_context_mut().insert("shutting_down".to_string(), 1);
+        assert!(matches!(
+            sm.handle_event(&CallEvent::Dial),
+            Err(StateMachineError::Vetoed {
+                to: CallState::Dialing,
+                ..
+            })
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+    }
+
+    #[test]
+    fn test_custom_hasher_and_capacity() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let mut sm: StateMachine<CallState, CallEvent, (), (), BuildHasherDefault<DefaultHasher>> =
+            StateMachine::with_capacity_and_hasher(CallState::Idle, (), 8, Default::default());
+        assert!(sm.transitions.capacity() >= 8);
+        sm.add_transition(CallState::Idle, CallEvent::Dial, |_sm, _event| {
+            Ok(Response::Transition(CallState::Dialing))
+        });
+        sm.shrink_to_fit();
+        assert!(sm.transitions.capacity() < 8);
+
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Dialing);
+    }
+
+    #[test]
+    fn test_existing_types_are_states_and_events() {
+        let mut sm: StateMachine<&str, u8, ()> = StateMachine::new("off", ());
+        sm.add_transition("off", 1, |_sm, _event| Ok(Response::Transition("on")));
+        sm.handle_event(&1).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &"on");
+    }
+
+    #[test]
+    fn test_lifecycle_hooks_update_context() {
+        let mut sm = init_state_machine();
+        sm.add_entry_hook(CallState::Connected, |context, from, _to, event| {
+            assert_eq!((from, event), (&CallState::Ringing, &CallEvent::Answer));
+            *context.entry("connected".to_string()).or_default() += 1;
+        });
+        sm.add_exit_hook(CallState::Connected, |context, _from, to, _event| {
+            assert_eq!(to, &CallState::Disconnected);
+            context.remove("connected");
+        });
+        sm.add_transition_hook(|context, _from, _to, _event| {
+            *context.entry("transitions".to_string()).or_default() += 1;
+        });
+
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        assert_eq!(sm.get_context().get("connected"), Some(&1));
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+        assert_eq!(sm.get_context().get("connected"), None);
+        assert_eq!(sm.get_context().get("transitions"), Some(&3));
+    }
+
+    #[test]
+    fn test_hooks_follow_the_hierarchy() {
+        let mut sm = init_state_machine();
+        sm.add_substate(CallState::Idle, CallState::Dialing);
+        sm.add_substate(CallState::Idle, CallState::Ringing);
+        sm.add_transition_to(CallState::Dialing, CallEvent::Incoming, CallState::Ringing);
+        let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        for state in [
+            CallState::Idle,
+            CallState::Dialing,
+            CallState::Ringing,
+            CallState::Disconnected,
+        ] {
+            let (exits, entries) = (log.clone(), log.clone());
+            let name = format!("{:?}", state);
+            let exited = format!("exit {}", name);
+            sm.add_exit_hook(state.clone(), move |_, _, _, _| {
+                exits.lock().unwrap().push(exited.clone())
+            });
+            sm.add_entry_hook(state, move |_, _, _, _| {
+                entries.lock().unwrap().push(format!("enter {}", name))
+            });
+        }
+        let mut step = |event| {
+            sm.handle_event(&event).unwrap();
+            std::mem::take(&mut *log.lock().unwrap())
+        };
+
+        // Into a substate of the current state, which is not left.
+        assert_eq!(step(CallEvent::Dial), ["enter Dialing"]);
+        // Between siblings, inside their parent.
+        assert_eq!(step(CallEvent::Incoming), ["exit Dialing", "enter Ringing"]);
+        // Out of the composite, innermost first.
+        assert_eq!(
+            step(CallEvent::HangUp),
+            ["exit Ringing", "exit Idle", "enter Disconnected"]
+        );
+        // Into the composite itself.
+        assert_eq!(step(CallEvent::Reset), ["exit Disconnected", "enter Idle"]);
+    }
+
+    #[test]
+    fn test_static_targets_with_actions() {
+        let mut sm = init_state_machine();
+        sm.add_transition_with_action(
+            CallState::Ringing,
+            CallEvent::Answer,
+            CallState::Connected,
+            |context, _event| {
+                context.insert("answered".to_string(), 1);
+            },
+        );
+        assert_eq!(
+            sm.static_target(&CallState::Ringing, &CallEvent::Answer),
+            Some(&CallState::Connected)
+        );
+        assert_eq!(sm.static_target(&CallState::Idle, &CallEvent::Dial), None);
+
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+        assert_eq!(sm.get_context().get("answered"), Some(&1));
+
+        sm.add_transition(CallState::Ringing, CallEvent::Answer, |_sm, _event| {
+            Ok(Response::Handled)
+        });
+        assert_eq!(
+            sm.static_target(&CallState::Ringing, &CallEvent::Answer),
+            None
+        );
+    }
+
+    #[test]
+    fn test_errors_as_json() {
+        let mut sm = init_state_machine();
+        let error = sm.dispatch(&CallEvent::Answer).unwrap_err();
+        assert_eq!(
+            error.to_json(),
+            "{\"kind\":\"transition_not_found\",\"from\":\"Idle\",\"event\":\"Answer\"}"
+        );
+        let rejected: StateMachineError<CallState, CallEvent> = StateMachineError::Rejected {
+            state: CallState::Idle,
+            event: CallEvent::Dial,
+            reason: generic::RejectReason::new("barred \"now\""),
+        };
+        assert!(rejected
+            .to_json()
+            .ends_with("\"reason\":\"barred \\\"now\\\"\"}"));
+        assert_eq!(
+            StateMachineError::<CallState, CallEvent>::NotInitialized.to_json(),
+            "{\"kind\":\"not_initialized\"}"
+        );
+    }
 }
@@ -0,0 +1,212 @@
+use crate::generic::{Event, State, StateMachine};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Human-friendly information about a state, shown in exports and available at runtime.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateMetadata {
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub tags: BTreeSet<String>,
+}
+
+impl StateMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn display_name(mut self, name: impl Into<String>) -> Self {
+        self.display_name = Some(name.into());
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.insert(tag.into());
+        self
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+}
+
+/// What a transition means to the business, shown in exports in place of the
+/// event's Debug representation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransitionMetadata {
+    pub label: Option<String>,
+    pub description: Option<String>,
+    /// Free-form key/value pairs for tooling; exports ignore them.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl TransitionMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    pub fn set_state_metadata(&mut self, state: S, metadata: StateMetadata) {
+        self.metadata.insert(state, metadata);
+    }
+
+    pub fn state_metadata(&self, state: &S) -> Option<&StateMetadata> {
+        self.metadata.get(state)
+    }
+
+    /// Whether `state` carries `tag`.
+    pub fn has_tag(&self, state: &S, tag: &str) -> bool {
+        self.metadata.get(state).is_some_and(|m| m.has_tag(tag))
+    }
+
+    /// Every state carrying `tag`, in no particular order.
+    pub fn states_tagged<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a S> + 'a {
+        self.metadata
+            .iter()
+            .filter(move |(_, m)| m.has_tag(tag))
+            .map(|(state, _)| state)
+    }
+
+    /// Describes the transition registered for `event` in `from`. Metadata
+    /// may be set before or after the transition itself.
+    pub fn set_transition_metadata(&mut self, from: S, event: E, metadata: TransitionMetadata) {
+        self.transition_metadata.insert((from, event), metadata);
+    }
+
+    pub fn transition_metadata(&self, from: &S, event: &E) -> Option<&TransitionMetadata> {
+        self.transition_metadata.get(&(from.clone(), event.clone()))
+    }
+
+    /// The label of the transition on `event` in `from`, falling back to the
+    /// event's Debug representation.
+    pub fn transition_label(&self, from: &S, event: &E) -> String {
+        self.transition_metadata(from, event)
+            .and_then(|m| m.label.clone())
+            .unwrap_or_else(|| format!("{:?}", event))
+    }
+
+    /// The display name of `state`, falling back to its Debug representation.
+    pub fn display_name(&self, state: &S) -> String {
+        self.metadata
+            .get(state)
+            .and_then(|m| m.display_name.clone())
+            .unwrap_or_else(|| format!("{:?}", state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    fn annotated() -> StateMachine<CallState, CallEvent> {
+        let mut sm = init_state_machine();
+        sm.set_state_metadata(
+            CallState::Connected,
+            StateMetadata::new()
+                .display_name("In call")
+                .description("Both parties are talking")
+                .tag("billable"),
+        );
+        sm
+    }
+
+    #[test]
+    fn test_metadata_lookup() {
+        let sm = annotated();
+        assert_eq!(sm.display_name(&CallState::Connected), "In call");
+        assert_eq!(sm.display_name(&CallState::Idle), "Idle");
+        assert!(sm.has_tag(&CallState::Connected, "billable"));
+        assert!(!sm.has_tag(&CallState::Idle, "billable"));
+        assert_eq!(
+            sm.states_tagged("billable").collect::<Vec<_>>(),
+            vec![&CallState::Connected]
+        );
+    }
+
+    #[test]
+    fn test_transition_metadata() {
+        let mut sm = annotated();
+        sm.set_transition_metadata(
+            CallState::Ringing,
+            CallEvent::Answer,
+            TransitionMetadata::new()
+                .label("callee picked up")
+                .description("The callee accepted the call")
+                .attribute("sla", "5s"),
+        );
+        assert_eq!(
+            sm.transition_label(&CallState::Ringing, &CallEvent::Answer),
+            "callee picked up"
+        );
+        assert_eq!(
+            sm.transition_label(&CallState::Idle, &CallEvent::Dial),
+            "Dial"
+        );
+        assert_eq!(
+            sm.transition_metadata(&CallState::Ringing, &CallEvent::Answer)
+                .unwrap()
+                .attributes["sla"],
+            "5s"
+        );
+
+        let dot = sm.to_dot();
+        assert!(dot.contains(
+            "\"Ringing\" -> \"Ringing_Answer\" [label=\"callee picked up\", tooltip=\"The callee accepted the call\"];"
+        ));
+        assert!(sm.to_mermaid().contains(
+            "    Ringing --> Ringing_Answer: callee picked up: The callee accepted the call\n"
+        ));
+        assert!(sm.to_plantuml().contains(
+            "Ringing --> Ringing_Answer : callee picked up\\nThe callee accepted the call\n"
+        ));
+
+        sm.set_transition_metadata(
+            CallState::Idle,
+            CallEvent::Dial,
+            TransitionMetadata::new().description("say \"hi\"; wait | retry"),
+        );
+        assert!(sm
+            .to_mermaid()
+            .contains("    Idle --> Idle_Dial: Dial: say #quot;hi#quot;#59; wait #124; retry\n"));
+        assert!(sm
+            .to_plantuml()
+            .contains("Idle --> Idle_Dial : Dial\\nsay <U+0022>hi<U+0022>; wait | retry\n"));
+    }
+
+    #[test]
+    fn test_metadata_in_exports() {
+        let sm = annotated();
+        assert!(sm.to_dot().contains(
+            "\"Connected\" [label=\"In call\\n#billable\", tooltip=\"Both parties are talking\"];"
+        ));
+
+        let mermaid = sm.to_mermaid();
+        assert!(mermaid.contains("    state \"In call #billable\" as Connected\n"));
+        assert!(mermaid.contains("    note right of Connected : Both parties are talking\n"));
+    }
+}
@@ -0,0 +1,100 @@
+//! Dispatching events by name.
+//!
+//! Tools that only know events as text, such as CLIs, HTTP endpoints and
+//! config files, dispatch with [`StateMachine::dispatch_named`] once the
+//! machine has an event parser. The parser is handed the name and an optional
+//! JSON payload, as text, for events that carry data.
+
+use crate::generic::{Event, HandlerResult, State, StateMachine, StateMachineError};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Maps an event name and its optional JSON payload to an event.
+pub type EventParser<E> = Arc<dyn Fn(&str, Option<&str>) -> Option<E> + Send + Sync>;
+
+struct Parser<E>(EventParser<E>);
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event + Send + Sync + 'static,
+    O: Default,
+{
+    /// Parses the events given to [`dispatch_named`](Self::dispatch_named),
+    /// replacing any earlier parser.
+    pub fn set_event_parser<F>(&mut self, parser: F)
+    where
+        F: Fn(&str, Option<&str>) -> Option<E> + 'static + Send + Sync,
+    {
+        self.insert_ext(Parser::<E>(Arc::new(parser)));
+    }
+
+    /// Parses event names with `E`'s `FromStr`, ignoring payloads.
+    pub fn set_event_parser_from_str(&mut self)
+    where
+        E: FromStr,
+    {
+        self.set_event_parser(|name, _| name.parse().ok());
+    }
+
+    /// Parses `name` and `payload` with the machine's event parser and
+    /// dispatches the result.
+    pub fn dispatch_named(&mut self, name: &str, payload: Option<&str>) -> HandlerResult<S, E, O> {
+        let event = self.parse_named(name, payload)?;
+        self.dispatch(&event)
+    }
+
+    /// Parses `name` and `payload` with the machine's event parser, for
+    /// callers dispatching the event some other way.
+    pub fn parse_named(
+        &self,
+        name: &str,
+        payload: Option<&str>,
+    ) -> Result<E, StateMachineError<S, E>> {
+        self.ext::<Parser<E>>()
+            .and_then(|Parser(parse)| parse(name, payload))
+            .ok_or_else(|| StateMachineError::UnknownEventName {
+                name: name.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_dispatch_named() {
+        let payloads = Arc::new(Mutex::new(Vec::new()));
+        let mut sm = init_state_machine();
+        assert!(matches!(
+            sm.dispatch_named("Dial", None),
+            Err(StateMachineError::UnknownEventName { .. })
+        ));
+
+        let seen = payloads.clone();
+        sm.set_event_parser(move |name, payload| {
+            seen.lock().unwrap().push(payload.map(str::to_string));
+            match name {
+                "Dial" => Some(CallEvent::Dial),
+                "HangUp" => Some(CallEvent::HangUp),
+                _ => None,
+            }
+        });
+        sm.dispatch_named("Dial", Some("{\"number\":\"+15550100\"}"))
+            .unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Dialing);
+
+        let error = sm.dispatch_named("Hold", None).unwrap_err();
+        assert_eq!(
+            error.to_json(),
+            "{\"kind\":\"unknown_event_name\",\"name\":\"Hold\"}"
+        );
+        assert_eq!(
+            *payloads.lock().unwrap(),
+            [Some("{\"number\":\"+15550100\"}".to_string()), None]
+        );
+    }
+}
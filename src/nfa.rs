@@ -0,0 +1,221 @@
+//! Nondeterministic machines and their determinization.
+//!
+//! An [`Nfa`] may have several targets for the same `(state, event)` and
+//! eventless (epsilon) moves, which is how tables built from patterns come out
+//! naturally. [`Nfa::determinize`] turns it into an ordinary
+//! [`StateMachine`] by subset construction: each of its states is the
+//! [`StateSet`] of NFA states the input could have reached, tagged
+//! [`ACCEPTING`] when it contains an accepting state.
+
+use crate::generic::{Event, State, StateMachine};
+use crate::metadata::StateMetadata;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// The tag [`Nfa::determinize`] puts on accepting states.
+pub const ACCEPTING: &str = "accepting";
+
+/// A set of states, ordered by Debug representation so equal sets compare
+/// and hash equal.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct StateSet<S>(Vec<S>);
+
+impl<S: State> StateSet<S> {
+    fn new(mut states: Vec<S>) -> Self {
+        states.sort_by_cached_key(|state| format!("{:?}", state));
+        states.dedup();
+        StateSet(states)
+    }
+
+    pub fn contains(&self, state: &S) -> bool {
+        self.0.contains(state)
+    }
+
+    pub fn as_slice(&self) -> &[S] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for StateSet<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(&self.0).finish()
+    }
+}
+
+pub struct Nfa<S, E> {
+    initial: S,
+    transitions: HashMap<(S, E), Vec<S>>,
+    epsilon: HashMap<S, Vec<S>>,
+    accepting: HashSet<S>,
+}
+
+impl<S: State, E: Event> Nfa<S, E> {
+    pub fn new(initial: S) -> Self {
+        Nfa {
+            initial,
+            transitions: HashMap::new(),
+            epsilon: HashMap::new(),
+            accepting: HashSet::new(),
+        }
+    }
+
+    /// Adds a move on `event`; unlike [`StateMachine::add_transition`], earlier
+    /// moves for the same `(from, event)` are kept.
+    pub fn add_transition(&mut self, from: S, event: E, to: S) {
+        let targets = self.transitions.entry((from, event)).or_default();
+        if !targets.contains(&to) {
+            targets.push(to);
+        }
+    }
+
+    /// Adds a move taken without consuming an event.
+    pub fn add_epsilon(&mut self, from: S, to: S) {
+        let targets = self.epsilon.entry(from).or_default();
+        if !targets.contains(&to) {
+            targets.push(to);
+        }
+    }
+
+    pub fn set_accepting(&mut self, state: S) {
+        self.accepting.insert(state);
+    }
+
+    pub fn is_accepting(&self, state: &S) -> bool {
+        self.accepting.contains(state)
+    }
+
+    /// Every state reachable from `states` by epsilon moves, `states` included.
+    fn closure(&self, states: impl IntoIterator<Item = S>) -> StateSet<S> {
+        let mut reached: Vec<S> = Vec::new();
+        let mut pending: Vec<S> = states.into_iter().collect();
+        while let Some(state) = pending.pop() {
+            if reached.contains(&state) {
+                continue;
+            }
+            pending.extend(self.epsilon.get(&state).into_iter().flatten().cloned());
+            reached.push(state);
+        }
+        StateSet::new(reached)
+    }
+
+    /// The states `current` can be in after `event`.
+    pub fn step(&self, current: &StateSet<S>, event: &E) -> StateSet<S> {
+        self.closure(current.0.iter().flat_map(|state| {
+            self.transitions
+                .get(&(state.clone(), event.clone()))
+                .into_iter()
+                .flatten()
+                .cloned()
+        }))
+    }
+
+    /// The states the machine can be in before any event.
+    pub fn start(&self) -> StateSet<S> {
+        self.closure([self.initial.clone()])
+    }
+
+    /// Whether some path through the machine consumes `events` and ends in an
+    /// accepting state.
+    pub fn accepts<'a>(&self, events: impl IntoIterator<Item = &'a E>) -> bool
+    where
+        E: 'a,
+    {
+        let end = events
+            .into_iter()
+            .fold(self.start(), |current, event| self.step(&current, event));
+        end.0.iter().any(|state| self.is_accepting(state))
+    }
+
+    /// Every event with a move, ordered by Debug representation.
+    fn events(&self) -> Vec<E> {
+        let mut events: Vec<E> = Vec::new();
+        for (_, event) in self.transitions.keys() {
+            if !events.contains(event) {
+                events.push(event.clone());
+            }
+        }
+        events.sort_by_cached_key(|event| format!("{:?}", event));
+        events
+    }
+
+    /// The equivalent deterministic machine, by subset construction over the
+    /// reachable sets. Events that lead nowhere have no transition, so
+    /// dispatching them fails with `TransitionNotFound`.
+    pub fn determinize<C>(&self, context: C) -> StateMachine<StateSet<S>, E, C>
+    where
+        S: Send + Sync + 'static,
+    {
+        let start = self.start();
+        let events = self.events();
+        let mut sm = StateMachine::new(start.clone(), context);
+        let mut seen = HashSet::from([start.clone()]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(set) = queue.pop_front() {
+            if set.0.iter().any(|state| self.is_accepting(state)) {
+                sm.set_state_metadata(set.clone(), StateMetadata::new().tag(ACCEPTING));
+            }
+            for event in &events {
+                let next = self.step(&set, event);
+                if next.is_empty() {
+                    continue;
+                }
+                if seen.insert(next.clone()) {
+                    queue.push_back(next.clone());
+                }
+                sm.add_transition_to(set.clone(), event.clone(), next);
+            }
+        }
+        sm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CallEvent;
+
+    #[test]
+    fn test_determinize_matches_nfa() {
+        // Streams ending in an answered call: a Dial may be guessed to start
+        // the last call, which must then see Answer and HangUp.
+        let mut nfa = Nfa::new(0);
+        nfa.add_transition(0, CallEvent::Dial, 0);
+        nfa.add_transition(0, CallEvent::HangUp, 0);
+        nfa.add_transition(0, CallEvent::Answer, 0);
+        nfa.add_transition(0, CallEvent::Dial, 1);
+        nfa.add_transition(1, CallEvent::Answer, 2);
+        nfa.add_transition(2, CallEvent::HangUp, 3);
+        nfa.add_epsilon(3, 4);
+        nfa.set_accepting(4);
+
+        let sequences: [&[CallEvent]; 4] = [
+            &[CallEvent::Dial, CallEvent::Answer, CallEvent::HangUp],
+            &[CallEvent::Dial, CallEvent::HangUp],
+            &[
+                CallEvent::Dial,
+                CallEvent::HangUp,
+                CallEvent::Dial,
+                CallEvent::Answer,
+                CallEvent::HangUp,
+            ],
+            &[],
+        ];
+        let expected = [true, false, true, false];
+        for (events, expected) in sequences.iter().zip(expected) {
+            assert_eq!(nfa.accepts(*events), expected, "{:?}", events);
+
+            let mut dfa = nfa.determinize(());
+            let matched = events.iter().all(|event| dfa.dispatch(event).is_ok())
+                && dfa.has_tag(dfa.get_current_state().unwrap(), ACCEPTING);
+            assert_eq!(matched, expected, "{:?}", events);
+        }
+
+        let dfa = nfa.determinize(());
+        assert_eq!(format!("{:?}", dfa.get_current_state().unwrap()), "{0}");
+        assert_eq!(dfa.states_tagged(ACCEPTING).count(), 1);
+    }
+}
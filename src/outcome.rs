@@ -0,0 +1,104 @@
+//! Structured results of single dispatches.
+//!
+//! [`dispatch_detailed`](StateMachine::dispatch_detailed) reports what a
+//! dispatch did as a [`DispatchOutcome`], so callers and tests can check it
+//! without attaching observers.
+
+use crate::clock::Clock;
+use crate::generic::{Event, State, StateMachine, StateMachineError};
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+/// Counted while an event is dispatched.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DispatchTrace {
+    pub(crate) actions_run: usize,
+    pub(crate) deferred: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatchOutcome<S, E, O = ()> {
+    pub previous: S,
+    pub event: E,
+    /// The state after the dispatch and any eventless follow-ups; equal to
+    /// `previous` if nothing was committed.
+    pub new_state: S,
+    /// The handler's output value.
+    pub output: O,
+    /// Exit, transition and entry hooks run, counting each hook once per
+    /// transition it ran for.
+    pub actions_run: usize,
+    /// How long the dispatch took by the machine's [`Clock`].
+    pub duration: Duration,
+    /// Whether a handler deferred the event with `Response::Super` to a
+    /// handler of a parent state.
+    pub deferred: bool,
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    O: Default,
+    H: BuildHasher,
+{
+    /// Like [`dispatch`](Self::dispatch), reporting what happened.
+    pub fn dispatch_detailed(
+        &mut self,
+        event: &E,
+    ) -> Result<DispatchOutcome<S, E, O>, StateMachineError<S, E>> {
+        let previous = self.get_current_state()?.clone();
+        let started = self.clock.now();
+        let outer = std::mem::take(&mut self.trace);
+        let result = self.dispatch(event);
+        let trace = std::mem::replace(&mut self.trace, outer);
+        let (_, output) = result?;
+        Ok(DispatchOutcome {
+            previous,
+            event: event.clone(),
+            new_state: self.get_current_state()?.clone(),
+            output,
+            actions_run: trace.actions_run,
+            duration: self.clock.now().saturating_duration_since(started),
+            deferred: trace.deferred,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::generic::Response;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_outcome_reports_hooks_and_deferral() {
+        let clock = ManualClock::new();
+        let mut sm = init_state_machine().with_clock(clock.clone());
+        sm.add_exit_hook(CallState::Idle, |_, _, _, _| {});
+        sm.add_entry_hook(CallState::Dialing, move |_, _, _, _| {
+            clock.advance(Duration::from_millis(5))
+        });
+        let outcome = sm.dispatch_detailed(&CallEvent::Dial).unwrap();
+        assert_eq!(outcome.previous, CallState::Idle);
+        assert_eq!(outcome.new_state, CallState::Dialing);
+        assert_eq!(outcome.actions_run, 2);
+        assert_eq!(outcome.duration, Duration::from_millis(5));
+        assert!(!outcome.deferred);
+
+        let mut sm: StateMachine<CallState, CallEvent, ()> =
+            StateMachine::new(CallState::Ringing, ());
+        sm.add_substate(CallState::Idle, CallState::Ringing);
+        sm.add_transition(CallState::Ringing, CallEvent::HangUp, |_, _| {
+            Ok(Response::Super)
+        });
+        sm.add_transition(CallState::Idle, CallEvent::HangUp, |_, _| {
+            Ok(Response::Transition(CallState::Disconnected))
+        });
+        let outcome = sm.dispatch_detailed(&CallEvent::HangUp).unwrap();
+        assert_eq!(outcome.new_state, CallState::Disconnected);
+        assert_eq!(outcome.actions_run, 0);
+        assert!(outcome.deferred);
+    }
+}
@@ -0,0 +1,330 @@
+//! Protocol validators declared as patterns over events.
+//!
+//! A [`Pattern`] is built from events with sequence, alternation and
+//! repetition, either in code or parsed from text such as
+//! `"Dial (Answer HangUp | HangUp) Reset?"`:
+//!
+//! - names separated by whitespace follow one another;
+//! - `a | b` matches either side;
+//! - a postfix `*`, `+` or `?` repeats the preceding name or parenthesised
+//!   group zero or more times, one or more times, or makes it optional.
+//!
+//! [`Pattern::to_machine`] compiles a pattern into a deterministic
+//! [`StateMachine`] that accepts exactly the matching sequences: an event
+//! outside the pattern fails to dispatch, and the machine is in a state
+//! tagged [`ACCEPTING`] after a complete match.
+
+use crate::generic::{Event, State, StateMachine};
+use crate::nfa::{Nfa, StateSet, ACCEPTING};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern<E> {
+    Event(E),
+    Sequence(Vec<Pattern<E>>),
+    Alternation(Vec<Pattern<E>>),
+    ZeroOrMore(Box<Pattern<E>>),
+    Optional(Box<Pattern<E>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    /// A name at byte `offset` that is not an event.
+    UnknownEvent {
+        offset: usize,
+        name: String,
+    },
+    Syntax {
+        offset: usize,
+        message: String,
+    },
+}
+
+impl<E: Event> Pattern<E> {
+    pub fn then(self, next: Pattern<E>) -> Self {
+        match self {
+            Pattern::Sequence(mut items) => {
+                items.push(next);
+                Pattern::Sequence(items)
+            }
+            first => Pattern::Sequence(vec![first, next]),
+        }
+    }
+
+    pub fn or(self, other: Pattern<E>) -> Self {
+        match self {
+            Pattern::Alternation(mut items) => {
+                items.push(other);
+                Pattern::Alternation(items)
+            }
+            first => Pattern::Alternation(vec![first, other]),
+        }
+    }
+
+    pub fn zero_or_more(self) -> Self {
+        Pattern::ZeroOrMore(Box::new(self))
+    }
+
+    pub fn one_or_more(self) -> Self {
+        self.clone().then(self.zero_or_more())
+    }
+
+    pub fn optional(self) -> Self {
+        Pattern::Optional(Box::new(self))
+    }
+
+    /// Parses the text syntax described in the [module docs](self), looking
+    /// event names up with `FromStr`.
+    pub fn parse(source: &str) -> Result<Self, PatternError>
+    where
+        E: FromStr,
+    {
+        let mut parser = Parser { source, offset: 0 };
+        let pattern = parser.alternation()?;
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => Ok(pattern),
+            Some(c) => Err(parser.error(format!("unexpected `{}`", c))),
+        }
+    }
+
+    /// Adds the pattern to `nfa` starting at `start`, returning its end state.
+    fn build(&self, nfa: &mut Nfa<usize, E>, next: &mut usize, start: usize) -> usize {
+        let mut fresh = || {
+            *next += 1;
+            *next
+        };
+        match self {
+            Pattern::Event(event) => {
+                let end = fresh();
+                nfa.add_transition(start, event.clone(), end);
+                end
+            }
+            Pattern::Sequence(items) => items
+                .iter()
+                .fold(start, |from, item| item.build(nfa, next, from)),
+            Pattern::Alternation(items) => {
+                let end = fresh();
+                for item in items {
+                    let branch = *next + 1;
+                    *next = branch;
+                    nfa.add_epsilon(start, branch);
+                    let branch_end = item.build(nfa, next, branch);
+                    nfa.add_epsilon(branch_end, end);
+                }
+                end
+            }
+            Pattern::ZeroOrMore(item) => {
+                let repeat = fresh();
+                nfa.add_epsilon(start, repeat);
+                let item_end = item.build(nfa, next, repeat);
+                nfa.add_epsilon(item_end, repeat);
+                repeat
+            }
+            Pattern::Optional(item) => {
+                let end = item.build(nfa, next, start);
+                nfa.add_epsilon(start, end);
+                end
+            }
+        }
+    }
+
+    /// The nondeterministic machine for the pattern, by Thompson's construction.
+    pub fn to_nfa(&self) -> Nfa<usize, E> {
+        let mut nfa = Nfa::new(0);
+        let mut next = 0;
+        let end = self.build(&mut nfa, &mut next, 0);
+        nfa.set_accepting(end);
+        nfa
+    }
+
+    /// The deterministic machine accepting exactly the matching sequences.
+    pub fn to_machine(&self) -> StateMachine<StateSet<usize>, E, ()>
+    where
+        E: Send + Sync + 'static,
+    {
+        self.to_nfa().determinize(())
+    }
+
+    pub fn matches<'a>(&self, events: impl IntoIterator<Item = &'a E>) -> bool
+    where
+        E: 'a,
+    {
+        self.to_nfa().accepts(events)
+    }
+}
+
+/// Whether `machine`, built by [`Pattern::to_machine`], has seen a complete match.
+pub fn is_match<S: State, E: Event, C, O>(machine: &StateMachine<S, E, C, O>) -> bool {
+    machine
+        .get_current_state()
+        .is_ok_and(|state| machine.has_tag(state, ACCEPTING))
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.source[self.offset..];
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    fn error(&self, message: String) -> PatternError {
+        PatternError::Syntax {
+            offset: self.offset,
+            message,
+        }
+    }
+
+    fn alternation<E: Event + FromStr>(&mut self) -> Result<Pattern<E>, PatternError> {
+        let mut pattern = self.sequence()?;
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('|') {
+                return Ok(pattern);
+            }
+            self.offset += 1;
+            pattern = pattern.or(self.sequence()?);
+        }
+    }
+
+    fn sequence<E: Event + FromStr>(&mut self) -> Result<Pattern<E>, PatternError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(c) if c == '(' || c.is_alphanumeric() || c == '_' => {
+                    items.push(self.postfix()?)
+                }
+                _ => break,
+            }
+        }
+        match items.len() {
+            0 => Err(self.error(String::from("expected an event or `(`"))),
+            1 => Ok(items.remove(0)),
+            _ => Ok(Pattern::Sequence(items)),
+        }
+    }
+
+    fn postfix<E: Event + FromStr>(&mut self) -> Result<Pattern<E>, PatternError> {
+        let mut pattern = self.atom()?;
+        loop {
+            pattern = match self.peek() {
+                Some('*') => pattern.zero_or_more(),
+                Some('+') => pattern.one_or_more(),
+                Some('?') => pattern.optional(),
+                _ => return Ok(pattern),
+            };
+            self.offset += 1;
+        }
+    }
+
+    fn atom<E: Event + FromStr>(&mut self) -> Result<Pattern<E>, PatternError> {
+        if self.peek() == Some('(') {
+            self.offset += 1;
+            let pattern = self.alternation()?;
+            self.skip_whitespace();
+            if self.peek() != Some(')') {
+                return Err(self.error(String::from("expected `)`")));
+            }
+            self.offset += 1;
+            return Ok(pattern);
+        }
+        let start = self.offset;
+        let rest = &self.source[start..];
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        self.offset += len;
+        let name = &rest[..len];
+        name.parse()
+            .map(Pattern::Event)
+            .map_err(|_| PatternError::UnknownEvent {
+                offset: start,
+                name: name.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Call {
+        Incoming,
+        Dial,
+        Answer,
+        HangUp,
+        Reset,
+    }
+
+    impl FromStr for Call {
+        type Err = ();
+        fn from_str(s: &str) -> Result<Self, ()> {
+            match s {
+                "Incoming" => Ok(Call::Incoming),
+                "Dial" => Ok(Call::Dial),
+                "Answer" => Ok(Call::Answer),
+                "HangUp" => Ok(Call::HangUp),
+                "Reset" => Ok(Call::Reset),
+                _ => Err(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pattern_machine_validates_protocol() {
+        let pattern: Pattern<Call> =
+            Pattern::parse("Dial (Answer HangUp | HangUp) (Reset Dial HangUp)*").unwrap();
+        assert!(pattern.matches(&[Call::Dial, Call::HangUp]));
+        assert!(!pattern.matches(&[Call::Dial, Call::Answer]));
+
+        let mut sm = pattern.to_machine();
+        for event in [Call::Dial, Call::Answer] {
+            sm.dispatch(&event).unwrap();
+        }
+        assert!(!is_match(&sm));
+        assert!(sm.dispatch(&Call::Dial).is_err());
+        sm.dispatch(&Call::HangUp).unwrap();
+        assert!(is_match(&sm));
+        for event in [Call::Reset, Call::Dial, Call::HangUp] {
+            sm.dispatch(&event).unwrap();
+        }
+        assert!(is_match(&sm));
+
+        let built = Pattern::Event(Call::Incoming)
+            .then(Pattern::Event(Call::Answer).optional())
+            .then(Pattern::Event(Call::HangUp));
+        assert_eq!(
+            Pattern::parse("Incoming Answer? HangUp").as_ref(),
+            Ok(&built)
+        );
+    }
+
+    #[test]
+    fn test_pattern_errors() {
+        assert_eq!(
+            Pattern::<Call>::parse("Dial Pickup"),
+            Err(PatternError::UnknownEvent {
+                offset: 5,
+                name: String::from("Pickup")
+            })
+        );
+        assert!(matches!(
+            Pattern::<Call>::parse("Dial (HangUp"),
+            Err(PatternError::Syntax { offset: 12, .. })
+        ));
+        assert!(matches!(
+            Pattern::<Call>::parse("Dial | "),
+            Err(PatternError::Syntax { offset: 7, .. })
+        ));
+    }
+}
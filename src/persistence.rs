@@ -0,0 +1,931 @@
+use crate::clock::Clock;
+use crate::generic::{Event, Response, State, StateMachine, StateMachineError, Stateful};
+use crate::snapshot::Snapshot;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A committed transition, one per step: eventless and completion
+/// follow-ups get entries of their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry<S, E> {
+    pub from: S,
+    pub event: E,
+    pub to: S,
+    /// Made outside [`PersistentStateMachine::handle_event`], for example by
+    /// a watchdog, a deadline escalation or through
+    /// [`machine_mut`](PersistentStateMachine::machine_mut). Replay commits
+    /// such a move rather than dispatching `event` again.
+    pub forced: bool,
+}
+
+/// Storage for a single machine's latest snapshot and its transition journal.
+pub trait PersistenceBackend<S, E, C> {
+    type Error: Debug;
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot<S, C>) -> Result<(), Self::Error>;
+
+    fn load_snapshot(&mut self) -> Result<Option<Snapshot<S, C>>, Self::Error>;
+
+    fn append_journal(&mut self, entry: &JournalEntry<S, E>) -> Result<(), Self::Error>;
+
+    /// The entries appended since the latest snapshot was saved, oldest first;
+    /// all of them if there is no snapshot.
+    fn load_journal(&mut self) -> Result<Vec<JournalEntry<S, E>>, Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum PersistenceError<S, E, B> {
+    Machine(StateMachineError<S, E>),
+    Backend(B),
+    /// Replaying `entry` on open did not reproduce the journaled transition,
+    /// typically because the handlers changed; the machine stopped in `reached`.
+    Replay {
+        entry: JournalEntry<S, E>,
+        reached: S,
+    },
+}
+
+impl<S, E, B> From<StateMachineError<S, E>> for PersistenceError<S, E, B> {
+    fn from(e: StateMachineError<S, E>) -> Self {
+        PersistenceError::Machine(e)
+    }
+}
+
+/// Keeps everything in memory; mostly useful for tests.
+#[derive(Debug, Clone)]
+pub struct MemoryBackend<S, E, C> {
+    pub snapshot: Option<Snapshot<S, C>>,
+    pub journal: Vec<JournalEntry<S, E>>,
+    /// The journal's length when `snapshot` was saved.
+    pub snapshot_at: usize,
+}
+
+impl<S, E, C> Default for MemoryBackend<S, E, C> {
+    fn default() -> Self {
+        MemoryBackend {
+            snapshot: None,
+            journal: Vec::new(),
+            snapshot_at: 0,
+        }
+    }
+}
+
+impl<S, E, C> PersistenceBackend<S, E, C> for MemoryBackend<S, E, C>
+where
+    S: Clone,
+    E: Clone,
+    C: Clone,
+{
+    type Error = std::convert::Infallible;
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot<S, C>) -> Result<(), Self::Error> {
+        self.snapshot = Some(snapshot.clone());
+        self.snapshot_at = self.journal.len();
+        Ok(())
+    }
+
+    fn load_snapshot(&mut self) -> Result<Option<Snapshot<S, C>>, Self::Error> {
+        Ok(self.snapshot.clone())
+    }
+
+    fn append_journal(&mut self, entry: &JournalEntry<S, E>) -> Result<(), Self::Error> {
+        self.journal.push(entry.clone());
+        Ok(())
+    }
+
+    fn load_journal(&mut self) -> Result<Vec<JournalEntry<S, E>>, Self::Error> {
+        Ok(self.journal[self.snapshot_at..].to_vec())
+    }
+}
+
+/// Turns snapshots and journal entries into single lines of text for [`FileBackend`].
+#[cfg(feature = "file-backend")]
+pub trait Codec<S, E, C> {
+    fn encode_snapshot(&self, snapshot: &Snapshot<S, C>) -> String;
+
+    fn decode_snapshot(&self, text: &str) -> Result<Snapshot<S, C>, String>;
+
+    fn encode_entry(&self, entry: &JournalEntry<S, E>) -> String;
+
+    fn decode_entry(&self, text: &str) -> Result<JournalEntry<S, E>, String>;
+}
+
+/// Stores the snapshot in `<dir>/snapshot` and appends the journal to
+/// `<dir>/journal`, one encoded entry per line. The snapshot file starts with
+/// a line holding the journal's length in bytes when it was saved, so the
+/// entries after it can be replayed.
+///
+/// Snapshots are written to a temporary file, synced and renamed into place,
+/// so a crash mid-write leaves the previous snapshot intact. Journal entries
+/// are synced before `append_journal` returns.
+#[cfg(feature = "file-backend")]
+pub struct FileBackend<K> {
+    dir: std::path::PathBuf,
+    codec: K,
+}
+
+#[cfg(feature = "file-backend")]
+impl<K> FileBackend<K> {
+    pub fn open(dir: impl Into<std::path::PathBuf>, codec: K) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(FileBackend { dir, codec })
+    }
+
+    pub fn dir(&self) -> &std::path::Path {
+        &self.dir
+    }
+}
+
+#[cfg(feature = "file-backend")]
+impl<S, E, C, K> PersistenceBackend<S, E, C> for FileBackend<K>
+where
+    K: Codec<S, E, C>,
+{
+    type Error = std::io::Error;
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot<S, C>) -> Result<(), Self::Error> {
+        use std::io::Write;
+        let tmp = self.dir.join("snapshot.tmp");
+        let journaled = match std::fs::metadata(self.dir.join("journal")) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let mut file = std::fs::File::create(&tmp)?;
+        writeln!(file, "{}", journaled)?;
+        file.write_all(self.codec.encode_snapshot(snapshot).as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, self.dir.join("snapshot"))?;
+        // The rename itself is only durable once the directory is synced.
+        #[cfg(unix)]
+        std::fs::File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    fn load_snapshot(&mut self) -> Result<Option<Snapshot<S, C>>, Self::Error> {
+        match self.read_snapshot()? {
+            Some((_, text)) => self
+                .codec
+                .decode_snapshot(&text)
+                .map(Some)
+                .map_err(invalid_data),
+            None => Ok(None),
+        }
+    }
+
+    fn append_journal(&mut self, entry: &JournalEntry<S, E>) -> Result<(), Self::Error> {
+        use std::io::Write;
+        let mut journal = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("journal"))?;
+        writeln!(journal, "{}", self.codec.encode_entry(entry))?;
+        journal.sync_data()
+    }
+
+    fn load_journal(&mut self) -> Result<Vec<JournalEntry<S, E>>, Self::Error> {
+        let offset = self.read_snapshot()?.map_or(0, |(offset, _)| offset);
+        let journal = match std::fs::read(self.dir.join("journal")) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let since = journal
+            .get(offset as usize..)
+            .ok_or_else(|| invalid_data("snapshot is ahead of the journal".to_string()))?;
+        String::from_utf8_lossy(since)
+            .lines()
+            .map(|line| self.codec.decode_entry(line).map_err(invalid_data))
+            .collect()
+    }
+}
+
+#[cfg(feature = "file-backend")]
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(feature = "file-backend")]
+impl<K> FileBackend<K> {
+    /// The journal offset and encoded snapshot, if one was saved.
+    fn read_snapshot(&self) -> std::io::Result<Option<(u64, String)>> {
+        let text = match std::fs::read_to_string(self.dir.join("snapshot")) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (offset, snapshot) = text.split_once('\n').unwrap_or((&text, ""));
+        let offset = offset
+            .parse()
+            .map_err(|_| invalid_data(format!("bad journal offset {:?}", offset)))?;
+        Ok(Some((offset, snapshot.to_string())))
+    }
+}
+
+enum Job<S, E, C, Err> {
+    Snapshot(Snapshot<S, C>),
+    Entry(JournalEntry<S, E>),
+    Load(mpsc::Sender<Result<Option<Snapshot<S, C>>, Err>>),
+    LoadJournal(mpsc::Sender<Result<Vec<JournalEntry<S, E>>, Err>>),
+    Flush(mpsc::Sender<()>),
+}
+
+type JobSender<S, E, C, Err> = mpsc::Sender<Job<S, E, C, Err>>;
+
+#[derive(Debug)]
+pub enum BackgroundError<B> {
+    Backend(B),
+    /// The writer thread has gone, after a panic in the wrapped backend.
+    Stopped,
+}
+
+/// Moves writes of another backend onto a dedicated thread, so saving a
+/// snapshot or appending to the journal returns immediately.
+///
+/// A failed write is reported by the next call, whose own job is still
+/// queued, and [`flush`](Self::flush) waits until every queued write has
+/// been attempted. Loads wait for the writes queued before them.
+pub struct BackgroundBackend<S, E, C, B: PersistenceBackend<S, E, C>> {
+    jobs: Option<JobSender<S, E, C, B::Error>>,
+    failed: Arc<Mutex<Option<B::Error>>>,
+    worker: Option<JoinHandle<B>>,
+}
+
+impl<S, E, C, B> BackgroundBackend<S, E, C, B>
+where
+    S: Send + 'static,
+    E: Send + 'static,
+    C: Send + 'static,
+    B: PersistenceBackend<S, E, C> + Send + 'static,
+    B::Error: Send,
+{
+    pub fn new(mut backend: B) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job<S, E, C, B::Error>>();
+        let failed = Arc::new(Mutex::new(None));
+        let report = failed.clone();
+        let worker = thread::spawn(move || {
+            for job in queue {
+                let result = match job {
+                    Job::Snapshot(snapshot) => backend.save_snapshot(&snapshot),
+                    Job::Entry(entry) => backend.append_journal(&entry),
+                    Job::Load(reply) => {
+                        let _ = reply.send(backend.load_snapshot());
+                        Ok(())
+                    }
+                    Job::LoadJournal(reply) => {
+                        let _ = reply.send(backend.load_journal());
+                        Ok(())
+                    }
+                    Job::Flush(reply) => {
+                        let _ = reply.send(());
+                        Ok(())
+                    }
+                };
+                if let Err(e) = result {
+                    report.lock().unwrap().get_or_insert(e);
+                }
+            }
+            backend
+        });
+        BackgroundBackend {
+            jobs: Some(jobs),
+            failed,
+            worker: Some(worker),
+        }
+    }
+
+    /// Queues `job`, then reports an earlier write's failure if there was one.
+    fn submit(&self, job: Job<S, E, C, B::Error>) -> Result<(), BackgroundError<B::Error>> {
+        // Taken first, so a failure of `job` itself waits for the next call.
+        let failed = self.failed.lock().unwrap().take();
+        self.jobs
+            .as_ref()
+            .and_then(|jobs| jobs.send(job).ok())
+            .ok_or(BackgroundError::Stopped)?;
+        match failed {
+            Some(e) => Err(BackgroundError::Backend(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for every queued write, reporting the first that failed.
+    pub fn flush(&self) -> Result<(), BackgroundError<B::Error>> {
+        let (reply, done) = mpsc::channel();
+        self.submit(Job::Flush(reply))?;
+        done.recv().map_err(|_| BackgroundError::Stopped)?;
+        match self.failed.lock().unwrap().take() {
+            Some(e) => Err(BackgroundError::Backend(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Finishes the queued writes and returns the wrapped backend.
+    pub fn into_inner(mut self) -> Result<B, BackgroundError<B::Error>> {
+        self.jobs.take();
+        let worker = self
+            .worker
+            .take()
+            .expect("worker is only taken here or on drop");
+        worker.join().map_err(|_| BackgroundError::Stopped)
+    }
+}
+
+impl<S, E, C, B: PersistenceBackend<S, E, C>> Drop for BackgroundBackend<S, E, C, B> {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<S, E, C, B> PersistenceBackend<S, E, C> for BackgroundBackend<S, E, C, B>
+where
+    S: Clone + Send + 'static,
+    E: Clone + Send + 'static,
+    C: Clone + Send + 'static,
+    B: PersistenceBackend<S, E, C> + Send + 'static,
+    B::Error: Send,
+{
+    type Error = BackgroundError<B::Error>;
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot<S, C>) -> Result<(), Self::Error> {
+        self.submit(Job::Snapshot(snapshot.clone()))
+    }
+
+    fn load_snapshot(&mut self) -> Result<Option<Snapshot<S, C>>, Self::Error> {
+        let (reply, loaded) = mpsc::channel();
+        self.submit(Job::Load(reply))?;
+        loaded
+            .recv()
+            .map_err(|_| BackgroundError::Stopped)?
+            .map_err(BackgroundError::Backend)
+    }
+
+    fn append_journal(&mut self, entry: &JournalEntry<S, E>) -> Result<(), Self::Error> {
+        self.submit(Job::Entry(entry.clone()))
+    }
+
+    fn load_journal(&mut self) -> Result<Vec<JournalEntry<S, E>>, Self::Error> {
+        let (reply, loaded) = mpsc::channel();
+        self.submit(Job::LoadJournal(reply))?;
+        loaded
+            .recv()
+            .map_err(|_| BackgroundError::Stopped)?
+            .map_err(BackgroundError::Backend)
+    }
+}
+
+/// When [`PersistentStateMachine`] saves a snapshot on its own. Conditions
+/// combine: a checkpoint is taken after a transition when any of them is met.
+/// The journal is appended to on every transition regardless.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointPolicy<S> {
+    every_transitions: Option<usize>,
+    every: Option<Duration>,
+    on_states: Vec<S>,
+}
+
+impl<S> Default for CheckpointPolicy<S> {
+    /// A checkpoint after every transition.
+    fn default() -> Self {
+        Self::never().every_transitions(1)
+    }
+}
+
+impl<S> CheckpointPolicy<S> {
+    /// No automatic checkpoints; call [`PersistentStateMachine::checkpoint`] yourself.
+    pub fn never() -> Self {
+        CheckpointPolicy {
+            every_transitions: None,
+            every: None,
+            on_states: Vec::new(),
+        }
+    }
+
+    /// Checkpoints once `count` transitions have happened since the last one.
+    pub fn every_transitions(mut self, count: usize) -> Self {
+        self.every_transitions = Some(count.max(1));
+        self
+    }
+
+    /// Checkpoints on the first transition at least `interval` after the last
+    /// checkpoint.
+    pub fn every(mut self, interval: Duration) -> Self {
+        self.every = Some(interval);
+        self
+    }
+
+    /// Checkpoints whenever the machine enters `state`.
+    pub fn on_state(mut self, state: S) -> Self {
+        self.on_states.push(state);
+        self
+    }
+}
+
+impl<S: PartialEq> CheckpointPolicy<S> {
+    fn is_due(&self, pending: usize, since: Duration, to: &S) -> bool {
+        self.every_transitions.is_some_and(|count| pending >= count)
+            || self.every.is_some_and(|interval| since >= interval)
+            || self.on_states.contains(to)
+    }
+}
+
+/// The transitions a machine committed since they were last journaled.
+struct Recorder<S, E> {
+    steps: Vec<JournalEntry<S, E>>,
+    /// Set while `handle_event` dispatches, so other moves count as forced.
+    dispatching: bool,
+}
+
+/// Records every transition `machine` commits from now on.
+fn record<S, E, C, O>(machine: &mut StateMachine<S, E, C, O>) -> Arc<Mutex<Recorder<S, E>>>
+where
+    S: State + Send + 'static,
+    E: Event + Send + 'static,
+{
+    let recorder = Arc::new(Mutex::new(Recorder {
+        steps: Vec::new(),
+        dispatching: false,
+    }));
+    let steps = recorder.clone();
+    machine.add_observer(move |from, event, to| {
+        let mut recorder = steps.lock().unwrap();
+        let forced = !recorder.dispatching;
+        recorder.steps.push(JournalEntry {
+            from: from.clone(),
+            event: event.clone(),
+            to: to.clone(),
+            forced,
+        });
+    });
+    recorder
+}
+
+/// Replays `journal`: re-dispatches the event of each entry that came from a
+/// dispatch, checking it reproduces that entry and its follow-ups, and
+/// commits forced ones.
+fn replay<S, E, C, O, B>(
+    machine: &mut StateMachine<S, E, C, O>,
+    recorder: &Mutex<Recorder<S, E>>,
+    journal: Vec<JournalEntry<S, E>>,
+) -> Result<(), PersistenceError<S, E, B>>
+where
+    S: State,
+    E: Event,
+    O: Default,
+{
+    let mut journal = VecDeque::from(journal);
+    while let Some(entry) = journal.pop_front() {
+        if machine.get_current_state()? == &entry.from {
+            if entry.forced {
+                machine.commit(entry.to.clone(), &entry.event);
+            } else {
+                recorder.lock().unwrap().dispatching = true;
+                // A failed dispatch can still have moved, through a recovery policy.
+                let _ = machine.dispatch(&entry.event);
+                recorder.lock().unwrap().dispatching = false;
+            }
+        }
+        let steps = std::mem::take(&mut recorder.lock().unwrap().steps);
+        let reproduced = steps.first() == Some(&entry)
+            && steps[1..]
+                .iter()
+                .all(|step| journal.pop_front().as_ref() == Some(step));
+        if !reproduced {
+            let reached = machine.get_current_state()?.clone();
+            return Err(PersistenceError::Replay { entry, reached });
+        }
+    }
+    Ok(())
+}
+
+/// Wraps a machine so every committed transition is journaled, and
+/// checkpointed as its [`CheckpointPolicy`] says.
+///
+/// Transitions are recorded as the machine commits them, wherever they come
+/// from, and written to the journal by the next
+/// [`handle_event`](Self::handle_event) or [`sync`](Self::sync). Moves made
+/// outside `handle_event` are written before its event is dispatched.
+pub struct PersistentStateMachine<S, E, C, B, O = ()>
+where
+    S: State,
+    E: Event,
+{
+    machine: StateMachine<S, E, C, O>,
+    recorder: Arc<Mutex<Recorder<S, E>>>,
+    backend: B,
+    policy: CheckpointPolicy<S>,
+    clock: Arc<dyn Clock>,
+    /// Transitions journaled since the last checkpoint.
+    pending: usize,
+    last_checkpoint: Instant,
+}
+
+impl<S, E, C, B, O> PersistentStateMachine<S, E, C, B, O>
+where
+    S: State + Send + 'static,
+    E: Event + Send + 'static,
+    C: Clone,
+    B: PersistenceBackend<S, E, C>,
+    O: Default,
+{
+    /// Wraps `machine`, restoring the backend's latest snapshot if there is
+    /// one and replaying the journal entries after it, so no transition is
+    /// lost whatever the checkpoint policy.
+    ///
+    /// Replay dispatches each journaled event again, so handlers must be
+    /// deterministic and hooks and observers already registered see the
+    /// replayed transitions; register side-effecting observers after `open`.
+    /// Forced moves are committed without running handlers.
+    pub fn open(
+        mut machine: StateMachine<S, E, C, O>,
+        mut backend: B,
+    ) -> Result<Self, PersistenceError<S, E, B::Error>> {
+        if let Some(snapshot) = backend.load_snapshot().map_err(PersistenceError::Backend)? {
+            machine.restore(snapshot);
+        }
+        let journal = backend.load_journal().map_err(PersistenceError::Backend)?;
+        let pending = journal.len();
+        let recorder = record(&mut machine);
+        replay(&mut machine, &recorder, journal)?;
+        let clock = Arc::new(machine.clock.clone());
+        Ok(PersistentStateMachine {
+            last_checkpoint: clock.now(),
+            machine,
+            recorder,
+            backend,
+            policy: CheckpointPolicy::default(),
+            clock,
+            pending,
+        })
+    }
+
+    pub fn with_checkpoint_policy(mut self, policy: CheckpointPolicy<S>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Measures [`CheckpointPolicy::every`] intervals with `clock` rather than
+    /// the machine's own.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.last_checkpoint = clock.now();
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Dispatches `event` and journals the transitions it committed.
+    ///
+    /// On a backend error the transitions not yet written stay recorded and
+    /// are written by the next call; if that happens after dispatching, the
+    /// machine has already moved.
+    pub fn handle_event(
+        &mut self,
+        event: &E,
+    ) -> Result<Response<S>, PersistenceError<S, E, B::Error>> {
+        self.sync()?;
+        self.recorder.lock().unwrap().dispatching = true;
+        let response = self.machine.handle_event(event);
+        self.recorder.lock().unwrap().dispatching = false;
+        self.sync()?;
+        Ok(response?)
+    }
+
+    /// Journals the transitions committed since the last call, such as those
+    /// of a watchdog or deadline escalation, then checkpoints if the policy
+    /// says so.
+    pub fn sync(&mut self) -> Result<(), PersistenceError<S, E, B::Error>> {
+        let steps = std::mem::take(&mut self.recorder.lock().unwrap().steps);
+        let mut due = false;
+        for (written, entry) in steps.iter().enumerate() {
+            if let Err(error) = self.backend.append_journal(entry) {
+                let mut recorder = self.recorder.lock().unwrap();
+                let newer = std::mem::replace(&mut recorder.steps, steps[written..].to_vec());
+                recorder.steps.extend(newer);
+                return Err(PersistenceError::Backend(error));
+            }
+            self.pending += 1;
+            let since = self
+                .clock
+                .now()
+                .saturating_duration_since(self.last_checkpoint);
+            due |= self.policy.is_due(self.pending, since, &entry.to);
+        }
+        // Only once every entry is written, as replay starts from the snapshot.
+        if due {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Saves a snapshot of the machine right now.
+    pub fn checkpoint(&mut self) -> Result<(), PersistenceError<S, E, B::Error>> {
+        let snapshot = self.machine.snapshot()?;
+        self.backend
+            .save_snapshot(&snapshot)
+            .map_err(PersistenceError::Backend)?;
+        self.pending = 0;
+        self.last_checkpoint = self.clock.now();
+        Ok(())
+    }
+
+    /// Transitions journaled since the last checkpoint.
+    pub fn pending_transitions(&self) -> usize {
+        self.pending
+    }
+
+    pub fn machine(&self) -> &StateMachine<S, E, C, O> {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut StateMachine<S, E, C, O> {
+        &mut self.machine
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn into_inner(self) -> (StateMachine<S, E, C, O>, B) {
+        (self.machine, self.backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_checkpoints_after_transitions() {
+        let mut sm =
+            PersistentStateMachine::open(init_state_machine(), MemoryBackend::default()).unwrap();
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        assert!(sm.handle_event(&CallEvent::Dial).is_err());
+
+        let (_, backend) = sm.into_inner();
+        assert_eq!(backend.journal.len(), 2);
+        assert_eq!(backend.journal[1].to, CallState::Connected);
+
+        let reopened = PersistentStateMachine::open(init_state_machine(), backend).unwrap();
+        assert_eq!(
+            reopened.machine().get_current_state().unwrap(),
+            &CallState::Connected
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_policy() {
+        use crate::clock::ManualClock;
+
+        let clock = ManualClock::new();
+        let policy = CheckpointPolicy::never()
+            .every_transitions(3)
+            .every(Duration::from_secs(60))
+            .on_state(CallState::Disconnected);
+        let mut sm = PersistentStateMachine::open(init_state_machine(), MemoryBackend::default())
+            .unwrap()
+            .with_checkpoint_policy(policy)
+            .with_clock(clock.clone());
+
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        assert_eq!(sm.pending_transitions(), 2);
+        assert!(sm.backend().snapshot.is_none());
+
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+        assert_eq!(
+            sm.backend().snapshot.as_ref().map(|s| &s.state),
+            Some(&CallState::Disconnected)
+        );
+        sm.handle_event(&CallEvent::Reset).unwrap();
+        clock.advance(Duration::from_secs(60));
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        assert_eq!(sm.pending_transitions(), 0);
+        assert_eq!(sm.backend().journal.len(), 5);
+    }
+
+    #[test]
+    fn test_open_replays_journal_after_snapshot() {
+        let mut sm = PersistentStateMachine::open(init_state_machine(), MemoryBackend::default())
+            .unwrap()
+            .with_checkpoint_policy(CheckpointPolicy::never());
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.checkpoint().unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+
+        let (_, backend) = sm.into_inner();
+        let reopened = PersistentStateMachine::open(init_state_machine(), backend.clone()).unwrap();
+        assert_eq!(
+            reopened.machine().get_current_state().unwrap(),
+            &CallState::Disconnected
+        );
+        assert_eq!(reopened.pending_transitions(), 2);
+
+        // A machine whose handlers no longer reproduce the journal is refused.
+        let mut changed = init_state_machine();
+        changed.add_transition_to(CallState::Ringing, CallEvent::Answer, CallState::Idle);
+        assert!(matches!(
+            PersistentStateMachine::open(changed, backend),
+            Err(PersistenceError::Replay {
+                reached: CallState::Idle,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_open_replays_forced_transitions() {
+        use crate::clock::ManualClock;
+        use crate::deadline::OnDeadline;
+
+        let clock = ManualClock::new();
+        let machine = || {
+            let mut sm = init_state_machine().with_clock(clock.clone());
+            sm.add_transition_to(CallState::Ringing, CallEvent::Reset, CallState::Idle);
+            sm.set_state_deadline(
+                CallState::Ringing,
+                Duration::from_secs(30),
+                OnDeadline::Dispatch(CallEvent::Reset),
+            );
+            sm
+        };
+        let mut sm = PersistentStateMachine::open(machine(), MemoryBackend::default())
+            .unwrap()
+            .with_checkpoint_policy(CheckpointPolicy::never());
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        clock.advance(Duration::from_secs(30));
+        sm.machine_mut().check_deadlines().unwrap().unwrap();
+        sm.sync().unwrap();
+        assert!(sm.backend().journal[1].forced);
+        sm.handle_event(&CallEvent::Dial).unwrap();
+
+        let (_, backend) = sm.into_inner();
+        assert_eq!(backend.journal.len(), 3);
+        // Without the escalation's handler, dispatching Reset in Ringing would
+        // fail; the forced move is committed instead.
+        let reopened = PersistentStateMachine::open(init_state_machine(), backend).unwrap();
+        assert_eq!(
+            reopened.machine().get_current_state().unwrap(),
+            &CallState::Dialing
+        );
+    }
+
+    #[test]
+    fn test_background_backend_queues_after_failure() {
+        struct FailOnce(
+            MemoryBackend<CallState, CallEvent, HashMap<String, usize>>,
+            bool,
+        );
+
+        impl PersistenceBackend<CallState, CallEvent, HashMap<String, usize>> for FailOnce {
+            type Error = ();
+
+            fn save_snapshot(
+                &mut self,
+                _: &Snapshot<CallState, HashMap<String, usize>>,
+            ) -> Result<(), ()> {
+                if std::mem::replace(&mut self.1, false) {
+                    return Err(());
+                }
+                Ok(())
+            }
+
+            fn load_snapshot(
+                &mut self,
+            ) -> Result<Option<Snapshot<CallState, HashMap<String, usize>>>, ()> {
+                Ok(None)
+            }
+
+            fn append_journal(
+                &mut self,
+                entry: &JournalEntry<CallState, CallEvent>,
+            ) -> Result<(), ()> {
+                self.0.append_journal(entry).map_err(|_| ())
+            }
+
+            fn load_journal(&mut self) -> Result<Vec<JournalEntry<CallState, CallEvent>>, ()> {
+                self.0.load_journal().map_err(|_| ())
+            }
+        }
+
+        let mut backend = BackgroundBackend::new(FailOnce(MemoryBackend::default(), true));
+        let snapshot = init_state_machine().snapshot().unwrap();
+        backend.save_snapshot(&snapshot).unwrap();
+        let entry = JournalEntry {
+            from: CallState::Idle,
+            event: CallEvent::Dial,
+            to: CallState::Dialing,
+            forced: false,
+        };
+        while backend.failed.lock().unwrap().is_none() {
+            thread::yield_now();
+        }
+        // The earlier failure is reported, but this entry is still written.
+        assert!(matches!(
+            backend.append_journal(&entry),
+            Err(BackgroundError::Backend(()))
+        ));
+        assert_eq!(backend.load_journal().unwrap(), [entry]);
+    }
+
+    #[test]
+    fn test_background_backend_writes_off_thread() {
+        let backend = BackgroundBackend::new(MemoryBackend::default());
+        let mut sm = PersistentStateMachine::open(init_state_machine(), backend).unwrap();
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        sm.backend().flush().unwrap();
+
+        let (_, backend) = sm.into_inner();
+        let mut inner = backend.into_inner().unwrap();
+        assert_eq!(inner.journal.len(), 2);
+        assert_eq!(
+            inner.load_snapshot().unwrap().map(|s| s.state),
+            Some(CallState::Connected)
+        );
+    }
+
+    #[cfg(feature = "file-backend")]
+    #[test]
+    fn test_file_backend_round_trip() {
+        struct DebugCodec;
+
+        impl Codec<CallState, CallEvent, HashMap<String, usize>> for DebugCodec {
+            fn encode_snapshot(
+                &self,
+                snapshot: &Snapshot<CallState, HashMap<String, usize>>,
+            ) -> String {
+                format!("{:?}", snapshot.state)
+            }
+
+            fn decode_snapshot(
+                &self,
+                text: &str,
+            ) -> Result<Snapshot<CallState, HashMap<String, usize>>, String> {
+                let state = match text {
+                    "Ringing" => CallState::Ringing,
+                    "Connected" => CallState::Connected,
+                    other => return Err(format!("unexpected state {}", other)),
+                };
+                Ok(Snapshot {
+                    state,
+                    context: HashMap::new(),
+                })
+            }
+
+            fn encode_entry(&self, entry: &JournalEntry<CallState, CallEvent>) -> String {
+                format!("{:?} {:?} {:?}", entry.from, entry.event, entry.to)
+            }
+
+            fn decode_entry(
+                &self,
+                text: &str,
+            ) -> Result<JournalEntry<CallState, CallEvent>, String> {
+                match text {
+                    "Ringing Answer Connected" => Ok(JournalEntry {
+                        from: CallState::Ringing,
+                        event: CallEvent::Answer,
+                        to: CallState::Connected,
+                        forced: false,
+                    }),
+                    other => Err(format!("unexpected entry {}", other)),
+                }
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("fsmportal-file-{}", std::process::id()));
+        let backend = FileBackend::open(&dir, DebugCodec).unwrap();
+        let mut sm = PersistentStateMachine::open(init_state_machine(), backend).unwrap();
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+
+        let backend = FileBackend::open(&dir, DebugCodec).unwrap();
+        let reopened = PersistentStateMachine::open(init_state_machine(), backend).unwrap();
+        assert_eq!(
+            reopened.machine().get_current_state().unwrap(),
+            &CallState::Ringing
+        );
+        let journal = std::fs::read_to_string(dir.join("journal")).unwrap();
+        assert_eq!(journal, "Idle Incoming Ringing\n");
+
+        // Entries after the snapshot are replayed on open.
+        let mut sm = reopened.with_checkpoint_policy(CheckpointPolicy::never());
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        let backend = FileBackend::open(&dir, DebugCodec).unwrap();
+        let reopened = PersistentStateMachine::open(init_state_machine(), backend).unwrap();
+        assert_eq!(
+            reopened.machine().get_current_state().unwrap(),
+            &CallState::Connected
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,643 @@
+//! Many independent machines, one per key.
+//!
+//! A [`MachinePool`] holds a machine per session key, creating machines for
+//! new keys with its factory. [`MachinePool::dispatch_all_parallel`] takes a
+//! batch of keyed events, groups them per key and processes the groups on a
+//! set of worker threads: events for the same key are dispatched in batch
+//! order on one thread, while different keys proceed in parallel. Bulk
+//! replays and batch jobs over many sessions therefore scale across cores.
+//!
+//! [`MachinePool::with_state_limit`] caps how many sessions may be in a state
+//! at once, such as one per trunk line in `Connected`. A transition into a
+//! full state is vetoed and fails with `StateMachineError::StateLimitReached`;
+//! under [`OnLimit::Defer`] the pool keeps the event and dispatches it again
+//! once a session leaves the state, after any events its own session received
+//! meanwhile, keeping the result for [`take_replayed`](MachinePool::take_replayed).
+//! Events deferred for a session are dropped when it is removed. Parallel
+//! batches never defer. The pool's check is each
+//! machine's last veto, and counts stay exact only while it remains so: add
+//! vetoes in the factory, not through [`get_mut`](MachinePool::get_mut).
+//!
+//! [`MachinePool::export_all`] writes every session's snapshot to one archive,
+//! a machine at a time, and [`MachinePool::import_all`] reads it back into
+//! machines made by the factory, so a new deployment can take over the
+//! sessions of the old one. An archive is a header line, one line per session
+//! in the caller's encoding and a trailer with the session count, which lets
+//! an import tell a truncated archive from a complete one.
+
+use crate::generic::{
+    Event, HandlerResult, RejectReason, State, StateMachine, StateMachineError, Veto,
+};
+use crate::snapshot::Snapshot;
+use crate::view::{MachineView, Projection};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::io::{self, BufRead, Write};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Creates the machine for a key seen for the first time.
+pub type MachineFactory<K, S, E, C, O> = Box<dyn Fn(&K) -> StateMachine<S, E, C, O> + Send + Sync>;
+
+/// Each key's dispatch results, in the order its events appeared in the batch.
+pub type BatchResults<K, S, E, O> = HashMap<K, Vec<HandlerResult<S, E, O>>>;
+
+type Job<K, S, E, C, O> = (K, StateMachine<S, E, C, O>, Vec<E>);
+
+const ARCHIVE_HEADER: &str = "fsmportal-pool 1";
+
+type Installer<S, E, C, O> = Box<dyn Fn(&mut StateMachine<S, E, C, O>) + Send + Sync>;
+
+/// What happens to an event whose transition would exceed a state's limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnLimit {
+    Reject,
+    /// Keep the event and dispatch it again once the state has room.
+    Defer,
+}
+
+struct StateLimits<S> {
+    max: HashMap<S, (usize, OnLimit)>,
+    /// Sessions per state, counted once any limit is set.
+    occupancy: HashMap<S, usize>,
+}
+
+impl<S: State> StateLimits<S> {
+    fn has_room(&self, state: &S) -> bool {
+        self.max
+            .get(state)
+            .is_none_or(|&(limit, _)| self.occupancy.get(state).copied().unwrap_or(0) < limit)
+    }
+
+    fn leave(&mut self, state: &S) {
+        if let Some(count) = self.occupancy.get_mut(state) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+fn limit_reason<S: State>(state: &S, limit: usize) -> String {
+    format!("{:?} is at its limit of {} sessions", state, limit)
+}
+
+/// Counts `machine` in its state and keeps the count current.
+fn install_limits<S, E, C, O>(
+    machine: &mut StateMachine<S, E, C, O>,
+    limits: &Arc<Mutex<StateLimits<S>>>,
+) where
+    S: State + Send + 'static,
+    E: Event,
+{
+    if let Some(state) = &machine.current_state {
+        *limits
+            .lock()
+            .unwrap()
+            .occupancy
+            .entry(state.clone())
+            .or_default() += 1;
+    }
+    // The slot claimed by the veto, which the observer then does not count again.
+    let reserved = Arc::new(Mutex::new(None::<S>));
+    let (check, reserve) = (limits.clone(), reserved.clone());
+    machine.add_veto(move |from, _, to, _| {
+        if from == to {
+            return Veto::Allow;
+        }
+        let mut limits = check.lock().unwrap();
+        if let Some(&(limit, _)) = limits.max.get(to).filter(|_| !limits.has_room(to)) {
+            return Veto::Deny(RejectReason::new(limit_reason(to, limit)));
+        }
+        // Claimed now, so a parallel dispatch cannot take the same slot.
+        *limits.occupancy.entry(to.clone()).or_default() += 1;
+        *reserve.lock().unwrap() = Some(to.clone());
+        Veto::Allow
+    });
+    let (count, settle) = (limits.clone(), reserved);
+    machine.add_observer(move |from, _, to| {
+        if from == to {
+            return;
+        }
+        let mut limits = count.lock().unwrap();
+        if settle.lock().unwrap().take().as_ref() != Some(to) {
+            *limits.occupancy.entry(to.clone()).or_default() += 1;
+        }
+        limits.leave(from);
+    });
+}
+
+pub struct MachinePool<K, S, E, C = HashMap<String, usize>, O = ()>
+where
+    S: State,
+    E: Event,
+{
+    machines: HashMap<K, StateMachine<S, E, C, O>>,
+    factory: MachineFactory<K, S, E, C, O>,
+    threads: usize,
+    limits: Arc<Mutex<StateLimits<S>>>,
+    install: Option<Installer<S, E, C, O>>,
+    /// Deferred events, with the state whose limit held them back.
+    deferred: VecDeque<(K, E, S)>,
+    /// Results of deferred events dispatched again, oldest first.
+    replayed: Vec<(K, HandlerResult<S, E, O>)>,
+}
+
+impl<K, S, E, C, O> MachinePool<K, S, E, C, O>
+where
+    K: Clone + Eq + Hash + Send,
+    S: State + Send,
+    E: Event + Send,
+    C: Send,
+    O: Default + Send,
+{
+    /// An empty pool using one worker per available core.
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: Fn(&K) -> StateMachine<S, E, C, O> + 'static + Send + Sync,
+    {
+        MachinePool {
+            machines: HashMap::new(),
+            factory: Box::new(factory),
+            threads: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            limits: Arc::new(Mutex::new(StateLimits {
+                max: HashMap::new(),
+                occupancy: HashMap::new(),
+            })),
+            install: None,
+            deferred: VecDeque::new(),
+            replayed: Vec::new(),
+        }
+    }
+
+    /// Uses at most `threads` workers for parallel dispatch.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Allows at most `limit` sessions in `state` at once, handling events
+    /// that would exceed it per `on_limit`.
+    pub fn with_state_limit(mut self, state: S, limit: usize, on_limit: OnLimit) -> Self
+    where
+        S: 'static,
+    {
+        self.limits
+            .lock()
+            .unwrap()
+            .max
+            .insert(state, (limit, on_limit));
+        if self.install.is_none() {
+            let limits = self.limits.clone();
+            let install: Installer<S, E, C, O> =
+                Box::new(move |machine| install_limits(machine, &limits));
+            for machine in self.machines.values_mut() {
+                install(machine);
+            }
+            self.install = Some(install);
+        }
+        self
+    }
+
+    /// Sessions currently in `state`; only counted once a limit is set.
+    pub fn occupancy(&self, state: &S) -> usize {
+        let limits = self.limits.lock().unwrap();
+        limits.occupancy.get(state).copied().unwrap_or(0)
+    }
+
+    /// Events waiting for a state to have room.
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// Takes the results of the deferred events dispatched again since the
+    /// last call, with their keys, oldest first. An event deferred once more
+    /// shows up as `StateLimitReached` with `deferred` set.
+    pub fn take_replayed(&mut self) -> Vec<(K, HandlerResult<S, E, O>)> {
+        std::mem::take(&mut self.replayed)
+    }
+
+    /// Adds or replaces the machine for `key`.
+    pub fn insert(&mut self, key: K, mut machine: StateMachine<S, E, C, O>) {
+        if let Some(install) = &self.install {
+            install(&mut machine);
+        }
+        if let Some(old) = self.machines.insert(key, machine) {
+            self.forget(&old);
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&StateMachine<S, E, C, O>> {
+        self.machines.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut StateMachine<S, E, C, O>> {
+        self.machines.get_mut(key)
+    }
+
+    /// Removes the machine for `key`, freeing its place in a limited state
+    /// and dropping the events deferred for it.
+    pub fn remove(&mut self, key: &K) -> Option<StateMachine<S, E, C, O>> {
+        let machine = self.machines.remove(key)?;
+        self.forget(&machine);
+        self.deferred.retain(|(deferred, _, _)| deferred != key);
+        self.replay_deferred();
+        Some(machine)
+    }
+
+    fn forget(&self, machine: &StateMachine<S, E, C, O>) {
+        if let (Some(_), Some(state)) = (&self.install, &machine.current_state) {
+            self.limits.lock().unwrap().leave(state);
+        }
+    }
+
+    /// A [view](crate::view) of every machine through `projection`, in no
+    /// particular order.
+    pub fn views(&self, projection: &Projection<C>) -> Vec<(K, MachineView<S>)> {
+        self.machines
+            .iter()
+            .map(|(key, machine)| (key.clone(), machine.view(projection)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    /// The machine for `key`, created with the factory if there is none yet.
+    pub fn get_or_create(&mut self, key: K) -> &mut StateMachine<S, E, C, O> {
+        if !self.machines.contains_key(&key) {
+            let machine = self.create(&key);
+            self.machines.insert(key.clone(), machine);
+        }
+        self.machines.get_mut(&key).unwrap()
+    }
+
+    /// A new machine for `key` from the factory, counted in any limits.
+    fn create(&self, key: &K) -> StateMachine<S, E, C, O> {
+        let mut machine = (self.factory)(key);
+        if let Some(install) = &self.install {
+            install(&mut machine);
+        }
+        machine
+    }
+
+    /// Dispatches `event` to the machine for `key`, creating it if needed.
+    pub fn dispatch(&mut self, key: K, event: &E) -> HandlerResult<S, E, O> {
+        let result = self.get_or_create(key.clone()).dispatch(event);
+        let result = self.limit_reached(key, result, true);
+        self.replay_deferred();
+        result
+    }
+
+    /// Turns the veto of a transition into a full state into
+    /// `StateLimitReached`, deferring the event if allowed and configured.
+    fn limit_reached(
+        &mut self,
+        key: K,
+        result: HandlerResult<S, E, O>,
+        may_defer: bool,
+    ) -> HandlerResult<S, E, O> {
+        let Err(StateMachineError::Vetoed {
+            event, to, reason, ..
+        }) = &result
+        else {
+            return result;
+        };
+        let limit = self.limits.lock().unwrap().max.get(to).copied();
+        let Some((limit, on_limit)) =
+            limit.filter(|&(limit, _)| reason.message == limit_reason(to, limit))
+        else {
+            return result;
+        };
+        let deferred = may_defer && on_limit == OnLimit::Defer;
+        if deferred {
+            self.deferred.push_back((key, event.clone(), to.clone()));
+        }
+        Err(StateMachineError::StateLimitReached {
+            state: to.clone(),
+            event: event.clone(),
+            limit,
+            deferred,
+        })
+    }
+
+    /// Dispatches the deferred events whose state now has room, in order.
+    fn replay_deferred(&mut self) {
+        for _ in 0..self.deferred.len() {
+            let Some((key, event, state)) = self.deferred.pop_front() else {
+                break;
+            };
+            if self.limits.lock().unwrap().has_room(&state) {
+                let Some(machine) = self.machines.get_mut(&key) else {
+                    continue;
+                };
+                let result = machine.dispatch(&event);
+                let result = self.limit_reached(key.clone(), result, true);
+                self.replayed.push((key, result));
+            } else {
+                self.deferred.push_back((key, event, state));
+            }
+        }
+    }
+
+    /// Dispatches every `(key, event)` of `batch`, in parallel across keys
+    /// and in batch order within each key.
+    pub fn dispatch_all_parallel(
+        &mut self,
+        batch: impl IntoIterator<Item = (K, E)>,
+    ) -> BatchResults<K, S, E, O> {
+        let mut groups: Vec<(K, Vec<E>)> = Vec::new();
+        let mut index: HashMap<K, usize> = HashMap::new();
+        for (key, event) in batch {
+            match index.get(&key) {
+                Some(&i) => groups[i].1.push(event),
+                None => {
+                    index.insert(key.clone(), groups.len());
+                    groups.push((key, vec![event]));
+                }
+            }
+        }
+
+        // The largest groups go first so one long session does not finish last.
+        groups.sort_by_key(|(_, events)| std::cmp::Reverse(events.len()));
+        let jobs: Vec<Job<K, S, E, C, O>> = groups
+            .into_iter()
+            .map(|(key, events)| {
+                let machine = match self.machines.remove(&key) {
+                    Some(machine) => machine,
+                    None => self.create(&key),
+                };
+                (key, machine, events)
+            })
+            .collect();
+        let workers = self.threads.min(jobs.len());
+        let queue = Mutex::new(jobs.into_iter());
+        let done = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let Some((key, mut machine, events)) = queue.lock().unwrap().next() else {
+                        return;
+                    };
+                    let results: Vec<_> =
+                        events.iter().map(|event| machine.dispatch(event)).collect();
+                    done.lock().unwrap().push((key, machine, results));
+                });
+            }
+        });
+
+        let mut results = HashMap::new();
+        for (key, machine, outcome) in done.into_inner().unwrap() {
+            self.machines.insert(key.clone(), machine);
+            let outcome = outcome
+                .into_iter()
+                .map(|result| self.limit_reached(key.clone(), result, false))
+                .collect();
+            results.insert(key, outcome);
+        }
+        self.replay_deferred();
+        results
+    }
+}
+
+impl<K, S, E, C, O> MachinePool<K, S, E, C, O>
+where
+    K: Clone + Eq + Hash + Send,
+    S: State + Send,
+    E: Event + Send,
+    C: Clone + Send,
+    O: Default + Send,
+{
+    /// Writes an archive of every session to `writer`, each encoded as one
+    /// line by `encode`, and returns how many were written. Unstarted
+    /// machines are left out.
+    pub fn export_all<W, F>(&self, mut writer: W, encode: F) -> io::Result<usize>
+    where
+        W: Write,
+        F: Fn(&K, &Snapshot<S, C>) -> String,
+    {
+        writeln!(writer, "{}", ARCHIVE_HEADER)?;
+        let mut count = 0;
+        for (key, machine) in &self.machines {
+            let Ok(snapshot) = machine.snapshot() else {
+                continue;
+            };
+            let line = encode(key, &snapshot);
+            if line.contains('\n') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "encoded session spans several lines",
+                ));
+            }
+            writeln!(writer, "{}", line)?;
+            count += 1;
+        }
+        writeln!(writer, "end {}", count)?;
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Reads an archive written by [`export_all`](Self::export_all), restoring
+    /// each session decoded by `decode` into a machine from the factory that
+    /// replaces any machine for its key, and returns how many were read.
+    ///
+    /// Sessions are imported as they are read: on an error, including a
+    /// missing trailer, the ones before it are already in the pool.
+    pub fn import_all<R, F>(&mut self, reader: R, decode: F) -> io::Result<usize>
+    where
+        R: BufRead,
+        F: Fn(&str) -> Result<(K, Snapshot<S, C>), String>,
+    {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut lines = reader.lines();
+        match lines.next().transpose()? {
+            Some(header) if header == ARCHIVE_HEADER => {}
+            other => return Err(invalid(format!("not a pool archive: {:?}", other))),
+        }
+        let mut count = 0;
+        for line in lines {
+            let line = line?;
+            if let Some(expected) = line.strip_prefix("end ") {
+                if expected.parse() != Ok(count) {
+                    return Err(invalid(format!(
+                        "archive holds {} sessions, read {}",
+                        expected, count
+                    )));
+                }
+                return Ok(count);
+            }
+            let (key, snapshot) = decode(&line).map_err(invalid)?;
+            let mut machine = (self.factory)(&key);
+            machine.restore(snapshot);
+            self.insert(key, machine);
+            count += 1;
+        }
+        Err(invalid(format!("archive ends after {} sessions", count)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_parallel_dispatch_keeps_per_key_order() {
+        let mut pool = MachinePool::new(|_: &u32| init_state_machine()).with_threads(4);
+        let mut batch = Vec::new();
+        for session in 0..50 {
+            batch.push((session, CallEvent::Incoming));
+        }
+        for session in 0..50 {
+            batch.push((session, CallEvent::Answer));
+            if session % 2 == 0 {
+                batch.push((session, CallEvent::HangUp));
+            }
+        }
+
+        let results = pool.dispatch_all_parallel(batch);
+        assert_eq!(pool.len(), 50);
+        assert!(results.values().flatten().all(Result::is_ok));
+        assert_eq!(results[&4].len(), 3);
+        assert_eq!(
+            pool.get(&4).unwrap().get_current_state().unwrap(),
+            &CallState::Disconnected
+        );
+        assert_eq!(
+            pool.get(&5).unwrap().get_current_state().unwrap(),
+            &CallState::Connected
+        );
+
+        assert!(pool.dispatch(5, &CallEvent::HangUp).is_ok());
+        assert!(pool.dispatch(99, &CallEvent::HangUp).is_err());
+        assert_eq!(pool.len(), 51);
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let mut pool = MachinePool::new(|_: &u32| init_state_machine());
+        pool.dispatch(1, &CallEvent::Dial).unwrap();
+        pool.dispatch(2, &CallEvent::Incoming).unwrap();
+        pool.get_mut(&2)
+            .unwrap()
+            .get_context_mut()
+            .insert("retries".to_string(), 3);
+
+        let encode = |key: &u32, snapshot: &Snapshot<CallState, HashMap<String, usize>>| {
+            let retries = snapshot.context.get("retries").copied().unwrap_or(0);
+            format!("{} {:?} {}", key, snapshot.state, retries)
+        };
+        let mut archive = Vec::new();
+        assert_eq!(pool.export_all(&mut archive, encode).unwrap(), 2);
+
+        let decode = |line: &str| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let state = match fields[1] {
+                "Dialing" => CallState::Dialing,
+                "Ringing" => CallState::Ringing,
+                other => return Err(format!("unknown state {}", other)),
+            };
+            let mut snapshot = Snapshot {
+                state,
+                context: HashMap::new(),
+            };
+            snapshot
+                .context
+                .insert("retries".to_string(), fields[2].parse().unwrap());
+            Ok((fields[0].parse().unwrap(), snapshot))
+        };
+        let mut restored = MachinePool::new(|_: &u32| init_state_machine());
+        assert_eq!(restored.import_all(&archive[..], decode).unwrap(), 2);
+        let session = restored.get(&2).unwrap();
+        assert_eq!(session.get_current_state().unwrap(), &CallState::Ringing);
+        assert_eq!(session.get_context()["retries"], 3);
+        assert!(restored.dispatch(1, &CallEvent::Answer).is_ok());
+
+        let truncated = &archive[..archive.len() - "end 2\n".len()];
+        let error = MachinePool::new(|_: &u32| init_state_machine())
+            .import_all(truncated, decode)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_state_limits() {
+        let mut pool = MachinePool::new(|_: &u32| init_state_machine())
+            .with_state_limit(CallState::Connected, 1, OnLimit::Reject)
+            .with_state_limit(CallState::Ringing, 1, OnLimit::Defer);
+
+        pool.dispatch(1, &CallEvent::Dial).unwrap();
+        pool.dispatch(1, &CallEvent::Answer).unwrap();
+        pool.dispatch(2, &CallEvent::Dial).unwrap();
+        assert!(matches!(
+            pool.dispatch(2, &CallEvent::Answer),
+            Err(StateMachineError::StateLimitReached {
+                state: CallState::Connected,
+                limit: 1,
+                deferred: false,
+                ..
+            })
+        ));
+        assert_eq!(pool.occupancy(&CallState::Connected), 1);
+
+        pool.dispatch(3, &CallEvent::Incoming).unwrap();
+        let error = pool.dispatch(4, &CallEvent::Incoming).unwrap_err();
+        assert_eq!(
+            error.to_json(),
+            "{\"kind\":\"state_limit_reached\",\"state\":\"Ringing\",\"event\":\"Incoming\",\"limit\":1,\"deferred\":true}"
+        );
+        assert_eq!(pool.deferred_len(), 1);
+        assert_eq!(
+            pool.get(&4).unwrap().get_current_state().unwrap(),
+            &CallState::Idle
+        );
+
+        // Session 3 leaving Ringing makes room for the deferred event.
+        pool.dispatch(3, &CallEvent::HangUp).unwrap();
+        assert_eq!(pool.deferred_len(), 0);
+        assert_eq!(
+            pool.get(&4).unwrap().get_current_state().unwrap(),
+            &CallState::Ringing
+        );
+        assert_eq!(pool.occupancy(&CallState::Ringing), 1);
+
+        let replayed = pool.take_replayed();
+        assert_eq!(replayed.len(), 1);
+        assert!(matches!(replayed[0], (4, Ok(_))));
+
+        pool.remove(&1);
+        pool.dispatch(2, &CallEvent::Answer).unwrap();
+
+        // A removed session's deferred event is dropped, not replayed.
+        pool.dispatch(5, &CallEvent::Incoming).unwrap_err();
+        pool.remove(&5);
+        assert_eq!(pool.deferred_len(), 0);
+        pool.dispatch(4, &CallEvent::HangUp).unwrap();
+        assert!(pool.get(&5).is_none());
+        assert!(pool.take_replayed().is_empty());
+    }
+
+    #[test]
+    fn test_parallel_batch_respects_limits() {
+        let mut pool = MachinePool::new(|_: &u32| init_state_machine())
+            .with_threads(4)
+            .with_state_limit(CallState::Ringing, 1, OnLimit::Defer);
+        let results = pool.dispatch_all_parallel((1..=3).map(|key| (key, CallEvent::Incoming)));
+        let admitted = results.values().flatten().filter(|r| r.is_ok()).count();
+        assert_eq!(admitted, 1);
+        assert!(results.values().flatten().any(|r| matches!(
+            r,
+            Err(StateMachineError::StateLimitReached {
+                deferred: false,
+                ..
+            })
+        )));
+        assert_eq!(pool.occupancy(&CallState::Ringing), 1);
+        assert!(pool.dispatch(4, &CallEvent::Incoming).is_err());
+        assert_eq!(pool.occupancy(&CallState::Ringing), 1);
+    }
+}
@@ -0,0 +1,328 @@
+//! Publishing committed transitions to external message buses.
+//!
+//! Records are encoded with [`TransitionRecord::to_json`]. The NATS and MQTT
+//! adapters speak the wire protocols directly and are enabled with the `nats`
+//! and `mqtt` features. They hand each message to a writer thread, so a slow
+//! broker never holds up a dispatch; a failed write is reported by the next
+//! publish.
+
+use crate::generic::{Event, State, StateMachine, TransitionRecord};
+use std::io;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+
+pub trait TransitionPublisher<S, E>: Send + Sync {
+    fn publish(&self, record: &TransitionRecord<S, E>) -> io::Result<()>;
+
+    /// Called when [`publish`](Self::publish) fails; publishing never fails
+    /// the dispatch. Does nothing unless overridden.
+    fn on_error(&self, error: io::Error) {
+        let _ = error;
+    }
+}
+
+/// Writes frames to a stream on a dedicated thread; clones share the thread.
+#[cfg(any(feature = "nats", feature = "mqtt"))]
+#[derive(Clone)]
+struct BackgroundWriter {
+    frames: Sender<Vec<u8>>,
+    failed: std::sync::Arc<Mutex<Option<io::Error>>>,
+}
+
+#[cfg(any(feature = "nats", feature = "mqtt"))]
+impl BackgroundWriter {
+    fn spawn<W: io::Write + Send + 'static>(mut stream: W) -> Self {
+        let (frames, queue) = std::sync::mpsc::channel::<Vec<u8>>();
+        let failed = std::sync::Arc::new(Mutex::new(None));
+        let report = failed.clone();
+        std::thread::spawn(move || {
+            for frame in queue {
+                if let Err(e) = stream.write_all(&frame) {
+                    report.lock().unwrap().get_or_insert(e);
+                }
+            }
+        });
+        BackgroundWriter { frames, failed }
+    }
+
+    /// Queues `frame`, then reports an earlier write's failure if there was one.
+    fn send(&self, frame: Vec<u8>) -> io::Result<()> {
+        self.frames
+            .send(frame)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "writer thread stopped"))?;
+        match self.failed.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// Publishes every committed transition through `publisher`.
+    pub fn add_publisher<P>(&mut self, publisher: P)
+    where
+        P: TransitionPublisher<S, E> + 'static,
+    {
+        self.add_observer(move |from, event, to| {
+            let record = TransitionRecord {
+                from: from.clone(),
+                event: event.clone(),
+                to: to.clone(),
+            };
+            if let Err(e) = publisher.publish(&record) {
+                publisher.on_error(e);
+            }
+        });
+    }
+}
+
+/// Forwards records to an in-process channel.
+pub struct ChannelPublisher<S, E> {
+    sender: Mutex<Sender<TransitionRecord<S, E>>>,
+}
+
+impl<S, E> ChannelPublisher<S, E> {
+    pub fn new(sender: Sender<TransitionRecord<S, E>>) -> Self {
+        ChannelPublisher {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl<S, E> TransitionPublisher<S, E> for ChannelPublisher<S, E>
+where
+    S: Clone + Send,
+    E: Clone + Send,
+{
+    fn publish(&self, record: &TransitionRecord<S, E>) -> io::Result<()> {
+        self.sender
+            .lock()
+            .unwrap()
+            .send(record.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver dropped"))
+    }
+}
+
+#[cfg(feature = "nats")]
+pub use nats::NatsPublisher;
+
+#[cfg(feature = "nats")]
+mod nats {
+    use super::{BackgroundWriter, TransitionPublisher};
+    use crate::generic::TransitionRecord;
+    use std::fmt::Debug;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::thread;
+
+    /// Publishes each record as a NATS message on `subject`.
+    pub struct NatsPublisher {
+        writer: BackgroundWriter,
+        subject: String,
+    }
+
+    impl NatsPublisher {
+        pub fn connect(addr: impl ToSocketAddrs, subject: impl Into<String>) -> io::Result<Self> {
+            let mut stream = TcpStream::connect(addr)?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+
+            let mut info = String::new();
+            reader.read_line(&mut info)?;
+            if !info.starts_with("INFO") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("expected INFO from server, got {:?}", info.trim()),
+                ));
+            }
+            stream.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")?;
+            let writer = BackgroundWriter::spawn(stream);
+
+            // The server drops clients that leave its keep-alive PINGs unanswered.
+            // PONGs go through the writer so they never split a message.
+            let pong = writer.clone();
+            thread::spawn(move || {
+                let mut line = String::new();
+                while matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+                    if line.starts_with("PING") && pong.send(b"PONG\r\n".to_vec()).is_err() {
+                        break;
+                    }
+                    line.clear();
+                }
+            });
+
+            Ok(NatsPublisher {
+                writer,
+                subject: subject.into(),
+            })
+        }
+    }
+
+    impl<S, E> TransitionPublisher<S, E> for NatsPublisher
+    where
+        S: Debug,
+        E: Debug,
+    {
+        fn publish(&self, record: &TransitionRecord<S, E>) -> io::Result<()> {
+            let payload = record.to_json();
+            let message = format!("PUB {} {}\r\n{}\r\n", self.subject, payload.len(), payload);
+            self.writer.send(message.into_bytes())
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+pub use mqtt::MqttPublisher;
+
+#[cfg(feature = "mqtt")]
+mod mqtt {
+    use super::{BackgroundWriter, TransitionPublisher};
+    use crate::generic::TransitionRecord;
+    use std::fmt::Debug;
+    use std::io::{self, Read, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    /// Publishes each record at QoS 0 on `topic` using MQTT 3.1.1.
+    pub struct MqttPublisher {
+        writer: BackgroundWriter,
+        topic: String,
+    }
+
+    fn push_string(packet: &mut Vec<u8>, value: &str) {
+        packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        packet.extend_from_slice(value.as_bytes());
+    }
+
+    fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut packet = vec![kind];
+        let mut remaining = body.len();
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if remaining == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    impl MqttPublisher {
+        pub fn connect(
+            addr: impl ToSocketAddrs,
+            client_id: &str,
+            topic: impl Into<String>,
+        ) -> io::Result<Self> {
+            let mut stream = TcpStream::connect(addr)?;
+
+            let mut body = Vec::new();
+            push_string(&mut body, "MQTT");
+            // Protocol level 4, clean session, keep-alive disabled.
+            body.extend_from_slice(&[4, 0x02, 0, 0]);
+            push_string(&mut body, client_id);
+            stream.write_all(&packet(0x10, &body))?;
+
+            let mut connack = [0u8; 4];
+            stream.read_exact(&mut connack)?;
+            if connack[0] != 0x20 || connack[3] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("broker refused connection with code {}", connack[3]),
+                ));
+            }
+
+            Ok(MqttPublisher {
+                writer: BackgroundWriter::spawn(stream),
+                topic: topic.into(),
+            })
+        }
+    }
+
+    impl<S, E> TransitionPublisher<S, E> for MqttPublisher
+    where
+        S: Debug,
+        E: Debug,
+    {
+        fn publish(&self, record: &TransitionRecord<S, E>) -> io::Result<()> {
+            let mut body = Vec::new();
+            push_string(&mut body, &self.topic);
+            body.extend_from_slice(record.to_json().as_bytes());
+            self.writer.send(packet(0x30, &body))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::packet;
+
+        #[test]
+        fn test_remaining_length_encoding() {
+            assert_eq!(&packet(0x30, &[0; 127])[..2], &[0x30, 127]);
+            assert_eq!(&packet(0x30, &[0; 321])[..3], &[0x30, 0xC1, 0x02]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Stateful;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_channel_publisher_receives_records() {
+        let (sender, receiver) = channel();
+        let mut sm = init_state_machine();
+        sm.add_publisher(ChannelPublisher::new(sender));
+
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+
+        let records: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].from, CallState::Dialing);
+        assert_eq!(
+            records[1].to_json(),
+            "{\"from\":\"Dialing\",\"event\":\"HangUp\",\"to\":\"Disconnected\"}"
+        );
+    }
+
+    #[cfg(feature = "nats")]
+    #[test]
+    fn test_nats_publisher_sends_pub() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"INFO {}\r\n").unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut lines = Vec::new();
+            for _ in 0..3 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                lines.push(line);
+            }
+            lines
+        });
+
+        let mut sm = init_state_machine();
+        sm.add_publisher(NatsPublisher::connect(addr, "calls.transitions").unwrap());
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+
+        let lines = server.join().unwrap();
+        assert!(lines[0].starts_with("CONNECT "));
+        assert!(lines[1].starts_with("PUB calls.transitions "));
+        assert!(lines[2].contains("\"to\":\"Ringing\""));
+    }
+}
@@ -0,0 +1,288 @@
+//! A pure variant of the machine, stepped by value.
+//!
+//! A [`PureMachine`]'s handlers take the context and return the next one along
+//! with descriptions of the effects to perform, instead of performing them.
+//! [`step`] consumes a machine and returns its successor, so exploring several
+//! what-ifs is a matter of cloning; the handler table is shared between clones.
+//!
+//! [`PureMachine::from_machine`] derives the table from an existing machine,
+//! such as one built from a [`MachineSpec`](crate::spec::MachineSpec), so the
+//! two never drift apart.
+
+use crate::generic::{Event, Response, State, StateMachine, StateMachineError};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::hash::BuildHasher;
+use std::sync::Arc;
+
+/// A pure handler: the event and the current context in, the response, the
+/// next context and the effects to perform out.
+pub type PureTransition<S, E, C, F> = Arc<dyn Fn(&E, C) -> (Response<S>, C, Vec<F>) + Send + Sync>;
+
+/// The next machine and the effects it asks for, or the machine, still in its
+/// state, and the dispatch error.
+pub type StepResult<S, E, C, F> =
+    Result<(PureMachine<S, E, C, F>, Vec<F>), (PureMachine<S, E, C, F>, StateMachineError<S, E>)>;
+
+struct Table<S, E, C, F> {
+    transitions: HashMap<(S, E), PureTransition<S, E, C, F>>,
+    parents: HashMap<S, S>,
+}
+
+impl<S: Clone, E: Clone, C, F> Clone for Table<S, E, C, F> {
+    fn clone(&self) -> Self {
+        Table {
+            transitions: self.transitions.clone(),
+            parents: self.parents.clone(),
+        }
+    }
+}
+
+pub struct PureMachine<S, E, C, F> {
+    table: Arc<Table<S, E, C, F>>,
+    state: S,
+    context: C,
+}
+
+impl<S: Clone, E, C: Clone, F> Clone for PureMachine<S, E, C, F> {
+    fn clone(&self) -> Self {
+        PureMachine {
+            table: self.table.clone(),
+            state: self.state.clone(),
+            context: self.context.clone(),
+        }
+    }
+}
+
+impl<S: Debug, E, C: Debug, F> Debug for PureMachine<S, E, C, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PureMachine")
+            .field("state", &self.state)
+            .field("context", &self.context)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, E, C, F> PureMachine<S, E, C, F>
+where
+    S: State,
+    E: Event,
+{
+    pub fn new(initial_state: S, context: C) -> Self {
+        PureMachine {
+            table: Arc::new(Table {
+                transitions: HashMap::new(),
+                parents: HashMap::new(),
+            }),
+            state: initial_state,
+            context,
+        }
+    }
+
+    /// A pure machine in `machine`'s current state with `machine`'s static
+    /// transitions and substates. Each derived transition moves to its
+    /// declared target, leaving the context as it is and asking for no
+    /// effects; handlers without a static target are left out, and any
+    /// transition can be replaced with [`add_transition`](Self::add_transition).
+    pub fn from_machine<C2, O, H>(
+        machine: &StateMachine<S, E, C2, O, H>,
+        context: C,
+    ) -> Result<Self, StateMachineError<S, E>>
+    where
+        S: Send + Sync + 'static,
+        H: BuildHasher,
+    {
+        let mut pure = Self::new(machine.get_current_state()?.clone(), context);
+        let table = Arc::make_mut(&mut pure.table);
+        for ((from, event), to) in &machine.targets {
+            let to = to.clone();
+            let transition: PureTransition<S, E, C, F> =
+                Arc::new(move |_, context| (Response::Transition(to.clone()), context, Vec::new()));
+            table
+                .transitions
+                .insert((from.clone(), event.clone()), transition);
+        }
+        table.parents = machine.parents.clone();
+        Ok(pure)
+    }
+
+    pub fn add_transition<T>(&mut self, from: S, event: E, transition: T)
+    where
+        T: Fn(&E, C) -> (Response<S>, C, Vec<F>) + 'static + Send + Sync,
+    {
+        Arc::make_mut(&mut self.table)
+            .transitions
+            .insert((from, event), Arc::new(transition));
+    }
+
+    /// Makes `child` a substate of `parent`; handlers answering `Response::Super`
+    /// defer to the parent's handler, as in [`StateMachine`](crate::generic::StateMachine).
+    pub fn add_substate(&mut self, parent: S, child: S) {
+        Arc::make_mut(&mut self.table).parents.insert(child, parent);
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    /// The same machine, moved to `state` with `context`, sharing the handler table.
+    pub fn with_state(mut self, state: S, context: C) -> Self {
+        self.state = state;
+        self.context = context;
+        self
+    }
+
+    /// Consumes the machine, returning its successor and the effects of handling `event`.
+    pub fn step(self, event: &E) -> StepResult<S, E, C, F> {
+        let PureMachine {
+            table,
+            state,
+            mut context,
+        } = self;
+        let mut effects = Vec::new();
+        let mut handled_by = Some(state.clone());
+        // Guards against a cycle in the substate relation, as the dispatch
+        // chain of a `StateMachine` does.
+        let mut visited = Vec::new();
+        let mut found = false;
+
+        while let Some(current) = handled_by {
+            visited.push(current.clone());
+            let parent = table
+                .parents
+                .get(&current)
+                .filter(|parent| !visited.contains(parent))
+                .cloned();
+            let Some(transition) = table.transitions.get(&(current.clone(), event.clone())) else {
+                handled_by = parent;
+                continue;
+            };
+            found = true;
+            let (response, next, produced) = transition(event, context);
+            context = next;
+            effects.extend(produced);
+            let state = match response {
+                Response::Super => {
+                    handled_by = parent;
+                    continue;
+                }
+                Response::Handled => state,
+                Response::Transition(to) => to,
+            };
+            let next = PureMachine {
+                table,
+                state,
+                context,
+            };
+            return Ok((next, effects));
+        }
+
+        let error = if found {
+            StateMachineError::UnexpectedEvent {
+                state: state.clone(),
+                event: event.clone(),
+            }
+        } else {
+            StateMachineError::TransitionNotFound {
+                from: state.clone(),
+                event: event.clone(),
+            }
+        };
+        let machine = PureMachine {
+            table,
+            state,
+            context,
+        };
+        Err((machine, error))
+    }
+}
+
+/// Handles `event` on `machine`, returning the next machine and the effects to perform.
+pub fn step<S, E, C, F>(machine: PureMachine<S, E, C, F>, event: &E) -> StepResult<S, E, C, F>
+where
+    S: State,
+    E: Event,
+{
+    machine.step(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CallEvent, CallState};
+
+    #[derive(Debug, PartialEq)]
+    enum Effect {
+        Ring,
+        Log(&'static str),
+    }
+
+    fn phone() -> PureMachine<CallState, CallEvent, u32, Effect> {
+        let mut machine = PureMachine::new(CallState::Idle, 0);
+        machine.add_transition(CallState::Idle, CallEvent::Incoming, |_, calls| {
+            (
+                Response::Transition(CallState::Ringing),
+                calls + 1,
+                vec![Effect::Ring],
+            )
+        });
+        machine.add_transition(CallState::Ringing, CallEvent::HangUp, |_, calls| {
+            (Response::Super, calls, vec![Effect::Log("missed")])
+        });
+        machine.add_substate(CallState::Disconnected, CallState::Ringing);
+        machine.add_transition(CallState::Disconnected, CallEvent::HangUp, |_, calls| {
+            (Response::Transition(CallState::Idle), calls, Vec::new())
+        });
+        machine
+    }
+
+    #[test]
+    fn test_step_returns_effects() {
+        let (ringing, effects) = step(phone(), &CallEvent::Incoming).unwrap();
+        assert_eq!(effects, vec![Effect::Ring]);
+        assert_eq!(
+            (ringing.state(), ringing.context()),
+            (&CallState::Ringing, &1)
+        );
+
+        // Exploring a branch leaves the original untouched.
+        let (idle, effects) = step(ringing.clone(), &CallEvent::HangUp).unwrap();
+        assert_eq!(effects, vec![Effect::Log("missed")]);
+        assert_eq!(idle.state(), &CallState::Idle);
+        assert_eq!(ringing.state(), &CallState::Ringing);
+
+        let (idle, error) = step(idle, &CallEvent::Answer).unwrap_err();
+        assert!(matches!(
+            error,
+            StateMachineError::TransitionNotFound { .. }
+        ));
+        assert_eq!((idle.state(), idle.context()), (&CallState::Idle, &1));
+    }
+
+    #[test]
+    fn test_from_machine_and_parent_cycles() {
+        let mut sm: StateMachine<_, _, ()> = StateMachine::new(CallState::Idle, ());
+        sm.add_transition_to(CallState::Idle, CallEvent::Dial, CallState::Dialing);
+        sm.add_substate(CallState::Idle, CallState::Dialing);
+        sm.add_substate(CallState::Dialing, CallState::Idle);
+
+        let machine: PureMachine<_, _, u32, Effect> = PureMachine::from_machine(&sm, 0).unwrap();
+        let (dialing, effects) = step(machine, &CallEvent::Dial).unwrap();
+        assert_eq!(
+            (dialing.state(), effects),
+            (&CallState::Dialing, Vec::new())
+        );
+
+        // Dialing and Idle are each other's parent; the climb still ends.
+        let (dialing, error) = step(dialing, &CallEvent::Answer).unwrap_err();
+        assert!(matches!(
+            error,
+            StateMachineError::TransitionNotFound { .. }
+        ));
+        assert_eq!(dialing.state(), &CallState::Dialing);
+    }
+}
@@ -0,0 +1,219 @@
+//! What happens when a handler fails.
+//!
+//! By default a handler returning an error leaves the machine in the state it
+//! was in. A [`RecoveryPolicy`] installed with
+//! [`set_recovery_policy`](StateMachine::set_recovery_policy) records each
+//! failure, readable afterwards with
+//! [`last_failure`](StateMachine::last_failure), and can move the machine to
+//! a designated error or quarantine state and run a recovery hook.
+//!
+//! The move is committed like any transition: exit, entry and transition
+//! hooks and observers run, so a
+//! [`PersistentStateMachine`](crate::persistence::PersistentStateMachine)
+//! journals it, and replay reproduces it by running the failing handler
+//! again. The dispatch still fails with the handler's error, which is what
+//! audit sinks record. Only errors returned by handlers
+//! count; an event refused by a validator or without a transition does not.
+
+use crate::clock::Clock;
+use crate::generic::{Event, State, StateMachine, StateMachineError};
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Runs after a failed handler, with the context to update and the failure.
+pub type RecoveryHook<S, E, C> = Arc<dyn Fn(&mut C, &Failure<S, E>) + Send + Sync>;
+
+/// A handler error, as recorded by the recovery policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure<S, E> {
+    /// The state the handler ran in.
+    pub state: S,
+    pub event: E,
+    /// The error's [`kind`](StateMachineError::kind).
+    pub kind: &'static str,
+    /// The error's [`to_json`](StateMachineError::to_json) encoding.
+    pub error: String,
+    pub at: SystemTime,
+}
+
+pub struct RecoveryPolicy<S, E, C> {
+    target: Option<S>,
+    hook: Option<RecoveryHook<S, E, C>>,
+}
+
+impl<S, E, C> Default for RecoveryPolicy<S, E, C> {
+    fn default() -> Self {
+        RecoveryPolicy {
+            target: None,
+            hook: None,
+        }
+    }
+}
+
+impl<S, E, C> Clone for RecoveryPolicy<S, E, C>
+where
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        RecoveryPolicy {
+            target: self.target.clone(),
+            hook: self.hook.clone(),
+        }
+    }
+}
+
+impl<S, E, C> RecoveryPolicy<S, E, C> {
+    /// A policy that only records failures.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the machine to `state` after a failure.
+    pub fn transition_to(mut self, state: S) -> Self {
+        self.target = Some(state);
+        self
+    }
+
+    /// Runs `hook` after a failure, once the machine is in its recovery state.
+    pub fn on_failure<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut C, &Failure<S, E>) + 'static + Send + Sync,
+    {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+}
+
+pub(crate) struct Recovery<S, E, C> {
+    policy: Option<RecoveryPolicy<S, E, C>>,
+    last: Option<Failure<S, E>>,
+}
+
+impl<S, E, C> Recovery<S, E, C> {
+    pub(crate) fn new() -> Self {
+        Recovery {
+            policy: None,
+            last: None,
+        }
+    }
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    pub fn set_recovery_policy(&mut self, policy: RecoveryPolicy<S, E, C>) {
+        self.recovery.policy = Some(policy);
+    }
+
+    /// The most recent handler failure since the policy was installed.
+    pub fn last_failure(&self) -> Option<&Failure<S, E>> {
+        self.recovery.last.as_ref()
+    }
+
+    /// Applies the recovery policy to a handler's `error` in `state`, handing
+    /// the error back for the caller.
+    pub(crate) fn recover(
+        &mut self,
+        state: &S,
+        event: &E,
+        error: StateMachineError<S, E>,
+    ) -> StateMachineError<S, E> {
+        let Some(policy) = self.recovery.policy.clone() else {
+            return error;
+        };
+        let failure = Failure {
+            state: state.clone(),
+            event: event.clone(),
+            kind: error.kind(),
+            error: error.to_json(),
+            at: self.clock.wall_time(),
+        };
+        if let Some(target) = policy.target {
+            self.commit_with(target, event, self.exit_hooks_pending());
+        }
+        if let Some(hook) = &policy.hook {
+            hook(&mut self.context, &failure);
+        }
+        self.recovery.last = Some(failure);
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::RejectReason;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_failed_handler_moves_to_recovery_state() {
+        let mut sm = init_state_machine();
+        sm.add_transition(CallState::Idle, CallEvent::Dial, |sm, event| {
+            Err(StateMachineError::Rejected {
+                state: sm.get_current_state()?.clone(),
+                event: event.clone(),
+                reason: RejectReason::new("trunk unavailable"),
+            })
+        });
+        assert!(sm.dispatch(&CallEvent::Dial).is_err());
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+        assert!(sm.last_failure().is_none());
+
+        sm.set_recovery_policy(
+            RecoveryPolicy::new()
+                .transition_to(CallState::Disconnected)
+                .on_failure(|context: &mut HashMap<String, usize>, failure| {
+                    *context.entry(failure.kind.to_string()).or_insert(0) += 1;
+                }),
+        );
+        assert!(sm.dispatch(&CallEvent::Dial).is_err());
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Disconnected);
+        assert_eq!(sm.get_context().get("rejected"), Some(&1));
+        let failure = sm.last_failure().unwrap();
+        assert_eq!(
+            (&failure.state, &failure.event),
+            (&CallState::Idle, &CallEvent::Dial)
+        );
+        assert!(failure.error.contains("trunk unavailable"));
+
+        // Unhandled events are not handler failures.
+        assert!(sm.dispatch(&CallEvent::Answer).is_err());
+        assert_eq!(sm.last_failure().unwrap().event, CallEvent::Dial);
+    }
+
+    #[test]
+    fn test_recovery_moves_are_journaled() {
+        use crate::persistence::{CheckpointPolicy, MemoryBackend, PersistentStateMachine};
+
+        let machine = || {
+            let mut sm = init_state_machine();
+            sm.add_transition(CallState::Ringing, CallEvent::Answer, |sm, event| {
+                Err(StateMachineError::Rejected {
+                    state: sm.get_current_state()?.clone(),
+                    event: event.clone(),
+                    reason: RejectReason::new("no media"),
+                })
+            });
+            sm.set_recovery_policy(RecoveryPolicy::new().transition_to(CallState::Disconnected));
+            sm
+        };
+        let mut sm = PersistentStateMachine::open(machine(), MemoryBackend::default())
+            .unwrap()
+            .with_checkpoint_policy(CheckpointPolicy::never());
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        assert!(sm.handle_event(&CallEvent::Answer).is_err());
+
+        let (_, backend) = sm.into_inner();
+        assert_eq!(backend.journal[1].to, CallState::Disconnected);
+        let reopened = PersistentStateMachine::open(machine(), backend).unwrap();
+        assert_eq!(
+            reopened.machine().get_current_state().unwrap(),
+            &CallState::Disconnected
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Line-oriented REPL for stepping a machine by hand, enabled with the `repl`
+//! feature.
+//!
+//! Each line names an event, optionally followed by a JSON payload
+//! (`Dial {"number":"+15550100"}`), and is dispatched with
+//! [`dispatch_named`](StateMachine::dispatch_named), so the machine needs an
+//! event parser. After every step the REPL prints the transition, the new
+//! state and the events accepted from it. `:state`, `:help` and `:quit` are
+//! commands. Unlike the `inspector`, output is plain text that can be piped
+//! or diffed.
+
+use crate::generic::{Event, Response, State, StateMachine};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "type an event name, optionally followed by a JSON payload
+commands: :state  :help  :quit";
+
+fn status<S, E, C, O>(sm: &StateMachine<S, E, C, O>, output: &mut impl Write) -> io::Result<()>
+where
+    S: State,
+    E: Event,
+{
+    let events: BTreeSet<String> = sm.available_events().map(|e| format!("{:?}", e)).collect();
+    let events: Vec<String> = events.into_iter().collect();
+    match &sm.current_state {
+        Some(state) => writeln!(output, "state: {:?}", state)?,
+        None => writeln!(output, "state: <not initialized>")?,
+    }
+    writeln!(output, "events: {}", events.join(", "))
+}
+
+/// Runs the REPL on `sm` until `:quit` or end of input.
+pub fn run<S, E, C, O, R, W>(
+    sm: &mut StateMachine<S, E, C, O>,
+    input: R,
+    mut output: W,
+) -> io::Result<()>
+where
+    S: State,
+    E: Event + Send + Sync + 'static,
+    O: Default + std::fmt::Debug,
+    R: BufRead,
+    W: Write,
+{
+    status(sm, &mut output)?;
+    write!(output, "> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let (name, payload) = match line.trim().split_once(char::is_whitespace) {
+            Some((name, payload)) => (name, Some(payload.trim())),
+            None => (line.trim(), None),
+        };
+        match name {
+            "" => {}
+            ":quit" => return Ok(()),
+            ":help" => writeln!(output, "{}", HELP)?,
+            ":state" => status(sm, &mut output)?,
+            _ => {
+                let from = sm.current_state.clone();
+                match sm.dispatch_named(name, payload) {
+                    Ok((response, output_value)) => {
+                        match (from, response) {
+                            (Some(from), Response::Transition(to)) => {
+                                writeln!(output, "{:?} --{}--> {:?}", from, name, to)?
+                            }
+                            (_, response) => writeln!(output, "{}: {:?}", name, response)?,
+                        }
+                        let output_text = format!("{:?}", output_value);
+                        if output_text != "()" {
+                            writeln!(output, "output: {}", output_text)?;
+                        }
+                        status(sm, &mut output)?;
+                    }
+                    Err(e) => writeln!(output, "error: {}", e.to_json())?,
+                }
+            }
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent};
+
+    #[test]
+    fn test_repl_steps_the_machine() {
+        let mut sm = init_state_machine();
+        sm.set_event_parser(|name, payload| match (name, payload) {
+            ("Dial", Some(payload)) if payload.starts_with('{') => Some(CallEvent::Dial),
+            ("HangUp", None) => Some(CallEvent::HangUp),
+            _ => None,
+        });
+        let input = "Dial {\"number\": \"+15550100\"}\nDial\n:quit\nHangUp\n";
+        let mut output = Vec::new();
+        run(&mut sm, input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("state: Idle\nevents: Dial, Incoming\n> "));
+        assert!(output.contains("Idle --Dial--> Dialing\nstate: Dialing\nevents: Answer, HangUp\n"));
+        assert!(output.contains("error: {\"kind\":\"unknown_event_name\",\"name\":\"Dial\"}\n"));
+        assert_eq!(sm.get_current_state().unwrap(), &crate::CallState::Dialing);
+    }
+}
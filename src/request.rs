@@ -0,0 +1,127 @@
+//! Events that expect a typed reply.
+//!
+//! A request is an ordinary event whose handler produces the reply as its
+//! output value. [`StateMachine::dispatch_request`] returns the reply directly;
+//! [`ActorHandle::dispatch_request`](crate::actor::ActorHandle::dispatch_request)
+//! delivers it through a [`oneshot`] channel.
+
+use crate::generic::{Event, State, StateMachine, StateMachineError};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum RequestError<S, E> {
+    Machine(StateMachineError<S, E>),
+    /// The handler for `event` produced no reply.
+    NoReply {
+        event: E,
+    },
+    /// The machine went away before replying.
+    Disconnected,
+}
+
+impl<S, E> From<StateMachineError<S, E>> for RequestError<S, E> {
+    fn from(e: StateMachineError<S, E>) -> Self {
+        RequestError::Machine(e)
+    }
+}
+
+/// The replier dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+/// Creates a channel carrying exactly one value.
+pub fn oneshot<T>() -> (ReplySender<T>, ReplyReceiver<T>) {
+    let (sender, receiver) = mpsc::sync_channel(1);
+    (ReplySender { sender }, ReplyReceiver { receiver })
+}
+
+pub struct ReplySender<T> {
+    sender: SyncSender<T>,
+}
+
+impl<T> ReplySender<T> {
+    /// Sends the reply, handing it back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        self.sender.send(value).map_err(|e| e.0)
+    }
+}
+
+pub struct ReplyReceiver<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> ReplyReceiver<T> {
+    /// A receiver that already holds `value`.
+    pub(crate) fn ready(value: T) -> Self {
+        let (sender, receiver) = oneshot();
+        let _ = sender.send(value);
+        receiver
+    }
+
+    pub fn recv(self) -> Result<T, Canceled> {
+        self.receiver.recv().map_err(|_| Canceled)
+    }
+
+    /// Waits at most `timeout`; the receiver is handed back if it elapses.
+    pub fn recv_timeout(self, timeout: Duration) -> Result<T, Result<Self, Canceled>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(value) => Ok(value),
+            Err(RecvTimeoutError::Timeout) => Err(Ok(self)),
+            Err(RecvTimeoutError::Disconnected) => Err(Err(Canceled)),
+        }
+    }
+}
+
+impl<T, S, E> ReplyReceiver<Result<T, RequestError<S, E>>> {
+    /// Waits for the reply, reporting a dropped replier as `Disconnected`.
+    pub fn wait(self) -> Result<T, RequestError<S, E>> {
+        self.recv().unwrap_or(Err(RequestError::Disconnected))
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+    O: Default,
+{
+    /// Dispatches `request` and extracts the reply from the handler's output.
+    pub fn dispatch_request<Req, Resp>(&mut self, request: Req) -> Result<Resp, RequestError<S, E>>
+    where
+        Req: Into<E>,
+        O: Into<Option<Resp>>,
+    {
+        let event = request.into();
+        let (_, output) = self.dispatch(&event)?;
+        output.into().ok_or(RequestError::NoReply { event })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Response;
+    use crate::{CallEvent, CallState};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_sync_request_reply() {
+        let mut sm: StateMachine<CallState, CallEvent, HashMap<String, usize>, Option<u32>> =
+            StateMachine::new(CallState::Ringing, HashMap::new());
+        sm.add_transition_with_output(CallState::Ringing, CallEvent::Answer, |_sm, _event| {
+            Ok((Response::Transition(CallState::Connected), Some(7)))
+        });
+        sm.add_transition(CallState::Connected, CallEvent::HangUp, |_sm, _event| {
+            Ok(Response::Transition(CallState::Disconnected))
+        });
+
+        assert_eq!(sm.dispatch_request::<_, u32>(CallEvent::Answer).unwrap(), 7);
+        assert!(matches!(
+            sm.dispatch_request::<_, u32>(CallEvent::HangUp),
+            Err(RequestError::NoReply {
+                event: CallEvent::HangUp
+            })
+        ));
+    }
+}
@@ -0,0 +1,256 @@
+//! Typed resources injected into handlers.
+//!
+//! Resources are the values in the machine's extension store
+//! ([`insert_ext`](StateMachine::insert_ext)): database pools, billing
+//! clients and the like. A handler registered with
+//! [`add_transition_with_resources`](StateMachine::add_transition_with_resources)
+//! declares the ones it needs as parameters, [`Res<T>`] for shared and
+//! [`ResMut<T>`] for exclusive access, followed by the event:
+//!
+//! ```
+//! # use fsmportal::generic::{Response, StateMachine};
+//! # use fsmportal::resources::ResMut;
+//! # use fsmportal::{CallEvent, CallState};
+//! struct Billing {
+//!     calls: u32,
+//! }
+//!
+//! let mut sm: StateMachine<CallState, CallEvent, ()> = StateMachine::new(CallState::Ringing, ());
+//! sm.insert_ext(Billing { calls: 0 });
+//! sm.add_transition_with_resources(
+//!     CallState::Ringing,
+//!     CallEvent::Answer,
+//!     |mut billing: ResMut<Billing>, _event: &CallEvent| {
+//!         billing.calls += 1;
+//!         Ok(Response::Transition(CallState::Connected))
+//!     },
+//! );
+//! sm.dispatch(&CallEvent::Answer).unwrap();
+//! assert_eq!(sm.ext::<Billing>().unwrap().calls, 1);
+//! ```
+//!
+//! A handler can take up to two resources, of different types. Dispatching
+//! fails with `StateMachineError::MissingResource` when one is not in the store.
+
+use crate::generic::{Event, Response, State, StateMachine, StateMachineError};
+use std::any::{type_name, Any, TypeId};
+use std::ops::{Deref, DerefMut};
+
+type Value = Box<dyn Any + Send + Sync>;
+
+/// Shared access to the resource of type `T`.
+pub struct Res<'a, T>(&'a T);
+
+/// Exclusive access to the resource of type `T`.
+pub struct ResMut<'a, T>(&'a mut T);
+
+impl<T> Deref for Res<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0
+    }
+}
+
+impl<T> Deref for ResMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0
+    }
+}
+
+impl<T> DerefMut for ResMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0
+    }
+}
+
+/// A handler parameter fetched from the extension store.
+pub trait Resource {
+    type Item<'a>;
+
+    fn type_id() -> TypeId;
+
+    fn type_name() -> &'static str;
+
+    fn fetch(value: &mut Value) -> Option<Self::Item<'_>>;
+}
+
+impl<T: Any + Send + Sync> Resource for Res<'_, T> {
+    type Item<'a> = Res<'a, T>;
+
+    fn type_id() -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn type_name() -> &'static str {
+        type_name::<T>()
+    }
+
+    fn fetch(value: &mut Value) -> Option<Res<'_, T>> {
+        value.downcast_ref().map(Res)
+    }
+}
+
+impl<T: Any + Send + Sync> Resource for ResMut<'_, T> {
+    type Item<'a> = ResMut<'a, T>;
+
+    fn type_id() -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn type_name() -> &'static str {
+        type_name::<T>()
+    }
+
+    fn fetch(value: &mut Value) -> Option<ResMut<'_, T>> {
+        value.downcast_mut().map(ResMut)
+    }
+}
+
+/// A handler taking resources; `M` names its parameters so one closure type
+/// implements it only once.
+pub trait ResourceHandler<S, E, M>: Send + Sync + 'static {
+    fn call<C, O>(
+        &self,
+        sm: &mut StateMachine<S, E, C, O>,
+        event: &E,
+    ) -> Result<Response<S>, StateMachineError<S, E>>
+    where
+        S: State,
+        E: Event;
+}
+
+fn missing<S: State, E: Event>(
+    state: S,
+    event: &E,
+    resource: &'static str,
+) -> StateMachineError<S, E> {
+    StateMachineError::MissingResource {
+        state,
+        event: event.clone(),
+        resource,
+    }
+}
+
+impl<S, E, F, A> ResourceHandler<S, E, fn(A)> for F
+where
+    A: Resource,
+    F: Fn(A, &E) -> Result<Response<S>, StateMachineError<S, E>>
+        + for<'a> Fn(A::Item<'a>, &E) -> Result<Response<S>, StateMachineError<S, E>>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn call<C, O>(
+        &self,
+        sm: &mut StateMachine<S, E, C, O>,
+        event: &E,
+    ) -> Result<Response<S>, StateMachineError<S, E>>
+    where
+        S: State,
+        E: Event,
+    {
+        let state = sm.get_current_state()?.clone();
+        let Some([a]) = sm.extensions.entries_mut([&A::type_id()]) else {
+            return Err(missing(state, event, A::type_name()));
+        };
+        match A::fetch(a) {
+            Some(a) => self(a, event),
+            None => Err(missing(state, event, A::type_name())),
+        }
+    }
+}
+
+impl<S, E, F, A, B> ResourceHandler<S, E, fn(A, B)> for F
+where
+    A: Resource,
+    B: Resource,
+    F: Fn(A, B, &E) -> Result<Response<S>, StateMachineError<S, E>>
+        + for<'a> Fn(A::Item<'a>, B::Item<'a>, &E) -> Result<Response<S>, StateMachineError<S, E>>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn call<C, O>(
+        &self,
+        sm: &mut StateMachine<S, E, C, O>,
+        event: &E,
+    ) -> Result<Response<S>, StateMachineError<S, E>>
+    where
+        S: State,
+        E: Event,
+    {
+        let state = sm.get_current_state()?.clone();
+        let ids = [&A::type_id(), &B::type_id()];
+        let names = [A::type_name(), B::type_name()];
+        let Some(entries) = sm.extensions.entries_mut(ids) else {
+            // The same type twice cannot be borrowed for both parameters.
+            return Err(missing(state, event, names[1]));
+        };
+        let [a, b] = entries;
+        match (A::fetch(a), B::fetch(b)) {
+            (Some(a), Some(b)) => self(a, b, event),
+            (None, _) => Err(missing(state, event, names[0])),
+            (_, None) => Err(missing(state, event, names[1])),
+        }
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// Registers a handler whose parameters are resources from the extension
+    /// store, fetched on every dispatch.
+    pub fn add_transition_with_resources<M, F>(&mut self, from: S, event: E, handler: F)
+    where
+        F: ResourceHandler<S, E, M>,
+        O: Default,
+    {
+        self.add_transition(from, event, move |sm, event| handler.call(sm, event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CallEvent, CallState};
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Billing {
+        charged: u32,
+    }
+
+    struct Tariff {
+        per_call: u32,
+    }
+
+    #[test]
+    fn test_handlers_receive_resources() {
+        let mut sm: StateMachine<CallState, CallEvent, ()> =
+            StateMachine::new(CallState::Connected, ());
+        sm.add_transition_with_resources(
+            CallState::Connected,
+            CallEvent::HangUp,
+            |mut billing: ResMut<Billing>, tariff: Res<Tariff>, _: &CallEvent| {
+                billing.charged += tariff.per_call;
+                Ok(Response::Transition(CallState::Disconnected))
+            },
+        );
+        sm.insert_ext(Billing::default());
+
+        assert!(matches!(
+            sm.dispatch(&CallEvent::HangUp),
+            Err(StateMachineError::MissingResource { resource, .. })
+                if resource.ends_with("Tariff")
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+
+        sm.insert_ext(Tariff { per_call: 3 });
+        sm.dispatch(&CallEvent::HangUp).unwrap();
+        assert_eq!(sm.ext::<Billing>(), Some(&Billing { charged: 3 }));
+    }
+}
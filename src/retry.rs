@@ -0,0 +1,220 @@
+//! Retrying handlers that fail transiently.
+//!
+//! A [`RetryPolicy`] set with
+//! [`set_retry_policy`](StateMachine::set_retry_policy) makes the engine call
+//! the handler for a `(state, event)` again when it returns an error, up to
+//! its attempt limit and with a [`Backoff`] delay between attempts, before the
+//! last error is returned (and handed to the
+//! [recovery policy](crate::recovery)). Handlers should only fail before
+//! committing anything, as a retried handler runs from the same state.
+//!
+//! [`dispatch`](StateMachine::dispatch) sleeps the calling thread between
+//! attempts; [`dispatch_async`](StateMachine::dispatch_async) waits without
+//! blocking and stops retrying once its token is cancelled. A cancelled
+//! attempt is never retried.
+
+use crate::containment::{contain, panicked};
+use crate::generic::{
+    Event, HandlerResult, State, StateMachine, StateMachineError, TransitionFunction,
+};
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Decides whether an error is worth another attempt.
+pub type RetryPredicate<S, E> = Arc<dyn Fn(&StateMachineError<S, E>) -> bool + Send + Sync>;
+
+/// The delay before each retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    Fixed(Duration),
+    /// `initial` before the first retry, doubling after each one up to `max`.
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    /// The delay after the `failures`-th failed attempt.
+    pub fn delay(&self, failures: u32) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .checked_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+                .map_or(max, |delay| delay.min(max)),
+        }
+    }
+}
+
+pub struct RetryPolicy<S, E> {
+    max_attempts: u32,
+    backoff: Backoff,
+    retry_if: Option<RetryPredicate<S, E>>,
+}
+
+impl<S, E> Clone for RetryPolicy<S, E> {
+    fn clone(&self) -> Self {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            retry_if: self.retry_if.clone(),
+        }
+    }
+}
+
+impl<S, E> RetryPolicy<S, E> {
+    /// Up to `max_attempts` calls in all, the first included, retrying
+    /// immediately on any error.
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            backoff: Backoff::Fixed(Duration::ZERO),
+            retry_if: None,
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Retries only errors `predicate` accepts, such as those from a network call.
+    pub fn retry_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&StateMachineError<S, E>) -> bool + 'static + Send + Sync,
+    {
+        self.retry_if = Some(Arc::new(predicate));
+        self
+    }
+
+    /// The delay before retrying after the `failures`-th failure with
+    /// `error`, or `None` to give up.
+    pub(crate) fn next_delay(
+        &self,
+        failures: u32,
+        error: &StateMachineError<S, E>,
+    ) -> Option<Duration> {
+        let retry = failures < self.max_attempts
+            && !matches!(error, StateMachineError::Cancelled { .. })
+            && self
+                .retry_if
+                .as_ref()
+                .is_none_or(|retry_if| retry_if(error));
+        retry.then(|| self.backoff.delay(failures))
+    }
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    /// Retries the handler for `event` in `from` per `policy`, replacing any
+    /// earlier policy for it.
+    pub fn set_retry_policy(&mut self, from: S, event: E, policy: RetryPolicy<S, E>) {
+        self.retries.insert((from, event), policy);
+    }
+
+    pub(crate) fn retry_policy(&self, from: &S, event: &E) -> Option<RetryPolicy<S, E>> {
+        if self.retries.is_empty() {
+            return None;
+        }
+        self.retries.get(&(from.clone(), event.clone())).cloned()
+    }
+
+    /// Calls `transition`, registered for `event` in `from`, until it succeeds
+    /// or its retry policy gives up.
+    pub(crate) fn call_with_retries(
+        &mut self,
+        from: &S,
+        event: &E,
+        transition: &TransitionFunction<S, E, C, O, H>,
+    ) -> HandlerResult<S, E, O> {
+        let policy = self.retry_policy(from, event);
+        let mut failures = 0;
+        loop {
+            let contain_panics = self.contain_panics;
+            let attempt = contain(contain_panics, || transition(self, event))
+                .unwrap_or_else(|message| Err(panicked(from, event, message)));
+            let error = match attempt {
+                Err(error) => error,
+                result => return result,
+            };
+            failures += 1;
+            match policy.as_ref().and_then(|p| p.next_delay(failures, &error)) {
+                Some(delay) => thread::sleep(delay),
+                None => return Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::{RejectReason, Response};
+    use crate::task::{block_on, CancellationToken};
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    fn flaky_dial(failures: usize) -> StateMachine<CallState, CallEvent> {
+        let mut sm = init_state_machine();
+        sm.get_context_mut()
+            .insert(String::from("failures"), failures);
+        sm.add_async_transition(CallState::Idle, CallEvent::Dial, |sm, event, _token| {
+            let remaining = sm.get_context_mut().get_mut("failures").unwrap();
+            let result = if *remaining > 0 {
+                *remaining -= 1;
+                Err(StateMachineError::Rejected {
+                    state: CallState::Idle,
+                    event: event.clone(),
+                    reason: RejectReason::new("network unreachable"),
+                })
+            } else {
+                Ok((Response::Transition(CallState::Dialing), ()))
+            };
+            Box::pin(async move { result })
+        });
+        sm
+    }
+
+    #[test]
+    fn test_transient_failures_are_retried() {
+        let policy = RetryPolicy::new(3).backoff(Backoff::Exponential {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(2),
+        });
+
+        let mut sm = flaky_dial(2);
+        sm.set_retry_policy(CallState::Idle, CallEvent::Dial, policy.clone());
+        sm.dispatch(&CallEvent::Dial).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Dialing);
+
+        let mut sm = flaky_dial(3);
+        sm.set_retry_policy(CallState::Idle, CallEvent::Dial, policy);
+        let token = CancellationToken::new();
+        assert!(block_on(sm.dispatch_async(&CallEvent::Dial, &token)).is_err());
+        assert_eq!(sm.get_context()["failures"], 0);
+        assert!(block_on(sm.dispatch_async(&CallEvent::Dial, &token)).is_ok());
+
+        let mut sm = flaky_dial(1);
+        sm.set_retry_policy(
+            CallState::Idle,
+            CallEvent::Dial,
+            RetryPolicy::new(5).retry_if(|e| !matches!(e, StateMachineError::Rejected { .. })),
+        );
+        assert!(sm.dispatch(&CallEvent::Dial).is_err());
+    }
+
+    #[test]
+    fn test_exponential_backoff_is_capped() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        let delays: Vec<u128> = (1..=5).map(|n| backoff.delay(n).as_millis()).collect();
+        assert_eq!(delays, [10, 20, 40, 50, 50]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Dispatch semantics profiles.
+//!
+//! [`Semantics::CLASSIC`] is the default and matches how the machine has
+//! always behaved. [`Semantics::UML`] follows UML state machine rules for the
+//! choices a profile makes: unhandled events, self-transitions, exit timing
+//! and dispatch order. Both profiles leave and enter composite states the UML
+//! way, exiting innermost first and entering outermost first.
+
+/// What happens to an event with no transition from the current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unhandled {
+    /// Fail with `TransitionNotFound`.
+    Error,
+    /// Consume the event without effect, as UML does.
+    Discard,
+}
+
+/// How a transition back into the current state is treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTransition {
+    /// The state is exited and re-entered; observers see the transition.
+    External,
+    /// The state is never left; exit and observers are skipped.
+    Local,
+}
+
+/// When a state's exit hooks run relative to the handler of a dispatch.
+/// Entry hooks always run after the handler, once the target is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitTiming {
+    /// Before the handler runs, whatever it returns. The target is not known
+    /// yet, so hooks are passed the exited state as `to`.
+    BeforeHandler,
+    /// Only once the handler has chosen to leave the state.
+    OnTransition,
+}
+
+/// Which state of a composite hierarchy is offered an event first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchOrder {
+    /// The innermost state first, then its ancestors; inner transitions win.
+    ChildFirst,
+    /// The outermost ancestor first, down to the current state.
+    ParentFirst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Semantics {
+    pub dispatch: DispatchOrder,
+    pub unhandled: Unhandled,
+    pub self_transition: SelfTransition,
+    pub exit: ExitTiming,
+}
+
+impl Semantics {
+    pub const CLASSIC: Semantics = Semantics {
+        dispatch: DispatchOrder::ChildFirst,
+        unhandled: Unhandled::Error,
+        self_transition: SelfTransition::External,
+        exit: ExitTiming::OnTransition,
+    };
+
+    pub const UML: Semantics = Semantics {
+        dispatch: DispatchOrder::ChildFirst,
+        unhandled: Unhandled::Discard,
+        self_transition: SelfTransition::External,
+        exit: ExitTiming::OnTransition,
+    };
+
+    pub fn with_dispatch(mut self, dispatch: DispatchOrder) -> Self {
+        self.dispatch = dispatch;
+        self
+    }
+
+    pub fn with_unhandled(mut self, unhandled: Unhandled) -> Self {
+        self.unhandled = unhandled;
+        self
+    }
+
+    pub fn with_self_transition(mut self, self_transition: SelfTransition) -> Self {
+        self.self_transition = self_transition;
+        self
+    }
+
+    pub fn with_exit(mut self, exit: ExitTiming) -> Self {
+        self.exit = exit;
+        self
+    }
+}
+
+impl Default for Semantics {
+    fn default() -> Self {
+        Semantics::CLASSIC
+    }
+}
+
+/// Conformance suite: every profile is exercised against the same machine.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::{Response, StateMachine, StateMachineError, Stateful};
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn machine(semantics: Semantics) -> (StateMachine<CallState, CallEvent>, Arc<AtomicUsize>) {
+        let mut sm = init_state_machine();
+        sm.set_semantics(semantics);
+        // Re-dialing while Dialing restarts the attempt without changing state.
+        sm.add_transition(CallState::Dialing, CallEvent::Dial, |_, _| {
+            Ok(Response::Transition(CallState::Dialing))
+        });
+        let transitions = Arc::new(AtomicUsize::new(0));
+        let counter = transitions.clone();
+        sm.add_observer(move |_, _, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        (sm, transitions)
+    }
+
+    #[test]
+    fn test_classic_rejects_unhandled_events() {
+        let (mut sm, _) = machine(Semantics::CLASSIC);
+        assert!(matches!(
+            sm.handle_event(&CallEvent::HangUp),
+            Err(StateMachineError::TransitionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_uml_discards_unhandled_events() {
+        let (mut sm, transitions) = machine(Semantics::UML);
+        assert!(matches!(
+            sm.handle_event(&CallEvent::HangUp),
+            Ok(Response::Handled)
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+        assert_eq!(transitions.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_self_transitions() {
+        for semantics in [Semantics::CLASSIC, Semantics::UML] {
+            let (mut sm, transitions) = machine(semantics);
+            sm.handle_event(&CallEvent::Dial).unwrap();
+            sm.handle_event(&CallEvent::Dial).unwrap();
+            assert_eq!(transitions.load(Ordering::SeqCst), 2);
+        }
+
+        let local = Semantics::UML.with_self_transition(SelfTransition::Local);
+        let (mut sm, transitions) = machine(local);
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        assert!(matches!(
+            sm.handle_event(&CallEvent::Dial),
+            Ok(Response::Transition(CallState::Dialing))
+        ));
+        assert_eq!(transitions.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_exit_timing() {
+        for (exit, expected) in [
+            (ExitTiming::OnTransition, ["handler", "exit"]),
+            (ExitTiming::BeforeHandler, ["exit", "handler"]),
+        ] {
+            let (mut sm, _) = machine(Semantics::CLASSIC.with_exit(exit));
+            let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let (exits, handled) = (log.clone(), log.clone());
+            sm.add_exit_hook(CallState::Idle, move |_, _, _, _| {
+                exits.lock().unwrap().push("exit")
+            });
+            sm.add_transition(CallState::Idle, CallEvent::Dial, move |_, _| {
+                handled.lock().unwrap().push("handler");
+                Ok(Response::Transition(CallState::Dialing))
+            });
+
+            sm.handle_event(&CallEvent::Dial).unwrap();
+            assert_eq!(*log.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_local_self_transition_runs_eventless_transitions() {
+        let local = Semantics::UML.with_self_transition(SelfTransition::Local);
+        let (mut sm, _) = machine(local);
+        sm.add_transition(CallState::Dialing, CallEvent::Dial, |sm, _| {
+            sm.get_context_mut().insert("gave_up".into(), 1);
+            Ok(Response::Transition(CallState::Dialing))
+        });
+        sm.add_eventless_transition(CallState::Dialing, CallState::Disconnected, |sm| {
+            sm.get_context().contains_key("gave_up")
+        });
+
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        assert!(matches!(
+            sm.handle_event(&CallEvent::Dial),
+            Ok(Response::Transition(CallState::Disconnected))
+        ));
+    }
+
+    #[test]
+    fn test_composite_exit_and_entry_order() {
+        for semantics in [Semantics::CLASSIC, Semantics::UML] {
+            let (mut sm, _) = machine(semantics);
+            sm.add_substate(CallState::Idle, CallState::Dialing);
+            sm.add_substate(CallState::Connected, CallState::Ringing);
+            let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+            for state in [
+                CallState::Idle,
+                CallState::Dialing,
+                CallState::Connected,
+                CallState::Ringing,
+            ] {
+                let (exits, entries) = (log.clone(), log.clone());
+                let exited = format!("exit {:?}", state);
+                let entered = format!("enter {:?}", state);
+                sm.add_exit_hook(state.clone(), move |_, _, _, _| {
+                    exits.lock().unwrap().push(exited.clone())
+                });
+                sm.add_entry_hook(state, move |_, _, _, _| {
+                    entries.lock().unwrap().push(entered.clone())
+                });
+            }
+            let transitions = log.clone();
+            sm.add_transition_hook(move |_, _, _, _| {
+                transitions.lock().unwrap().push("transition".into())
+            });
+            sm.add_transition_to(CallState::Dialing, CallEvent::Incoming, CallState::Ringing);
+
+            sm.handle_event(&CallEvent::Dial).unwrap();
+            log.lock().unwrap().clear();
+            sm.handle_event(&CallEvent::Incoming).unwrap();
+            assert_eq!(
+                *log.lock().unwrap(),
+                [
+                    "exit Dialing",
+                    "exit Idle",
+                    "transition",
+                    "enter Connected",
+                    "enter Ringing"
+                ],
+                "{:?}",
+                semantics
+            );
+        }
+    }
+
+    #[test]
+    fn test_dispatch_order() {
+        for (semantics, expected) in [
+            (Semantics::CLASSIC, CallState::Disconnected),
+            (
+                Semantics::CLASSIC.with_dispatch(DispatchOrder::ParentFirst),
+                CallState::Idle,
+            ),
+        ] {
+            let (mut sm, _) = machine(semantics);
+            // Nesting Dialing in Idle gives Reset a handler at both levels.
+            sm.add_substate(CallState::Idle, CallState::Dialing);
+            sm.add_transition(CallState::Dialing, CallEvent::Reset, |_, _| {
+                Ok(Response::Transition(CallState::Disconnected))
+            });
+            sm.add_transition(CallState::Idle, CallEvent::Reset, |_, _| {
+                Ok(Response::Transition(CallState::Idle))
+            });
+
+            sm.handle_event(&CallEvent::Dial).unwrap();
+            sm.handle_event(&CallEvent::Reset).unwrap();
+            assert_eq!(sm.get_current_state().unwrap(), &expected);
+        }
+    }
+}
@@ -0,0 +1,493 @@
+//! Stochastic walks through a machine, for load modelling and Monte Carlo
+//! analysis of call flows.
+//!
+//! Transitions carry a weight (1.0 unless set with
+//! [`set_transition_weight`](StateMachine::set_transition_weight));
+//! [`simulate_random`](StateMachine::simulate_random) repeatedly picks one of
+//! the enabled events of the current state with probability proportional to
+//! its weight and dispatches it. [`EventSequences`] instead walks the table
+//! of static targets without running any handler, producing event sequences
+//! to feed to a machine in soak tests, and [`MarkovModel`] learns transition
+//! frequencies from a recorded journal to replay realistic traffic.
+
+use crate::generic::{Event, State, StateMachine, StateMachineError, TransitionRecord};
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A source of random numbers for simulations.
+pub trait Rng {
+    fn next_u64(&mut self) -> u64;
+
+    /// A uniform value in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A small, fast, seedable generator (SplitMix64). Not cryptographically secure.
+#[derive(Debug, Clone)]
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+}
+
+impl Rng for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// One dispatched event of a simulation and the state it led to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedStep<S, E> {
+    pub from: S,
+    pub event: E,
+    pub to: S,
+}
+
+struct Weights<S, E>(HashMap<(S, E), f64>);
+
+/// Picks an index from `weights` with probability proportional to its weight,
+/// or `None` if no weight is positive.
+pub(crate) fn pick_weighted<R: Rng + ?Sized>(rng: &mut R, weights: &[f64]) -> Option<usize> {
+    let total: f64 = weights.iter().filter(|w| **w > 0.0).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut remaining = rng.next_f64() * total;
+    let mut last = None;
+    for (index, weight) in weights.iter().enumerate().filter(|(_, w)| **w > 0.0) {
+        if remaining < *weight {
+            return Some(index);
+        }
+        remaining -= weight;
+        last = Some(index);
+    }
+    last
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    O: Default,
+{
+    /// Sets the relative likelihood of the transition on `event` in `from`
+    /// during simulations. A weight of zero or less keeps it from being chosen.
+    pub fn set_transition_weight(&mut self, from: S, event: E, weight: f64) {
+        match self.ext_mut::<Weights<S, E>>() {
+            Some(Weights(weights)) => {
+                weights.insert((from, event), weight);
+            }
+            None => {
+                self.insert_ext(Weights(HashMap::from([((from, event), weight)])));
+            }
+        }
+    }
+
+    /// The weight of the transition on `event` in `from`; 1.0 unless set.
+    pub fn transition_weight(&self, from: &S, event: &E) -> f64 {
+        self.ext::<Weights<S, E>>()
+            .and_then(|Weights(weights)| weights.get(&(from.clone(), event.clone())))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Dispatches up to `steps` events chosen at random among those enabled
+    /// in the current state, weighted by [`transition_weight`](Self::transition_weight).
+    /// Stops early when no event can be chosen, and on the first dispatch error.
+    ///
+    /// Candidates are ordered by Debug representation, so a seeded `rng`
+    /// reproduces the same walk.
+    pub fn simulate_random<R: Rng + ?Sized>(
+        &mut self,
+        rng: &mut R,
+        steps: usize,
+    ) -> Result<Vec<SimulatedStep<S, E>>, StateMachineError<S, E>> {
+        let mut walk = Vec::with_capacity(steps);
+        for _ in 0..steps {
+            let from = self.get_current_state()?.clone();
+            let mut events: Vec<E> = self.enabled_events().cloned().collect();
+            events.sort_by_cached_key(|event| format!("{:?}", event));
+            let weights: Vec<f64> = events
+                .iter()
+                .map(|event| self.transition_weight(&from, event))
+                .collect();
+            let Some(index) = pick_weighted(rng, &weights) else {
+                break;
+            };
+            let event = events.swap_remove(index);
+            self.dispatch(&event)?;
+            let to = self.get_current_state()?.clone();
+            walk.push(SimulatedStep { from, event, to });
+        }
+        Ok(walk)
+    }
+}
+
+type Edges<S, E> = HashMap<S, Vec<(E, S, f64)>>;
+
+/// Generates valid event sequences by walking the transitions declared with
+/// [`add_transition_to`](StateMachine::add_transition_to) and friends;
+/// handlers that choose their target at runtime are not followed.
+///
+/// A walk ends after [`max_length`](Self::max_length) events or on reaching
+/// a terminal state: one marked with [`terminal`](Self::terminal), or one
+/// with no outgoing transitions.
+pub struct EventSequences<S, E> {
+    edges: Edges<S, E>,
+    initial: S,
+    terminals: HashSet<S>,
+    max_length: usize,
+}
+
+impl<S: State, E: Event> EventSequences<S, E> {
+    /// Caps each walk at `max_length` events; 32 by default.
+    pub fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    pub fn terminal(mut self, state: S) -> Self {
+        self.terminals.insert(state);
+        self
+    }
+
+    fn is_terminal(&self, state: &S) -> bool {
+        self.terminals.contains(state) || !self.edges.contains_key(state)
+    }
+
+    /// One random walk's events, and whether it ended in a terminal state.
+    pub fn generate<R: Rng + ?Sized>(&self, rng: &mut R) -> (Vec<E>, bool) {
+        let mut events = Vec::new();
+        let mut state = &self.initial;
+        while !self.is_terminal(state) && events.len() < self.max_length {
+            let edges = &self.edges[state];
+            let weights: Vec<f64> = edges.iter().map(|(_, _, weight)| *weight).collect();
+            let Some(index) = pick_weighted(rng, &weights) else {
+                break;
+            };
+            events.push(edges[index].0.clone());
+            state = &edges[index].1;
+        }
+        (events, self.is_terminal(state))
+    }
+
+    /// A walk that reaches a terminal state within the length limit, trying
+    /// up to `attempts` walks.
+    pub fn generate_terminating<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        attempts: usize,
+    ) -> Option<Vec<E>> {
+        (0..attempts)
+            .map(|_| self.generate(rng))
+            .find(|(_, terminated)| *terminated)
+            .map(|(events, _)| events)
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+    O: Default,
+{
+    /// A generator of event sequences from the current state over the static
+    /// transition table, honouring [`transition_weight`](Self::transition_weight).
+    pub fn event_sequences(&self) -> Result<EventSequences<S, E>, StateMachineError<S, E>> {
+        let mut edges: Edges<S, E> = HashMap::new();
+        for ((from, event), to) in &self.targets {
+            let weight = self.transition_weight(from, event);
+            edges
+                .entry(from.clone())
+                .or_default()
+                .push((event.clone(), to.clone(), weight));
+        }
+        for targets in edges.values_mut() {
+            targets.sort_by_cached_key(|(event, _, _)| format!("{:?}", event));
+        }
+        Ok(EventSequences {
+            edges,
+            initial: self.get_current_state()?.clone(),
+            terminals: HashSet::new(),
+            max_length: 32,
+        })
+    }
+}
+
+/// Empirical transition frequencies learned from observed transitions: a
+/// persistence journal via [`from_journal`](Self::from_journal), or any other
+/// source via [`observe`](Self::observe).
+#[derive(Debug, Clone)]
+pub struct MarkovModel<S, E> {
+    /// Per source state, each observed `(event, to)` and how often it occurred,
+    /// in order of first observation.
+    counts: HashMap<S, Vec<(E, S, u64)>>,
+}
+
+impl<S: State, E: Event> Default for MarkovModel<S, E> {
+    fn default() -> Self {
+        MarkovModel {
+            counts: HashMap::new(),
+        }
+    }
+}
+
+impl<S: State, E: Event> MarkovModel<S, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_journal<'a, I>(journal: I) -> Self
+    where
+        I: IntoIterator<Item = &'a TransitionRecord<S, E>>,
+        S: 'a,
+        E: 'a,
+    {
+        let mut model = Self::new();
+        for record in journal {
+            model.observe(&record.from, &record.event, &record.to);
+        }
+        model
+    }
+
+    pub fn observe(&mut self, from: &S, event: &E, to: &S) {
+        let observed = self.counts.entry(from.clone()).or_default();
+        match observed.iter_mut().find(|(e, t, _)| e == event && t == to) {
+            Some((_, _, count)) => *count += 1,
+            None => observed.push((event.clone(), to.clone(), 1)),
+        }
+    }
+
+    /// The observed probability of `event` being dispatched in `from`.
+    pub fn probability(&self, from: &S, event: &E) -> f64 {
+        let Some(observed) = self.counts.get(from) else {
+            return 0.0;
+        };
+        let total: u64 = observed.iter().map(|(_, _, count)| count).sum();
+        let matching: u64 = observed
+            .iter()
+            .filter(|(e, _, _)| e == event)
+            .map(|(_, _, count)| count)
+            .sum();
+        matching as f64 / total as f64
+    }
+
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R, from: &S) -> Option<&(E, S, u64)> {
+        let observed = self.counts.get(from)?;
+        let weights: Vec<f64> = observed.iter().map(|(_, _, count)| *count as f64).collect();
+        pick_weighted(rng, &weights).map(|index| &observed[index])
+    }
+
+    /// Up to `length` events sampled from `initial`, following the observed
+    /// targets; stops early in a state never seen as a source.
+    pub fn generate<R: Rng + ?Sized>(&self, rng: &mut R, initial: &S, length: usize) -> Vec<E> {
+        let mut events = Vec::with_capacity(length);
+        let mut state = initial;
+        while events.len() < length {
+            let Some((event, to, _)) = self.sample(rng, state) else {
+                break;
+            };
+            events.push(event.clone());
+            state = to;
+        }
+        events
+    }
+
+    /// Dispatches up to `count` events into `machine`, each sampled from the
+    /// machine's actual current state, starting one every `interval`
+    /// (`Duration::ZERO` for as fast as possible). Stops early in a state
+    /// never seen as a source, and on the first dispatch error.
+    pub fn replay<C, O, R>(
+        &self,
+        machine: &mut StateMachine<S, E, C, O>,
+        rng: &mut R,
+        count: usize,
+        interval: Duration,
+    ) -> Result<Vec<SimulatedStep<S, E>>, StateMachineError<S, E>>
+    where
+        O: Default,
+        R: Rng + ?Sized,
+    {
+        let start = Instant::now();
+        let mut steps = Vec::with_capacity(count);
+        for index in 0..count {
+            let from = machine.get_current_state()?.clone();
+            let Some((event, _, _)) = self.sample(rng, &from) else {
+                break;
+            };
+            let due = start + interval * index as u32;
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+            machine.dispatch(event)?;
+            let to = machine.get_current_state()?.clone();
+            steps.push(SimulatedStep {
+                from,
+                event: event.clone(),
+                to,
+            });
+        }
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_weights_steer_the_walk() {
+        let mut sm = init_state_machine();
+        sm.set_transition_weight(CallState::Idle, CallEvent::Dial, 0.0);
+        sm.set_transition_weight(CallState::Connected, CallEvent::HangUp, 3.0);
+        assert_eq!(
+            sm.transition_weight(&CallState::Idle, &CallEvent::Dial),
+            0.0
+        );
+        assert_eq!(
+            sm.transition_weight(&CallState::Ringing, &CallEvent::Answer),
+            1.0
+        );
+
+        let walk = sm.simulate_random(&mut SplitMix64::new(7), 40).unwrap();
+        assert_eq!(walk.len(), 40);
+        assert!(walk.iter().all(|step| step.event != CallEvent::Dial));
+        assert_eq!(walk[0].event, CallEvent::Incoming);
+        for pair in walk.windows(2) {
+            assert_eq!(pair[0].to, pair[1].from);
+        }
+
+        let mut again = init_state_machine();
+        again.set_transition_weight(CallState::Idle, CallEvent::Dial, 0.0);
+        again.set_transition_weight(CallState::Connected, CallEvent::HangUp, 3.0);
+        assert_eq!(
+            again.simulate_random(&mut SplitMix64::new(7), 40).unwrap(),
+            walk
+        );
+    }
+
+    fn dialer() -> StateMachine<CallState, CallEvent, ()> {
+        let mut sm = StateMachine::new(CallState::Idle, ());
+        for (from, event, to) in [
+            (CallState::Idle, CallEvent::Dial, CallState::Dialing),
+            (CallState::Dialing, CallEvent::Answer, CallState::Connected),
+            (
+                CallState::Dialing,
+                CallEvent::HangUp,
+                CallState::Disconnected,
+            ),
+            (
+                CallState::Connected,
+                CallEvent::HangUp,
+                CallState::Disconnected,
+            ),
+            (CallState::Disconnected, CallEvent::Reset, CallState::Idle),
+        ] {
+            sm.add_transition_to(from, event, to);
+        }
+        sm
+    }
+
+    #[test]
+    fn test_event_sequences_follow_the_table() {
+        let sequences = dialer()
+            .event_sequences()
+            .unwrap()
+            .terminal(CallState::Disconnected);
+        let mut rng = SplitMix64::new(3);
+        for _ in 0..20 {
+            let events = sequences.generate_terminating(&mut rng, 10).unwrap();
+            assert_eq!(events.first(), Some(&CallEvent::Dial));
+            assert_eq!(events.last(), Some(&CallEvent::HangUp));
+
+            let mut sm = dialer();
+            for event in &events {
+                sm.dispatch(event).unwrap();
+            }
+            assert_eq!(sm.get_current_state().ok(), Some(&CallState::Disconnected));
+        }
+
+        let (events, terminated) = dialer()
+            .event_sequences()
+            .unwrap()
+            .max_length(5)
+            .generate(&mut rng);
+        assert_eq!(events.len(), 5);
+        assert!(!terminated);
+    }
+
+    #[test]
+    fn test_markov_model_replays_observed_traffic() {
+        let record = |from, event, to| TransitionRecord { from, event, to };
+        let mut journal = Vec::new();
+        for answered in [true, true, true, false] {
+            journal.push(record(CallState::Idle, CallEvent::Dial, CallState::Dialing));
+            if answered {
+                journal.push(record(
+                    CallState::Dialing,
+                    CallEvent::Answer,
+                    CallState::Connected,
+                ));
+            }
+            let from = if answered {
+                CallState::Connected
+            } else {
+                CallState::Dialing
+            };
+            journal.push(record(from, CallEvent::HangUp, CallState::Disconnected));
+            journal.push(record(
+                CallState::Disconnected,
+                CallEvent::Reset,
+                CallState::Idle,
+            ));
+        }
+        let model = MarkovModel::from_journal(&journal);
+        assert_eq!(
+            model.probability(&CallState::Dialing, &CallEvent::Answer),
+            0.75
+        );
+        assert_eq!(
+            model.probability(&CallState::Ringing, &CallEvent::Answer),
+            0.0
+        );
+
+        let mut rng = SplitMix64::new(11);
+        let events = model.generate(&mut rng, &CallState::Idle, 12);
+        assert_eq!(events.len(), 12);
+        assert_eq!(events[0], CallEvent::Dial);
+
+        let mut sm = dialer();
+        let steps = model
+            .replay(&mut sm, &mut rng, 8, Duration::from_millis(1))
+            .unwrap();
+        assert_eq!(steps.len(), 8);
+        assert_eq!(steps[0].from, CallState::Idle);
+        assert_eq!(sm.get_current_state().ok(), Some(&steps[7].to));
+
+        let mut ringing = StateMachine::<CallState, CallEvent, ()>::new(CallState::Ringing, ());
+        assert!(model
+            .replay(&mut ringing, &mut rng, 3, Duration::ZERO)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let mut rng = SplitMix64::new(1);
+        assert_eq!(pick_weighted(&mut rng, &[0.0, -1.0]), None);
+        assert_eq!(pick_weighted(&mut rng, &[0.0, 2.0, 0.0]), Some(1));
+        let picks = (0..1000)
+            .filter(|_| pick_weighted(&mut rng, &[1.0, 3.0]) == Some(1))
+            .count();
+        assert!((650..850).contains(&picks));
+    }
+}
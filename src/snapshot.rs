@@ -0,0 +1,223 @@
+use crate::generic::{Event, State, StateMachine, StateMachineError};
+use std::collections::BTreeMap;
+
+/// The state and context of a machine at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot<S, C> {
+    pub state: S,
+    pub context: C,
+}
+
+/// A snapshot in its stored representation `T` (a parsed JSON document, a
+/// row, ...), tagged with the schema version that wrote it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub data: T,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The snapshot was written by a newer schema than the one running.
+    NewerVersion { found: u32, current: u32 },
+    /// No step is registered to migrate out of version `from`.
+    MissingStep { from: u32 },
+    /// The step out of version `from` rejected the snapshot.
+    StepFailed { from: u32, reason: String },
+    /// The fully migrated snapshot could not be decoded into the machine's types.
+    Decode { reason: String },
+}
+
+pub type MigrationStep<T> = Box<dyn Fn(T) -> Result<T, String> + Send + Sync>;
+
+/// An ordered chain of single-version migration steps up to `current_version`.
+pub struct Migrations<T> {
+    current_version: u32,
+    steps: BTreeMap<u32, MigrationStep<T>>,
+}
+
+impl<T> Migrations<T> {
+    pub fn new(current_version: u32) -> Self {
+        Migrations {
+            current_version,
+            steps: BTreeMap::new(),
+        }
+    }
+
+    pub fn current_version(&self) -> u32 {
+        self.current_version
+    }
+
+    /// Registers the step migrating snapshots from version `from` to `from + 1`.
+    pub fn register<F>(mut self, from: u32, step: F) -> Self
+    where
+        F: Fn(T) -> Result<T, String> + 'static + Send + Sync,
+    {
+        self.steps.insert(from, Box::new(step));
+        self
+    }
+
+    /// Chains the registered steps to bring `stored` up to the current version.
+    pub fn migrate(&self, stored: Versioned<T>) -> Result<T, MigrationError> {
+        if stored.version > self.current_version {
+            return Err(MigrationError::NewerVersion {
+                found: stored.version,
+                current: self.current_version,
+            });
+        }
+
+        let mut data = stored.data;
+        for from in stored.version..self.current_version {
+            let step = self
+                .steps
+                .get(&from)
+                .ok_or(MigrationError::MissingStep { from })?;
+            data = step(data).map_err(|reason| MigrationError::StepFailed { from, reason })?;
+        }
+        Ok(data)
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    pub fn snapshot(&self) -> Result<Snapshot<S, C>, StateMachineError<S, E>>
+    where
+        C: Clone,
+    {
+        Ok(Snapshot {
+            state: self.get_current_state()?.clone(),
+            context: self.context.clone(),
+        })
+    }
+
+    /// Replaces the current state and context; transitions and observers are
+    /// kept, state-local values are dropped.
+    pub fn restore(&mut self, snapshot: Snapshot<S, C>) {
+        self.state_locals.exit();
+        self.current_state = Some(snapshot.state);
+        self.context = snapshot.context;
+        self.announce_state();
+    }
+
+    /// Migrates `stored` to the current schema, decodes it and restores it.
+    /// The machine is left untouched if any step fails.
+    pub fn restore_versioned<T, D>(
+        &mut self,
+        stored: Versioned<T>,
+        migrations: &Migrations<T>,
+        decode: D,
+    ) -> Result<(), MigrationError>
+    where
+        D: FnOnce(T) -> Result<Snapshot<S, C>, String>,
+    {
+        let data = migrations.migrate(stored)?;
+        let snapshot = decode(data).map_err(|reason| MigrationError::Decode { reason })?;
+        self.restore(snapshot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Stateful;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::collections::HashMap;
+
+    type Stored = HashMap<String, String>;
+
+    // v1 called the connected state "Talking"; v3 renamed the "count" key to "dials".
+    fn migrations() -> Migrations<Stored> {
+        Migrations::new(3)
+            .register(1, |mut old: Stored| {
+                if old.get("state").map(String::as_str) == Some("Talking") {
+                    old.insert("state".into(), "Connected".into());
+                }
+                Ok(old)
+            })
+            .register(2, |mut old: Stored| {
+                let count = old.remove("count").ok_or("missing count")?;
+                old.insert("dials".into(), count);
+                Ok(old)
+            })
+    }
+
+    fn decode(data: Stored) -> Result<Snapshot<CallState, HashMap<String, usize>>, String> {
+        let state = match data.get("state").map(String::as_str) {
+            Some("Connected") => CallState::Connected,
+            Some("Idle") => CallState::Idle,
+            other => return Err(format!("unknown state {:?}", other)),
+        };
+        let dials = data["dials"].parse().map_err(|_| "bad dials")?;
+        Ok(Snapshot {
+            state,
+            context: HashMap::from([("dials".to_string(), dials)]),
+        })
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut sm = init_state_machine();
+        sm.handle_event(&CallEvent::Dial).unwrap();
+        let snapshot = sm.snapshot().unwrap();
+
+        let mut restored = init_state_machine();
+        restored.restore(snapshot);
+        assert_eq!(restored.get_current_state().unwrap(), &CallState::Dialing);
+        restored.handle_event(&CallEvent::Answer).unwrap();
+    }
+
+    #[test]
+    fn test_restore_chains_migrations() {
+        let mut sm = init_state_machine();
+        let stored = Versioned {
+            version: 1,
+            data: Stored::from([
+                ("state".into(), "Talking".into()),
+                ("count".into(), "2".into()),
+            ]),
+        };
+        sm.restore_versioned(stored, &migrations(), decode).unwrap();
+
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+        assert_eq!(sm.get_context()["dials"], 2);
+    }
+
+    #[test]
+    fn test_unmigratable_snapshots() {
+        let mut sm = init_state_machine();
+        let newer = Versioned {
+            version: 4,
+            data: Stored::new(),
+        };
+        assert!(matches!(
+            sm.restore_versioned(newer, &migrations(), decode),
+            Err(MigrationError::NewerVersion {
+                found: 4,
+                current: 3
+            })
+        ));
+
+        let missing_count = Versioned {
+            version: 2,
+            data: Stored::from([("state".into(), "Idle".into())]),
+        };
+        assert!(matches!(
+            sm.restore_versioned(missing_count, &migrations(), decode),
+            Err(MigrationError::StepFailed { from: 2, .. })
+        ));
+
+        let too_old = Versioned {
+            version: 0,
+            data: Stored::new(),
+        };
+        assert!(matches!(
+            sm.restore_versioned(too_old, &migrations(), decode),
+            Err(MigrationError::MissingStep { from: 0 })
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+    }
+}
@@ -0,0 +1,585 @@
+//! Machine definitions loaded from text.
+//!
+//! A spec has one `initial <State>` line and one `From --Event--> To` line per
+//! transition. Blank lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! initial Idle
+//! Idle --Dial--> Dialing
+//! Dialing --HangUp--> Disconnected
+//! ```
+
+use crate::generic::{Event, Response, State, StateMachine, TransitionFunction};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Debug)]
+pub enum SpecError {
+    Io(io::Error),
+    Parse {
+        line: usize,
+        message: String,
+    },
+    MissingInitial,
+    UnknownState {
+        line: usize,
+        name: String,
+    },
+    UnknownEvent {
+        line: usize,
+        name: String,
+    },
+    DuplicateTransition {
+        line: usize,
+        from: String,
+        event: String,
+    },
+    StateRemoved {
+        state: String,
+    },
+}
+
+impl From<io::Error> for SpecError {
+    fn from(e: io::Error) -> Self {
+        SpecError::Io(e)
+    }
+}
+
+/// A spec error located in its source, rendered compiler-style by `Display`:
+///
+/// ```text
+/// error: transition references unknown state `Connected_`
+///  --> line 14
+///    |
+/// 14 | Ringing --Answer--> Connected_
+///    |                     ^^^^^^^^^^
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub line: usize,
+    pub source_line: String,
+    /// Byte range within `source_line` of the offending text.
+    pub span: Range<usize>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let lead = self.source_line[..self.span.start].chars().count();
+        let width = self.source_line[self.span.clone()].chars().count().max(1);
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{} --> line {}", gutter, self.line)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", number, self.source_line)?;
+        write!(f, "{} | {}{}", gutter, " ".repeat(lead), "^".repeat(width))
+    }
+}
+
+/// The byte range of `name` in `line` as a whole word, else of the trimmed line.
+fn span_of(line: &str, name: Option<&str>) -> Range<usize> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    name.filter(|name| !name.is_empty())
+        .and_then(|name| {
+            line.match_indices(name)
+                .map(|(start, _)| start..start + name.len())
+                .find(|span| {
+                    !line[..span.start].ends_with(is_word) && !line[span.end..].starts_with(is_word)
+                })
+        })
+        .unwrap_or_else(|| {
+            let start = line.len() - line.trim_start().len();
+            start..line.trim_end().len().max(start)
+        })
+}
+
+impl SpecError {
+    /// Locates the error in `source`, the spec text it came from. Errors
+    /// without a line, such as I/O failures, have no diagnostic.
+    pub fn diagnostic(&self, source: &str) -> Option<Diagnostic> {
+        let (line, message, name) = match self {
+            SpecError::Parse { line, message } => (*line, message.clone(), None),
+            SpecError::UnknownState { line, name } => (
+                *line,
+                format!("transition references unknown state `{}`", name),
+                Some(name),
+            ),
+            SpecError::UnknownEvent { line, name } => {
+                (*line, format!("unknown event `{}`", name), Some(name))
+            }
+            SpecError::DuplicateTransition { line, from, event } => (
+                *line,
+                format!("`{}` already has a transition on `{}`", from, event),
+                Some(event),
+            ),
+            SpecError::Io(_) | SpecError::MissingInitial | SpecError::StateRemoved { .. } => {
+                return None
+            }
+        };
+        let source_line = source.lines().nth(line.checked_sub(1)?)?.to_string();
+        let span = span_of(&source_line, name.map(String::as_str));
+        Some(Diagnostic {
+            message,
+            line,
+            source_line,
+            span,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransitionSpec {
+    pub from: String,
+    pub event: String,
+    pub to: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineSpec {
+    pub initial: String,
+    pub initial_line: usize,
+    pub transitions: Vec<TransitionSpec>,
+}
+
+impl fmt::Display for MachineSpec {
+    /// Writes the spec back in the text format [`parse`](Self::parse) reads.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "initial {}", self.initial)?;
+        for t in &self.transitions {
+            writeln!(f, "{} --{}--> {}", t.from, t.event, t.to)?;
+        }
+        Ok(())
+    }
+}
+
+pub type TransitionTable<S, E, C, O = ()> = HashMap<(S, E), TransitionFunction<S, E, C, O>>;
+
+fn parse_name<T: FromStr>(name: &str) -> Option<T> {
+    name.parse().ok()
+}
+
+impl MachineSpec {
+    pub fn parse(source: &str) -> Result<Self, SpecError> {
+        let mut initial = None;
+        let mut transitions = Vec::new();
+
+        for (index, raw) in source.lines().enumerate() {
+            let line = index + 1;
+            let text = raw.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = text.strip_prefix("initial ") {
+                if initial.is_some() {
+                    return Err(SpecError::Parse {
+                        line,
+                        message: String::from("initial state declared twice"),
+                    });
+                }
+                initial = Some((name.trim().to_string(), line));
+                continue;
+            }
+
+            let parsed = text.split_once("--").and_then(|(from, rest)| {
+                let (event, to) = rest.split_once("-->")?;
+                Some((from.trim(), event.trim(), to.trim()))
+            });
+            match parsed {
+                Some((from, event, to))
+                    if !from.is_empty() && !event.is_empty() && !to.is_empty() =>
+                {
+                    transitions.push(TransitionSpec {
+                        from: from.to_string(),
+                        event: event.to_string(),
+                        to: to.to_string(),
+                        line,
+                    })
+                }
+                _ => {
+                    return Err(SpecError::Parse {
+                        line,
+                        message: format!("expected `From --Event--> To`, found `{}`", text),
+                    })
+                }
+            }
+        }
+
+        let (initial, initial_line) = initial.ok_or(SpecError::MissingInitial)?;
+        Ok(MachineSpec {
+            initial,
+            initial_line,
+            transitions,
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, SpecError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Names of every state mentioned by the spec.
+    pub fn states(&self) -> BTreeSet<&str> {
+        std::iter::once(self.initial.as_str())
+            .chain(
+                self.transitions
+                    .iter()
+                    .flat_map(|t| [t.from.as_str(), t.to.as_str()]),
+            )
+            .collect()
+    }
+
+    /// Validates every name against `S` and `E` and builds the transition table.
+    pub fn transition_table<S, E, C, O>(&self) -> Result<TransitionTable<S, E, C, O>, SpecError>
+    where
+        S: State + FromStr + Send + Sync + 'static,
+        E: Event + FromStr,
+        O: Default,
+    {
+        let mut table: TransitionTable<S, E, C, O> = HashMap::new();
+        for (key, to) in self.targets::<S, E>()? {
+            let handler: TransitionFunction<S, E, C, O> =
+                Arc::new(move |_sm, _event| Ok((Response::Transition(to.clone()), O::default())));
+            table.insert(key, handler);
+        }
+        Ok(table)
+    }
+
+    /// Validates every name against `S` and `E`, mapping each `(from, event)` to its target.
+    fn targets<S, E>(&self) -> Result<HashMap<(S, E), S>, SpecError>
+    where
+        S: State + FromStr,
+        E: Event + FromStr,
+    {
+        let mut targets = HashMap::new();
+        for t in &self.transitions {
+            let unknown_state = |name: &str| SpecError::UnknownState {
+                line: t.line,
+                name: name.to_string(),
+            };
+            let from: S = parse_name(&t.from).ok_or_else(|| unknown_state(&t.from))?;
+            let to: S = parse_name(&t.to).ok_or_else(|| unknown_state(&t.to))?;
+            let event: E = parse_name(&t.event).ok_or_else(|| SpecError::UnknownEvent {
+                line: t.line,
+                name: t.event.clone(),
+            })?;
+
+            if targets.contains_key(&(from.clone(), event.clone())) {
+                return Err(SpecError::DuplicateTransition {
+                    line: t.line,
+                    from: t.from.clone(),
+                    event: t.event.clone(),
+                });
+            }
+            targets.insert((from, event), to);
+        }
+        Ok(targets)
+    }
+
+    pub fn build<S, E, C, O>(&self, context: C) -> Result<StateMachine<S, E, C, O>, SpecError>
+    where
+        S: State + FromStr + Send + Sync + 'static,
+        E: Event + FromStr,
+        O: Default,
+    {
+        let initial: S = parse_name(&self.initial).ok_or_else(|| SpecError::UnknownState {
+            line: self.initial_line,
+            name: self.initial.clone(),
+        })?;
+        let mut sm = StateMachine::new(initial, context);
+        sm.transitions = self.transition_table()?;
+        sm.targets = self.targets()?;
+        Ok(sm)
+    }
+}
+
+/// Maps a state dropped by a reload to its replacement and the event the
+/// move is reported under.
+pub type StateRemap<S, E> = Box<dyn Fn(&S) -> Option<(S, E)> + Send>;
+
+/// Reloads a machine's transitions whenever its spec file changes.
+///
+/// Call [`SpecWatcher::poll`] from the loop that owns the machine; a changed
+/// file is parsed and validated in full before the table is swapped in, so a
+/// broken edit leaves the running machine untouched. Guards, metadata and
+/// retry policies of transitions the new spec drops are discarded with them.
+pub struct SpecWatcher<S, E> {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    remap: Option<StateRemap<S, E>>,
+}
+
+impl<S, E> SpecWatcher<S, E>
+where
+    S: State + FromStr + Send + Sync + 'static,
+    E: Event + FromStr,
+{
+    /// Watches `path`, treating its current contents as already loaded.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        SpecWatcher {
+            path,
+            modified,
+            remap: None,
+        }
+    }
+
+    /// Maps the current state when a reload drops it from the spec;
+    /// returning `None` refuses the reload. The machine moves to the new
+    /// state like any transition on the returned event, running exit and
+    /// entry hooks and notifying observers.
+    pub fn with_remap<F>(mut self, remap: F) -> Self
+    where
+        F: Fn(&S) -> Option<(S, E)> + Send + 'static,
+    {
+        self.remap = Some(Box::new(remap));
+        self
+    }
+
+    /// Reloads the spec if the file changed since the last successful
+    /// reload, so a broken edit is retried on every poll until it is fixed.
+    /// Returns `Ok(true)` when a new table was swapped in.
+    pub fn poll<C, O>(&mut self, sm: &mut StateMachine<S, E, C, O>) -> Result<bool, SpecError>
+    where
+        O: Default,
+    {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        if self.modified == Some(modified) {
+            return Ok(false);
+        }
+        self.reload(sm)?;
+        self.modified = Some(modified);
+        Ok(true)
+    }
+
+    /// Unconditionally reloads the spec into `sm`.
+    pub fn reload<C, O>(&self, sm: &mut StateMachine<S, E, C, O>) -> Result<(), SpecError>
+    where
+        O: Default,
+    {
+        let spec = MachineSpec::from_file(&self.path)?;
+        let table = spec.transition_table::<S, E, C, O>()?;
+        let states: Vec<S> = spec.states().into_iter().filter_map(parse_name).collect();
+
+        let remapped = match &sm.current_state {
+            Some(current) if !states.contains(current) => {
+                let mapped = self
+                    .remap
+                    .as_ref()
+                    .and_then(|remap| remap(current))
+                    .filter(|(mapped, _)| states.contains(mapped));
+                match mapped {
+                    Some(mapped) => Some(mapped),
+                    None => {
+                        return Err(SpecError::StateRemoved {
+                            state: format!("{:?}", current),
+                        })
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        sm.targets = spec.targets()?;
+        sm.transitions = table;
+        sm.async_transitions.clear();
+        let transitions = &sm.transitions;
+        sm.guards.retain(|key, _| transitions.contains_key(key));
+        sm.transition_metadata
+            .retain(|key, _| transitions.contains_key(key));
+        sm.retries.retain(|key, _| transitions.contains_key(key));
+        if let Some((state, event)) = remapped {
+            sm.commit(state, &event);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Stateful;
+    use crate::metadata::TransitionMetadata;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Light {
+        Off,
+        On,
+        Dimmed,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Switch {
+        Toggle,
+        Dim,
+    }
+
+    impl FromStr for Light {
+        type Err = ();
+        fn from_str(s: &str) -> Result<Self, ()> {
+            match s {
+                "Off" => Ok(Light::Off),
+                "On" => Ok(Light::On),
+                "Dimmed" => Ok(Light::Dimmed),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl FromStr for Switch {
+        type Err = ();
+        fn from_str(s: &str) -> Result<Self, ()> {
+            match s {
+                "Toggle" => Ok(Switch::Toggle),
+                "Dim" => Ok(Switch::Dim),
+                _ => Err(()),
+            }
+        }
+    }
+
+    const SPEC: &str =
+        "# light\ninitial Off\nOff --Toggle--> On\nOn --Toggle--> Off\nOn --Dim--> Dimmed\n";
+
+    #[test]
+    fn test_build_from_spec() {
+        let spec = MachineSpec::parse(SPEC).unwrap();
+        let mut sm: StateMachine<Light, Switch, ()> = spec.build(()).unwrap();
+        assert_eq!(
+            sm.static_target(&Light::Off, &Switch::Toggle),
+            Some(&Light::On)
+        );
+
+        sm.handle_event(&Switch::Toggle).unwrap();
+        sm.handle_event(&Switch::Dim).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &Light::Dimmed);
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        assert!(matches!(
+            MachineSpec::parse("Off --Toggle--> On"),
+            Err(SpecError::MissingInitial)
+        ));
+        assert!(matches!(
+            MachineSpec::parse("initial Off\nOff -> On"),
+            Err(SpecError::Parse { line: 2, .. })
+        ));
+
+        let spec = MachineSpec::parse("initial Off\n\nOff --Toggle--> Blinking").unwrap();
+        assert!(matches!(
+            spec.build::<Light, Switch, (), ()>(()),
+            Err(SpecError::UnknownState { line: 3, ref name }) if name == "Blinking"
+        ));
+    }
+
+    #[test]
+    fn test_diagnostics_point_at_the_name() {
+        let source = "initial Off\nOff --Toggle--> Off_";
+        let error = MachineSpec::parse(source)
+            .unwrap()
+            .build::<Light, Switch, (), ()>(())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.diagnostic(source).unwrap().to_string(),
+            "error: transition references unknown state `Off_`\n  \
+             --> line 2\n  |\n2 | Off --Toggle--> Off_\n  |                 ^^^^"
+        );
+
+        let source = "initial Off\n  Off -> On  ";
+        let diagnostic = MachineSpec::parse(source)
+            .unwrap_err()
+            .diagnostic(source)
+            .unwrap();
+        assert_eq!(&diagnostic.source_line[diagnostic.span], "Off -> On");
+    }
+
+    #[test]
+    fn test_reload_swaps_table_or_refuses() {
+        let path = std::env::temp_dir().join(format!("fsmportal-spec-{}.fsm", std::process::id()));
+        fs::write(&path, SPEC).unwrap();
+        let mut sm: StateMachine<Light, Switch, ()> =
+            MachineSpec::from_file(&path).unwrap().build(()).unwrap();
+        let mut watcher = SpecWatcher::new(&path);
+        assert!(!watcher.poll(&mut sm).unwrap());
+
+        sm.handle_event(&Switch::Toggle).unwrap();
+        fs::write(
+            &path,
+            "initial Off\nOn --Dim--> Dimmed\nDimmed --Toggle--> Off\n",
+        )
+        .unwrap();
+        watcher.reload(&mut sm).unwrap();
+        assert!(sm.handle_event(&Switch::Toggle).is_err());
+
+        sm.handle_event(&Switch::Dim).unwrap();
+        fs::write(&path, "initial Off\nOff --Toggle--> On\n").unwrap();
+        assert!(matches!(
+            watcher.reload(&mut sm),
+            Err(SpecError::StateRemoved { .. })
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &Light::Dimmed);
+
+        let watcher = watcher.with_remap(|_| Some((Light::Off, Switch::Toggle)));
+        let exits = Arc::new(Mutex::new(Vec::new()));
+        let seen = exits.clone();
+        sm.add_exit_hook(Light::Dimmed, move |_, from, to, event| {
+            seen.lock()
+                .unwrap()
+                .push((from.clone(), event.clone(), to.clone()))
+        });
+        watcher.reload(&mut sm).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &Light::Off);
+        assert_eq!(
+            *exits.lock().unwrap(),
+            [(Light::Dimmed, Switch::Toggle, Light::Off)]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_prunes_dropped_pairs_and_retries_broken_edits() {
+        let path =
+            std::env::temp_dir().join(format!("fsmportal-spec-prune-{}.fsm", std::process::id()));
+        fs::write(&path, SPEC).unwrap();
+        let mut sm: StateMachine<Light, Switch, ()> =
+            MachineSpec::from_file(&path).unwrap().build(()).unwrap();
+        sm.set_transition_metadata(
+            Light::On,
+            Switch::Dim,
+            TransitionMetadata::new().label("dim"),
+        );
+        let mut watcher = SpecWatcher::new(&path);
+
+        fs::write(
+            &path,
+            "initial Off\nOff --Toggle--> On\nOn --Toggle--> Off\n",
+        )
+        .unwrap();
+        watcher.reload(&mut sm).unwrap();
+        fs::write(&path, SPEC).unwrap();
+        watcher.reload(&mut sm).unwrap();
+        // The re-added transition does not inherit the dropped one's metadata.
+        assert!(sm.transition_metadata(&Light::On, &Switch::Dim).is_none());
+
+        fs::write(&path, "initial Off\nOff -> On\n").unwrap();
+        assert!(watcher.poll(&mut sm).is_err());
+        assert!(watcher.poll(&mut sm).is_err());
+        fs::write(&path, "initial Off\nOff --Dim--> Dimmed\n").unwrap();
+        assert!(watcher.poll(&mut sm).unwrap());
+        assert!(!watcher.poll(&mut sm).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
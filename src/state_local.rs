@@ -0,0 +1,132 @@
+//! Storage scoped to the current state.
+//!
+//! A state-local value lives only while the machine stays in the state that
+//! created it: everything stored is dropped as soon as a transition leaves the
+//! state, after its exit hooks, so resources such as a ring-tone task handle
+//! are released however the state is exited. Self-transitions exit and
+//! re-enter, and so start afresh.
+//!
+//! Values are created by initializers registered with
+//! [`add_state_local`](StateMachine::add_state_local), which run after the
+//! state's entry hooks, or stored from a handler with
+//! [`set_state_local`](StateMachine::set_state_local). Initializers do not run
+//! for the initial state, which is never entered. Restoring a snapshot also
+//! drops every value.
+
+use crate::extensions::Extensions;
+use crate::generic::{Event, State, StateMachine};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Creates a state-local value from the context and the entering event.
+type StateLocalInit<E, C> = Arc<dyn Fn(&mut C, &E, &mut Extensions) + Send + Sync>;
+
+pub(crate) struct StateLocals<S, E, C> {
+    inits: HashMap<S, Vec<StateLocalInit<E, C>>>,
+    /// The current state's values.
+    pub(crate) values: Extensions,
+}
+
+impl<S: State, E, C> StateLocals<S, E, C> {
+    pub(crate) fn new() -> Self {
+        StateLocals {
+            inits: HashMap::new(),
+            values: Extensions::new(),
+        }
+    }
+
+    /// Drops every value of the state being left.
+    pub(crate) fn exit(&mut self) {
+        self.values = Extensions::new();
+    }
+
+    pub(crate) fn enter(&mut self, state: &S, context: &mut C, event: &E) {
+        for init in self.inits.get(state).into_iter().flatten() {
+            init(context, event, &mut self.values);
+        }
+    }
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// Stores the value `init` returns each time `state` is entered, until it
+    /// is exited. A later initializer for the same type replaces the value.
+    pub fn add_state_local<T, F>(&mut self, state: S, init: F)
+    where
+        T: Any + Send + Sync,
+        F: Fn(&mut C, &E) -> T + 'static + Send + Sync,
+    {
+        self.state_locals
+            .inits
+            .entry(state)
+            .or_default()
+            .push(Arc::new(move |context, event, values| {
+                values.insert(init(context, event));
+            }));
+    }
+
+    /// The current state's value of type `T`.
+    pub fn state_local<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.state_locals.values.get()
+    }
+
+    pub fn state_local_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.state_locals.values.get_mut()
+    }
+
+    /// Stores `value` until the current state is exited, returning the
+    /// previous value of the same type. A handler storing a value and then
+    /// transitioning away sees it dropped straight away.
+    pub fn set_state_local<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.state_locals.values.insert(value)
+    }
+
+    pub fn take_state_local<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.state_locals.values.remove()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Stands in for the handle of a task playing the ring tone.
+    struct RingTone(Arc<AtomicUsize>);
+
+    impl Drop for RingTone {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_state_local_dropped_on_any_exit() {
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let mut sm = init_state_machine();
+        let tones = stopped.clone();
+        sm.add_state_local(CallState::Ringing, move |_, _| RingTone(tones.clone()));
+
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        assert!(sm.state_local::<RingTone>().is_some());
+        sm.dispatch(&CallEvent::Answer).unwrap();
+        assert!(sm.state_local::<RingTone>().is_none());
+        assert_eq!(stopped.load(Ordering::SeqCst), 1);
+
+        sm.dispatch(&CallEvent::HangUp).unwrap();
+        sm.dispatch(&CallEvent::Reset).unwrap();
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        sm.dispatch(&CallEvent::HangUp).unwrap();
+        assert_eq!(stopped.load(Ordering::SeqCst), 2);
+
+        assert_eq!(sm.set_state_local(5u32), None);
+        assert_eq!(sm.state_local::<u32>(), Some(&5));
+        sm.dispatch(&CallEvent::Reset).unwrap();
+        assert_eq!(sm.state_local::<u32>(), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Machines whose transition table is compiled into a single `match`.
+//!
+//! [`static_machine!`](crate::static_machine) trades the runtime table of
+//! `Arc<dyn Fn>` handlers for a generated `match (state, event)`, so
+//! dispatching makes no virtual calls and touches no reference counts. Every
+//! transition has a static target and an optional action on the context;
+//! hierarchy, guards, observers and the other runtime extensions are not
+//! available.
+
+/// Generates a struct whose `dispatch` is one `match` over the listed transitions.
+///
+/// States and events must be enums in scope; each line names the source state,
+/// the event, the target state and, optionally, an action run with the
+/// context and event before the state changes.
+///
+/// ```
+/// use fsmportal::{static_machine, CallEvent, CallState};
+///
+/// static_machine! {
+///     pub struct Phone: CallState, CallEvent, u32;
+///     Idle, Incoming => Ringing;
+///     Ringing, Answer => Connected, |answered: &mut u32, _| *answered += 1;
+///     Connected, HangUp => Disconnected;
+/// }
+///
+/// let mut phone = Phone::new(CallState::Idle, 0);
+/// phone.dispatch(&CallEvent::Incoming).unwrap();
+/// phone.dispatch(&CallEvent::Answer).unwrap();
+/// assert_eq!(phone.state(), &CallState::Connected);
+/// assert_eq!(*phone.context(), 1);
+/// ```
+#[macro_export]
+macro_rules! static_machine {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident : $state:ident, $event:ident, $context:ty;
+        $($from:ident, $on:ident => $to:ident $(, $action:expr)?;)*
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            state: $state,
+            context: $context,
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            $vis fn new(initial_state: $state, context: $context) -> Self {
+                $name {
+                    state: initial_state,
+                    context,
+                }
+            }
+
+            $vis fn state(&self) -> &$state {
+                &self.state
+            }
+
+            $vis fn context(&self) -> &$context {
+                &self.context
+            }
+
+            $vis fn context_mut(&mut self) -> &mut $context {
+                &mut self.context
+            }
+
+            $vis fn dispatch(
+                &mut self,
+                event: &$event,
+            ) -> ::std::result::Result<
+                $crate::generic::Response<$state>,
+                $crate::generic::StateMachineError<$state, $event>,
+            > {
+                #[allow(unreachable_patterns)]
+                let to = match (&self.state, event) {
+                    $(($state::$from, $event::$on) => {
+                        $(($action)(&mut self.context, event);)?
+                        $state::$to
+                    })*
+                    _ => {
+                        return Err($crate::generic::StateMachineError::TransitionNotFound {
+                            from: self.state.clone(),
+                            event: event.clone(),
+                        })
+                    }
+                };
+                self.state = to.clone();
+                Ok($crate::generic::Response::Transition(to))
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generic::{Response, StateMachineError};
+    use crate::{CallEvent, CallState};
+
+    static_machine! {
+        struct Phone: CallState, CallEvent, Vec<CallEvent>;
+        Idle, Dial => Dialing, |log: &mut Vec<CallEvent>, event: &CallEvent| log.push(event.clone());
+        Dialing, HangUp => Disconnected;
+        Disconnected, Reset => Idle;
+    }
+
+    #[test]
+    fn test_generated_dispatch() {
+        let mut phone = Phone::new(CallState::Idle, Vec::new());
+        assert!(matches!(
+            phone.dispatch(&CallEvent::Dial),
+            Ok(Response::Transition(CallState::Dialing))
+        ));
+        assert!(matches!(
+            phone.dispatch(&CallEvent::Answer),
+            Err(StateMachineError::TransitionNotFound {
+                from: CallState::Dialing,
+                event: CallEvent::Answer
+            })
+        ));
+        phone.dispatch(&CallEvent::HangUp).unwrap();
+        assert_eq!(phone.state(), &CallState::Disconnected);
+        assert_eq!(phone.context(), &vec![CallEvent::Dial]);
+    }
+}
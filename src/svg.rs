@@ -0,0 +1,491 @@
+//! SVG state diagrams without external tools.
+//!
+//! [`StateMachine::to_svg`] lays the diagram out itself: states are ranked left
+//! to right by their distance from the current (or initial) state, each rank is
+//! ordered by the average position of its predecessors, and edges are drawn as
+//! curves, looping below the diagram when they point backwards. The current
+//! state is highlighted. The output is a standalone document that any browser
+//! can open; each state's group carries a `data-state` attribute with its
+//! Debug name, so pages can move the highlight without re-rendering.
+
+use crate::export::{choice_id, edge_label, node_label};
+use crate::generic::{Event, State, StateMachine};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+
+const MARGIN: f64 = 20.0;
+// Room above the diagram for self-loops and below it for backward edges.
+const LOOP_SPACE: f64 = 40.0;
+const BACK_SPACE: f64 = 60.0;
+const RANK_GAP: f64 = 90.0;
+const ROW_GAP: f64 = 36.0;
+const NODE_HEIGHT: f64 = 36.0;
+const CHOICE_SIZE: f64 = 18.0;
+// Rough advance of a 13px sans-serif glyph, enough to size boxes.
+const CHAR_WIDTH: f64 = 7.5;
+const FONT: &str = "font-family=\"sans-serif\" font-size=\"13\"";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct Node {
+    // The state's Debug name, whatever its label.
+    name: String,
+    label: String,
+    description: Option<String>,
+    choice: bool,
+    width: f64,
+    rank: usize,
+    x: f64,
+    y: f64,
+}
+
+impl Node {
+    fn height(&self) -> f64 {
+        if self.choice {
+            CHOICE_SIZE
+        } else {
+            NODE_HEIGHT
+        }
+    }
+}
+
+struct Edge {
+    from: usize,
+    to: usize,
+    label: Option<String>,
+    description: Option<String>,
+    line: Line,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Line {
+    Transition,
+    Eventless,
+    // Parent to substate; drawn without an arrow, since nothing is traversed.
+    Nesting,
+}
+
+/// Ranks every node by breadth-first distance from `start`, then from each
+/// node left unreached, in order.
+fn rank(nodes: &mut [Node], edges: &[Edge], start: Option<usize>) {
+    let mut ranked = vec![false; nodes.len()];
+    for seed in start.into_iter().chain(0..nodes.len()) {
+        if ranked[seed] {
+            continue;
+        }
+        ranked[seed] = true;
+        nodes[seed].rank = 0;
+        let mut queue = VecDeque::from([seed]);
+        while let Some(node) = queue.pop_front() {
+            for edge in edges.iter().filter(|e| e.from == node) {
+                if ranked[edge.to] {
+                    continue;
+                }
+                ranked[edge.to] = true;
+                nodes[edge.to].rank = nodes[node].rank + 1;
+                queue.push_back(edge.to);
+            }
+        }
+    }
+}
+
+/// Orders each rank by the mean row of its predecessors in earlier ranks and
+/// assigns coordinates. Returns the rows, one list of node indexes per rank.
+fn place(nodes: &mut [Node], edges: &[Edge]) -> Vec<Vec<usize>> {
+    let ranks = nodes.iter().map(|n| n.rank + 1).max().unwrap_or(0);
+    let mut rows: Vec<Vec<usize>> = vec![Vec::new(); ranks];
+    for (index, node) in nodes.iter().enumerate() {
+        rows[node.rank].push(index);
+    }
+    let mut row_of = vec![0.0; nodes.len()];
+    for (rank, row) in rows.iter_mut().enumerate() {
+        let mut keyed: Vec<(f64, usize)> = row
+            .iter()
+            .enumerate()
+            .map(|(row, &index)| {
+                let parents: Vec<f64> = edges
+                    .iter()
+                    .filter(|e| e.to == index && nodes[e.from].rank < rank)
+                    .map(|e| row_of[e.from])
+                    .collect();
+                let key = if parents.is_empty() {
+                    row as f64
+                } else {
+                    parents.iter().sum::<f64>() / parents.len() as f64
+                };
+                (key, index)
+            })
+            .collect();
+        keyed.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        *row = keyed.into_iter().map(|(_, index)| index).collect();
+        for (position, &index) in row.iter().enumerate() {
+            row_of[index] = position as f64;
+        }
+    }
+
+    let tallest = rows.iter().map(Vec::len).max().unwrap_or(0) as f64;
+    let mut x = MARGIN;
+    for row in &rows {
+        let width = row.iter().map(|&i| nodes[i].width).fold(0.0, f64::max);
+        let offset = (tallest - row.len() as f64) * (NODE_HEIGHT + ROW_GAP) / 2.0;
+        for (position, &index) in row.iter().enumerate() {
+            let node = &mut nodes[index];
+            node.x = x + width / 2.0;
+            node.y = MARGIN
+                + LOOP_SPACE
+                + offset
+                + position as f64 * (NODE_HEIGHT + ROW_GAP)
+                + NODE_HEIGHT / 2.0;
+        }
+        x += width + RANK_GAP;
+    }
+    rows
+}
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State,
+    E: Event,
+{
+    /// Renders the transition table as a standalone SVG document, with the
+    /// current state highlighted.
+    pub fn to_svg(&self) -> String {
+        let mut nodes = Vec::new();
+        let mut index = BTreeMap::new();
+        let targets = self.target_names();
+        let mut states = self.state_names();
+        // Static targets need a box even when nothing else mentions them.
+        for (_, _, to) in targets.keys() {
+            states.entry(to.clone()).or_insert(None);
+        }
+        for (state, metadata) in states {
+            let label = match metadata {
+                Some(metadata) => node_label(&state, metadata, " "),
+                None => state.clone(),
+            };
+            index.insert(state.clone(), nodes.len());
+            nodes.push(Node {
+                name: state,
+                width: label.chars().count() as f64 * CHAR_WIDTH + 24.0,
+                label,
+                description: metadata.and_then(|m| m.description.clone()),
+                choice: false,
+                rank: 0,
+                x: 0.0,
+                y: 0.0,
+            });
+        }
+
+        let mut edges = Vec::new();
+        for ((from, event), metadata) in self.transition_names() {
+            edges.push(Edge {
+                from: index[&from],
+                to: nodes.len(),
+                label: Some(edge_label(&event, metadata)),
+                description: metadata.and_then(|m| m.description.clone()),
+                line: Line::Transition,
+            });
+            nodes.push(Node {
+                name: choice_id(&from, &event),
+                label: choice_id(&from, &event),
+                description: None,
+                choice: true,
+                width: CHOICE_SIZE,
+                rank: 0,
+                x: 0.0,
+                y: 0.0,
+            });
+        }
+        for ((from, event, to), metadata) in targets {
+            edges.push(Edge {
+                from: index[&from],
+                to: index[&to],
+                label: Some(edge_label(&event, metadata)),
+                description: metadata.and_then(|m| m.description.clone()),
+                line: Line::Transition,
+            });
+        }
+        for (from, to) in self.eventless_names() {
+            edges.push(Edge {
+                from: index[&from],
+                to: index[&to],
+                label: None,
+                description: None,
+                line: Line::Eventless,
+            });
+        }
+        let nesting: BTreeSet<(String, String)> = self
+            .parents
+            .iter()
+            .map(|(child, parent)| (format!("{:?}", parent), format!("{:?}", child)))
+            .collect();
+        for (parent, child) in nesting {
+            edges.push(Edge {
+                from: index[&parent],
+                to: index[&child],
+                label: None,
+                description: None,
+                line: Line::Nesting,
+            });
+        }
+
+        let current = self
+            .current_state
+            .as_ref()
+            .map(|state| index[&format!("{:?}", state)]);
+        let start = current.or_else(|| self.initial().map(|state| index[&format!("{:?}", state)]));
+        rank(&mut nodes, &edges, start);
+        let rows = place(&mut nodes, &edges);
+
+        let tallest = rows.iter().map(Vec::len).max().unwrap_or(0) as f64;
+        let width = nodes
+            .iter()
+            .map(|n| n.x + n.width / 2.0)
+            .fold(0.0, f64::max)
+            + MARGIN;
+        let height = MARGIN * 2.0 + LOOP_SPACE + BACK_SPACE + tallest * (NODE_HEIGHT + ROW_GAP);
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" viewBox=\"0 0 {:.0} {:.0}\">",
+            width, height, width, height
+        );
+        out.push_str(
+            "  <defs>\n    <marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" \
+             markerWidth=\"8\" markerHeight=\"8\" orient=\"auto-start-reverse\">\n      \
+             <path d=\"M 0 0 L 10 5 L 0 10 z\" fill=\"#334155\"/>\n    </marker>\n  </defs>\n",
+        );
+        let _ = writeln!(
+            out,
+            "  <rect width=\"100%\" height=\"100%\" fill=\"#ffffff\"/>"
+        );
+
+        // Parallel edges between the same two nodes are fanned out.
+        let mut seen: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+        let bottom = height - MARGIN - BACK_SPACE;
+        for edge in &edges {
+            let fan = seen.entry((edge.from, edge.to)).or_default();
+            let spread = *fan as f64 * 16.0;
+            *fan += 1;
+            let (from, to) = (&nodes[edge.from], &nodes[edge.to]);
+            let (path, label_x, label_y) = if edge.from == edge.to {
+                let top = from.y - from.height() / 2.0;
+                let (left, right) = (from.x - from.width / 4.0, from.x + from.width / 4.0);
+                let peak = top - 28.0 - spread;
+                (
+                    format!(
+                        "M {:.1} {:.1} C {:.1} {:.1} {:.1} {:.1} {:.1} {:.1}",
+                        left, top, left, peak, right, peak, right, top
+                    ),
+                    from.x,
+                    peak + 2.0,
+                )
+            } else if to.rank > from.rank {
+                let (x1, y1) = (from.x + from.width / 2.0, from.y);
+                let (x2, y2) = (to.x - to.width / 2.0, to.y);
+                let bend = (x2 - x1) / 2.0;
+                let (cy1, cy2) = (y1 - spread, y2 - spread);
+                (
+                    format!(
+                        "M {:.1} {:.1} C {:.1} {:.1} {:.1} {:.1} {:.1} {:.1}",
+                        x1,
+                        y1,
+                        x1 + bend,
+                        cy1,
+                        x2 - bend,
+                        cy2,
+                        x2,
+                        y2
+                    ),
+                    (x1 + x2) / 2.0,
+                    (y1 + 3.0 * cy1 + 3.0 * cy2 + y2) / 8.0 - 6.0,
+                )
+            } else {
+                let (x1, y1) = (from.x, from.y + from.height() / 2.0);
+                let (x2, y2) = (to.x, to.y + to.height() / 2.0);
+                let dip = bottom + 20.0 + spread;
+                (
+                    format!(
+                        "M {:.1} {:.1} Q {:.1} {:.1} {:.1} {:.1}",
+                        x1,
+                        y1,
+                        (x1 + x2) / 2.0,
+                        dip,
+                        x2,
+                        y2
+                    ),
+                    (x1 + x2) / 2.0,
+                    (y1 + 2.0 * dip + y2) / 4.0 + 14.0,
+                )
+            };
+            let (class, style) = match edge.line {
+                Line::Transition => ("edge", "stroke=\"#334155\" marker-end=\"url(#arrow)\""),
+                Line::Eventless => (
+                    "edge",
+                    "stroke=\"#334155\" stroke-dasharray=\"6 4\" marker-end=\"url(#arrow)\"",
+                ),
+                Line::Nesting => (
+                    "edge substate",
+                    "stroke=\"#94a3b8\" stroke-dasharray=\"2 3\"",
+                ),
+            };
+            let _ = writeln!(out, "  <g class=\"{}\">", class);
+            if let Some(description) = &edge.description {
+                let _ = writeln!(out, "    <title>{}</title>", escape(description));
+            }
+            let _ = writeln!(out, "    <path d=\"{}\" fill=\"none\" {}/>", path, style);
+            if let Some(label) = &edge.label {
+                let _ = writeln!(
+                    out,
+                    "    <text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\" {} fill=\"#334155\">{}</text>",
+                    label_x,
+                    label_y,
+                    FONT,
+                    escape(label)
+                );
+            }
+            out.push_str("  </g>\n");
+        }
+
+        for (i, node) in nodes.iter().enumerate() {
+            let highlighted = current == Some(i);
+            let (class, fill, stroke) = if highlighted {
+                ("state current", "#fde68a", "2.5")
+            } else {
+                ("state", "#f8fafc", "1.2")
+            };
+            if node.choice {
+                let r = CHOICE_SIZE / 2.0;
+                let _ = writeln!(
+                    out,
+                    "  <g class=\"choice\">\n    <title>{}</title>\n    <polygon points=\"{:.1},{:.1} {:.1},{:.1} {:.1},{:.1} {:.1},{:.1}\" fill=\"#e2e8f0\" stroke=\"#334155\"/>\n  </g>",
+                    escape(&node.label),
+                    node.x,
+                    node.y - r,
+                    node.x + r,
+                    node.y,
+                    node.x,
+                    node.y + r,
+                    node.x - r,
+                    node.y
+                );
+                continue;
+            }
+            let _ = writeln!(
+                out,
+                "  <g class=\"{}\" data-state=\"{}\">",
+                class,
+                escape(&node.name)
+            );
+            if let Some(description) = &node.description {
+                let _ = writeln!(out, "    <title>{}</title>", escape(description));
+            }
+            let _ = writeln!(
+                out,
+                "    <rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" rx=\"8\" fill=\"{}\" stroke=\"#334155\" stroke-width=\"{}\"/>",
+                node.x - node.width / 2.0,
+                node.y - NODE_HEIGHT / 2.0,
+                node.width,
+                NODE_HEIGHT,
+                fill,
+                stroke
+            );
+            let _ = writeln!(
+                out,
+                "    <text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\" dominant-baseline=\"central\" {}>{}</text>",
+                node.x,
+                node.y,
+                FONT,
+                escape(&node.label)
+            );
+            out.push_str("  </g>\n");
+        }
+
+        out.push_str("</svg>\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generic::StateMachine;
+    use crate::init_state_machine;
+    use crate::metadata::StateMetadata;
+    use crate::{CallEvent, CallState};
+    use std::collections::HashMap;
+
+    type Machine = StateMachine<CallState, CallEvent>;
+
+    // The `d` attribute of every edge, in drawing order.
+    fn edge_paths(svg: &str) -> Vec<&str> {
+        svg.lines()
+            .filter_map(|line| line.trim().strip_prefix("<path d=\""))
+            .filter(|d| !d.starts_with("M 0 0"))
+            .map(|d| &d[..d.find('"').unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn test_svg_highlights_current_state() {
+        let mut sm = init_state_machine();
+        sm.set_state_metadata(
+            CallState::Connected,
+            StateMetadata::new().display_name("Talking <live>"),
+        );
+        sm.dispatch(&CallEvent::Dial).unwrap();
+
+        let svg = sm.to_svg();
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("class=\"state current\"").count(), 1);
+        let current = svg.find("class=\"state current\"").unwrap();
+        assert!(svg[current..].contains(">Dialing</text>"));
+        assert!(svg.contains(">Talking &lt;live&gt;</text>"));
+        for state in ["Idle", "Ringing", "Disconnected"] {
+            assert!(svg.contains(&format!(">{}</text>", state)));
+        }
+        assert_eq!(svg, sm.to_svg());
+    }
+
+    #[test]
+    fn test_svg_self_loops_and_parallel_edges() {
+        let mut sm = Machine::new(CallState::Idle, HashMap::new());
+        sm.add_transition_to(CallState::Idle, CallEvent::Reset, CallState::Idle);
+        sm.add_transition_to(CallState::Idle, CallEvent::Dial, CallState::Dialing);
+        sm.add_transition_to(CallState::Idle, CallEvent::Incoming, CallState::Dialing);
+
+        let svg = sm.to_svg();
+        let paths = edge_paths(&svg);
+        assert_eq!(paths.len(), 3);
+        // The self-loop leaves and re-enters the top of the same box.
+        let self_loop: Vec<&str> = paths[2].split(' ').collect();
+        assert_eq!(self_loop[2], self_loop[9]);
+        // Parallel edges are fanned out rather than drawn on top of each other.
+        assert_ne!(paths[0], paths[1]);
+        for label in [">Reset</text>", ">Dial</text>", ">Incoming</text>"] {
+            assert!(svg.contains(label));
+        }
+    }
+
+    #[test]
+    fn test_svg_substates_and_empty_machine() {
+        let mut sm = Machine::new(CallState::Idle, HashMap::new());
+        sm.set_initial_substate(CallState::Connected, CallState::Ringing);
+
+        let svg = sm.to_svg();
+        assert_eq!(svg.matches("class=\"edge substate\"").count(), 1);
+        assert_eq!(svg.matches("stroke-dasharray=\"6 4\"").count(), 1);
+        assert!(svg.contains(">Connected</text>") && svg.contains(">Ringing</text>"));
+
+        let empty = Machine::unstarted(HashMap::new()).to_svg();
+        assert!(empty.starts_with("<svg "));
+        assert!(!empty.contains("class=\"state"));
+        assert!(edge_paths(&empty).is_empty());
+    }
+}
@@ -0,0 +1,537 @@
+//! Async handlers and the small executor that drives them.
+//!
+//! Handlers registered with [`StateMachine::add_async_transition`] do their
+//! synchronous work with the machine, then return a future that completes the
+//! transition. The future is given a [`CancellationToken`]; once the token is
+//! cancelled the future is dropped at its next suspension point and the
+//! dispatch fails with `StateMachineError::Cancelled`.
+//!
+//! A handler given a limit with [`StateMachine::set_handler_timeout`] is
+//! dropped the same way when it runs longer, and the dispatch fails with
+//! `StateMachineError::HandlerTimeout`. Like any handler error, the timeout is
+//! retried under the transition's retry policy and then handed to the
+//! recovery policy, which can move the machine to an error state.
+
+use crate::audit::AuditContext;
+use crate::containment::{contain, panicked};
+use crate::correlation;
+use crate::generic::{ChainHandler, Event, HandlerResult, State, StateMachine, StateMachineError};
+use std::cmp::Ordering as Order;
+use std::collections::hash_map::RandomState;
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+pub type AsyncTransitionFunction<S, E, C, O = (), H = RandomState> = Arc<
+    dyn Fn(
+            &mut StateMachine<S, E, C, O, H>,
+            &E,
+            CancellationToken,
+        ) -> BoxFuture<HandlerResult<S, E, O>>
+        + Send
+        + Sync,
+>;
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    wakers: Mutex<Wakers>,
+}
+
+/// One waker per pending [`CancellationToken::cancelled`] future, by key.
+#[derive(Default)]
+struct Wakers {
+    next: u64,
+    waiting: HashMap<u64, Waker>,
+}
+
+/// Shared flag telling async work to stop. Clones observe the same flag.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        let waiting = std::mem::take(&mut self.state.wakers.lock().unwrap().waiting);
+        for waker in waiting.into_values() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the token is cancelled.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + '_ {
+        Cancelled {
+            token: self,
+            key: None,
+        }
+    }
+}
+
+/// Keeps a single registered waker, refreshed on each poll and removed on drop.
+struct Cancelled<'a> {
+    token: &'a CancellationToken,
+    key: Option<u64>,
+}
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if this.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        let mut wakers = this.token.state.wakers.lock().unwrap();
+        match this.key.and_then(|key| wakers.waiting.get_mut(&key)) {
+            Some(waker) => {
+                if !waker.will_wake(cx.waker()) {
+                    waker.clone_from(cx.waker());
+                }
+            }
+            None => {
+                let key = wakers.next;
+                wakers.next += 1;
+                wakers.waiting.insert(key, cx.waker().clone());
+                this.key = Some(key);
+            }
+        }
+        drop(wakers);
+        // Cancellation may have raced with registering the waker.
+        if this.token.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Cancelled<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.token.state.wakers.lock().unwrap().waiting.remove(&key);
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` to completion on the current thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// The deadline of a [`timeout`] passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// A waker due at `deadline`; the earliest deadline is the greatest.
+struct Timer {
+    deadline: Instant,
+    waker: Waker,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Order> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Order {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// Wakes `waker` at `deadline` from the timer thread shared by every [`timeout`].
+fn wake_at(deadline: Instant, waker: Waker) {
+    static TIMERS: OnceLock<Sender<Timer>> = OnceLock::new();
+    let timers = TIMERS.get_or_init(|| {
+        let (timers, queue) = mpsc::channel();
+        thread::spawn(move || run_timers(queue));
+        timers
+    });
+    let _ = timers.send(Timer { deadline, waker });
+}
+
+fn run_timers(queue: Receiver<Timer>) {
+    let mut pending = BinaryHeap::new();
+    loop {
+        let now = Instant::now();
+        while pending
+            .peek()
+            .is_some_and(|timer: &Timer| timer.deadline <= now)
+        {
+            pending.pop().unwrap().waker.wake();
+        }
+        let next = match pending.peek() {
+            Some(timer) => queue.recv_timeout(timer.deadline - now),
+            None => queue.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok(timer) => pending.push(timer),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Resolves with `future`'s output, or `Elapsed` if it takes longer than `duration`.
+///
+/// The deadline is watched by a timer thread shared by every call, so this
+/// works under any executor.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let deadline = Instant::now() + duration;
+    let mut future = pin!(future);
+    let mut timer_started = false;
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        if Instant::now() >= deadline {
+            return Poll::Ready(Err(Elapsed));
+        }
+        if !timer_started {
+            timer_started = true;
+            wake_at(deadline, cx.waker().clone());
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    O: Default,
+    H: BuildHasher,
+{
+    /// Registers a handler whose transition completes asynchronously.
+    ///
+    /// [`dispatch`](Self::dispatch) blocks on the returned future with a token
+    /// that is never cancelled; [`dispatch_async`](Self::dispatch_async) and
+    /// the actor can cancel it.
+    pub fn add_async_transition<F>(&mut self, from: S, event: E, transition: F)
+    where
+        F: Fn(
+                &mut StateMachine<S, E, C, O, H>,
+                &E,
+                CancellationToken,
+            ) -> BoxFuture<HandlerResult<S, E, O>>
+            + 'static
+            + Send
+            + Sync,
+        S: 'static,
+        E: 'static,
+        C: 'static,
+        O: 'static,
+        H: 'static,
+    {
+        let transition: AsyncTransitionFunction<S, E, C, O, H> = Arc::new(transition);
+        let blocking = transition.clone();
+        self.add_transition_with_output(from.clone(), event.clone(), move |sm, event| {
+            block_on(blocking(sm, event, CancellationToken::new()))
+        });
+        self.async_transitions.insert((from, event), transition);
+    }
+
+    /// Fails the async handler for `event` in `from` with
+    /// `StateMachineError::HandlerTimeout` when an attempt takes longer than
+    /// `limit`, dropping its future. The limit applies to each retry
+    /// separately, and is enforced by [`dispatch_async`](Self::dispatch_async)
+    /// and the actor; [`dispatch`](Self::dispatch) still blocks until the
+    /// handler finishes.
+    pub fn set_handler_timeout(&mut self, from: S, event: E, limit: Duration) {
+        self.handler_timeouts.insert((from, event), limit);
+    }
+
+    /// Dispatches `event`, awaiting its handler if it was registered with
+    /// [`add_async_transition`](Self::add_async_transition).
+    pub async fn dispatch_async(
+        &mut self,
+        event: &E,
+        token: &CancellationToken,
+    ) -> HandlerResult<S, E, O> {
+        self.dispatch_async_as(event, token, &AuditContext::default())
+            .await
+    }
+
+    /// Like [`dispatch_async`](Self::dispatch_async), recording `context` in the audit log.
+    pub async fn dispatch_async_as(
+        &mut self,
+        event: &E,
+        token: &CancellationToken,
+        context: &AuditContext,
+    ) -> HandlerResult<S, E, O> {
+        let id = context.correlation_id.clone().or_else(correlation::current);
+        correlation::within(id, self.dispatch_async_scoped(event, token, context)).await
+    }
+
+    async fn dispatch_async_scoped(
+        &mut self,
+        event: &E,
+        token: &CancellationToken,
+        context: &AuditContext,
+    ) -> HandlerResult<S, E, O> {
+        let current_state = self.get_current_state()?.clone();
+        let handlers = self
+            .chain_handlers(&current_state, event)
+            .filter(|((state, _), _)| {
+                let key = (state.clone(), event.clone());
+                self.async_transitions.contains_key(&key)
+            });
+        let Some((first, fallbacks)) = handlers else {
+            return self.dispatch_as(event, context);
+        };
+
+        let before = self.capture_context();
+        self.redirected_from = None;
+        self.enter_dispatch();
+        let result = match self.deduplicate(event, context) {
+            Some(duplicate) => duplicate,
+            None => {
+                let handlers = std::iter::once(first).chain(fallbacks).collect();
+                self.run_async(handlers, &current_state, event, token).await
+            }
+        };
+        self.remember_key(context, &result);
+        self.audit(current_state, before, event, context, &result);
+        self.leave_dispatch(context, result.is_ok());
+        result
+    }
+
+    /// Runs the first of `handlers` and, while each returns `Super`, the
+    /// next; async ones are awaited.
+    async fn run_async(
+        &mut self,
+        handlers: Vec<ChainHandler<S, E, C, O, H>>,
+        current_state: &S,
+        event: &E,
+        token: &CancellationToken,
+    ) -> HandlerResult<S, E, O> {
+        self.validate(event)?;
+        self.exit_before_handler(event);
+        for (state, transition) in handlers {
+            let key = (state.clone(), event.clone());
+            let result = match self.async_transitions.get(&key).cloned() {
+                Some(handler) => match self.attempt_async(handler, &state, event, token).await {
+                    Some(result) => result,
+                    None => {
+                        return Err(StateMachineError::Cancelled {
+                            state: current_state.clone(),
+                            event: event.clone(),
+                        })
+                    }
+                },
+                None => self.call_with_retries(&state, event, &transition),
+            };
+            let (response, output) = result.map_err(|e| self.recover(current_state, event, e))?;
+            if let Some(result) = self.complete(response, output, event) {
+                return result;
+            }
+        }
+        Err(StateMachineError::UnexpectedEvent {
+            state: current_state.clone(),
+            event: event.clone(),
+        })
+    }
+
+    /// Runs `handler`, registered in `from`, retrying per its retry policy
+    /// and limiting each attempt to its timeout; `None` once `token` is
+    /// cancelled.
+    async fn attempt_async(
+        &mut self,
+        handler: AsyncTransitionFunction<S, E, C, O, H>,
+        from: &S,
+        event: &E,
+        token: &CancellationToken,
+    ) -> Option<HandlerResult<S, E, O>> {
+        let policy = self.retry_policy(from, event);
+        let limit = self
+            .handler_timeouts
+            .get(&(from.clone(), event.clone()))
+            .copied();
+        let mut failures = 0;
+        loop {
+            let contain_panics = self.contain_panics;
+            let mut future = match contain(contain_panics, || handler(self, event, token.clone())) {
+                Ok(future) => future,
+                Err(message) => return Some(Err(panicked(from, event, message))),
+            };
+            let mut cancelled = pin!(token.cancelled());
+            let attempt = std::future::poll_fn(|cx| {
+                if token.is_cancelled() {
+                    return Poll::Ready(None);
+                }
+                match contain(contain_panics, || future.as_mut().poll(cx)) {
+                    Ok(Poll::Ready(result)) => return Poll::Ready(Some(result)),
+                    Ok(Poll::Pending) => {}
+                    Err(message) => return Poll::Ready(Some(Err(panicked(from, event, message)))),
+                }
+                cancelled.as_mut().poll(cx).map(|()| None)
+            });
+            let result = match limit {
+                Some(limit) => timeout(limit, attempt).await.unwrap_or_else(|Elapsed| {
+                    Some(Err(StateMachineError::HandlerTimeout {
+                        from: from.clone(),
+                        event: event.clone(),
+                    }))
+                }),
+                None => attempt.await,
+            };
+            drop(future);
+
+            let Some(Err(error)) = result else {
+                return result;
+            };
+            failures += 1;
+            let Some(delay) = policy.as_ref().and_then(|p| p.next_delay(failures, &error)) else {
+                return Some(Err(error));
+            };
+            if timeout(delay, token.cancelled()).await.is_ok() {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Response;
+    use crate::recovery::RecoveryPolicy;
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_timeout() {
+        let slow = timeout(Duration::from_millis(10), std::future::pending::<()>());
+        assert_eq!(block_on(slow), Err(Elapsed));
+        assert_eq!(
+            block_on(timeout(Duration::from_secs(5), async { 1 })),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn test_async_transition() {
+        let mut sm = init_state_machine();
+        sm.add_async_transition(CallState::Idle, CallEvent::Dial, |_sm, _event, _token| {
+            Box::pin(async { Ok((Response::Transition(CallState::Dialing), ())) })
+        });
+        let token = CancellationToken::new();
+        block_on(sm.dispatch_async(&CallEvent::Dial, &token)).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Dialing);
+    }
+
+    #[test]
+    fn test_cancelled_handler_leaves_state() {
+        let mut sm = init_state_machine();
+        sm.add_async_transition(CallState::Idle, CallEvent::Dial, |_sm, _event, _token| {
+            Box::pin(std::future::pending())
+        });
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(10));
+            canceller.cancel();
+        });
+        assert!(matches!(
+            block_on(sm.dispatch_async(&CallEvent::Dial, &token)),
+            Err(StateMachineError::Cancelled {
+                state: CallState::Idle,
+                event: CallEvent::Dial
+            })
+        ));
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Idle);
+    }
+
+    #[test]
+    fn test_handler_timeout_goes_to_recovery() {
+        let mut sm = init_state_machine();
+        sm.add_async_transition(CallState::Idle, CallEvent::Dial, |_sm, _event, _token| {
+            Box::pin(std::future::pending())
+        });
+        sm.set_handler_timeout(CallState::Idle, CallEvent::Dial, Duration::from_millis(10));
+        sm.set_recovery_policy(RecoveryPolicy::new().transition_to(CallState::Disconnected));
+        let token = CancellationToken::new();
+        let error = block_on(sm.dispatch_async(&CallEvent::Dial, &token)).unwrap_err();
+        assert_eq!(
+            error.to_json(),
+            "{\"kind\":\"handler_timeout\",\"from\":\"Idle\",\"event\":\"Dial\"}"
+        );
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Disconnected);
+        assert_eq!(sm.last_failure().unwrap().kind, "handler_timeout");
+    }
+
+    #[test]
+    fn test_async_super_falls_back_to_parent() {
+        let mut sm = init_state_machine();
+        sm.add_substate(CallState::Idle, CallState::Dialing);
+        sm.add_transition(CallState::Idle, CallEvent::Reset, |_, _| {
+            Ok(Response::Transition(CallState::Disconnected))
+        });
+        sm.add_async_transition(CallState::Dialing, CallEvent::Reset, |_, _, _| {
+            Box::pin(async { Ok((Response::Super, ())) })
+        });
+        sm.dispatch(&CallEvent::Dial).unwrap();
+
+        let token = CancellationToken::new();
+        block_on(sm.dispatch_async(&CallEvent::Reset, &token)).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Disconnected);
+    }
+
+    #[test]
+    fn test_cancelled_keeps_one_waker_per_future() {
+        let token = CancellationToken::new();
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        {
+            let mut cancelled = pin!(token.cancelled());
+            for _ in 0..3 {
+                assert!(cancelled.as_mut().poll(&mut cx).is_pending());
+            }
+            assert_eq!(token.state.wakers.lock().unwrap().waiting.len(), 1);
+        }
+        assert!(token.state.wakers.lock().unwrap().waiting.is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+//! The phone-call machine, ready to embed.
+//!
+//! [`call_machine`] builds the call lifecycle of [`CallState`] and
+//! [`CallEvent`] over a [`CallContext`] that tracks the caller and how long
+//! the call was connected. Applications attach their own behaviour with
+//! [`Hooks`], which run after a transition's context update and before it
+//! is committed.
+
+pub use crate::{CallEvent, CallState};
+
+use crate::clock::Clock;
+use crate::generic::{Response, StateMachine};
+use crate::CALL_TRANSITIONS;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallContext {
+    /// Kept across calls.
+    pub caller_id: Option<String>,
+    /// When the call was answered; cleared on reset.
+    pub connected_at: Option<Instant>,
+    /// How long the call was connected, set when it is hung up.
+    pub duration: Option<Duration>,
+}
+
+impl CallContext {
+    pub fn new(caller_id: impl Into<String>) -> Self {
+        CallContext {
+            caller_id: Some(caller_id.into()),
+            ..Self::default()
+        }
+    }
+}
+
+pub type CallHook = Arc<dyn Fn(&CallState, &CallContext) + Send + Sync>;
+
+/// Callbacks keyed by the transition they run on; several may share a key.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: HashMap<(CallState, CallEvent), Vec<CallHook>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `hook` with the target state and updated context whenever `event`
+    /// is handled in `from`.
+    pub fn on<F>(mut self, from: CallState, event: CallEvent, hook: F) -> Self
+    where
+        F: Fn(&CallState, &CallContext) + 'static + Send + Sync,
+    {
+        self.hooks
+            .entry((from, event))
+            .or_default()
+            .push(Arc::new(hook));
+        self
+    }
+}
+
+fn update(context: &mut CallContext, event: &CallEvent, now: Instant) {
+    match event {
+        CallEvent::Answer => context.connected_at = Some(now),
+        CallEvent::HangUp => {
+            context.duration = context
+                .connected_at
+                .map(|at| now.saturating_duration_since(at))
+        }
+        // The caller stays; only the last call's timings are cleared.
+        CallEvent::Reset => {
+            context.connected_at = None;
+            context.duration = None;
+        }
+        CallEvent::Dial | CallEvent::Incoming => {}
+    }
+}
+
+/// The call lifecycle starting in `Idle` with `context`.
+pub fn call_machine(
+    context: CallContext,
+    hooks: Hooks,
+) -> StateMachine<CallState, CallEvent, CallContext> {
+    let mut sm = StateMachine::with_capacity(CallState::Idle, context, CALL_TRANSITIONS.len());
+    for (from, event, to) in CALL_TRANSITIONS {
+        let hooks = hooks
+            .hooks
+            .get(&(from.clone(), event.clone()))
+            .cloned()
+            .unwrap_or_default();
+        sm.add_transition(from, event, move |sm, event| {
+            let now = sm.clock.now();
+            update(sm.get_context_mut(), event, now);
+            for hook in &hooks {
+                hook(&to, sm.get_context());
+            }
+            Ok(Response::Transition(to.clone()))
+        });
+    }
+    sm
+}
+
+/// Like [`call_machine`], timing calls with `clock`; the same as setting it
+/// with [`with_clock`](StateMachine::with_clock).
+pub fn call_machine_with_clock(
+    context: CallContext,
+    hooks: Hooks,
+    clock: impl Clock + 'static,
+) -> StateMachine<CallState, CallEvent, CallContext> {
+    call_machine(context, hooks).with_clock(clock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::generic::Stateful;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_hooks_see_call_context() {
+        let ended = Arc::new(Mutex::new(None));
+        let hooks = Hooks::new().on(CallState::Connected, CallEvent::HangUp, {
+            let ended = ended.clone();
+            move |to, context| *ended.lock().unwrap() = Some((to.clone(), context.clone()))
+        });
+        let clock = ManualClock::new();
+        let mut sm = call_machine_with_clock(CallContext::new("+15550100"), hooks, clock.clone());
+
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        sm.handle_event(&CallEvent::Answer).unwrap();
+        clock.advance(Duration::from_secs(90));
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+
+        let (to, context) = ended.lock().unwrap().clone().unwrap();
+        assert_eq!(to, CallState::Disconnected);
+        assert_eq!(context.caller_id.as_deref(), Some("+15550100"));
+        assert_eq!(context.duration, Some(Duration::from_secs(90)));
+
+        sm.handle_event(&CallEvent::Reset).unwrap();
+        assert_eq!(sm.get_context(), &CallContext::new("+15550100"));
+    }
+}
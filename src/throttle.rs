@@ -0,0 +1,208 @@
+//! Per-event rate limiting and debouncing for the actor front-end.
+//!
+//! A [`Throttle`] is checked when an event is handed to an
+//! [`ActorHandle`](crate::actor::ActorHandle), before it is queued, so a
+//! flapping source never reaches the machine at all. Rates and windows are
+//! measured with the throttle's own [`Clock`], the system's unless set with
+//! [`with_clock`](Throttle::with_clock).
+
+use crate::clock::{Clock, SystemClock};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What happens to an event that exceeds its policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExceeded {
+    /// Fail with `StateMachineError::Throttled`.
+    Reject,
+    /// Drop the event as if it had been handled.
+    Coalesce,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admission {
+    Accept,
+    Exceeded(OnExceeded),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Policy {
+    /// A token bucket holding `burst` tokens, refilled at `burst` per `per`.
+    RateLimit { burst: u32, per: Duration },
+    /// Occurrences closer than the window to the previous one are suppressed.
+    Debounce(Duration),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    policy: Policy,
+    on_exceeded: OnExceeded,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+#[derive(Clone)]
+pub struct Throttle<E> {
+    rules: HashMap<E, Rule>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<E> Default for Throttle<E> {
+    fn default() -> Self {
+        Throttle {
+            rules: HashMap::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for Throttle<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Throttle")
+            .field("rules", &self.rules)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: Eq + Hash> Throttle<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Measures rates and windows with `clock`.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Admits at most `burst` occurrences of `event` at once, refilling at
+    /// `burst` per `per`.
+    pub fn rate_limit(
+        mut self,
+        event: E,
+        burst: u32,
+        per: Duration,
+        on_exceeded: OnExceeded,
+    ) -> Self {
+        self.rules.insert(
+            event,
+            Rule {
+                policy: Policy::RateLimit { burst, per },
+                on_exceeded,
+                tokens: burst as f64,
+                last: None,
+            },
+        );
+        self
+    }
+
+    /// Suppresses occurrences of `event` that follow the previous one, admitted
+    /// or not, within `window`; a steadily flapping source is held off until it
+    /// goes quiet.
+    pub fn debounce(mut self, event: E, window: Duration, on_exceeded: OnExceeded) -> Self {
+        self.rules.insert(
+            event,
+            Rule {
+                policy: Policy::Debounce(window),
+                on_exceeded,
+                tokens: 0.0,
+                last: None,
+            },
+        );
+        self
+    }
+
+    /// Admits `event` as of the throttle's clock.
+    pub(crate) fn admit_now(&mut self, event: &E) -> Admission {
+        let now = self.clock.now();
+        self.admit(event, now)
+    }
+
+    fn admit(&mut self, event: &E, now: Instant) -> Admission {
+        let Some(rule) = self.rules.get_mut(event) else {
+            return Admission::Accept;
+        };
+        let elapsed = rule.last.map(|last| now.saturating_duration_since(last));
+        let admitted = match rule.policy {
+            Policy::RateLimit { burst, per } => {
+                if let Some(elapsed) = elapsed {
+                    let refill = elapsed.as_secs_f64() / per.as_secs_f64() * burst as f64;
+                    rule.tokens = (rule.tokens + refill).min(burst as f64);
+                }
+                rule.last = Some(now);
+                if rule.tokens >= 1.0 {
+                    rule.tokens -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+            Policy::Debounce(window) => {
+                rule.last = Some(now);
+                elapsed.is_none_or(|elapsed| elapsed >= window)
+            }
+        };
+        if admitted {
+            Admission::Accept
+        } else {
+            Admission::Exceeded(rule.on_exceeded)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::CallEvent;
+
+    #[test]
+    fn test_token_bucket_refills() {
+        let clock = ManualClock::new();
+        let mut throttle = Throttle::new()
+            .rate_limit(
+                CallEvent::Incoming,
+                2,
+                Duration::from_secs(1),
+                OnExceeded::Reject,
+            )
+            .with_clock(clock.clone());
+        assert_eq!(throttle.admit_now(&CallEvent::Incoming), Admission::Accept);
+        assert_eq!(throttle.admit_now(&CallEvent::Incoming), Admission::Accept);
+        assert_eq!(
+            throttle.admit_now(&CallEvent::Incoming),
+            Admission::Exceeded(OnExceeded::Reject)
+        );
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(throttle.admit_now(&CallEvent::Incoming), Admission::Accept);
+        assert_eq!(throttle.admit_now(&CallEvent::Dial), Admission::Accept);
+    }
+
+    #[test]
+    fn test_debounce_restarts_window() {
+        let start = Instant::now();
+        let window = Duration::from_millis(100);
+        let mut throttle =
+            Throttle::new().debounce(CallEvent::Incoming, window, OnExceeded::Coalesce);
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(
+            throttle.admit(&CallEvent::Incoming, at(0)),
+            Admission::Accept
+        );
+        assert_eq!(
+            throttle.admit(&CallEvent::Incoming, at(60)),
+            Admission::Exceeded(OnExceeded::Coalesce)
+        );
+        assert_eq!(
+            throttle.admit(&CallEvent::Incoming, at(120)),
+            Admission::Exceeded(OnExceeded::Coalesce)
+        );
+        assert_eq!(
+            throttle.admit(&CallEvent::Incoming, at(300)),
+            Admission::Accept
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Timed-automaton clocks: named stopwatches reset on chosen transitions and
+//! read by guards, such as "`Answer` is only accepted within 30s of `Dial`".
+//!
+//! Clocks read the machine's [`Clock`] source ([`SystemClock`](crate::clock::SystemClock) unless set
+//! with [`with_clock`](StateMachine::with_clock)), so tests can drive them
+//! with a [`ManualClock`](crate::clock::ManualClock).
+
+use crate::clock::{Clock, SharedClock};
+use crate::generic::{Event, State, StateMachine};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct ClockState {
+    source: SharedClock,
+    resets: HashMap<String, Instant>,
+}
+
+impl ClockState {
+    fn reset(&mut self, clock: &str) {
+        let now = self.source.now();
+        self.resets.insert(clock.to_string(), now);
+    }
+}
+
+/// Shared with the observers installed by `reset_clock_on`.
+struct Clocks(Arc<Mutex<ClockState>>);
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+{
+    fn clocks(&mut self) -> Arc<Mutex<ClockState>> {
+        if let Some(Clocks(state)) = self.ext::<Clocks>() {
+            return state.clone();
+        }
+        let state = Arc::new(Mutex::new(ClockState {
+            source: self.clock.clone(),
+            resets: HashMap::new(),
+        }));
+        self.insert_ext(Clocks(state.clone()));
+        state
+    }
+
+    /// Resets `clock` to zero now.
+    pub fn reset_clock(&mut self, clock: &str) {
+        self.clocks().lock().unwrap().reset(clock);
+    }
+
+    /// Resets `clock` whenever the transition on `event` in `from` commits.
+    pub fn reset_clock_on(&mut self, clock: &str, from: S, event: E) {
+        let state = self.clocks();
+        let clock = clock.to_string();
+        self.add_observer(move |f, e, _| {
+            if *f == from && *e == event {
+                state.lock().unwrap().reset(&clock);
+            }
+        });
+    }
+
+    /// Time since `clock` was last reset, or `None` if it never was.
+    pub fn clock_elapsed(&self, clock: &str) -> Option<Duration> {
+        let Clocks(state) = self.ext::<Clocks>()?;
+        let state = state.lock().unwrap();
+        let reset = *state.resets.get(clock)?;
+        Some(state.source.now().saturating_duration_since(reset))
+    }
+}
+
+/// A guard for [`add_guarded_transition`](StateMachine::add_guarded_transition)
+/// passing while `clock` has been running for less than `limit`.
+pub fn within<S, E, C, O>(
+    clock: &str,
+    limit: Duration,
+) -> impl Fn(&StateMachine<S, E, C, O>) -> bool + Send + Sync + 'static
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+{
+    let clock = clock.to_string();
+    move |sm| {
+        sm.clock_elapsed(&clock)
+            .is_some_and(|elapsed| elapsed < limit)
+    }
+}
+
+/// A guard passing once `clock` has been running for at least `delay`.
+pub fn after<S, E, C, O>(
+    clock: &str,
+    delay: Duration,
+) -> impl Fn(&StateMachine<S, E, C, O>) -> bool + Send + Sync + 'static
+where
+    S: State + Send + Sync + 'static,
+    E: Event + Send + Sync + 'static,
+{
+    let clock = clock.to_string();
+    move |sm| {
+        sm.clock_elapsed(&clock)
+            .is_some_and(|elapsed| elapsed >= delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::generic::{Response, StateMachineError};
+    use crate::{CallEvent, CallState};
+
+    #[test]
+    fn test_answer_only_within_window() {
+        let clock = ManualClock::new();
+        let mut sm: StateMachine<CallState, CallEvent, ()> =
+            StateMachine::new(CallState::Idle, ()).with_clock(clock.clone());
+        sm.add_transition_to(CallState::Idle, CallEvent::Dial, CallState::Dialing);
+        sm.add_transition_to(CallState::Dialing, CallEvent::HangUp, CallState::Idle);
+        sm.add_guarded_transition(
+            CallState::Dialing,
+            CallEvent::Answer,
+            within("dial", Duration::from_secs(30)),
+            |_, _| Ok(Response::Transition(CallState::Connected)),
+        );
+        sm.reset_clock_on("dial", CallState::Idle, CallEvent::Dial);
+        assert_eq!(sm.clock_elapsed("dial"), None);
+
+        sm.dispatch(&CallEvent::Dial).unwrap();
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(sm.clock_elapsed("dial"), Some(Duration::from_secs(31)));
+        assert!(matches!(
+            sm.dispatch(&CallEvent::Answer),
+            Err(StateMachineError::TransitionNotFound { .. })
+        ));
+
+        sm.dispatch(&CallEvent::HangUp).unwrap();
+        sm.dispatch(&CallEvent::Dial).unwrap();
+        clock.advance(Duration::from_secs(29));
+        sm.dispatch(&CallEvent::Answer).unwrap();
+        assert_eq!(sm.get_current_state().ok(), Some(&CallState::Connected));
+        assert!(after::<CallState, CallEvent, (), ()>(
+            "dial",
+            Duration::from_secs(29)
+        )(&sm));
+    }
+}
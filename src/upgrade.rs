@@ -0,0 +1,172 @@
+//! Upgrading a running machine to a new definition.
+//!
+//! [`migrate_definition`](StateMachine::migrate_definition) swaps a live
+//! machine's transition table for that of a machine built from the new
+//! version, maps its current state into the new state set and keeps its
+//! context. A state counts as known to a definition if its table names it
+//! anywhere: as the source or static target of a transition, in the
+//! hierarchy, as an initial or final state, or in its metadata.
+
+use crate::generic::{Event, State, StateMachine};
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+
+/// Why [`migrate_definition`](StateMachine::migrate_definition) refused a
+/// mapping; each list is ordered by Debug representation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationDiagnostics<S> {
+    /// States of the running definition that are neither mapped nor known
+    /// to the new one.
+    pub unmapped: Vec<S>,
+    /// Mapping targets the new definition does not know.
+    pub unknown_targets: Vec<S>,
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    H: BuildHasher,
+{
+    fn known_states(&self) -> HashSet<S> {
+        let mut states: HashSet<S> = self
+            .transitions
+            .keys()
+            .map(|(from, _)| from.clone())
+            .collect();
+        states.extend(self.targets.values().cloned());
+        for (from, targets) in &self.eventless {
+            states.insert(from.clone());
+            states.extend(targets.iter().map(|(to, _)| to.clone()));
+        }
+        for (child, parent) in self.parents.iter().chain(&self.completions) {
+            states.insert(child.clone());
+            states.insert(parent.clone());
+        }
+        for (composite, substate) in &self.initial.substates {
+            states.insert(composite.clone());
+            states.insert(substate.clone());
+        }
+        states.extend(self.finals.iter().cloned());
+        states.extend(self.metadata.keys().cloned());
+        states.extend(self.initial().cloned());
+        states.extend(self.current_state.clone());
+        states
+    }
+
+    /// Replaces this machine's definition with `definition`'s, moving the
+    /// current state through `mapping`; a state the mapping leaves out stays
+    /// as it is if the new definition knows it.
+    ///
+    /// Transitions, guards, targets, the hierarchy, initial and final states,
+    /// metadata, retry policies and handler timeouts come from `definition`,
+    /// whose context and current state are dropped. The context, hooks,
+    /// observers, vetoes, audit sinks and extensions of this machine stay.
+    /// A migration that moves the current state counts as a change of state:
+    /// the [version](Self::version) is bumped and state listeners are told.
+    ///
+    /// Nothing changes unless every state of the running definition has a
+    /// place in the new one.
+    pub fn migrate_definition(
+        &mut self,
+        definition: StateMachine<S, E, C, O, H>,
+        mapping: &HashMap<S, S>,
+    ) -> Result<(), MigrationDiagnostics<S>> {
+        let known = definition.known_states();
+        let mut unmapped: Vec<S> = self
+            .known_states()
+            .into_iter()
+            .filter(|state| !mapping.contains_key(state) && !known.contains(state))
+            .collect();
+        let mut unknown_targets: Vec<S> = mapping
+            .values()
+            .filter(|state| !known.contains(state))
+            .cloned()
+            .collect();
+        if !unmapped.is_empty() || !unknown_targets.is_empty() {
+            unmapped.sort_by_cached_key(|state| format!("{:?}", state));
+            unknown_targets.sort_by_cached_key(|state| format!("{:?}", state));
+            unknown_targets.dedup();
+            return Err(MigrationDiagnostics {
+                unmapped,
+                unknown_targets,
+            });
+        }
+
+        self.initial = definition.initial;
+        self.transitions = definition.transitions;
+        self.async_transitions = definition.async_transitions;
+        self.guards = definition.guards;
+        self.targets = definition.targets;
+        self.eventless = definition.eventless;
+        self.parents = definition.parents;
+        self.finals = definition.finals;
+        self.completions = definition.completions;
+        self.metadata = definition.metadata;
+        self.transition_metadata = definition.transition_metadata;
+        self.retries = definition.retries;
+        self.handler_timeouts = definition.handler_timeouts;
+
+        let mapped = self
+            .current_state
+            .as_ref()
+            .and_then(|state| mapping.get(state))
+            .cloned();
+        if let Some(state) = mapped {
+            if self.current_state.replace(state.clone()) != Some(state) {
+                self.announce_state();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic::Stateful;
+    use crate::{CallEvent, CallState};
+
+    #[test]
+    fn test_migration_maps_current_state() {
+        let mut sm: StateMachine<CallState, CallEvent, u32> = StateMachine::new(CallState::Idle, 0);
+        sm.add_transition_to(CallState::Idle, CallEvent::Incoming, CallState::Ringing);
+        sm.add_transition_to(CallState::Ringing, CallEvent::Answer, CallState::Connected);
+        sm.add_transition_to(CallState::Connected, CallEvent::HangUp, CallState::Idle);
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        *sm.get_context_mut() = 7;
+
+        // The new version answers automatically and has no Ringing state.
+        let definition = || {
+            let mut sm: StateMachine<CallState, CallEvent, u32> =
+                StateMachine::new(CallState::Idle, 0);
+            sm.add_transition_to(CallState::Idle, CallEvent::Incoming, CallState::Connected);
+            sm.add_transition_to(CallState::Connected, CallEvent::HangUp, CallState::Idle);
+            sm
+        };
+
+        let wrong = HashMap::from([(CallState::Ringing, CallState::Dialing)]);
+        assert_eq!(
+            sm.migrate_definition(definition(), &wrong),
+            Err(MigrationDiagnostics {
+                unmapped: vec![],
+                unknown_targets: vec![CallState::Dialing],
+            })
+        );
+
+        assert_eq!(
+            sm.migrate_definition(definition(), &HashMap::new())
+                .unwrap_err()
+                .unmapped,
+            vec![CallState::Ringing]
+        );
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Ringing);
+
+        let mapping = HashMap::from([(CallState::Ringing, CallState::Connected)]);
+        sm.migrate_definition(definition(), &mapping).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+        assert_eq!(sm.get_context(), &7);
+        assert!(sm.handle_event(&CallEvent::Answer).is_err());
+        sm.handle_event(&CallEvent::HangUp).unwrap();
+    }
+}
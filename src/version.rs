@@ -0,0 +1,68 @@
+//! Optimistic concurrency through machine versions.
+//!
+//! Every change of state, by a committed transition, `start` or `restore`,
+//! bumps the machine's [`version`](StateMachine::version). A caller that read
+//! the state at some version and decided what to do about it dispatches with
+//! [`dispatch_if_version`](StateMachine::dispatch_if_version), which fails with
+//! `StateMachineError::StaleVersion` instead of acting on a state someone else
+//! has since moved on from.
+//! [`ActorHandle::dispatch_if_version`](crate::actor::ActorHandle::dispatch_if_version)
+//! checks when the event's turn in the mailbox comes.
+
+use crate::generic::{Event, HandlerResult, State, StateMachine, StateMachineError};
+use std::hash::BuildHasher;
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+    O: Default,
+    H: BuildHasher,
+{
+    /// How many times the state has changed since the machine was created.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Fails with `StateMachineError::StaleVersion` unless the machine is at
+    /// version `expected`.
+    pub fn expect_version(&self, expected: u64) -> Result<(), StateMachineError<S, E>> {
+        if self.version == expected {
+            Ok(())
+        } else {
+            Err(StateMachineError::StaleVersion {
+                expected,
+                actual: self.version,
+            })
+        }
+    }
+
+    /// Dispatches `event` only if the machine is still at version `expected`.
+    pub fn dispatch_if_version(&mut self, expected: u64, event: &E) -> HandlerResult<S, E, O> {
+        self.expect_version(expected)?;
+        self.dispatch(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{init_state_machine, CallEvent, CallState};
+
+    #[test]
+    fn test_dispatch_if_version() {
+        let mut sm = init_state_machine();
+        assert_eq!(sm.version(), 0);
+        let seen = sm.version();
+        sm.dispatch(&CallEvent::Incoming).unwrap();
+        assert_eq!(sm.version(), 1);
+
+        let error = sm.dispatch_if_version(seen, &CallEvent::Dial).unwrap_err();
+        assert_eq!(
+            error.to_json(),
+            "{\"kind\":\"stale_version\",\"expected\":0,\"actual\":1}"
+        );
+        sm.dispatch_if_version(1, &CallEvent::Answer).unwrap();
+        assert_eq!(sm.get_current_state().unwrap(), &CallState::Connected);
+        assert_eq!(sm.version(), 2);
+    }
+}
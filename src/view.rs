@@ -0,0 +1,103 @@
+//! Read-only views of machines for dashboards and other inspectors.
+//!
+//! A [`MachineView`] holds the state, the [version](StateMachine::version) and
+//! only the context fields a [`Projection`] names, rendered as strings, so
+//! taking one costs the projection rather than a clone of the whole context.
+//! [`ActorHandle::view`](crate::actor::ActorHandle::view) projects on the
+//! actor thread between commands, and
+//! [`MachinePool::views`](crate::pool::MachinePool::views) views every machine
+//! of a pool.
+
+use crate::generic::{Event, State, StateMachine};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+type FieldProjection<C> = Arc<dyn Fn(&C) -> String + Send + Sync>;
+
+/// The context fields a view exposes, each rendered by its own function.
+pub struct Projection<C> {
+    fields: Vec<(String, FieldProjection<C>)>,
+}
+
+impl<C> Clone for Projection<C> {
+    fn clone(&self) -> Self {
+        Projection {
+            fields: self.fields.clone(),
+        }
+    }
+}
+
+impl<C> Default for Projection<C> {
+    fn default() -> Self {
+        Projection { fields: Vec::new() }
+    }
+}
+
+impl<C> Projection<C> {
+    /// A projection exposing no context fields.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exposes `name`, rendered from the context by `render`.
+    pub fn field<F>(mut self, name: impl Into<String>, render: F) -> Self
+    where
+        F: Fn(&C) -> String + 'static + Send + Sync,
+    {
+        self.fields.push((name.into(), Arc::new(render)));
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineView<S> {
+    /// `None` for a machine not yet started.
+    pub state: Option<S>,
+    pub version: u64,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl<S, E, C, O, H> StateMachine<S, E, C, O, H>
+where
+    S: State,
+    E: Event,
+{
+    /// The current state and the fields `projection` exposes.
+    pub fn view(&self, projection: &Projection<C>) -> MachineView<S> {
+        MachineView {
+            state: self.current_state.clone(),
+            version: self.version,
+            fields: projection
+                .fields
+                .iter()
+                .map(|(name, render)| (name.clone(), render(&self.context)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_view_exposes_projected_fields() {
+        let mut sm = init_state_machine();
+        sm.get_context_mut().insert("retries".to_string(), 2);
+        sm.get_context_mut().insert("secret".to_string(), 7);
+        sm.dispatch(&CallEvent::Dial).unwrap();
+
+        let projection = Projection::new().field("retries", |c: &HashMap<String, usize>| {
+            c["retries"].to_string()
+        });
+        let view = sm.view(&projection);
+        assert_eq!(view.state, Some(CallState::Dialing));
+        assert_eq!(view.version, 1);
+        assert_eq!(
+            view.fields.into_iter().collect::<Vec<_>>(),
+            vec![("retries".to_string(), "2".to_string())]
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! A single-value channel whose receivers can wait for the value to change.
+//!
+//! Receivers always see the latest value; intermediate values sent between
+//! two looks are skipped. Any number of receivers can watch one sender, and
+//! their futures work under any executor.
+
+use crate::generic::{Event, State, StateMachine};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+struct Slot<T> {
+    value: T,
+    version: u64,
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+struct Shared<T> {
+    slot: Mutex<Slot<T>>,
+}
+
+/// The sender went away; the value will not change again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+pub fn channel<T>(value: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot {
+            value,
+            version: 0,
+            closed: false,
+            wakers: Vec::new(),
+        }),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, seen: 0 },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: PartialEq> Sender<T> {
+    /// Replaces the value, waking receivers if it changed.
+    pub fn send(&self, value: T) {
+        let mut slot = self.shared.slot.lock().unwrap();
+        if slot.value == value {
+            return;
+        }
+        slot.value = value;
+        slot.version += 1;
+        for waker in slot.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut slot = self.shared.slot.lock().unwrap();
+        slot.closed = true;
+        for waker in slot.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    pub fn subscribe(&self) -> Receiver<T> {
+        let seen = self.shared.slot.lock().unwrap().version;
+        Receiver {
+            shared: self.shared.clone(),
+            seen,
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// The version last returned by `borrow` or `changed`.
+    seen: u64,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver {
+            shared: self.shared.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// The latest value, which is then no longer reported by [`changed`](Self::changed).
+    pub fn borrow_and_update(&mut self) -> T {
+        let slot = self.shared.slot.lock().unwrap();
+        self.seen = slot.version;
+        slot.value.clone()
+    }
+
+    /// The latest value.
+    pub fn borrow(&self) -> T {
+        self.shared.slot.lock().unwrap().value.clone()
+    }
+
+    /// Whether a value arrived since this receiver last looked.
+    pub fn has_changed(&self) -> bool {
+        self.shared.slot.lock().unwrap().version != self.seen
+    }
+
+    /// Resolves once a value this receiver has not seen arrives.
+    pub async fn changed(&mut self) -> Result<(), Closed> {
+        std::future::poll_fn(|cx| {
+            let mut slot = self.shared.slot.lock().unwrap();
+            if slot.version != self.seen {
+                self.seen = slot.version;
+                return Poll::Ready(Ok(()));
+            }
+            if slot.closed {
+                return Poll::Ready(Err(Closed));
+            }
+            slot.wakers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Resolves with the first value, current or future, that satisfies `predicate`.
+    pub fn wait_for<P>(&self, predicate: P) -> WaitFor<T, P>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        WaitFor {
+            shared: self.shared.clone(),
+            predicate,
+        }
+    }
+}
+
+pub struct WaitFor<T, P> {
+    shared: Arc<Shared<T>>,
+    predicate: P,
+}
+
+// Nothing in `WaitFor` is pinned in place.
+impl<T, P> Unpin for WaitFor<T, P> {}
+
+impl<T, P> Future for WaitFor<T, P>
+where
+    T: Clone,
+    P: FnMut(&T) -> bool,
+{
+    type Output = Result<T, Closed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut slot = this.shared.slot.lock().unwrap();
+        if (this.predicate)(&slot.value) {
+            return Poll::Ready(Ok(slot.value.clone()));
+        }
+        if slot.closed {
+            return Poll::Ready(Err(Closed));
+        }
+        slot.wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// The machine's shared state sender, kept in its extensions.
+struct StatePublisher<S>(Arc<Sender<Option<S>>>);
+
+impl<S, E, C, O> StateMachine<S, E, C, O>
+where
+    S: State + Send + 'static,
+    E: Event,
+{
+    /// A receiver of the current state, updated after every committed
+    /// transition and when the machine is started or restored. It holds
+    /// `None` until an [`unstarted`](StateMachine::unstarted) machine starts.
+    pub fn subscribe(&mut self) -> Receiver<Option<S>> {
+        self.state_sender().subscribe()
+    }
+
+    /// The sender behind [`subscribe`](Self::subscribe), created on first use.
+    pub(crate) fn state_sender(&mut self) -> Arc<Sender<Option<S>>> {
+        if let Some(StatePublisher(sender)) = self.ext::<StatePublisher<S>>() {
+            return sender.clone();
+        }
+        let sender = Arc::new(channel(self.current_state.clone()).0);
+        let observed = sender.clone();
+        self.add_observer(move |_, _, to| observed.send(Some(to.clone())));
+        let announced = sender.clone();
+        self.state_listeners
+            .push(Arc::new(move |state| announced.send(Some(state.clone()))));
+        self.insert_ext(StatePublisher(sender.clone()));
+        sender
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::block_on;
+
+    #[test]
+    fn test_receivers_see_latest_value() {
+        let (sender, mut first) = channel(0);
+        let mut second = sender.subscribe();
+        sender.send(1);
+        sender.send(2);
+        assert!(first.has_changed());
+        block_on(first.changed()).unwrap();
+        assert_eq!(first.borrow(), 2);
+        assert!(!first.has_changed());
+        assert_eq!(second.borrow_and_update(), 2);
+
+        drop(sender);
+        assert_eq!(block_on(second.changed()), Err(Closed));
+    }
+
+    #[test]
+    fn test_machine_subscription() {
+        use crate::generic::Stateful;
+        use crate::{init_state_machine, CallEvent, CallState};
+
+        let mut sm = init_state_machine();
+        let first = sm.subscribe();
+        let second = sm.subscribe();
+        sm.handle_event(&CallEvent::Incoming).unwrap();
+        assert_eq!(first.borrow(), Some(CallState::Ringing));
+        assert!(second.has_changed());
+        assert_eq!(sm.observers.len(), 1);
+    }
+
+    #[test]
+    fn test_unstarted_machine_subscription() {
+        use crate::{CallEvent, CallState};
+
+        let mut sm: StateMachine<CallState, CallEvent, ()> = StateMachine::unstarted(());
+        sm.set_initial_state(CallState::Idle);
+        let mut states = sm.subscribe();
+        assert_eq!(states.borrow_and_update(), None);
+        sm.start().unwrap();
+        assert!(states.has_changed());
+        assert_eq!(states.borrow(), Some(CallState::Idle));
+    }
+}
@@ -0,0 +1,279 @@
+//! Machine-level watchdog for actors that stop making progress.
+//!
+//! One [`Watchdog`] can watch any number of actors, of any machine types. An
+//! actor is stuck once no transition has been committed for its window; its
+//! escalation then runs and the window starts over.
+//!
+//! Escalating never waits on the stuck actor: alerts read the last published
+//! state, and the other escalations jump the actor's queue, cancelling the
+//! command in progress, without waiting for it to yield.
+//!
+//! Windows are measured with the watchdog's [`Clock`], the system's unless set
+//! with [`with_clock`](Watchdog::with_clock).
+
+use crate::actor::ActorHandle;
+use crate::clock::{Clock, SystemClock};
+use crate::generic::{Event, State, StateMachine};
+use crate::snapshot::Snapshot;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type AlertCallback<S> = Box<dyn Fn(&S, Duration) + Send>;
+pub type SnapshotSink<S, C> = Box<dyn Fn(Snapshot<S, C>) + Send>;
+
+/// What the watchdog does about a stuck machine.
+pub enum Escalation<S, E, C> {
+    /// Reports the current state and how long it has been stuck.
+    Alert(AlertCallback<S>),
+    /// Moves the machine to `to`, typically an error state, as if a handler
+    /// had returned it for `event`: exit, transition and entry hooks and
+    /// observers run, then any eventless transitions. Handlers do not.
+    ForceTransition { to: S, event: E },
+    /// Hands a snapshot to the callback and aborts the actor.
+    SnapshotAndAbort(SnapshotSink<S, C>),
+}
+
+struct Entry {
+    last_transition: Arc<Mutex<Instant>>,
+    window: Duration,
+    /// Returns whether the actor should still be watched.
+    escalate: Box<dyn FnMut(Duration) -> bool + Send>,
+}
+
+#[derive(Clone)]
+pub struct Watchdog {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            entries: Arc::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Measures windows with `clock`; set it before watching anything.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Starts watching `handle`'s machine, which is stuck once `window` passes
+    /// without a transition. The watchdog keeps a handle, so the actor runs for
+    /// as long as it is watched.
+    pub fn watch<S, E, C, O>(
+        &self,
+        handle: &ActorHandle<S, E, C, O>,
+        window: Duration,
+        escalation: Escalation<S, E, C>,
+    ) where
+        S: State + Send + 'static,
+        E: Event + Send + 'static,
+        C: Clone + Send + 'static,
+        O: Default + Send + 'static,
+    {
+        let last_transition = Arc::new(Mutex::new(self.clock.now()));
+        let stamp = last_transition.clone();
+        let clock = self.clock.clone();
+        // Waiting makes sure no transition after `watch` returns goes unnoticed.
+        let _ = handle
+            .with(move |machine| {
+                machine.add_observer(move |_, _, _| *stamp.lock().unwrap() = clock.now());
+            })
+            .recv();
+
+        let handle = handle.clone();
+        let mut escalation = escalation;
+        let escalate = move |stuck_for: Duration| -> bool {
+            match &mut escalation {
+                Escalation::Alert(alert) => {
+                    if let Some(state) = handle.subscribe().borrow() {
+                        alert(&state, stuck_for);
+                    }
+                }
+                Escalation::ForceTransition { to, event } => {
+                    let (to, event) = (to.clone(), event.clone());
+                    drop(
+                        handle.with_urgent(move |machine: &mut StateMachine<S, E, C, O>| {
+                            machine.commit(to, &event);
+                            machine.run_to_completion(&event)
+                        }),
+                    );
+                }
+                Escalation::SnapshotAndAbort(save) => {
+                    // Runs once: the actor is no longer watched afterwards.
+                    let save = std::mem::replace(save, Box::new(|_| {}));
+                    handle.finish_urgently(move |machine| {
+                        if let Ok(snapshot) = machine.snapshot() {
+                            save(snapshot);
+                        }
+                    });
+                    return false;
+                }
+            }
+            !handle.is_stopped()
+        };
+
+        self.entries.lock().unwrap().push(Entry {
+            last_transition,
+            window,
+            escalate: Box::new(escalate),
+        });
+    }
+
+    /// Escalates every machine stuck as of now, returning how many were.
+    pub fn check(&self) -> usize {
+        let now = self.clock.now();
+        // Escalating outside the lock lets `watch` and other checks proceed.
+        let overdue: Vec<(Entry, Duration)> = {
+            let mut entries = self.entries.lock().unwrap();
+            let (overdue, fine) = entries.drain(..).partition::<Vec<_>, _>(|entry| {
+                let last = *entry.last_transition.lock().unwrap();
+                now.saturating_duration_since(last) >= entry.window
+            });
+            *entries = fine;
+            overdue
+                .into_iter()
+                .map(|entry| {
+                    let last = *entry.last_transition.lock().unwrap();
+                    *entry.last_transition.lock().unwrap() = now;
+                    (entry, now.saturating_duration_since(last))
+                })
+                .collect()
+        };
+        let stuck = overdue.len();
+        let kept: Vec<Entry> = overdue
+            .into_iter()
+            .filter_map(|(mut entry, stuck_for)| (entry.escalate)(stuck_for).then_some(entry))
+            .collect();
+        self.entries.lock().unwrap().extend(kept);
+        stuck
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks every `interval` on a background thread, for as long as the
+    /// process runs.
+    pub fn spawn(self, interval: Duration) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            self.check();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::generic::Response;
+    use crate::request::RequestError;
+    use crate::{init_state_machine, CallEvent, CallState};
+    use std::sync::mpsc::channel;
+
+    const WINDOW: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn test_force_transition_after_window() {
+        let (exited, exits) = channel();
+        let exited = Mutex::new(exited);
+        let mut sm = init_state_machine();
+        sm.add_exit_hook(CallState::Dialing, move |_, from, _, _| {
+            exited.lock().unwrap().send(from.clone()).unwrap()
+        });
+        let handle = ActorHandle::spawn(sm);
+        let clock = ManualClock::new();
+        let watchdog = Watchdog::new().with_clock(clock.clone());
+        watchdog.watch(
+            &handle,
+            WINDOW,
+            Escalation::ForceTransition {
+                to: CallState::Disconnected,
+                event: CallEvent::HangUp,
+            },
+        );
+        handle.dispatch(CallEvent::Dial).recv().unwrap().unwrap();
+
+        assert_eq!(watchdog.check(), 0);
+        assert_eq!(handle.state().unwrap(), CallState::Dialing);
+        clock.advance(WINDOW);
+        assert_eq!(watchdog.check(), 1);
+        assert_eq!(handle.state().unwrap(), CallState::Disconnected);
+        assert_eq!(exits.recv().unwrap(), CallState::Dialing);
+    }
+
+    #[test]
+    fn test_stuck_actor_does_not_block_checks() {
+        let (release, stuck) = channel::<()>();
+        let stuck = Mutex::new(stuck);
+        let mut sm = init_state_machine();
+        sm.add_transition(CallState::Idle, CallEvent::Dial, move |_, _| {
+            let _ = stuck.lock().unwrap().recv();
+            Ok(Response::Transition(CallState::Dialing))
+        });
+        let handle = ActorHandle::spawn(sm);
+        let (alerts, alerted) = channel();
+        let clock = ManualClock::new();
+        let watchdog = Watchdog::new().with_clock(clock.clone());
+        watchdog.watch(
+            &handle,
+            WINDOW,
+            Escalation::Alert(Box::new(move |state, _| {
+                alerts.send(state.clone()).unwrap()
+            })),
+        );
+        handle.send(CallEvent::Dial);
+
+        clock.advance(WINDOW);
+        assert_eq!(watchdog.check(), 1);
+        assert_eq!(alerted.recv().unwrap(), CallState::Idle);
+        release.send(()).unwrap();
+        assert_eq!(handle.state().unwrap(), CallState::Dialing);
+    }
+
+    #[test]
+    fn test_alert_and_abort() {
+        let (alerts, alerted) = channel();
+        let (snapshots, saved) = channel();
+        let alerting = ActorHandle::spawn(init_state_machine());
+        let aborting = ActorHandle::spawn(init_state_machine());
+        let clock = ManualClock::new();
+        let watchdog = Watchdog::new().with_clock(clock.clone());
+        watchdog.watch(
+            &alerting,
+            WINDOW,
+            Escalation::Alert(Box::new(move |state, _| {
+                alerts.send(state.clone()).unwrap()
+            })),
+        );
+        watchdog.watch(
+            &aborting,
+            WINDOW,
+            Escalation::SnapshotAndAbort(Box::new(move |snapshot| {
+                snapshots.send(snapshot.state).unwrap()
+            })),
+        );
+
+        clock.advance(WINDOW);
+        assert_eq!(watchdog.check(), 2);
+        assert_eq!(alerted.recv().unwrap(), CallState::Idle);
+        assert_eq!(saved.recv().unwrap(), CallState::Idle);
+        assert!(matches!(aborting.state(), Err(RequestError::Disconnected)));
+        assert_eq!(watchdog.len(), 1);
+    }
+}
@@ -0,0 +1,18 @@
+//! Compiles a module generated by `fsmportal::codegen`; the unit tests keep
+//! the fixture in step with the generator.
+
+mod generated {
+    include!("fixtures/light.rs");
+}
+
+use generated::{light, Event, Light};
+
+#[test]
+fn test_generated_machine_runs() {
+    let mut sm = light(());
+    sm.dispatch(&Event::Toggle).unwrap();
+    sm.dispatch(&"Dim".parse().unwrap()).unwrap();
+    assert_eq!(sm.get_current_state().unwrap(), &Light::Dimmed);
+    assert!(sm.dispatch(&Event::Dim).is_err());
+    assert_eq!("Bright".parse::<Light>(), Err(()));
+}
@@ -0,0 +1,48 @@
+// Generated by fsmportal::codegen. Do not edit.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Light {
+    Dimmed,
+    Off,
+    On,
+}
+
+impl ::std::str::FromStr for Light {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, ()> {
+        match s {
+            "Dimmed" => Ok(Light::Dimmed),
+            "Off" => Ok(Light::Off),
+            "On" => Ok(Light::On),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Event {
+    Dim,
+    Toggle,
+}
+
+impl ::std::str::FromStr for Event {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, ()> {
+        match s {
+            "Dim" => Ok(Event::Dim),
+            "Toggle" => Ok(Event::Toggle),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn light<C>(context: C) -> ::fsmportal::generic::StateMachine<Light, Event, C> {
+    let mut sm = ::fsmportal::generic::StateMachine::with_capacity(Light::Off, context, 4);
+    sm.add_transition_to(Light::Off, Event::Toggle, Light::On);
+    sm.add_transition_to(Light::On, Event::Toggle, Light::Off);
+    sm.add_transition_to(Light::On, Event::Dim, Light::Dimmed);
+    sm.add_transition_to(Light::Dimmed, Event::Toggle, Light::Off);
+    sm
+}